@@ -0,0 +1,70 @@
+//! Time the dispatcher takes to deliver a storm of session change events, such as the lock and
+//! unlock events of a terminal server, with and without coalescing:
+//!
+//! ```text
+//! cargo bench --features test-util --bench session_storm
+//! ```
+
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use ceviche::channel::EventPolicy;
+use ceviche::test::{session, ScriptedRun};
+use ceviche::ServiceEvent;
+
+const EVENTS: usize = 100_000;
+const SESSIONS: u32 = 4;
+
+// Each session locks and unlocks in turn, every event is repeated as the session monitors do
+// when several notifications report the same change.
+fn storm() -> impl Iterator<Item = ServiceEvent<()>> {
+    (0..EVENTS).map(|index| {
+        let session = session(index as u32 / 4 % SESSIONS);
+        match index / 2 % 2 {
+            0 => ServiceEvent::SessionLock(session),
+            _ => ServiceEvent::SessionUnlock(session),
+        }
+    })
+}
+
+fn run(name: &str, policy: EventPolicy) {
+    let mut run = storm().fold(ScriptedRun::new("storm"), |run, event| {
+        run.after(Duration::ZERO, event)
+    });
+    run = run
+        .with_event_policy(policy)
+        .after(Duration::ZERO, ServiceEvent::Custom(()));
+    run.timeout = Duration::from_secs(60);
+
+    let start = Instant::now();
+    let outcome = run.run(|rx: mpsc::Receiver<ServiceEvent<()>>, _tx, _ctx| {
+        let mut received = 0;
+        for event in rx {
+            if let ServiceEvent::Custom(()) = event {
+                break;
+            }
+            received += 1;
+        }
+        received
+    });
+    let elapsed = start.elapsed();
+    println!(
+        "{:<24} {:>8} events in {:>10.3?}, {:>6} ns/event, {} received",
+        name,
+        outcome.events_sent,
+        elapsed,
+        elapsed.as_nanos() / outcome.events_sent as u128,
+        outcome.exit_code.unwrap_or_default()
+    );
+}
+
+fn main() {
+    run("unbounded", EventPolicy::new());
+    run(
+        "unbounded, coalesced",
+        EventPolicy {
+            coalesce_session_events: true,
+            ..EventPolicy::new()
+        },
+    );
+}
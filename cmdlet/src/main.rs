@@ -10,8 +10,9 @@ extern crate base64;
 use std::env;
 use std::sync::mpsc;
 
+use ceviche::context::ServiceContext;
 use ceviche::controller::*;
-use ceviche::{Service, ServiceEvent};
+use ceviche::{Service, ServiceEvent, StopReason};
 
 use log::LevelFilter;
 use log4rs::append::console::ConsoleAppender;
@@ -65,13 +66,12 @@ fn init_logging(service: &CmdletService, standalone_mode: bool) -> Option<()> {
 fn cmdlet_service_main(
     rx: mpsc::Receiver<ServiceEvent<CustomServiceEvent>>,
     _tx: mpsc::Sender<ServiceEvent<CustomServiceEvent>>,
-    args: Vec<String>,
-    standalone_mode: bool,
+    ctx: ServiceContext,
 ) -> u32 {
     let service = CmdletService::load().expect("unable to load service manifest");
-    init_logging(&service, standalone_mode);
+    init_logging(&service, ctx.is_standalone());
     info!("{} service started", service.get_service_name());
-    info!("args: {:?}", args);
+    info!("args: {:?}", ctx.args());
 
     service.start();
 
@@ -79,7 +79,7 @@ fn cmdlet_service_main(
         if let Ok(control_code) = rx.recv() {
             info!("Received control code: {}", control_code);
             match control_code {
-                ServiceEvent::Stop => {
+                ServiceEvent::Stop { .. } => {
                     service.stop();
                     break
                 }
@@ -126,10 +126,10 @@ fn main() {
                 let _tx = tx.clone();
         
                 ctrlc::set_handler(move || {
-                    let _ = tx.send(ServiceEvent::Stop);
+                    let _ = tx.send(ServiceEvent::Stop { reason: Some(StopReason::Requested) });
                 }).expect("Failed to register Ctrl-C handler");
         
-                cmdlet_service_main(rx, _tx, vec![], true);
+                cmdlet_service_main(rx, _tx, ServiceContext::standalone(service.get_service_name(), vec![]));
             }
             _ => {
                 println!("invalid command: {}", cmd);
@@ -3,10 +3,13 @@ extern crate clap;
 #[macro_use]
 extern crate log;
 
+use std::process;
 use std::sync::mpsc;
+use std::time::Duration;
 
+use ceviche::context::ServiceContext;
 use ceviche::controller::*;
-use ceviche::{Service, ServiceEvent};
+use ceviche::{standalone, Service, ServiceEvent};
 use clap::App;
 use log::LevelFilter;
 use log4rs::append::console::ConsoleAppender;
@@ -60,18 +63,17 @@ fn init_logging(standalone_mode: bool) -> Option<()> {
 fn my_service_main(
     rx: mpsc::Receiver<ServiceEvent<CustomServiceEvent>>,
     _tx: mpsc::Sender<ServiceEvent<CustomServiceEvent>>,
-    args: Vec<String>,
-    standalone_mode: bool,
+    ctx: ServiceContext,
 ) -> u32 {
-    init_logging(standalone_mode);
+    init_logging(ctx.is_standalone());
     info!("foobar service started");
-    info!("args: {:?}", args);
+    info!("args: {:?}", ctx.args());
 
     loop {
         if let Ok(control_code) = rx.recv() {
             info!("Received control code: {}", control_code);
             match control_code {
-                ServiceEvent::Stop => break,
+                ServiceEvent::Stop { .. } => break,
                 _ => (),
             }
         }
@@ -83,44 +85,37 @@ fn my_service_main(
 
 Service!("Foobar", my_service_main);
 
+fn print_result(result: ceviche::Result<()>, json: bool) {
+    if json {
+        println!("{}", ceviche::result_to_json(&result));
+    } else if let Err(e) = result {
+        println!("{}", e);
+    }
+}
+
 fn main() {
     let yaml = load_yaml!("cli.yml");
     let app = App::from_yaml(yaml);
     let matches = app.version(crate_version!()).get_matches();
     let cmd = matches.value_of("cmd").unwrap_or("").to_string();
+    let json = matches.is_present("json");
+
+    if matches.is_present("self-test") {
+        let seconds = matches.value_of("self-test").unwrap_or("5");
+        let run_for = Duration::from_secs(seconds.parse().expect("invalid self-test duration"));
+        let exit_code = standalone::self_test(SERVICE_NAME, vec![], run_for, my_service_main);
+        process::exit(exit_code as i32);
+    }
 
     let mut controller = Controller::new(SERVICE_NAME, DISPLAY_NAME, DESCRIPTION);
 
     match cmd.as_str() {
-        "create" => {
-            if let Err(e) = controller.create() {
-                println!("{}", e);
-            }
-        }
-        "delete" => {
-            if let Err(e) = controller.delete() {
-                println!("{}", e);
-            }
-        }
-        "start" => {
-            if let Err(e) = controller.start() {
-                println!("{}", e);
-            }
-        }
-        "stop" => {
-            if let Err(e) = controller.stop() {
-                println!("{}", e);
-            }
-        }
+        "create" => print_result(controller.create(), json),
+        "delete" => print_result(controller.delete(), json),
+        "start" => print_result(controller.start(), json),
+        "stop" => print_result(controller.stop(), json),
         "standalone" => {
-            let (tx, rx) = mpsc::channel();
-            let _tx = tx.clone();
-
-            ctrlc::set_handler(move || {
-                let _ = tx.send(ServiceEvent::Stop);
-            }).expect("Failed to register Ctrl-C handler");
-
-            my_service_main(rx, _tx, vec![], true);
+            standalone::run(SERVICE_NAME, vec![], my_service_main);
         }
         _ => {
             let _result = controller.register(service_main_wrapper);
@@ -0,0 +1,578 @@
+// The controller of the unsupported platforms doesn't record its operations.
+#![cfg_attr(
+    not(any(
+        windows,
+        target_os = "macos",
+        target_os = "linux",
+        target_os = "illumos",
+        target_os = "solaris",
+        target_os = "aix"
+    )),
+    allow(dead_code)
+)]
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use crate::Error;
+
+/// Hash chaining the first entry of a log.
+const GENESIS: [u8; 32] = [0; 32];
+
+#[derive(Debug)]
+enum Sink {
+    File(PathBuf),
+    EventLog(String),
+}
+
+/// Append-only log of the operations of a controller, in which each entry records when the
+/// operation ran, who ran it and its result. Every entry carries the SHA-256 hash of its
+/// content and of the hash of the previous entry, so that `verify()` detects an entry that was
+/// modified or removed before the last one. The chain isn't keyed: `verify()` doesn't detect
+/// the entries removed from the end of the log, or a log rewritten with the hashes recomputed.
+/// Set it as the `audit_log` of a controller:
+///
+/// ```rust,ignore
+/// controller.audit_log = Some(AuditLog::file("C:\\ProgramData\\Foobar\\audit.log"));
+/// ```
+///
+/// The clones share the chain. Dry runs aren't recorded and a failure to write an entry is
+/// logged without failing the operation.
+#[derive(Clone, Debug)]
+pub struct AuditLog {
+    sink: Arc<Sink>,
+    // Hash of the last entry written to the event log, the chain starts at the first entry
+    // written by the process. The chain of a file is read back from the file.
+    last_hash: Arc<Mutex<[u8; 32]>>,
+}
+
+impl AuditLog {
+    /// Log appending a line per entry to the file `path`, the chain continues the entries
+    /// already in the file.
+    pub fn file(path: impl Into<PathBuf>) -> AuditLog {
+        AuditLog::with_sink(Sink::File(path.into()))
+    }
+
+    /// Log writing its entries to the Application event log under the source `source` on
+    /// Windows, to syslog with the identity `source` elsewhere.
+    pub fn event_log(source: &str) -> AuditLog {
+        AuditLog::with_sink(Sink::EventLog(source.to_string()))
+    }
+
+    fn with_sink(sink: Sink) -> AuditLog {
+        AuditLog {
+            sink: Arc::new(sink),
+            last_hash: Arc::new(Mutex::new(GENESIS)),
+        }
+    }
+
+    /// Checks the chain of the log file `path` and returns the number of entries, fails on
+    /// the first entry whose hash doesn't match.
+    pub fn verify(path: impl AsRef<Path>) -> Result<usize, Error> {
+        let file =
+            File::open(path.as_ref()).map_err(|e| Error::io("Failed to open the audit log", &e))?;
+        let mut previous = GENESIS;
+        let mut count = 0;
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| Error::io("Failed to read the audit log", &e))?;
+            let (content, hash) = line.rsplit_once('\t').unwrap_or((&line, ""));
+            if hash != to_hex(&chain(&previous, content)) {
+                return Err(Error::new(&format!(
+                    "Entry {} of the audit log was modified or the entry before was removed",
+                    count + 1
+                )));
+            }
+            previous = from_hex(hash).unwrap_or(GENESIS);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Records the operation `operation` on the service `service_name` and its `result`.
+    fn record<T>(&self, service_name: &str, operation: &str, result: &Result<T, Error>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let outcome = match result {
+            Ok(_) => "ok".to_string(),
+            Err(e) => format!("failed: {}", e),
+        };
+        let content = [
+            timestamp.to_string(),
+            current_user(),
+            service_name.to_string(),
+            operation.to_string(),
+            outcome,
+        ]
+        .map(|field| field.replace(['\t', '\r', '\n'], " "))
+        .join("\t");
+
+        let mut last_hash = self.last_hash.lock().unwrap();
+        let written = match &*self.sink {
+            Sink::File(path) => append_to_file(path, &content),
+            Sink::EventLog(source) => {
+                let hash = chain(&last_hash, &content);
+                report_event(source, &format!("{}\t{}", content, to_hex(&hash)))
+                    .map(|()| *last_hash = hash)
+            }
+        };
+        if let Err(e) = written {
+            warn!("Failed to write the audit log entry \"{}\": {}", content, e);
+        }
+    }
+}
+
+/// Audit log and service of a controller, cloned before the operation borrows the
+/// controller.
+pub(crate) struct Target {
+    log: AuditLog,
+    service_name: String,
+}
+
+impl Target {
+    pub(crate) fn record<T>(self, operation: &str, result: &Result<T, Error>) {
+        self.log.record(&self.service_name, operation, result);
+    }
+}
+
+pub(crate) fn target(log: &Option<AuditLog>, service_name: &str) -> Option<Target> {
+    log.as_ref().map(|log| Target {
+        log: log.clone(),
+        service_name: service_name.to_string(),
+    })
+}
+
+fn append_to_file(path: &Path, content: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+    // The last entry is read back so that the processes sharing the file share the chain, the
+    // file stays locked until the entry chained to it is written.
+    lock(&file)?;
+    let previous = BufReader::new(&file)
+        .lines()
+        .map_while(Result::ok)
+        .last()
+        .and_then(|line| from_hex(line.rsplit_once('\t')?.1))
+        .unwrap_or(GENESIS);
+    let written = writeln!(file, "{}\t{}", content, to_hex(&chain(&previous, content)))
+        .and_then(|()| file.sync_data());
+    unlock(&file)?;
+    written
+}
+
+#[cfg(windows)]
+fn lock(file: &File) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use winapi::um::fileapi::LockFileEx;
+    use winapi::um::minwinbase::LOCKFILE_EXCLUSIVE_LOCK;
+
+    let mut overlapped = unsafe { std::mem::zeroed() };
+    match unsafe {
+        LockFileEx(
+            file.as_raw_handle() as _,
+            LOCKFILE_EXCLUSIVE_LOCK,
+            0,
+            u32::MAX,
+            u32::MAX,
+            &mut overlapped,
+        )
+    } {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(windows)]
+fn unlock(file: &File) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use winapi::um::fileapi::UnlockFileEx;
+
+    let mut overlapped = unsafe { std::mem::zeroed() };
+    match unsafe {
+        UnlockFileEx(
+            file.as_raw_handle() as _,
+            0,
+            u32::MAX,
+            u32::MAX,
+            &mut overlapped,
+        )
+    } {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+// Solaris has no `flock`, `lockf` takes a POSIX record lock from the offset, which is still
+// the start of the file.
+#[cfg(all(unix, not(target_os = "solaris")))]
+fn lock(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(all(unix, not(target_os = "solaris")))]
+fn unlock(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(target_os = "solaris")]
+fn lock(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    match unsafe { libc::lockf(file.as_raw_fd(), libc::F_LOCK, 0) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(target_os = "solaris")]
+fn unlock(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    match unsafe { libc::lockf(file.as_raw_fd(), libc::F_ULOCK, 0) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+// The platforms without file locks only have the lock of the chain within the process.
+#[cfg(not(any(windows, unix)))]
+fn lock(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(any(windows, unix)))]
+fn unlock(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+fn chain(previous: &[u8; 32], content: &str) -> [u8; 32] {
+    let mut message = previous.to_vec();
+    message.extend_from_slice(content.as_bytes());
+    sha256(&message)
+}
+
+fn to_hex(hash: &[u8; 32]) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut hash = [0; 32];
+    for (index, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).ok()?;
+    }
+    Some(hash)
+}
+
+#[cfg(windows)]
+fn current_user() -> String {
+    use winapi::um::winbase::GetUserNameW;
+
+    let mut name = [0u16; 257];
+    let mut size = name.len() as u32;
+    if unsafe { GetUserNameW(name.as_mut_ptr(), &mut size) } == 0 || size == 0 {
+        return "unknown".to_string();
+    }
+    // The size includes the terminating nul character.
+    String::from_utf16_lossy(&name[..size as usize - 1])
+}
+
+#[cfg(unix)]
+fn current_user() -> String {
+    let uid = unsafe { libc::geteuid() };
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    let status = unsafe {
+        libc::getpwuid_r(
+            uid,
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    if status != 0 || found.is_null() {
+        return format!("uid {}", uid);
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) };
+    format!("{} (uid {})", name.to_string_lossy(), uid)
+}
+
+#[cfg(not(any(windows, unix)))]
+fn current_user() -> String {
+    "unknown".to_string()
+}
+
+#[cfg(windows)]
+fn report_event(source: &str, message: &str) -> io::Result<()> {
+    use std::ptr;
+    use winapi::um::winbase::{DeregisterEventSource, RegisterEventSourceW, ReportEventW};
+    use winapi::um::winnt::EVENTLOG_INFORMATION_TYPE;
+
+    let source = widestring::WideCString::from_str(source)
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let message = widestring::WideCString::from_str(message)
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    unsafe {
+        let handle = RegisterEventSourceW(ptr::null(), source.as_ptr());
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let strings = [message.as_ptr()];
+        let reported = ReportEventW(
+            handle,
+            EVENTLOG_INFORMATION_TYPE,
+            0,
+            0,
+            ptr::null_mut(),
+            1,
+            0,
+            strings.as_ptr() as *mut _,
+            ptr::null_mut(),
+        );
+        let result = match reported {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        };
+        DeregisterEventSource(handle);
+        result
+    }
+}
+
+#[cfg(unix)]
+fn report_event(source: &str, message: &str) -> io::Result<()> {
+    use std::ffi::CString;
+
+    let source = CString::new(source).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let message =
+        CString::new(message).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    // The source is part of the message, openlog() would keep a pointer to it.
+    unsafe {
+        libc::syslog(
+            libc::LOG_AUTH | libc::LOG_NOTICE,
+            b"%s: %s\0".as_ptr() as *const libc::c_char,
+            source.as_ptr(),
+            message.as_ptr(),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(any(windows, unix)))]
+fn report_event(_source: &str, _message: &str) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+// SHA-256 as specified by FIPS 180-4.
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+fn sha256(message: &[u8]) -> [u8; 32] {
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+    let mut padded = message.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((message.len() as u64) * 8).to_be_bytes());
+
+    for block in padded.chunks(64) {
+        let mut w = [0u32; 64];
+        for (index, word) in block.chunks(4).enumerate() {
+            w[index] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for index in 16..64 {
+            let s0 = w[index - 15].rotate_right(7)
+                ^ w[index - 15].rotate_right(18)
+                ^ (w[index - 15] >> 3);
+            let s1 = w[index - 2].rotate_right(17)
+                ^ w[index - 2].rotate_right(19)
+                ^ (w[index - 2] >> 10);
+            w[index] = w[index - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[index - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for index in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[index])
+                .wrapping_add(w[index]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (value, added) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *value = value.wrapping_add(added);
+        }
+    }
+
+    let mut digest = [0; 32];
+    for (bytes, value) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn sha256_hex(message: &[u8]) -> String {
+        to_hex(&sha256(message))
+    }
+
+    #[test]
+    fn sha256_matches_the_fips_180_4_examples() {
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn sha256_hashes_multi_block_messages() {
+        assert_eq!(
+            sha256_hex(
+                b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmn\
+                  hijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu"
+            ),
+            "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1"
+        );
+        assert_eq!(
+            sha256_hex(&vec![b'a'; 1_000_000]),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        let hash = sha256(b"abc");
+        assert_eq!(from_hex(&to_hex(&hash)), Some(hash));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex(&"g".repeat(64)), None);
+    }
+
+    // Log file with three entries, removed when dropped.
+    struct TestLog(PathBuf);
+
+    impl TestLog {
+        fn new(name: &str) -> TestLog {
+            let path = std::env::temp_dir().join(format!(
+                "ceviche-audit-{}-{}.log",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_file(&path);
+            let log = AuditLog::file(&path);
+            log.record("foobar", "create", &Ok::<(), Error>(()));
+            log.record("foobar", "start", &Err::<(), Error>(Error::new("denied")));
+            log.record("foobar", "stop", &Ok::<(), Error>(()));
+            TestLog(path)
+        }
+
+        fn lines(&self) -> Vec<String> {
+            fs::read_to_string(&self.0)
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect()
+        }
+
+        fn rewrite(&self, lines: &[String]) {
+            fs::write(&self.0, lines.join("\n") + "\n").unwrap();
+        }
+    }
+
+    impl Drop for TestLog {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn verify_counts_the_chained_entries() {
+        let log = TestLog::new("chained");
+        assert_eq!(AuditLog::verify(&log.0).unwrap(), 3);
+        let lines = log.lines();
+        assert!(lines[1].contains("\tfoobar\tstart\tfailed: denied\t"));
+
+        // The entries appended by another log continue the chain of the file.
+        AuditLog::file(&log.0).record("foobar", "delete", &Ok::<(), Error>(()));
+        assert_eq!(AuditLog::verify(&log.0).unwrap(), 4);
+    }
+
+    #[test]
+    fn verify_detects_a_modified_entry() {
+        let log = TestLog::new("modified");
+        let mut lines = log.lines();
+        lines[1] = lines[1].replace("failed: denied", "ok");
+        log.rewrite(&lines);
+        let e = AuditLog::verify(&log.0).unwrap_err();
+        assert!(e.to_string().contains("Entry 2 "), "{}", e);
+    }
+
+    #[test]
+    fn verify_detects_a_removed_entry() {
+        let log = TestLog::new("removed");
+        let mut lines = log.lines();
+        lines.remove(1);
+        log.rewrite(&lines);
+        let e = AuditLog::verify(&log.0).unwrap_err();
+        assert!(e.to_string().contains("Entry 2 "), "{}", e);
+    }
+}
@@ -0,0 +1,296 @@
+// The dummy controller has no dispatcher, only the public configuration is used there.
+#![cfg_attr(
+    not(any(
+        windows,
+        target_os = "macos",
+        target_os = "linux",
+        target_os = "illumos",
+        target_os = "solaris",
+        target_os = "aix"
+    )),
+    allow(dead_code)
+)]
+
+use std::mem::{self, Discriminant};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+use crate::deadline;
+use crate::ring::Ring;
+use crate::shutdown;
+use crate::ServiceEvent;
+
+static DROPPED_EVENTS: AtomicUsize = AtomicUsize::new(0);
+static EVENT_POLICY: Mutex<EventPolicy> = Mutex::new(EventPolicy::new());
+
+/// Controls how the events sent by the system are queued for the service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EventPolicy {
+    /// Maximum number of events waiting to be received by the service, `None` for an unbounded
+    /// queue. Events sent by the system while the queue is full are dropped and counted by
+    /// `dropped_events()`; custom events sent by the service on its `tx` are never dropped,
+    /// nor are the control events (`Stop`, `StopRequested`, `StopNow`, `Pause` and
+    /// `Continue`), which bypass the queue and are received before the queued events.
+    /// The bounded queue is a pre-allocated lock-free ring of at least two events, pushing an
+    /// event to it doesn't allocate. Sending an event is not lock-free as a whole though: the
+    /// coalescing of the session events and the recording of a stop request take short
+    /// locks, and the control events are queued on a channel that may allocate.
+    pub capacity: Option<usize>,
+    /// Drops a session event identical to the previous session event sent by the system, such
+    /// as repeated `SessionLock` notifications for the same session. Has no effect without the
+    /// `session-events` feature.
+    pub coalesce_session_events: bool,
+}
+
+impl EventPolicy {
+    pub const fn new() -> Self {
+        EventPolicy {
+            capacity: None,
+            coalesce_session_events: false,
+        }
+    }
+}
+
+impl Default for EventPolicy {
+    fn default() -> Self {
+        EventPolicy::new()
+    }
+}
+
+/// Number of events sent by the system that were dropped because the queue was full.
+pub fn dropped_events() -> usize {
+    DROPPED_EVENTS.load(Ordering::Relaxed)
+}
+
+pub(crate) fn set_event_policy(policy: EventPolicy) {
+    *EVENT_POLICY.lock().unwrap() = policy;
+}
+
+/// Interval at which the thread forwarding the events of the ring checks whether the system
+/// senders were dropped.
+const FORWARD_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+enum Inner<T> {
+    Unbounded(mpsc::Sender<ServiceEvent<T>>),
+    // The forwarder is unparked when an event is pushed, the control events are sent on the
+    // unbounded channel so that they can't be dropped.
+    Bounded(
+        Arc<Ring<ServiceEvent<T>>>,
+        mpsc::Sender<ServiceEvent<T>>,
+        Thread,
+    ),
+}
+
+/// Sends the events produced by the system (control handler, session monitors, signal
+/// handlers) to the service, applying the configured `EventPolicy`.
+pub(crate) struct EventSender<T> {
+    inner: Inner<T>,
+    last_session_event: Option<Arc<Mutex<Option<SessionEventKey<T>>>>>,
+}
+
+// The session is reduced to a number, `Session_::key()`, so that remembering it doesn't copy
+// the names of the logind sessions.
+type SessionEventKey<T> = (Discriminant<ServiceEvent<T>>, u64);
+
+impl<T> Clone for EventSender<T> {
+    fn clone(&self) -> Self {
+        EventSender {
+            inner: match &self.inner {
+                Inner::Unbounded(tx) => Inner::Unbounded(tx.clone()),
+                Inner::Bounded(ring, control, forwarder) => {
+                    Inner::Bounded(ring.clone(), control.clone(), forwarder.clone())
+                }
+            },
+            last_session_event: self.last_session_event.clone(),
+        }
+    }
+}
+
+impl<T> EventSender<T> {
+    pub(crate) fn send(&self, event: ServiceEvent<T>) {
+        self.deliver(event);
+    }
+
+    /// Sends `event` like `send()`, returns false once the dispatcher knows that the service
+    /// dropped its receiver. The events coalesced or dropped by the policy count as delivered.
+    pub(crate) fn deliver(&self, event: ServiceEvent<T>) -> bool {
+        if let ServiceEvent::Stop { .. } | ServiceEvent::StopNow = event {
+            deadline::arm();
+            shutdown::service_token().shutdown();
+        }
+
+        if let Some(last_session_event) = &self.last_session_event {
+            let mut last_session_event = last_session_event.lock().unwrap();
+            if let Some(session) = session_key(&event) {
+                let key = (mem::discriminant(&event), session);
+                if *last_session_event == Some(key) {
+                    return true;
+                }
+                *last_session_event = Some(key);
+            }
+        }
+
+        match &self.inner {
+            Inner::Unbounded(tx) => tx.send(event).is_ok(),
+            Inner::Bounded(_, control, forwarder) if is_control(&event) => {
+                let delivered = control.send(event).is_ok();
+                forwarder.unpark();
+                delivered
+            }
+            Inner::Bounded(ring, _, forwarder) => {
+                match ring.push(event) {
+                    Ok(()) => forwarder.unpark(),
+                    Err(_) => {
+                        DROPPED_EVENTS.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                true
+            }
+        }
+    }
+}
+
+/// Whether the service must receive `event` whatever the capacity of the queue: a lost stop
+/// request or pause would leave the service running or pending forever.
+fn is_control<T>(event: &ServiceEvent<T>) -> bool {
+    matches!(
+        event,
+        ServiceEvent::Stop { .. }
+            | ServiceEvent::StopRequested
+            | ServiceEvent::StopNow
+            | ServiceEvent::Pause
+            | ServiceEvent::Continue
+    )
+}
+
+#[cfg(feature = "session-events")]
+fn session_key<T>(event: &ServiceEvent<T>) -> Option<u64> {
+    match event {
+        ServiceEvent::SessionConnect(session)
+        | ServiceEvent::SessionDisconnect(session)
+        | ServiceEvent::SessionRemoteConnect(session)
+        | ServiceEvent::SessionRemoteDisconnect(session)
+        | ServiceEvent::SessionLogon(session)
+        | ServiceEvent::SessionLogoff(session)
+        | ServiceEvent::SessionLock(session)
+        | ServiceEvent::SessionUnlock(session)
+        | ServiceEvent::SessionIdle(session)
+        | ServiceEvent::SessionActive(session) => Some(session.key()),
+        _ => None,
+    }
+}
+
+#[cfg(not(feature = "session-events"))]
+fn session_key<T>(_event: &ServiceEvent<T>) -> Option<u64> {
+    None
+}
+
+/// Creates the channel between the system and the service main function according to the
+/// event policy configured on the controller. Returns the sender used by the system, the
+/// receiver and the sender that are passed to the service main function.
+#[allow(clippy::type_complexity)]
+pub(crate) fn channel<T: Send + 'static>() -> (
+    EventSender<T>,
+    mpsc::Receiver<ServiceEvent<T>>,
+    mpsc::Sender<ServiceEvent<T>>,
+) {
+    channel_with(*EVENT_POLICY.lock().unwrap())
+}
+
+/// Creates the channel of `channel()` with `policy` instead of the configured one.
+#[allow(clippy::type_complexity)]
+pub(crate) fn channel_with<T: Send + 'static>(
+    policy: EventPolicy,
+) -> (
+    EventSender<T>,
+    mpsc::Receiver<ServiceEvent<T>>,
+    mpsc::Sender<ServiceEvent<T>>,
+) {
+    let last_session_event = if policy.coalesce_session_events {
+        Some(Arc::new(Mutex::new(None)))
+    } else {
+        None
+    };
+
+    match policy.capacity {
+        None => {
+            let (tx, rx) = mpsc::channel();
+            let sender = EventSender {
+                inner: Inner::Unbounded(tx.clone()),
+                last_session_event,
+            };
+            (sender, rx, tx)
+        }
+        Some(capacity) => {
+            // The service receives the events through a rendezvous channel, the ring holds the
+            // queued ones.
+            let ring = Arc::new(Ring::new(capacity));
+            let (control_tx, control_rx) = mpsc::channel();
+            let (sync_tx, rx) = mpsc::sync_channel(0);
+            let forward_tx = sync_tx.clone();
+            let forwarder = {
+                let ring = ring.clone();
+                thread::spawn(move || loop {
+                    match control_rx.try_recv().ok().or_else(|| ring.pop()) {
+                        Some(event) => {
+                            if sync_tx.send(event).is_err() {
+                                break;
+                            }
+                        }
+                        // Only the forwarder is left once the system senders are dropped.
+                        None if Arc::strong_count(&ring) == 1 => break,
+                        None => thread::park_timeout(FORWARD_POLL_INTERVAL),
+                    }
+                })
+            };
+            let (tx, service_rx) = mpsc::channel();
+            thread::spawn(move || {
+                for event in service_rx {
+                    if forward_tx.send(event).is_err() {
+                        break;
+                    }
+                }
+            });
+            let sender = EventSender {
+                inner: Inner::Bounded(ring, control_tx, forwarder.thread().clone()),
+                last_session_event,
+            };
+            (sender, rx, tx)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StopReason;
+
+    #[test]
+    fn control_events_bypass_a_full_queue() {
+        set_event_policy(EventPolicy {
+            capacity: Some(2),
+            ..EventPolicy::new()
+        });
+        let (sender, rx, _tx) = channel::<()>();
+        set_event_policy(EventPolicy::new());
+        let dropped = dropped_events();
+        for _ in 0..10 {
+            sender.send(ServiceEvent::LowResources);
+        }
+        sender.send(ServiceEvent::Stop {
+            reason: Some(StopReason::Requested),
+        });
+
+        let mut received_stop = false;
+        while let Ok(event) = rx.recv_timeout(Duration::from_secs(5)) {
+            if let ServiceEvent::Stop { .. } = event {
+                received_stop = true;
+                break;
+            }
+        }
+        assert!(received_stop);
+        assert!(dropped_events() > dropped);
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Interval at which `Clock::sleep_until()` checks a clock that doesn't follow the real time.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// Clock of the stop grace period, the stop deadline and the startup gates, see
+// `test::set_clock()`.
+static CLOCK: Mutex<Option<Arc<dyn Clock>>> = Mutex::new(None);
+
+/// Time source of the delays of a `ScriptedRun` and of the timers of the crate. A service
+/// whose timers take the clock too, instead of calling `thread::sleep()`, is tested without
+/// waiting with a `FakeClock`.
+// Only the scripted runs let time pass themselves, the timers wait for it.
+#[cfg_attr(not(any(test, feature = "test-util")), allow(dead_code))]
+pub trait Clock: Send + Sync {
+    /// Time elapsed since the clock was created.
+    fn elapsed(&self) -> Duration;
+    /// Lets `duration` pass.
+    fn sleep(&self, duration: Duration);
+    /// Waits until `elapsed()` reaches `deadline` without moving the clock, so that a timer
+    /// on a `FakeClock` fires once the test advanced it. Polls the clock by default.
+    fn sleep_until(&self, deadline: Duration) {
+        while self.elapsed() < deadline {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Clock following the real time.
+#[derive(Clone, Copy, Debug)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    fn sleep_until(&self, deadline: Duration) {
+        thread::sleep(deadline.saturating_sub(self.elapsed()));
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+pub(crate) fn set_clock(clock: Arc<dyn Clock>) {
+    *CLOCK.lock().unwrap() = Some(clock);
+}
+
+/// Clock of the timers, a `SystemClock` unless a test set another one.
+pub(crate) fn clock() -> Arc<dyn Clock> {
+    CLOCK
+        .lock()
+        .unwrap()
+        .get_or_insert_with(|| Arc::new(SystemClock::new()))
+        .clone()
+}
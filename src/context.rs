@@ -0,0 +1,320 @@
+// The dummy controller doesn't install anything, the directories are never created there.
+#![cfg_attr(
+    not(any(
+        windows,
+        target_os = "macos",
+        target_os = "linux",
+        target_os = "illumos",
+        target_os = "solaris",
+        target_os = "aix"
+    )),
+    allow(dead_code)
+)]
+
+#[cfg(target_os = "macos")]
+use std::env;
+use std::fs;
+#[cfg(windows)]
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+#[cfg(windows)]
+use std::ptr;
+use std::time::Duration;
+
+#[cfg(windows)]
+use winapi::shared::minwindef::{BOOL, DWORD};
+#[cfg(windows)]
+use winapi::um::winbase::LocalFree;
+#[cfg(windows)]
+use winapi::um::winnt::PVOID;
+#[cfg(windows)]
+use winapi::um::winsvc::SERVICE_STATUS_HANDLE;
+
+use crate::logging::LoggerHandle;
+use crate::pause;
+use crate::restart;
+use crate::shutdown::{self, ShutdownToken};
+use crate::startup_gate;
+use crate::stop;
+use crate::Error;
+
+/// Information about the running service, passed to the service main function.
+#[derive(Clone, Debug)]
+pub struct ServiceContext {
+    service_name: String,
+    args: Vec<String>,
+    standalone_mode: bool,
+    data_dir: PathBuf,
+    log_dir: PathBuf,
+    logger: Option<LoggerHandle>,
+    #[cfg(windows)]
+    status_handle: SERVICE_STATUS_HANDLE,
+}
+
+// `status_handle` is an opaque SCM handle that is never dereferenced and can be used from any
+// thread, the other fields are plain data.
+#[cfg(windows)]
+unsafe impl Send for ServiceContext {}
+#[cfg(windows)]
+unsafe impl Sync for ServiceContext {}
+
+impl ServiceContext {
+    pub(crate) fn new(service_name: &str, args: Vec<String>) -> ServiceContext {
+        ServiceContext {
+            service_name: service_name.to_string(),
+            args,
+            standalone_mode: false,
+            data_dir: data_dir(service_name),
+            log_dir: log_dir(service_name),
+            logger: None,
+            #[cfg(windows)]
+            status_handle: ptr::null_mut(),
+        }
+    }
+
+    /// Context used to call the service main function directly, outside of the system
+    /// service support.
+    pub fn standalone(service_name: &str, args: Vec<String>) -> ServiceContext {
+        ServiceContext {
+            standalone_mode: true,
+            ..ServiceContext::new(service_name, args)
+        }
+    }
+
+    pub(crate) fn set_logger(&mut self, logger: LoggerHandle) {
+        self.logger = Some(logger);
+    }
+
+    #[cfg(windows)]
+    pub(crate) fn with_status_handle(mut self, status_handle: SERVICE_STATUS_HANDLE) -> Self {
+        self.status_handle = status_handle;
+        self
+    }
+
+    /// Name the service was started as. On Windows it is the name the SCM passed to the
+    /// service main function, a binary installed as several services learns which one runs.
+    pub fn service_name(&self) -> &str {
+        &self.service_name
+    }
+
+    /// Arguments that were passed to the service.
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn into_args(self) -> Vec<String> {
+        self.args
+    }
+
+    /// True when the service main function is called directly, outside of the system service
+    /// support.
+    pub fn is_standalone(&self) -> bool {
+        self.standalone_mode
+    }
+
+    /// Directory where the service keeps its data: `%ProgramData%\<name>` on Windows,
+    /// `/Library/Application Support/<name>` on macOS (`~/Library/Application Support/<name>`
+    /// for per-user agents) and `/var/lib/<name>` on the other platforms.
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    /// Directory where the service writes its logs: `%ProgramData%\<name>\logs` on Windows,
+    /// `/Library/Logs/<name>` on macOS (`~/Library/Logs/<name>` for per-user agents) and
+    /// `/var/log/<name>` on the other platforms.
+    pub fn log_dir(&self) -> &Path {
+        &self.log_dir
+    }
+
+    /// Token set when the service receives `Stop` or `StopNow`, which can be passed to worker
+    /// threads and tasks instead of the event receiver. It is never set in standalone mode.
+    pub fn shutdown_token(&self) -> ShutdownToken {
+        shutdown::service_token().clone()
+    }
+
+    /// Pushes back the `StopNow` event of a two-phase stop by `extra`, reporting the progress
+    /// of the stop to the system. Has no effect when no stop is pending.
+    pub fn delay_stop(&self, extra: Duration) {
+        stop::delay(extra);
+    }
+
+    /// Cancels a pending two-phase stop, the service keeps running and `StopNow` is not sent.
+    pub fn cancel_stop(&self) {
+        stop::cancel();
+    }
+
+    /// Reports that the service is ready to serve: with the `StartupGate::ready()` gate set on
+    /// the Windows controller, the service is reported as starting until this is called, so
+    /// that the services depending on it don't start before it listens. Has no effect
+    /// otherwise.
+    pub fn ready(&self) {
+        startup_gate::signal_ready();
+    }
+
+    /// Reports that the service finished pausing after a `Pause` event, the system shows the
+    /// service as pause pending until then. Only needed when `acknowledge_pause` is set on the
+    /// controller, `run_event_loop()` reports it once `EventHandler::on_pause()` returned.
+    pub fn pause_completed(&self) {
+        pause::completed(true);
+    }
+
+    /// Reports that the service resumed after a `Continue` event, see `pause_completed()`.
+    pub fn continue_completed(&self) {
+        pause::completed(false);
+    }
+
+    /// Restarts the service once the service main function returned, for example after an
+    /// update replaced its binary. On Unix the executable is executed again in the service
+    /// process, from the path it was started from. On Windows the SCM restarts the service
+    /// with a temporary failure action, the failure actions of the service are restored at the
+    /// next start. Has no effect in standalone mode.
+    pub fn request_self_restart(&self) {
+        if !self.standalone_mode {
+            restart::request();
+        }
+    }
+
+    /// Rotating file logger installed by the wrapper when a `LogConfig` is set on the
+    /// controller, `None` otherwise and in standalone mode.
+    pub fn logger(&self) -> Option<&LoggerHandle> {
+        self.logger.as_ref()
+    }
+
+    /// Handle used to report the service status to the SCM, null in standalone mode.
+    #[cfg(windows)]
+    #[deprecated(note = "use `raw_status_handle()`")]
+    pub fn status_handle(&self) -> SERVICE_STATUS_HANDLE {
+        self.status_handle
+    }
+
+    /// Raw handle returned by `RegisterServiceCtrlHandlerExW`, null in standalone mode. It lets
+    /// the service call `SetServiceStatus` or `RegisterDeviceNotificationW` with
+    /// `DEVICE_NOTIFY_SERVICE_HANDLE` itself for what the crate doesn't wrap. The wrapper keeps
+    /// reporting the status for the events it handles and when the service main function
+    /// returns, a status set through this handle is overwritten by the next report. The handle
+    /// must not be closed.
+    #[cfg(windows)]
+    pub fn raw_status_handle(&self) -> SERVICE_STATUS_HANDLE {
+        self.status_handle
+    }
+
+    /// Why the SCM started the service: a combination of the `SERVICE_START_REASON_*` flags
+    /// of `winsvc`, such as `SERVICE_START_REASON_TRIGGER` for a trigger-start service,
+    /// queried with `QueryServiceDynamicInformation`. Requires Windows 8 or later.
+    #[cfg(windows)]
+    pub fn start_reason(&self) -> Result<DWORD, Error> {
+        if self.status_handle.is_null() {
+            return Err(Error::new("The start reason is only known to services"));
+        }
+        unsafe {
+            let mut info: PVOID = ptr::null_mut();
+            if QueryServiceDynamicInformation(
+                self.status_handle,
+                SERVICE_DYNAMIC_INFORMATION_LEVEL_START_REASON,
+                &mut info,
+            ) == 0
+            {
+                return Err(Error {
+                    function: Some("QueryServiceDynamicInformation".to_string()),
+                    ..Error::from(io::Error::last_os_error())
+                });
+            }
+            let reason = *(info as *const DWORD);
+            LocalFree(info);
+            Ok(reason)
+        }
+    }
+}
+
+#[cfg(windows)]
+const SERVICE_DYNAMIC_INFORMATION_LEVEL_START_REASON: DWORD = 1;
+
+// Missing from winapi, available since Windows 8.
+#[cfg(windows)]
+#[link(name = "advapi32")]
+extern "system" {
+    fn QueryServiceDynamicInformation(
+        hServiceStatus: SERVICE_STATUS_HANDLE,
+        dwInfoLevel: DWORD,
+        ppDynamicInfo: *mut PVOID,
+    ) -> BOOL;
+}
+
+// macOS agents run as the logged on user and keep their files in the user's home directory.
+#[cfg(target_os = "macos")]
+pub(crate) fn is_per_user() -> bool {
+    unsafe { libc::geteuid() != 0 }
+}
+
+#[cfg(windows)]
+fn program_data() -> PathBuf {
+    std::env::var_os("ProgramData")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("C:\\ProgramData"))
+}
+
+#[cfg(target_os = "macos")]
+fn library() -> PathBuf {
+    match env::var_os("HOME") {
+        Some(home) if is_per_user() => Path::new(&home).join("Library"),
+        _ => PathBuf::from("/Library"),
+    }
+}
+
+pub(crate) fn data_dir(service_name: &str) -> PathBuf {
+    cfg_if! {
+        if #[cfg(windows)] {
+            program_data().join(service_name)
+        } else if #[cfg(target_os = "macos")] {
+            library().join("Application Support").join(service_name)
+        } else {
+            Path::new("/var/lib").join(service_name)
+        }
+    }
+}
+
+fn log_dir(service_name: &str) -> PathBuf {
+    cfg_if! {
+        if #[cfg(windows)] {
+            program_data().join(service_name).join("logs")
+        } else if #[cfg(target_os = "macos")] {
+            library().join("Logs").join(service_name)
+        } else {
+            Path::new("/var/log").join(service_name)
+        }
+    }
+}
+
+/// Creates the data and log directories of the service. They are created at install time by
+/// the administrator, which is also the account system wide services run as, or at startup for
+/// per-user agents, and are kept when the service is deleted.
+pub(crate) fn create_service_dirs(service_name: &str) -> Result<(), Error> {
+    for dir in [data_dir(service_name), log_dir(service_name)] {
+        if dir.is_dir() || crate::dry_run::skip(|| format!("create {}", dir.display())) {
+            continue;
+        }
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        builder.mode(0o750);
+        builder
+            .create(&dir)
+            .map_err(|e| Error::io(&format!("Failed to create {}", dir.display()), &e))?;
+    }
+    Ok(())
+}
+
+/// Adapts a service main function with the `LegacyServiceMainFn` signature, used by the
+/// `Service!(name, legacy function)` form of the macro.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __legacy_service_main {
+    ($function:expr) => {
+        |rx, tx, ctx: $crate::context::ServiceContext| {
+            let standalone_mode = ctx.is_standalone();
+            ($function)(rx, tx, ctx.into_args(), standalone_mode)
+        }
+    };
+}
@@ -1,37 +1,194 @@
 use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
+#[cfg(any(
+    windows,
+    target_os = "macos",
+    target_os = "linux",
+    target_os = "illumos",
+    target_os = "solaris",
+    target_os = "aix"
+))]
+use crate::audit;
+use crate::context::ServiceContext;
+use crate::messages;
 use crate::Error;
 use crate::ServiceEvent;
 
+mod backend;
+#[cfg(feature = "install")]
+mod batch;
+#[cfg(feature = "install")]
+mod doctor;
+mod names;
+pub use self::backend::Backend;
+#[cfg(feature = "install")]
+pub use self::batch::{batch, BatchMode, BatchResult, Operation};
+#[cfg(feature = "install")]
+pub use self::doctor::{doctor, Check, CheckStatus, DoctorReport};
+pub use self::names::{validate_display_name, validate_service_name, MAX_NAME_LEN};
+
 cfg_if! {
     if #[cfg(windows)] {
+        #[cfg(feature = "install")]
+        mod task_scheduler;
         mod windows;
         pub use self::windows::WindowsController as Controller;
+        #[cfg(feature = "install")]
+        pub use self::task_scheduler::{ScheduledTaskController, TaskTrigger};
+        pub use self::windows::{ServiceConfig, ServiceDll};
+        pub use self::windows::DriverType;
+        pub use self::windows::{FirewallDirection, FirewallProtocol, FirewallRule};
+        pub use self::windows::{ServiceTrigger, TriggerAction, TriggerData};
+        #[cfg(feature = "session-events")]
         pub use self::windows::Session as Session;
-        pub use self::windows::dispatch;
+        #[cfg(feature = "runtime")]
+        pub use self::windows::{dispatch, dispatch_hosted, dispatch_on_thread};
+        pub use self::windows::is_headless_sku;
+        pub use self::windows::{is_reactos, is_wine};
+        pub use self::windows::{native_path, BinaryArch};
     } else if #[cfg(target_os = "macos")] {
         mod macos;
         pub use self::macos::MacosController as Controller;
+        #[cfg(feature = "session-events")]
         pub use self::macos::Session as Session;
+        #[cfg(feature = "runtime")]
         pub use self::macos::dispatch;
         pub use self::macos::LaunchAgentTargetSesssion;
+        pub use self::macos::{KeepAlive, LaunchdJob};
     } else if #[cfg(target_os = "linux")] {
         mod linux;
         pub use self::linux::LinuxController as Controller;
+        pub use self::linux::InitSystem;
+        #[cfg(feature = "session-events")]
         pub use self::linux::Session as Session;
+        #[cfg(feature = "runtime")]
         pub use self::linux::dispatch;
+    } else if #[cfg(any(target_os = "illumos", target_os = "solaris"))] {
+        mod illumos;
+        pub use self::illumos::IllumosController as Controller;
+        #[cfg(feature = "session-events")]
+        pub use self::illumos::Session as Session;
+        #[cfg(feature = "runtime")]
+        pub use self::illumos::dispatch;
+    } else if #[cfg(target_os = "aix")] {
+        mod aix;
+        pub use self::aix::AixController as Controller;
+        #[cfg(feature = "session-events")]
+        pub use self::aix::Session as Session;
+        #[cfg(feature = "runtime")]
+        pub use self::aix::dispatch;
     } else {
         mod dummy;
         pub use self::dummy::DummyController as Controller;
+        #[cfg(feature = "session-events")]
         pub use self::dummy::Session as Session;
     }
 }
 
+// Controllers can be cloned and shared by orchestration code running on several threads.
+const _: () = {
+    const fn assert_send_sync<T: Clone + Send + Sync>() {}
+    assert_send_sync::<Controller>();
+};
+
+/// Name, display name and description of a service as `&'static str`, so that they can be
+/// declared once as a `const` or `static`, shared by the installer and the service, without
+/// running any code before `main`. The controller borrows them, like `Controller::const_new()`
+/// which builds a controller without allocating:
+///
+/// ```
+/// use ceviche::controller::{Controller, ServiceMetadata};
+///
+/// const SERVICE: ServiceMetadata = ServiceMetadata::new("foobar", "Foobar Service", "Foobar");
+/// static CONTROLLER: Controller = Controller::const_new("foobar", "Foobar Service", "Foobar");
+///
+/// let controller = Controller::from(SERVICE);
+/// assert_eq!(controller.service_name, SERVICE.name);
+/// assert_eq!(CONTROLLER.service_name, SERVICE.name);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ServiceMetadata {
+    pub name: &'static str,
+    pub display_name: &'static str,
+    pub description: &'static str,
+}
+
+impl ServiceMetadata {
+    pub const fn new(
+        name: &'static str,
+        display_name: &'static str,
+        description: &'static str,
+    ) -> ServiceMetadata {
+        ServiceMetadata {
+            name,
+            display_name,
+            description,
+        }
+    }
+}
+
+impl Controller {
+    /// Creates a controller like `new()` once the names are validated, so that an invalid
+    /// name is reported with the rule it breaks instead of failing deep inside `create()`.
+    pub fn try_new(
+        service_name: &str,
+        display_name: &str,
+        description: &str,
+    ) -> Result<Controller, Error> {
+        validate_service_name(service_name)?;
+        validate_display_name(display_name)?;
+        Ok(Controller::new(service_name, display_name, description))
+    }
+}
+
+impl From<ServiceMetadata> for Controller {
+    fn from(metadata: ServiceMetadata) -> Controller {
+        Controller::const_new(metadata.name, metadata.display_name, metadata.description)
+    }
+}
+
+/// Exit code of a service whose main function didn't return within the stop deadline set on
+/// the controller, the same code as `timeout(1)`.
+pub const STOP_DEADLINE_EXIT_CODE: u32 = 124;
+
 /// Signature of the service main function.
 /// `rx` receives the events that are sent to the service. `tx` can be used to send custom events on the channel.
+/// `ctx` describes the running service, see `ServiceContext`.
+/// The custom event type `T` must be `Send + 'static`, events are produced by system threads.
+pub type ServiceMainFn<T> = fn(
+    rx: mpsc::Receiver<ServiceEvent<T>>,
+    tx: mpsc::Sender<ServiceEvent<T>>,
+    ctx: ServiceContext,
+) -> u32;
+
+/// Service main functions accepted by `Service!`: a function with the `ServiceMainFn`
+/// signature, a method such as `MyService::run` or a closure, for instance one capturing the
+/// configuration it was built with.
+pub trait ServiceMain<T>:
+    FnOnce(mpsc::Receiver<ServiceEvent<T>>, mpsc::Sender<ServiceEvent<T>>, ServiceContext) -> u32
+    + Send
+    + 'static
+{
+}
+
+impl<T, F> ServiceMain<T> for F where
+    F: FnOnce(
+            mpsc::Receiver<ServiceEvent<T>>,
+            mpsc::Sender<ServiceEvent<T>>,
+            ServiceContext,
+        ) -> u32
+        + Send
+        + 'static
+{
+}
+
+/// Signature of the service main function before `ServiceContext` was introduced, still
+/// supported with `Service!(name, legacy function)`.
 /// `args` is the list or arguments that were passed to the service. When `standalone_mode` is true, the service
 /// main function is being called directly (outside of the system service support).
-pub type ServiceMainFn<T> = fn(
+pub type LegacyServiceMainFn<T> = fn(
     rx: mpsc::Receiver<ServiceEvent<T>>,
     tx: mpsc::Sender<ServiceEvent<T>>,
     args: Vec<String>,
@@ -40,6 +197,7 @@ pub type ServiceMainFn<T> = fn(
 
 /// Controllers implement this interface. They also need to implement the `register()` method; because the signature
 /// of service_main_wrapper depends on the system the method is not part of the interface.
+#[cfg(feature = "install")]
 pub trait ControllerInterface {
     /// Creates the service on the system.
     fn create(&mut self) -> Result<(), Error>;
@@ -49,6 +207,28 @@ pub trait ControllerInterface {
     fn start(&mut self) -> Result<(), Error>;
     /// Stops the service.
     fn stop(&mut self) -> Result<(), Error>;
+    /// Forcibly terminates the service process, for when a graceful `stop()` hangs. Fails for
+    /// a Windows service sharing its process with other services.
+    fn kill(&mut self) -> Result<(), Error>;
+    /// Pauses the service. Windows services receive `ServiceEvent::Pause`, on the other
+    /// systems the service process is suspended with `SIGSTOP`.
+    fn pause(&mut self) -> Result<(), Error>;
+    /// Resumes a paused service. Windows services receive `ServiceEvent::Continue`, on the
+    /// other systems the service process is resumed with `SIGCONT`.
+    fn resume(&mut self) -> Result<(), Error>;
+    /// Starts the service on a background thread, the returned handle resolves once the
+    /// service is running or failed to start.
+    fn start_background(&self) -> StartHandle
+    where
+        Self: Clone + Send + 'static,
+    {
+        let mut controller = self.clone();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(controller.start());
+        });
+        StartHandle { rx }
+    }
     cfg_if! {
         if #[cfg(target_os = "macos")] {
             /// Loads the agent service.
@@ -58,3 +238,168 @@ pub trait ControllerInterface {
         }
     }
 }
+
+/// Runs the body of the controller operation `name`, in a dry run when `dry_run` is set,
+/// recording it in the returned error and in the audit log of `audit`.
+#[cfg(any(
+    windows,
+    target_os = "macos",
+    target_os = "linux",
+    target_os = "illumos",
+    target_os = "solaris",
+    target_os = "aix"
+))]
+pub(crate) fn in_operation<T>(
+    name: &'static str,
+    dry_run: bool,
+    audit: Option<audit::Target>,
+    body: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    #[cfg(feature = "scm-tracing")]
+    let _span = tracing::debug_span!("operation", name).entered();
+    let result = crate::dry_run::scope(dry_run, body).map_err(|e| e.with_operation(name));
+    if let Some(audit) = audit.filter(|_| !dry_run) {
+        audit.record(name, &result);
+    }
+    result
+}
+
+/// Extracts the message of a panic caught with `catch_unwind`.
+pub(crate) fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+/// Handle on a service start running in the background, see `ControllerInterface::start_background()`.
+#[cfg(feature = "install")]
+pub struct StartHandle {
+    rx: mpsc::Receiver<Result<(), Error>>,
+}
+
+#[cfg(feature = "install")]
+impl StartHandle {
+    /// Blocks until the service is running or failed to start.
+    pub fn wait(self) -> Result<(), Error> {
+        self.rx
+            .recv()
+            .unwrap_or_else(|_| Err(Error::new("Service start thread panicked")))
+    }
+
+    /// Blocks until the service is running or failed to start, for at most `timeout`.
+    pub fn wait_timeout(self, timeout: Duration) -> Result<(), Error> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                Err(Error::from_message(messages::START_TIMED_OUT, &[]))
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Err(Error::new("Service start thread panicked"))
+            }
+        }
+    }
+
+    /// Returns the outcome of the start if it completed, without blocking.
+    pub fn try_wait(&self) -> Option<Result<(), Error>> {
+        match self.rx.try_recv() {
+            Ok(result) => Some(result),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                Some(Err(Error::new("Service start thread panicked")))
+            }
+        }
+    }
+}
+
+/// Service manager artifact returned by `render_manifest()`, for build pipelines reviewing
+/// and packaging what `create()` installs. A controller renders the formats of its platform.
+#[cfg(feature = "install")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ManifestFormat {
+    /// systemd unit of the Linux controller.
+    SystemdUnit,
+    /// `run` script of the runit and s6 service directories of the Linux controller.
+    RunScript,
+    /// launchd property list of the macOS controller.
+    LaunchdPlist,
+    /// WiX Toolset fragment installing the service from an MSI package.
+    WixFragment,
+    /// SMF manifest of the illumos controller.
+    SmfManifest,
+}
+
+#[cfg(feature = "install")]
+impl ManifestFormat {
+    pub(crate) fn unsupported(self) -> Error {
+        Error::new(&format!(
+            "{:?} manifests are not rendered on this platform",
+            self
+        ))
+    }
+}
+
+/// Escapes the XML special characters of `value`, for attribute values and text.
+#[cfg(any(windows, target_os = "illumos", target_os = "solaris"))]
+pub(crate) fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Setting of a service that differs between the installed service and the one `create()`
+/// was asked to install.
+#[cfg(feature = "install")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigDifference {
+    pub setting: &'static str,
+    pub existing: String,
+    pub requested: String,
+}
+
+/// Source of the `ErrorKind::ConflictingService` errors of `create()` on Windows, available
+/// through `Error::source()`: a service with the same name is installed with another
+/// configuration.
+#[cfg(feature = "install")]
+#[derive(Clone, Debug)]
+pub struct ConflictingService {
+    pub service_name: String,
+    pub differences: Vec<ConfigDifference>,
+}
+
+#[cfg(all(windows, feature = "install"))]
+impl ConflictingService {
+    pub(crate) fn into_error(self) -> Error {
+        Error {
+            kind: crate::ErrorKind::ConflictingService,
+            ..Error::new(&self.to_string())
+        }
+        .with_source(self)
+    }
+}
+
+#[cfg(feature = "install")]
+impl std::fmt::Display for ConflictingService {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} is already installed with another configuration",
+            self.service_name
+        )?;
+        for difference in &self.differences {
+            write!(
+                f,
+                "\n  {}: \"{}\" installed, \"{}\" requested",
+                difference.setting, difference.existing, difference.requested
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "install")]
+impl std::error::Error for ConflictingService {}
@@ -0,0 +1,470 @@
+use std::borrow::Cow;
+use std::env;
+use std::process::{self, Command};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ctrlc;
+use log::{debug, error, info};
+
+use crate::audit::{self, AuditLog};
+use crate::channel::{self, EventPolicy};
+use crate::context::{create_service_dirs, ServiceContext};
+#[cfg(feature = "install")]
+use crate::controller::doctor::{binary_check, Check};
+use crate::controller::{in_operation, Backend, ServiceMain};
+#[cfg(feature = "install")]
+use crate::controller::{ControllerInterface, ManifestFormat};
+use crate::deadline;
+use crate::dry_run;
+use crate::elevation::require_elevated;
+use crate::instance;
+use crate::logging::{self, LogConfig};
+use crate::messages;
+use crate::restart;
+use crate::run_once::{self, RunOnce};
+#[cfg(feature = "session-events")]
+use crate::session;
+#[cfg(feature = "state")]
+use crate::state;
+use crate::status::{self, AcceptedControls, ServiceStatus, StatusSubscription};
+use crate::stop;
+use crate::{Error, StopReason};
+
+type AixServiceMainWrapperFn = fn(args: Vec<String>);
+#[cfg(feature = "session-events")]
+pub type Session = session::Session_<u32>;
+
+// stopsrc sends SIGTERM for a normal stop, which is delivered to the service as a Stop event,
+// and SIGKILL for a forced stop (stopsrc -f).
+const SRC_NORMAL_STOP_SIGNAL: &str = "15";
+const SRC_FORCED_STOP_SIGNAL: &str = "9";
+
+fn execute(program: &str, args: &[&str]) -> Result<String, Error> {
+    // `lssrc` only reports the state of services, it still runs in a dry run.
+    if program != "lssrc" && dry_run::skip(|| format!("execute {} {}", program, args.join(" "))) {
+        return Ok(String::new());
+    }
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| Error {
+            function: Some(program.to_string()),
+            ..Error::io(&format!("Failed to execute command {}", program), &e)
+        })?;
+
+    if !output.status.success() {
+        return Err(Error {
+            function: Some(program.to_string()),
+            ..Error::new(&format!(
+                "Command \"{}\" failed ({}): {}",
+                program,
+                output.status.code().unwrap_or_default(),
+                std::str::from_utf8(&output.stderr).unwrap_or_default()
+            ))
+        });
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    if !stdout.is_empty() {
+        info!("{}", stdout);
+    }
+
+    Ok(stdout)
+}
+
+/// Manages the service with the System Resource Controller.
+#[derive(Clone)]
+pub struct AixController {
+    pub service_name: Cow<'static, str>,
+    pub display_name: Cow<'static, str>,
+    pub description: Cow<'static, str>,
+    pub event_policy: EventPolicy,
+    /// Installs a rotating file logger writing to the log directory of the service.
+    pub log_config: Option<LogConfig>,
+    /// Time the service main function has to return after the `Stop` event, the process then
+    /// exits with `STOP_DEADLINE_EXIT_CODE` instead of staying in the stopping state forever.
+    pub stop_deadline: Option<Duration>,
+    /// Enables the two-phase stop: stop requests send `StopRequested`, followed by `StopNow`
+    /// once this grace period ends.
+    pub stop_grace_period: Option<Duration>,
+    /// Lets only one instance of the service run, see `instance::acquire()`.
+    pub single_instance: bool,
+    /// Runs the service once at the next boot: it is disabled or deleted by the service
+    /// wrapper after its main function returned 0. The cleanup requires the `install` feature.
+    pub run_once: Option<RunOnce>,
+    /// Service manager the operations are delegated to instead of the one of the system.
+    pub backend: Option<Arc<dyn Backend>>,
+    /// Logs the commands, files and system calls of the operations instead of performing
+    /// them, to audit what an installer does.
+    pub dry_run: bool,
+    /// Records the operations and their results, see `AuditLog`.
+    pub audit_log: Option<AuditLog>,
+}
+
+impl AixController {
+    pub fn new(service_name: &str, display_name: &str, description: &str) -> AixController {
+        AixController::with_identity(
+            Cow::Owned(service_name.to_string()),
+            Cow::Owned(display_name.to_string()),
+            Cow::Owned(description.to_string()),
+        )
+    }
+
+    /// Creates a controller like `new()` from names known at compile time, which are borrowed
+    /// instead of copied so that the controller can be a `const` or a `static`.
+    pub const fn const_new(
+        service_name: &'static str,
+        display_name: &'static str,
+        description: &'static str,
+    ) -> AixController {
+        AixController::with_identity(
+            Cow::Borrowed(service_name),
+            Cow::Borrowed(display_name),
+            Cow::Borrowed(description),
+        )
+    }
+
+    const fn with_identity(
+        service_name: Cow<'static, str>,
+        display_name: Cow<'static, str>,
+        description: Cow<'static, str>,
+    ) -> AixController {
+        AixController {
+            service_name,
+            display_name,
+            description,
+            backend: None,
+            dry_run: false,
+            audit_log: None,
+            event_policy: EventPolicy::new(),
+            log_config: None,
+            stop_deadline: None,
+            stop_grace_period: None,
+            single_instance: false,
+            run_once: None,
+        }
+    }
+
+    /// Register the `service_main_wrapper` function, this function is generated by the `Service!` macro.
+    #[cfg(feature = "runtime")]
+    pub fn register(&mut self, service_main_wrapper: AixServiceMainWrapperFn) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            backend.register(&self.service_name)?;
+        }
+        channel::set_event_policy(self.event_policy);
+        logging::set_log_config(self.log_config);
+        deadline::set_stop_deadline(self.stop_deadline);
+        stop::set_grace_period(self.stop_grace_period);
+        instance::set_single_instance(self.single_instance);
+        #[cfg(feature = "install")]
+        run_once::set_run_once(self.run_once, self.clone(), Self::finish_run_once);
+        service_main_wrapper(env::args().collect());
+        Ok(())
+    }
+
+    /// Returns the status of the service, its state is the SRC status of the subsystem such
+    /// as `active` or `inoperative`.
+    pub fn status(&self) -> Result<ServiceStatus, Error> {
+        if let Some(backend) = &self.backend {
+            return backend.status(&self.service_name);
+        }
+        let output = execute("lssrc", &["-s", &self.service_name])?;
+        let state = output
+            .lines()
+            .nth(1)
+            .and_then(|line| line.split_whitespace().last())
+            .ok_or_else(|| Error::new("Failed to parse the lssrc output"))?;
+        Ok(ServiceStatus::new(
+            state,
+            status::load_start_record(&self.service_name),
+        ))
+    }
+
+    /// Returns the controls the service accepts, `STOP` and `PAUSE_CONTINUE` while it runs.
+    pub fn accepted_controls(&self) -> Result<AcceptedControls, Error> {
+        if self.backend.is_some() {
+            return Ok(AcceptedControls::signaled(
+                self.status()?.state == "running",
+            ));
+        }
+        let running = self.status()?.state == "active";
+        Ok(AcceptedControls::signaled(running))
+    }
+
+    /// Subscribes to the state transitions of the service, its status is queried every second.
+    pub fn subscribe_status(&self) -> StatusSubscription {
+        let controller = self.clone();
+        status::poll(move || controller.status())
+    }
+
+    // The inittab entry starts the subsystem at boot, SRC doesn't restart it once it exits.
+    #[cfg(feature = "install")]
+    fn finish_run_once(&mut self, run_once: RunOnce) -> Result<(), Error> {
+        match run_once {
+            RunOnce::Disable => execute("rmitab", &[&self.service_name]).map(|_| ()),
+            RunOnce::Delete => self.delete(),
+        }
+    }
+
+    /// The System Resource Controller is configured with `mkssys` arguments, there is no
+    /// manifest to render.
+    #[cfg(feature = "install")]
+    pub fn render_manifest(&self, format: ManifestFormat) -> Result<String, Error> {
+        Err(format.unsupported())
+    }
+
+    /// Checks of `doctor()` specific to the System Resource Controller.
+    #[cfg(feature = "install")]
+    pub(crate) fn diagnose(&self) -> Vec<Check> {
+        let manager = match execute("lssrc", &["-s", "srcmstr"]) {
+            Ok(_) => Check::passed("service manager"),
+            Err(e) => Check::failed("service manager", e.to_string()),
+        };
+        let name = match execute("lssrc", &["-s", &self.service_name]) {
+            Ok(_) => Check::warning(
+                "service name",
+                format!("the subsystem {} is already defined", self.service_name),
+            ),
+            Err(_) => Check::passed("service name"),
+        };
+        let binary = match env::current_exe() {
+            Ok(path) => binary_check(&path),
+            Err(e) => Check::failed("binary path", e.to_string()),
+        };
+        vec![manager, name, binary]
+    }
+
+    fn inittab_entry(&self) -> String {
+        format!(
+            "{}:2:once:/usr/bin/startsrc -s {} >/dev/console 2>&1",
+            self.service_name, self.service_name
+        )
+    }
+
+    // SRC has no pause request, the subsystem process is signaled directly. Its pid is the
+    // column before the status in the lssrc output, the group column may be empty.
+    fn signal(&self, signal: &str) -> Result<(), Error> {
+        let output = execute("lssrc", &["-s", &self.service_name])?;
+        let fields: Vec<&str> = output
+            .lines()
+            .nth(1)
+            .map(|line| line.split_whitespace().collect())
+            .unwrap_or_default();
+        match fields.as_slice() {
+            [.., pid, "active"] => execute("kill", &[signal, *pid]).map(|_| ()),
+            _ => Err(Error::from_message(messages::NOT_RUNNING, &[])),
+        }
+    }
+}
+
+#[cfg(feature = "install")]
+impl ControllerInterface for AixController {
+    fn create(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "create",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.create(&self.service_name, &self.display_name, &self.description),
+            );
+        }
+        in_operation(
+            "create",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || {
+                require_elevated()?;
+                create_service_dirs(&self.service_name)?;
+
+                let current_exe =
+                    env::current_exe().map_err(|e| Error::io("env::current_exe() failed", &e))?;
+                let current_exe = current_exe
+                    .to_str()
+                    .ok_or("Failed to parse the current executable path")?;
+
+                execute(
+                    "mkssys",
+                    &[
+                        "-s",
+                        &self.service_name,
+                        "-p",
+                        current_exe,
+                        "-u",
+                        "0",
+                        "-S",
+                        "-n",
+                        SRC_NORMAL_STOP_SIGNAL,
+                        "-f",
+                        SRC_FORCED_STOP_SIGNAL,
+                        "-Q",
+                    ],
+                )?;
+
+                execute("mkitab", &[&self.inittab_entry()]).map(|_| ())
+            },
+        )
+    }
+
+    fn delete(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "delete",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.delete(&self.service_name),
+            );
+        }
+        in_operation(
+            "delete",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || {
+                require_elevated()?;
+                execute("rmitab", &[&self.service_name])
+                    .map_err(|e| debug!("{}", e))
+                    .ok();
+                execute("rmssys", &["-s", &self.service_name]).map(|_| ())
+            },
+        )
+    }
+
+    fn start(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "start",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.start(&self.service_name),
+            );
+        }
+        in_operation(
+            "start",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || execute("startsrc", &["-s", &self.service_name]).map(|_| ()),
+        )
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "stop",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.stop(&self.service_name),
+            );
+        }
+        in_operation(
+            "stop",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || execute("stopsrc", &["-s", &self.service_name]).map(|_| ()),
+        )
+    }
+
+    fn kill(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "kill",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.kill(&self.service_name),
+            );
+        }
+        in_operation(
+            "kill",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || execute("stopsrc", &["-f", "-s", &self.service_name]).map(|_| ()),
+        )
+    }
+
+    fn pause(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "pause",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.pause(&self.service_name),
+            );
+        }
+        in_operation(
+            "pause",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || self.signal("-STOP"),
+        )
+    }
+
+    fn resume(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "resume",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.resume(&self.service_name),
+            );
+        }
+        in_operation(
+            "resume",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || self.signal("-CONT"),
+        )
+    }
+}
+
+/// Generates a `service_main_wrapper` that wraps the provided service main function.
+#[cfg(feature = "runtime")]
+#[macro_export]
+macro_rules! Service {
+    ($name:expr, legacy $function:expr) => {
+        fn service_main_wrapper(args: Vec<String>) {
+            dispatch($crate::__legacy_service_main!($function), $name, args);
+        }
+    };
+    ($name:expr, $function:expr) => {
+        fn service_main_wrapper(args: Vec<String>) {
+            dispatch($function, $name, args);
+        }
+    };
+    // The service main function runs on the main thread, its stack size is set by the system.
+    ($name:expr, $function:expr, stack_size = $stack_size:expr) => {
+        fn service_main_wrapper(args: Vec<String>) {
+            dispatch($function, $name, args);
+        }
+    };
+}
+
+#[cfg(feature = "runtime")]
+#[doc(hidden)]
+pub fn dispatch<T: Send + 'static, F: ServiceMain<T>>(
+    service_main: F,
+    name: &str,
+    args: Vec<String>,
+) {
+    let (tx, rx, _tx) = channel::channel();
+
+    ctrlc::set_handler(move || {
+        stop::request(&tx, StopReason::Requested);
+    })
+    .expect("Failed to register Ctrl-C handler");
+    restart::record_executable();
+    let mut ctx = ServiceContext::new(name, args);
+    logging::init_service_logger(&mut ctx);
+    let _instance = match instance::acquire_for_service(name) {
+        Ok(instance) => instance,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    status::record_start(name);
+    let exit_code = service_main(rx, _tx, ctx);
+    deadline::service_main_returned();
+    #[cfg(feature = "state")]
+    state::save_registered();
+    run_once::service_main_returned(exit_code);
+    restart::reexec_if_requested();
+}
@@ -0,0 +1,44 @@
+use crate::status::ServiceStatus;
+use crate::Error;
+
+/// Service manager used by a controller in place of the one of the system, such as a Nomad
+/// client or a proprietary supervisor, set in the `backend` field of the controller. The
+/// operations of `ControllerInterface` and `status()` are delegated to it.
+///
+/// The service process is still dispatched by `register()` like a service of the system: on
+/// Unix it runs as a plain process receiving signals, on Windows a process that wasn't
+/// started by the SCM runs in standalone mode.
+pub trait Backend: Send + Sync {
+    /// Creates the service.
+    fn create(
+        &self,
+        service_name: &str,
+        display_name: &str,
+        description: &str,
+    ) -> Result<(), Error>;
+    /// Deletes the service.
+    fn delete(&self, service_name: &str) -> Result<(), Error>;
+    /// Starts the service.
+    fn start(&self, service_name: &str) -> Result<(), Error>;
+    /// Stops the service.
+    fn stop(&self, service_name: &str) -> Result<(), Error>;
+    /// Forcibly terminates the service, stops it by default.
+    fn kill(&self, service_name: &str) -> Result<(), Error> {
+        self.stop(service_name)
+    }
+    /// Pauses the service, not supported by default.
+    fn pause(&self, _service_name: &str) -> Result<(), Error> {
+        Err(Error::new("The backend can't pause services"))
+    }
+    /// Resumes a paused service, not supported by default.
+    fn resume(&self, _service_name: &str) -> Result<(), Error> {
+        Err(Error::new("The backend can't resume services"))
+    }
+    /// Returns the status of the service, whose state is `running` while it runs.
+    fn status(&self, service_name: &str) -> Result<ServiceStatus, Error>;
+    /// Called by `register()` in the service process before the service main function runs,
+    /// for instance to tell the supervisor that the service started. Does nothing by default.
+    fn register(&self, _service_name: &str) -> Result<(), Error> {
+        Ok(())
+    }
+}
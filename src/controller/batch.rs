@@ -0,0 +1,107 @@
+use crate::controller::{Controller, ControllerInterface};
+use crate::group::GroupError;
+use crate::Error;
+
+/// Controller operation run by `batch()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Create,
+    Delete,
+    Start,
+    Stop,
+    Kill,
+    Pause,
+    Resume,
+}
+
+impl Operation {
+    fn run(self, controller: &mut Controller) -> Result<(), Error> {
+        match self {
+            Operation::Create => controller.create(),
+            Operation::Delete => controller.delete(),
+            Operation::Start => controller.start(),
+            Operation::Stop => controller.stop(),
+            Operation::Kill => controller.kill(),
+            Operation::Pause => controller.pause(),
+            Operation::Resume => controller.resume(),
+        }
+    }
+}
+
+/// What `batch()` does after an operation failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchMode {
+    /// The following operations are skipped.
+    StopOnError,
+    /// The following operations still run.
+    BestEffort,
+}
+
+/// Results of the operations of a batch, in the order they were given.
+#[derive(Debug)]
+pub struct BatchResult {
+    /// Service name, operation and result of each operation that ran.
+    pub completed: Vec<(String, Operation, Result<(), Error>)>,
+    /// Operations skipped after a failure in `StopOnError` mode.
+    pub skipped: Vec<(String, Operation)>,
+}
+
+impl BatchResult {
+    /// True when every operation ran and succeeded.
+    pub fn is_success(&self) -> bool {
+        self.skipped.is_empty() && self.completed.iter().all(|(_, _, result)| result.is_ok())
+    }
+
+    /// Returns the failed operations as a `GroupError`, keyed by service name.
+    pub fn into_result(self) -> Result<(), GroupError> {
+        let failures: Vec<(String, Error)> = self
+            .completed
+            .into_iter()
+            .filter_map(|(service_name, _, result)| result.err().map(|e| (service_name, e)))
+            .collect();
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(GroupError { failures })
+        }
+    }
+}
+
+/// Runs a sequence of controller operations across several services, such as the services
+/// of a product suite installed together. The operations run one after the other in the
+/// given order.
+///
+/// ```rust,ignore
+/// let result = controller::batch(
+///     vec![
+///         (Controller::new("db", "Database", ""), Operation::Stop),
+///         (Controller::new("api", "API", ""), Operation::Stop),
+///         (Controller::new("api", "API", ""), Operation::Delete),
+///     ],
+///     BatchMode::BestEffort,
+/// );
+/// result.into_result()?;
+/// ```
+pub fn batch<I>(operations: I, mode: BatchMode) -> BatchResult
+where
+    I: IntoIterator<Item = (Controller, Operation)>,
+{
+    let mut result = BatchResult {
+        completed: Vec::new(),
+        skipped: Vec::new(),
+    };
+    let mut failed = false;
+
+    for (mut controller, operation) in operations {
+        let service_name = controller.service_name.to_string();
+        if failed && mode == BatchMode::StopOnError {
+            result.skipped.push((service_name, operation));
+            continue;
+        }
+        let outcome = operation.run(&mut controller);
+        failed |= outcome.is_err();
+        result.completed.push((service_name, operation, outcome));
+    }
+
+    result
+}
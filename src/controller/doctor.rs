@@ -0,0 +1,133 @@
+use std::fmt;
+use std::path::Path;
+
+use crate::controller::Controller;
+use crate::elevation::is_elevated;
+
+/// Outcome of a check of `doctor()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CheckStatus {
+    Passed,
+    /// The operations may fail, for instance when a right may be granted through a group.
+    Warning(String),
+    /// The operations will fail until the problem is fixed.
+    Failed(String),
+}
+
+/// Prerequisite checked by `doctor()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+}
+
+impl Check {
+    pub(crate) fn passed(name: &'static str) -> Check {
+        Check {
+            name,
+            status: CheckStatus::Passed,
+        }
+    }
+
+    pub(crate) fn warning(name: &'static str, message: impl Into<String>) -> Check {
+        Check {
+            name,
+            status: CheckStatus::Warning(message.into()),
+        }
+    }
+
+    pub(crate) fn failed(name: &'static str, message: impl Into<String>) -> Check {
+        Check {
+            name,
+            status: CheckStatus::Failed(message.into()),
+        }
+    }
+}
+
+/// Checks run by `doctor()`, in the order they ran. Displayed one check per line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DoctorReport {
+    pub checks: Vec<Check>,
+}
+
+impl DoctorReport {
+    /// True when no check failed, warnings are allowed.
+    pub fn is_healthy(&self) -> bool {
+        !self
+            .checks
+            .iter()
+            .any(|check| matches!(check.status, CheckStatus::Failed(_)))
+    }
+
+    /// Describes the checks as a JSON array of objects with their `name`, `status` (`passed`,
+    /// `warning` or `failed`) and `message`.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> String {
+        let checks: Vec<serde_json::Value> = self
+            .checks
+            .iter()
+            .map(|check| {
+                let (status, message) = match &check.status {
+                    CheckStatus::Passed => ("passed", None),
+                    CheckStatus::Warning(message) => ("warning", Some(message)),
+                    CheckStatus::Failed(message) => ("failed", Some(message)),
+                };
+                serde_json::json!({ "name": check.name, "status": status, "message": message })
+            })
+            .collect();
+        serde_json::Value::from(checks).to_string()
+    }
+}
+
+impl fmt::Display for DoctorReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for check in &self.checks {
+            match &check.status {
+                CheckStatus::Passed => writeln!(f, "[ok] {}", check.name)?,
+                CheckStatus::Warning(message) => {
+                    writeln!(f, "[warning] {}: {}", check.name, message)?
+                }
+                CheckStatus::Failed(message) => {
+                    writeln!(f, "[failed] {}: {}", check.name, message)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks the prerequisites of the operations of `controller` on this system: the elevation
+/// of the process, the access to the service manager, services already using its names, the
+/// service binary and, on Windows, the rights of the service account. Nothing is changed on
+/// the system.
+pub fn doctor(controller: &Controller) -> DoctorReport {
+    let mut checks = vec![if is_elevated() {
+        Check::passed("elevation")
+    } else {
+        Check::failed(
+            "elevation",
+            "the process is not elevated, creating or controlling services requires it",
+        )
+    }];
+    if controller.backend.is_some() {
+        checks.push(Check::warning(
+            "service manager",
+            "the operations are delegated to a custom backend, which isn't checked",
+        ));
+    } else {
+        checks.extend(controller.diagnose());
+    }
+    DoctorReport { checks }
+}
+
+/// Checks that the service binary at `path` exists.
+pub(crate) fn binary_check(path: &Path) -> Check {
+    if path.is_file() {
+        Check::passed("binary path")
+    } else {
+        Check::failed(
+            "binary path",
+            format!("{} doesn't exist or isn't a file", path.display()),
+        )
+    }
+}
@@ -1,48 +1,170 @@
-use crate::controller::ControllerInterface;
+use std::borrow::Cow;
+use std::sync::Arc;
+
+#[cfg(feature = "install")]
+use crate::controller::doctor::{binary_check, Check};
+use crate::controller::Backend;
+#[cfg(feature = "install")]
+use crate::controller::{ControllerInterface, ManifestFormat};
+#[cfg(feature = "install")]
+use crate::dry_run;
+#[cfg(feature = "session-events")]
 use crate::session;
 /// The dummy controller is a mock controller, the only operation that as an
 /// effect is calling it in standalone mode.
 use crate::Error;
 
+#[cfg(feature = "session-events")]
 pub type Session = session::Session_<u32>;
 
-pub struct DummyController {}
+#[derive(Clone)]
+pub struct DummyController {
+    pub service_name: Cow<'static, str>,
+    pub display_name: Cow<'static, str>,
+    pub description: Cow<'static, str>,
+    /// Service manager the operations are delegated to instead of the one of the system.
+    pub backend: Option<Arc<dyn Backend>>,
+    /// Logs the commands, files and system calls of the operations instead of performing
+    /// them, to audit what an installer does.
+    pub dry_run: bool,
+}
 
+#[cfg(feature = "install")]
 impl ControllerInterface for DummyController {
     fn create(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return dry_run::scope(self.dry_run, || {
+                backend.create(&self.service_name, &self.display_name, &self.description)
+            });
+        }
         println!("Dummy controller: creating service (this has no effect on the system)");
         Ok(())
     }
 
     fn delete(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return dry_run::scope(self.dry_run, || backend.delete(&self.service_name));
+        }
         println!("Dummy controller: deleting service (this has no effect on the system)");
         Ok(())
     }
 
     fn start(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return dry_run::scope(self.dry_run, || backend.start(&self.service_name));
+        }
         println!("Dummy controller: starting service (this has no effect on the system)");
         Ok(())
     }
 
     fn stop(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return dry_run::scope(self.dry_run, || backend.stop(&self.service_name));
+        }
         println!("Dummy controller: stopping service (this has no effect on the system)");
         Ok(())
     }
+
+    fn kill(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return dry_run::scope(self.dry_run, || backend.kill(&self.service_name));
+        }
+        println!("Dummy controller: killing service (this has no effect on the system)");
+        Ok(())
+    }
+    fn pause(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return dry_run::scope(self.dry_run, || backend.pause(&self.service_name));
+        }
+        println!("Dummy controller: pausing service (this has no effect on the system)");
+        Ok(())
+    }
+    fn resume(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return dry_run::scope(self.dry_run, || backend.resume(&self.service_name));
+        }
+        println!("Dummy controller: resuming service (this has no effect on the system)");
+        Ok(())
+    }
 }
 
 impl DummyController {
-    pub fn new(_service_name: &str, _display_name: &str, _description: &str) -> DummyController {
-        DummyController {}
+    pub fn new(service_name: &str, display_name: &str, description: &str) -> DummyController {
+        DummyController::with_identity(
+            Cow::Owned(service_name.to_string()),
+            Cow::Owned(display_name.to_string()),
+            Cow::Owned(description.to_string()),
+        )
     }
 
-    pub fn register(&mut self, _service_main_wrapper: fn()) -> Result<(), Error> {
-        unimplemented!();
+    /// Creates a controller like `new()` from names known at compile time, which are borrowed
+    /// instead of copied so that the controller can be a `const` or a `static`.
+    pub const fn const_new(
+        service_name: &'static str,
+        display_name: &'static str,
+        description: &'static str,
+    ) -> DummyController {
+        DummyController::with_identity(
+            Cow::Borrowed(service_name),
+            Cow::Borrowed(display_name),
+            Cow::Borrowed(description),
+        )
+    }
+
+    const fn with_identity(
+        service_name: Cow<'static, str>,
+        display_name: Cow<'static, str>,
+        description: Cow<'static, str>,
+    ) -> DummyController {
+        DummyController {
+            service_name,
+            display_name,
+            description,
+            backend: None,
+            dry_run: false,
+        }
+    }
+
+    /// The dummy controller has no service manager and no manifest to render.
+    #[cfg(feature = "install")]
+    pub fn render_manifest(&self, format: ManifestFormat) -> Result<String, Error> {
+        Err(format.unsupported())
+    }
+
+    /// The dummy controller has no service manager, only the executable is checked.
+    #[cfg(feature = "install")]
+    pub(crate) fn diagnose(&self) -> Vec<Check> {
+        vec![match std::env::current_exe() {
+            Ok(path) => binary_check(&path),
+            Err(e) => Check::failed("binary path", e.to_string()),
+        }]
+    }
+
+    #[cfg(feature = "runtime")]
+    pub fn register(&mut self, service_main_wrapper: fn()) -> Result<(), Error> {
+        match &self.backend {
+            Some(backend) => backend.register(&self.service_name)?,
+            None => unimplemented!(),
+        }
+        service_main_wrapper();
+        Ok(())
     }
 }
 
+#[cfg(feature = "runtime")]
 #[macro_export]
 macro_rules! Service {
-    ($name:expr, $function:ident) => {
+    ($name:expr, legacy $function:expr) => {
+        fn service_main_wrapper() {
+            ()
+        }
+    };
+    ($name:expr, $function:expr) => {
+        fn service_main_wrapper() {
+            ()
+        }
+    };
+    ($name:expr, $function:expr, stack_size = $stack_size:expr) => {
         fn service_main_wrapper() {
             ()
         }
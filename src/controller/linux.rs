@@ -1,42 +1,78 @@
+use std::borrow::Cow;
 use std::env;
 use std::fs::{self, File};
 use std::io::Write;
+use std::os::unix::fs::{symlink, OpenOptionsExt};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::sync::mpsc;
+use std::process::{self, Command};
+use std::sync::Arc;
+use std::time::Duration;
 
 use ctrlc;
-use log::{debug, info};
-
-use crate::controller::{ControllerInterface, ServiceMainFn};
+use log::{debug, error, info};
+
+use crate::audit::{self, AuditLog};
+use crate::channel::{self, EventPolicy};
+use crate::context::{create_service_dirs, data_dir, ServiceContext};
+#[cfg(feature = "install")]
+use crate::controller::doctor::{binary_check, Check};
+use crate::controller::{in_operation, Backend, ServiceMain};
+#[cfg(feature = "install")]
+use crate::controller::{ControllerInterface, ManifestFormat};
+use crate::coredump;
+use crate::deadline;
+use crate::dry_run;
+use crate::elevation::require_elevated;
+use crate::instance;
+use crate::logging::{self, LogConfig};
+use crate::restart;
+use crate::run_once::{self, RunOnce};
+#[cfg(feature = "session-events")]
 use crate::session;
-use crate::Error;
-use crate::ServiceEvent;
+#[cfg(feature = "state")]
+use crate::state;
+use crate::status::{self, AcceptedControls, ServiceStatus, StatusSubscription};
+use crate::stop;
+use crate::{Error, StopReason};
 
-#[cfg(feature = "systemd-rs")]
+#[cfg(all(feature = "systemd-rs", feature = "session-events"))]
 use {
+    crate::channel::EventSender,
+    crate::ServiceEvent,
     systemd_rs::login::monitor::{Category, Monitor},
     systemd_rs::login::session as login_session,
 };
 
-type LinuxServiceMainWrapperFn = extern "system" fn(args: Vec<String>);
+type LinuxServiceMainWrapperFn = fn(args: Vec<String>);
+#[cfg(feature = "session-events")]
 pub type Session = session::Session_<String>;
 
-fn systemctl_execute(args: &[&str]) -> Result<(), Error> {
-    let mut process = Command::new("systemctl");
+fn execute(program: &str, args: &[&str]) -> Result<(), Error> {
+    if dry_run::skip(|| format!("execute {} {}", program, args.join(" "))) {
+        return Ok(());
+    }
+    let mut process = Command::new(program);
     process.args(args);
 
-    let output = process
-        .output()
-        .map_err(|e| Error::new(&format!("Failed to execute command {}: {}", args[0], e)))?;
+    let output = process.output().map_err(|e| Error {
+        function: Some(program.to_string()),
+        ..Error::io(
+            &format!("Failed to execute command {} {}", program, args[0]),
+            &e,
+        )
+    })?;
 
     if !output.status.success() {
-        return Err(Error::new(&format!(
-            "Command \"{}\" failed ({}): {}",
-            args[0],
-            output.status.code().expect("Process terminated by signal"),
-            std::str::from_utf8(&output.stderr).unwrap_or_default()
-        )));
+        return Err(Error {
+            function: Some(program.to_string()),
+            ..Error::new(&format!(
+                "Command \"{} {}\" failed ({}): {}",
+                program,
+                args[0],
+                output.status.code().expect("Process terminated by signal"),
+                std::str::from_utf8(&output.stderr).unwrap_or_default()
+            ))
+        });
     }
 
     if !output.stdout.is_empty() {
@@ -46,6 +82,22 @@ fn systemctl_execute(args: &[&str]) -> Result<(), Error> {
     Ok(())
 }
 
+/// Runs a command that reports a state on its output, the exit status is not checked.
+fn query(program: &str, args: &[&str]) -> Result<String, Error> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| Error {
+            function: Some(program.to_string()),
+            ..Error::io(&format!("Failed to execute command {}", program), &e)
+        })?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn systemctl_execute(args: &[&str]) -> Result<(), Error> {
+    execute("systemctl", args)
+}
+
 fn systemd_install_daemon(name: &str) -> Result<(), Error> {
     systemctl_execute(&["daemon-reload"])?;
     systemctl_execute(&["enable", name])
@@ -71,31 +123,195 @@ fn systemd_stop_daemon(name: &str) -> Result<(), Error> {
     systemctl_execute(&["stop", name])
 }
 
+fn systemd_kill_daemon(name: &str) -> Result<(), Error> {
+    systemd_signal_daemon(name, "SIGKILL")
+}
+
+fn systemd_signal_daemon(name: &str, signal: &str) -> Result<(), Error> {
+    systemctl_execute(&["kill", &format!("--signal={}", signal), name])
+}
+
+/// Init system supervising the service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InitSystem {
+    Systemd,
+    /// The service directory is created in `/etc/sv` and linked in `/var/service`.
+    Runit,
+    /// The service directory is created in `/etc/s6/sv` and linked in `/run/service`.
+    S6,
+}
+
+impl InitSystem {
+    fn definition_dir(self) -> &'static Path {
+        match self {
+            InitSystem::Systemd => Path::new("/lib/systemd/system/"),
+            InitSystem::Runit => Path::new("/etc/sv/"),
+            InitSystem::S6 => Path::new("/etc/s6/sv/"),
+        }
+    }
+
+    fn default_scan_dir(self) -> &'static Path {
+        match self {
+            InitSystem::Systemd => Path::new("/lib/systemd/system/"),
+            InitSystem::Runit => Path::new("/var/service/"),
+            InitSystem::S6 => Path::new("/run/service/"),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct LinuxController {
-    pub service_name: String,
-    pub display_name: String,
-    pub description: String,
+    pub service_name: Cow<'static, str>,
+    pub display_name: Cow<'static, str>,
+    pub description: Cow<'static, str>,
     pub config: Option<String>,
+    pub event_policy: EventPolicy,
+    /// Installs a rotating file logger writing to the log directory of the service.
+    pub log_config: Option<LogConfig>,
+    /// Time the service main function has to return after the `Stop` event, the process then
+    /// exits with `STOP_DEADLINE_EXIT_CODE` instead of staying in the stopping state forever.
+    pub stop_deadline: Option<Duration>,
+    /// Enables the two-phase stop: stop requests send `StopRequested`, followed by `StopNow`
+    /// once this grace period ends.
+    pub stop_grace_period: Option<Duration>,
+    /// Lets only one instance of the service run, see `instance::acquire()`.
+    pub single_instance: bool,
+    pub init_system: InitSystem,
+    /// Directory scanned by the runit or s6 supervisor, overrides the distribution default.
+    pub scan_dir: Option<PathBuf>,
+    /// Time the service needs to start, written as `TimeoutStartSec=` in the systemd unit.
+    pub startup_timeout: Option<Duration>,
+    /// Lets the service write core dumps: the core size limit is lifted in the unit or run
+    /// script and raised again at startup, and the working directory is set to the data
+    /// directory so that relative `kernel.core_pattern` values such as the default `core`
+    /// write the dumps there.
+    pub core_dumps: bool,
+    /// Runs the service once at the next boot: it is disabled or deleted by the service
+    /// wrapper after its main function returned 0. The cleanup requires the `install` feature.
+    pub run_once: Option<RunOnce>,
+    /// Service manager the operations are delegated to instead of the one of the system.
+    pub backend: Option<Arc<dyn Backend>>,
+    /// Logs the commands, files and system calls of the operations instead of performing
+    /// them, to audit what an installer does.
+    pub dry_run: bool,
+    /// Records the operations and their results, see `AuditLog`.
+    pub audit_log: Option<AuditLog>,
 }
 
 impl LinuxController {
     pub fn new(service_name: &str, display_name: &str, description: &str) -> LinuxController {
+        LinuxController::with_identity(
+            Cow::Owned(service_name.to_string()),
+            Cow::Owned(display_name.to_string()),
+            Cow::Owned(description.to_string()),
+        )
+    }
+
+    /// Creates a controller like `new()` from names known at compile time, which are borrowed
+    /// instead of copied so that the controller can be a `const` or a `static`.
+    pub const fn const_new(
+        service_name: &'static str,
+        display_name: &'static str,
+        description: &'static str,
+    ) -> LinuxController {
+        LinuxController::with_identity(
+            Cow::Borrowed(service_name),
+            Cow::Borrowed(display_name),
+            Cow::Borrowed(description),
+        )
+    }
+
+    const fn with_identity(
+        service_name: Cow<'static, str>,
+        display_name: Cow<'static, str>,
+        description: Cow<'static, str>,
+    ) -> LinuxController {
         LinuxController {
-            service_name: service_name.to_string(),
-            display_name: display_name.to_string(),
-            description: description.to_string(),
+            service_name,
+            display_name,
+            description,
+            backend: None,
+            dry_run: false,
+            audit_log: None,
             config: None,
+            event_policy: EventPolicy::new(),
+            log_config: None,
+            stop_deadline: None,
+            stop_grace_period: None,
+            single_instance: false,
+            init_system: InitSystem::Systemd,
+            scan_dir: None,
+            startup_timeout: None,
+            core_dumps: false,
+            run_once: None,
         }
     }
 
+    #[cfg(feature = "runtime")]
     pub fn register(
         &mut self,
         service_main_wrapper: LinuxServiceMainWrapperFn,
     ) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            backend.register(&self.service_name)?;
+        }
+        channel::set_event_policy(self.event_policy);
+        logging::set_log_config(self.log_config);
+        deadline::set_stop_deadline(self.stop_deadline);
+        stop::set_grace_period(self.stop_grace_period);
+        instance::set_single_instance(self.single_instance);
+        coredump::set_raise_at_startup(self.core_dumps);
+        #[cfg(feature = "install")]
+        run_once::set_run_once(self.run_once, self.clone(), Self::finish_run_once);
         service_main_wrapper(env::args().collect());
         Ok(())
     }
 
+    /// Checks of `doctor()` specific to the init system.
+    #[cfg(feature = "install")]
+    pub(crate) fn diagnose(&self) -> Vec<Check> {
+        let manager = match self.init_system {
+            InitSystem::Systemd if Path::new("/run/systemd/system").is_dir() => {
+                Check::passed("service manager")
+            }
+            InitSystem::Systemd => Check::failed("service manager", "systemd is not running"),
+            _ => {
+                let scan_dir = self.get_service_link();
+                match scan_dir.parent() {
+                    Some(dir) if dir.is_dir() => Check::passed("service manager"),
+                    _ => Check::failed(
+                        "service manager",
+                        format!("the scan directory of {} doesn't exist", scan_dir.display()),
+                    ),
+                }
+            }
+        };
+        let definition = match self.init_system {
+            InitSystem::Systemd => self.get_service_unit_path(),
+            _ => self.get_service_dir(),
+        };
+        let name = if definition.exists() {
+            Check::warning(
+                "service name",
+                format!(
+                    "{} is already installed at {}",
+                    self.service_name,
+                    definition.display()
+                ),
+            )
+        } else {
+            Check::passed("service name")
+        };
+        let binary = match fs::read_link("/proc/self/exe") {
+            Ok(path) => binary_check(&path),
+            Err(e) => Check::failed(
+                "binary path",
+                format!("Failed to read /proc/self/exe: {}", e),
+            ),
+        };
+        vec![manager, name, binary]
+    }
+
     fn get_service_file_name(&self) -> String {
         format!("{}.service", &self.service_name)
     }
@@ -108,7 +324,28 @@ impl LinuxController {
         Path::new("/lib/systemd/system/").join(format!("{}.d", self.get_service_file_name()))
     }
 
+    /// Renders the systemd unit or the `run` script `create()` installs, without installing
+    /// them. Both formats are rendered whatever the init system of the controller.
+    #[cfg(feature = "install")]
+    pub fn render_manifest(&self, format: ManifestFormat) -> Result<String, Error> {
+        match format {
+            ManifestFormat::SystemdUnit => self.get_service_unit_content(),
+            ManifestFormat::RunScript => self.get_run_script_content(),
+            format => Err(format.unsupported()),
+        }
+    }
+
     fn get_service_unit_content(&self) -> Result<String, Error> {
+        let mut directives = self
+            .startup_timeout
+            .map(|timeout| format!("TimeoutStartSec={}\n", timeout.as_secs().max(1)))
+            .unwrap_or_default();
+        if self.core_dumps {
+            directives.push_str(&format!(
+                "LimitCORE=infinity\nWorkingDirectory={}\n",
+                data_dir(&self.service_name).display()
+            ));
+        }
         Ok(format!(
             r#"
 [Unit]
@@ -116,77 +353,406 @@ Description={}
 
 [Service]
 ExecStart={}
-
+{}
 [Install]
 WantedBy=multi-user.target"#,
             self.service_name,
             fs::read_link("/proc/self/exe")
-                .map_err(|e| Error::new(&format!("Failed to read /proc/self/exe: {}", e)))?
+                .map_err(|e| Error::io("Failed to read /proc/self/exe", &e))?
                 .to_str()
-                .ok_or("Failed to parse /proc/self/exe")?
+                .ok_or("Failed to parse /proc/self/exe")?,
+            directives
         ))
     }
 
     fn write_service_config(&self) -> Result<(), Error> {
         let path = self.get_service_unit_path();
         let content = self.get_service_unit_content()?;
-        info!("Writing service file {}", path.display());
-        File::create(&path)
-            .and_then(|mut file| file.write_all(content.as_bytes()))
-            .map_err(|e| Error::new(&format!("Failed to write {}: {}", path.display(), e)))?;
+        if !dry_run::skip(|| format!("write service file {}:{}", path.display(), content)) {
+            info!("Writing service file {}", path.display());
+            File::create(&path)
+                .and_then(|mut file| file.write_all(content.as_bytes()))
+                .map_err(|e| Error::io(&format!("Failed to write {}", path.display()), &e))?;
+        }
 
         if let Some(ref config) = self.config {
             let path = self
                 .get_service_dropin_dir()
                 .join(format!("{}.conf", self.service_name));
+            if dry_run::skip(|| format!("write config file {}:\n{}", path.display(), config)) {
+                return Ok(());
+            }
             fs::create_dir(path.parent().unwrap())
-                .map_err(|e| Error::new(&format!("Failed to create {}: {}", path.display(), e)))?;
+                .map_err(|e| Error::io(&format!("Failed to create {}", path.display()), &e))?;
             info!("Writing config file {}", path.display());
             File::create(&path)
                 .and_then(|mut file| file.write_all(config.as_bytes()))
-                .map_err(|e| Error::new(&format!("Failed to write {}: {}", path.display(), e)))?;
+                .map_err(|e| Error::io(&format!("Failed to write {}", path.display()), &e))?;
         }
 
         Ok(())
     }
-}
 
-impl ControllerInterface for LinuxController {
-    fn create(&mut self) -> Result<(), Error> {
-        self.write_service_config()?;
+    fn get_service_dir(&self) -> PathBuf {
+        self.init_system.definition_dir().join(&*self.service_name)
+    }
 
-        systemd_install_daemon(&self.service_name)
+    fn get_service_link(&self) -> PathBuf {
+        self.scan_dir
+            .as_deref()
+            .unwrap_or_else(|| self.init_system.default_scan_dir())
+            .join(&*self.service_name)
     }
 
-    fn delete(&mut self) -> Result<(), Error> {
-        systemd_uninstall_daemon(&self.service_name)?;
+    fn get_run_script_content(&self) -> Result<String, Error> {
+        let core_dumps = if self.core_dumps {
+            format!(
+                "ulimit -c unlimited\ncd {}\n",
+                data_dir(&self.service_name).display()
+            )
+        } else {
+            String::new()
+        };
+        Ok(format!(
+            "#!/bin/sh\n{}exec {} 2>&1\n",
+            core_dumps,
+            fs::read_link("/proc/self/exe")
+                .map_err(|e| Error::io("Failed to read /proc/self/exe", &e))?
+                .to_str()
+                .ok_or("Failed to parse /proc/self/exe")?
+        ))
+    }
 
-        let path = self.get_service_unit_path();
-        fs::remove_file(&path)
-            .map_err(|e| debug!("Failed to delete {}: {}", path.display(), e))
-            .ok();
+    fn write_service_dir(&self) -> Result<(), Error> {
+        let dir = self.get_service_dir();
+        if !dry_run::skip(|| format!("create {}", dir.display())) {
+            fs::create_dir_all(&dir)
+                .map_err(|e| Error::io(&format!("Failed to create {}", dir.display()), &e))?;
+        }
 
-        let path = self.get_service_dropin_dir();
-        fs::remove_dir_all(self.get_service_dropin_dir())
-            .map_err(|e| debug!("Failed to delete {}: {}", path.display(), e))
+        // The finish script runs after each exit of the service, the delay avoids restarting
+        // a crashing service in a tight loop.
+        let scripts = [
+            ("run", self.get_run_script_content()?),
+            ("finish", "#!/bin/sh\nsleep 1\n".to_string()),
+        ];
+        for (name, content) in scripts.iter() {
+            let path = dir.join(name);
+            if dry_run::skip(|| format!("write script {}:\n{}", path.display(), content)) {
+                continue;
+            }
+            info!("Writing script {}", path.display());
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o755)
+                .open(&path)
+                .and_then(|mut file| file.write_all(content.as_bytes()))
+                .map_err(|e| Error::io(&format!("Failed to write {}", path.display()), &e))?;
+        }
+
+        let link = self.get_service_link();
+        if dry_run::skip(|| format!("link {} to {}", link.display(), dir.display())) {
+            return Ok(());
+        }
+        info!("Linking {} to {}", link.display(), dir.display());
+        symlink(&dir, &link)
+            .map_err(|e| Error::io(&format!("Failed to link {}", link.display()), &e))
+    }
+
+    /// Returns the status of the service, its state is the `ActiveState` of the unit with
+    /// systemd (such as `active` or `failed`), `run` or `down` with runit and `up` or `down`
+    /// with s6.
+    pub fn status(&self) -> Result<ServiceStatus, Error> {
+        if let Some(backend) = &self.backend {
+            return backend.status(&self.service_name);
+        }
+        let state = match self.init_system {
+            InitSystem::Systemd => {
+                query("systemctl", &["is-active", &self.get_service_file_name()])?
+            }
+            InitSystem::Runit | InitSystem::S6 => {
+                let link = self.get_service_link();
+                let link = link.to_str().ok_or("Failed to parse the service path")?;
+                let output = if self.init_system == InitSystem::Runit {
+                    query("sv", &["status", link])?
+                } else {
+                    query("s6-svstat", &[link])?
+                };
+                output
+                    .split(|c: char| c == ':' || c.is_whitespace())
+                    .next()
+                    .unwrap_or_default()
+                    .to_string()
+            }
+        };
+        Ok(ServiceStatus::new(
+            &state,
+            status::load_start_record(&self.service_name),
+        ))
+    }
+
+    /// Returns the controls the service accepts, `STOP` and `PAUSE_CONTINUE` while it runs.
+    pub fn accepted_controls(&self) -> Result<AcceptedControls, Error> {
+        if self.backend.is_some() {
+            return Ok(AcceptedControls::signaled(
+                self.status()?.state == "running",
+            ));
+        }
+        let running = matches!(self.status()?.state.as_str(), "active" | "run" | "up");
+        Ok(AcceptedControls::signaled(running))
+    }
+
+    /// Subscribes to the state transitions of the service, its status is queried every second.
+    pub fn subscribe_status(&self) -> StatusSubscription {
+        let controller = self.clone();
+        status::poll(move || controller.status())
+    }
+
+    fn supervise(&self, action: &str) -> Result<(), Error> {
+        let link = self.get_service_link();
+        let link = link.to_str().ok_or("Failed to parse the service path")?;
+        match self.init_system {
+            InitSystem::Runit => execute("sv", &[action, link]),
+            _ => {
+                let flag = match action {
+                    "start" => "-u",
+                    "stop" => "-d",
+                    "pause" => "-p",
+                    "cont" => "-c",
+                    _ => "-k",
+                };
+                execute("s6-svc", &[flag, link])
+            }
+        }
+    }
+
+    // Runs in the service process, which is stopped by the supervisor only once it exits.
+    #[cfg(feature = "install")]
+    fn finish_run_once(&mut self, run_once: RunOnce) -> Result<(), Error> {
+        match (run_once, self.init_system) {
+            (RunOnce::Delete, _) => self.delete(),
+            (RunOnce::Disable, InitSystem::Systemd) => {
+                systemctl_execute(&["disable", &self.get_service_file_name()])
+            }
+            (RunOnce::Disable, _) => {
+                // The supervisor doesn't start services with a down file.
+                let path = self.get_service_dir().join("down");
+                File::create(&path)
+                    .map_err(|e| Error::io(&format!("Failed to write {}", path.display()), &e))?;
+                self.supervise("stop")
+            }
+        }
+    }
+
+    fn delete_service_dir(&self) -> Result<(), Error> {
+        self.supervise("stop").map_err(|e| debug!("{}", e)).ok();
+
+        let link = self.get_service_link();
+        if !dry_run::skip(|| format!("delete {}", link.display())) {
+            fs::remove_file(&link)
+                .map_err(|e| Error::io(&format!("Failed to delete {}", link.display()), &e))?;
+        }
+
+        if self.init_system == InitSystem::S6 {
+            if let Some(scan_dir) = link.parent().and_then(|dir| dir.to_str()) {
+                execute("s6-svscanctl", &["-an", scan_dir])
+                    .map_err(|e| debug!("{}", e))
+                    .ok();
+            }
+        }
+
+        let dir = self.get_service_dir();
+        if dry_run::skip(|| format!("delete {}", dir.display())) {
+            return Ok(());
+        }
+        fs::remove_dir_all(&dir)
+            .map_err(|e| debug!("Failed to delete {}: {}", dir.display(), e))
             .ok();
 
         Ok(())
     }
+}
+
+#[cfg(feature = "install")]
+impl ControllerInterface for LinuxController {
+    fn create(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "create",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.create(&self.service_name, &self.display_name, &self.description),
+            );
+        }
+        in_operation(
+            "create",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || {
+                require_elevated()?;
+                create_service_dirs(&self.service_name)?;
+
+                if self.init_system != InitSystem::Systemd {
+                    return self.write_service_dir();
+                }
+
+                self.write_service_config()?;
+
+                systemd_install_daemon(&self.service_name)
+            },
+        )
+    }
+
+    fn delete(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "delete",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.delete(&self.service_name),
+            );
+        }
+        in_operation(
+            "delete",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || {
+                require_elevated()?;
+                if self.init_system != InitSystem::Systemd {
+                    return self.delete_service_dir();
+                }
+
+                systemd_uninstall_daemon(&self.service_name)?;
+
+                let path = self.get_service_unit_path();
+                if dry_run::skip(|| {
+                    format!(
+                        "delete {} and {}",
+                        path.display(),
+                        self.get_service_dropin_dir().display()
+                    )
+                }) {
+                    return Ok(());
+                }
+                fs::remove_file(&path)
+                    .map_err(|e| debug!("Failed to delete {}: {}", path.display(), e))
+                    .ok();
+
+                let path = self.get_service_dropin_dir();
+                fs::remove_dir_all(self.get_service_dropin_dir())
+                    .map_err(|e| debug!("Failed to delete {}: {}", path.display(), e))
+                    .ok();
+
+                Ok(())
+            },
+        )
+    }
 
     fn start(&mut self) -> Result<(), Error> {
-        systemd_start_daemon(&self.service_name)
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "start",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.start(&self.service_name),
+            );
+        }
+        in_operation(
+            "start",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || match self.init_system {
+                InitSystem::Systemd => systemd_start_daemon(&self.service_name),
+                _ => self.supervise("start"),
+            },
+        )
     }
 
     fn stop(&mut self) -> Result<(), Error> {
-        systemd_stop_daemon(&self.service_name)
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "stop",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.stop(&self.service_name),
+            );
+        }
+        in_operation(
+            "stop",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || match self.init_system {
+                InitSystem::Systemd => systemd_stop_daemon(&self.service_name),
+                _ => self.supervise("stop"),
+            },
+        )
+    }
+
+    fn kill(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "kill",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.kill(&self.service_name),
+            );
+        }
+        in_operation(
+            "kill",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || match self.init_system {
+                InitSystem::Systemd => systemd_kill_daemon(&self.service_name),
+                _ => self.supervise("kill"),
+            },
+        )
+    }
+
+    fn pause(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "pause",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.pause(&self.service_name),
+            );
+        }
+        in_operation(
+            "pause",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || match self.init_system {
+                InitSystem::Systemd => systemd_signal_daemon(&self.service_name, "SIGSTOP"),
+                _ => self.supervise("pause"),
+            },
+        )
+    }
+
+    fn resume(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "resume",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.resume(&self.service_name),
+            );
+        }
+        in_operation(
+            "resume",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || match self.init_system {
+                InitSystem::Systemd => systemd_signal_daemon(&self.service_name, "SIGCONT"),
+                _ => self.supervise("cont"),
+            },
+        )
     }
 }
 
-#[cfg(feature = "systemd-rs")]
-fn run_monitor<T: Send + 'static>(
-    tx: mpsc::Sender<ServiceEvent<T>>,
-) -> Result<Monitor, std::io::Error> {
+#[cfg(all(feature = "systemd-rs", feature = "session-events"))]
+fn run_monitor<T: Send + 'static>(tx: EventSender<T>) -> Result<Monitor, std::io::Error> {
     let monitor = Monitor::new()?;
 
     let mut current_session = match login_session::get_active_session() {
@@ -214,13 +780,13 @@ fn run_monitor<T: Send + 'static>(
 
         if session_changed {
             if let Some(active_session) = active_session.as_ref() {
-                let _ = tx.send(ServiceEvent::SessionConnect(Session::new(
+                tx.send(ServiceEvent::SessionConnect(Session::new(
                     active_session.identifier.to_string(),
                 )));
             }
 
             if let Some(current_session) = current_session.as_ref() {
-                let _ = tx.send(ServiceEvent::SessionDisconnect(Session::new(
+                tx.send(ServiceEvent::SessionDisconnect(Session::new(
                     current_session.identifier.to_string(),
                 )));
             }
@@ -232,29 +798,61 @@ fn run_monitor<T: Send + 'static>(
     Ok(monitor)
 }
 
+#[cfg(feature = "runtime")]
 #[macro_export]
 macro_rules! Service {
-    ($name:expr, $function:ident) => {
-        extern "system" fn service_main_wrapper(args: Vec<String>) {
-            dispatch($function, args);
+    ($name:expr, legacy $function:expr) => {
+        fn service_main_wrapper(args: Vec<String>) {
+            dispatch($crate::__legacy_service_main!($function), $name, args);
+        }
+    };
+    ($name:expr, $function:expr) => {
+        fn service_main_wrapper(args: Vec<String>) {
+            dispatch($function, $name, args);
+        }
+    };
+    // The service main function runs on the main thread, its stack size is set by the system.
+    ($name:expr, $function:expr, stack_size = $stack_size:expr) => {
+        fn service_main_wrapper(args: Vec<String>) {
+            dispatch($function, $name, args);
         }
     };
 }
 
+#[cfg(feature = "runtime")]
 #[doc(hidden)]
-pub fn dispatch<T: Send + 'static>(service_main: ServiceMainFn<T>, args: Vec<String>) {
-    let (tx, rx) = mpsc::channel();
-
-    #[cfg(feature = "systemd-rs")]
+pub fn dispatch<T: Send + 'static, F: ServiceMain<T>>(
+    service_main: F,
+    name: &str,
+    args: Vec<String>,
+) {
+    let (tx, rx, _tx) = channel::channel();
+
+    #[cfg(all(feature = "systemd-rs", feature = "session-events"))]
     {
         let _monitor = run_monitor(tx.clone()).expect("Failed to run session monitor");
     }
 
-    let _tx = tx.clone();
-
     ctrlc::set_handler(move || {
-        let _ = tx.send(ServiceEvent::Stop);
+        stop::request(&tx, StopReason::Requested);
     })
     .expect("Failed to register Ctrl-C handler");
-    service_main(rx, _tx, args, false);
+    coredump::raise_at_startup();
+    restart::record_executable();
+    let mut ctx = ServiceContext::new(name, args);
+    logging::init_service_logger(&mut ctx);
+    let _instance = match instance::acquire_for_service(name) {
+        Ok(instance) => instance,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    status::record_start(name);
+    let exit_code = service_main(rx, _tx, ctx);
+    deadline::service_main_returned();
+    #[cfg(feature = "state")]
+    state::save_registered();
+    run_once::service_main_returned(exit_code);
+    restart::reexec_if_requested();
 }
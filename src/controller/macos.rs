@@ -1,22 +1,29 @@
+// Without the session-events feature the session monitor is compiled but never started.
+#![cfg_attr(not(feature = "session-events"), allow(dead_code))]
+
 use std::{
+    borrow::Cow,
+    collections::HashSet,
     env,
     ffi::c_void,
     fmt,
     fs::{self, File},
     io::Write,
+    panic::{self, AssertUnwindSafe},
     path::{Path, PathBuf},
-    process::Command,
+    process::{self, Command},
     ptr,
     sync::{
         atomic::{AtomicBool, Ordering},
         mpsc, Arc, Mutex,
     },
     thread,
+    time::Duration,
 };
 
 use chrono;
 use ctrlc;
-use log::info;
+use log::{error, info, warn};
 use timer;
 
 use core_foundation::{
@@ -29,22 +36,50 @@ use core_foundation::{
     string::{CFString, CFStringRef},
 };
 
+use core_foundation_sys::{
+    array::{CFArrayGetCount, CFArrayGetTypeID, CFArrayGetValueAtIndex},
+    base::CFGetTypeID,
+    dictionary::{CFDictionaryGetTypeID, CFDictionaryGetValue, CFDictionaryRef},
+    number::{kCFNumberSInt32Type, CFNumberGetTypeID, CFNumberGetValue, CFNumberRef},
+};
+
 use system_configuration_sys::{
     dynamic_store::{
-        SCDynamicStoreContext, SCDynamicStoreCreate, SCDynamicStoreCreateRunLoopSource,
-        SCDynamicStoreRef, SCDynamicStoreSetNotificationKeys,
+        SCDynamicStoreContext, SCDynamicStoreCopyValue, SCDynamicStoreCreate,
+        SCDynamicStoreCreateRunLoopSource, SCDynamicStoreRef, SCDynamicStoreSetNotificationKeys,
     },
     dynamic_store_copy_specific::{uid_t, SCDynamicStoreCopyConsoleUser},
 };
 
-use crate::controller::{ControllerInterface, ServiceMainFn};
+use crate::audit::{self, AuditLog};
+use crate::channel::{self, EventPolicy, EventSender};
+use crate::context::{self, create_service_dirs, ServiceContext};
+#[cfg(feature = "install")]
+use crate::controller::doctor::{binary_check, Check};
+use crate::controller::{in_operation, panic_message, Backend, ServiceMain};
+#[cfg(feature = "install")]
+use crate::controller::{ControllerInterface, ManifestFormat};
+use crate::deadline;
+use crate::dry_run;
+use crate::elevation::require_elevated;
+use crate::instance;
+use crate::logging::{self, LogConfig};
+use crate::restart;
+use crate::run_once::{self, RunOnce};
+#[cfg(feature = "session-events")]
 use crate::session;
+#[cfg(feature = "state")]
+use crate::state;
+use crate::status::{self, AcceptedControls, ServiceStatus, StatusSubscription};
+use crate::stop;
 use crate::Error;
-use crate::ServiceEvent;
+use crate::{PowerEvent, ServiceEvent, StopReason};
 
-type MacosServiceMainWrapperFn = extern "system" fn(args: Vec<String>);
+type MacosServiceMainWrapperFn = fn(args: Vec<String>);
+#[cfg(feature = "session-events")]
 pub type Session = session::Session_<u32>;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum LaunchAgentTargetSesssion {
     GUI,
     NonGUI,
@@ -64,16 +99,18 @@ impl fmt::Display for LaunchAgentTargetSesssion {
 }
 
 fn launchctl_load_daemon(plist_path: &Path) -> Result<(), Error> {
+    if dry_run::skip(|| format!("launchctl load {}", plist_path.display())) {
+        return Ok(());
+    }
     let output = Command::new("launchctl")
         .arg("load")
         .arg(&plist_path.to_str().unwrap())
         .output()
         .map_err(|e| {
-            Error::new(&format!(
-                "Failed to load plist {}: {}",
-                plist_path.display(),
-                e
-            ))
+            Error::io(
+                &format!("Failed to load plist {}", plist_path.display()),
+                &e,
+            )
         })?;
     if output.stdout.len() > 0 {
         info!("{}", String::from_utf8_lossy(&output.stdout));
@@ -82,16 +119,18 @@ fn launchctl_load_daemon(plist_path: &Path) -> Result<(), Error> {
 }
 
 fn launchctl_unload_daemon(plist_path: &Path) -> Result<(), Error> {
+    if dry_run::skip(|| format!("launchctl unload {}", plist_path.display())) {
+        return Ok(());
+    }
     let output = Command::new("launchctl")
         .arg("unload")
         .arg(&plist_path.to_str().unwrap())
         .output()
         .map_err(|e| {
-            Error::new(&format!(
-                "Failed to unload plist {}: {}",
-                plist_path.display(),
-                e
-            ))
+            Error::io(
+                &format!("Failed to unload plist {}", plist_path.display()),
+                &e,
+            )
         })?;
     if output.stdout.len() > 0 {
         info!("{}", String::from_utf8_lossy(&output.stdout));
@@ -99,12 +138,78 @@ fn launchctl_unload_daemon(plist_path: &Path) -> Result<(), Error> {
     Ok(())
 }
 
+/// Runs `launchctl bootstrap` or `launchctl bootout` for `plist_path` in the GUI domain of the
+/// current user.
+fn launchctl_user_domain(subcommand: &str, plist_path: &Path) -> Result<(), Error> {
+    let domain = format!("gui/{}", unsafe { libc::getuid() });
+    if dry_run::skip(|| {
+        format!(
+            "launchctl {} {} {}",
+            subcommand,
+            domain,
+            plist_path.display()
+        )
+    }) {
+        return Ok(());
+    }
+    let output = Command::new("launchctl")
+        .arg(subcommand)
+        .arg(&domain)
+        .arg(plist_path)
+        .output()
+        .map_err(|e| Error {
+            function: Some("launchctl".to_string()),
+            ..Error::io(&format!("Failed to execute launchctl {}", subcommand), &e)
+        })?;
+    if !output.status.success() {
+        return Err(Error {
+            function: Some("launchctl".to_string()),
+            ..Error::new(&format!(
+                "Command \"launchctl {} {} {}\" failed: {}",
+                subcommand,
+                domain,
+                plist_path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        });
+    }
+    Ok(())
+}
+
+fn launchctl_disable(service_target: &str) -> Result<(), Error> {
+    if dry_run::skip(|| format!("launchctl disable {}", service_target)) {
+        return Ok(());
+    }
+    let output = Command::new("launchctl")
+        .arg("disable")
+        .arg(service_target)
+        .output()
+        .map_err(|e| Error {
+            function: Some("launchctl".to_string()),
+            ..Error::io("Failed to execute launchctl disable", &e)
+        })?;
+    if !output.status.success() {
+        return Err(Error {
+            function: Some("launchctl".to_string()),
+            ..Error::new(&format!(
+                "Command \"launchctl disable {}\" failed: {}",
+                service_target,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        });
+    }
+    Ok(())
+}
+
 fn launchctl_start_daemon(name: &str) -> Result<(), Error> {
+    if dry_run::skip(|| format!("launchctl start {}", name)) {
+        return Ok(());
+    }
     let output = Command::new("launchctl")
         .arg("start")
         .arg(name)
         .output()
-        .map_err(|e| Error::new(&format!("Failed to start {}: {}", name, e)))?;
+        .map_err(|e| Error::io(&format!("Failed to start {}", name), &e))?;
     if output.stdout.len() > 0 {
         info!("{}", String::from_utf8_lossy(&output.stdout));
     }
@@ -112,51 +217,302 @@ fn launchctl_start_daemon(name: &str) -> Result<(), Error> {
 }
 
 fn launchctl_stop_daemon(name: &str) -> Result<(), Error> {
+    if dry_run::skip(|| format!("launchctl stop {}", name)) {
+        return Ok(());
+    }
     let output = Command::new("launchctl")
         .arg("stop")
         .arg(name)
         .output()
-        .map_err(|e| Error::new(&format!("Failed to stop {}: {}", name, e)))?;
+        .map_err(|e| Error::io(&format!("Failed to stop {}", name), &e))?;
     if output.stdout.len() > 0 {
         info!("{}", String::from_utf8_lossy(&output.stdout));
     }
     Ok(())
 }
 
+fn launchctl_get_pid(name: &str) -> Result<libc::pid_t, Error> {
+    let output = Command::new("launchctl")
+        .arg("list")
+        .arg(name)
+        .output()
+        .map_err(|e| Error::io(&format!("Failed to query {}", name), &e))?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("\"PID\" = "))
+        .filter_map(|pid| pid.trim_end_matches(';').parse().ok())
+        .next()
+        .ok_or_else(|| Error::new(&format!("Failed to find the process of {}", name)))
+}
+
+fn launchctl_kill_daemon(name: &str) -> Result<(), Error> {
+    launchctl_signal_daemon(name, libc::SIGKILL, "kill")
+}
+
+fn launchctl_signal_daemon(name: &str, signal: libc::c_int, action: &str) -> Result<(), Error> {
+    let pid = launchctl_get_pid(name)?;
+    if dry_run::skip(|| format!("{} {} (kill -{} {})", action, name, signal, pid)) {
+        return Ok(());
+    }
+    if unsafe { libc::kill(pid, signal) } != 0 {
+        return Err(Error {
+            function: Some("kill".to_string()),
+            ..Error::io(
+                &format!("Failed to {} {} (pid {})", action, name, pid),
+                &std::io::Error::last_os_error(),
+            )
+        });
+    }
+    Ok(())
+}
+
+/// Conditions of the launchd `KeepAlive` dictionary, the job is kept running while any of them
+/// holds.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeepAlive {
+    /// Restarts the job after it exits successfully (`true`) or after it fails (`false`).
+    pub successful_exit: Option<bool>,
+    /// Keeps the job running while the network is up (`true`) or down (`false`). Recent
+    /// macOS versions ignore this condition.
+    pub network_state: Option<bool>,
+    /// Keeps the job running while each path exists (`true`) or doesn't exist (`false`).
+    pub path_state: Vec<(String, bool)>,
+}
+
+impl KeepAlive {
+    fn plist(&self) -> String {
+        let mut plist = String::from(
+            r#"
+<key>KeepAlive</key>
+<dict>"#,
+        );
+        if let Some(successful_exit) = self.successful_exit {
+            plist.push_str(&format!(
+                r#"
+<key>SuccessfulExit</key>
+<{}/>"#,
+                successful_exit
+            ));
+        }
+        if let Some(network_state) = self.network_state {
+            plist.push_str(&format!(
+                r#"
+<key>NetworkState</key>
+<{}/>"#,
+                network_state
+            ));
+        }
+        if !self.path_state.is_empty() {
+            plist.push_str(
+                r#"
+<key>PathState</key>
+<dict>"#,
+            );
+            for (path, exists) in &self.path_state {
+                plist.push_str(&format!(
+                    r#"
+<key>{}</key>
+<{}/>"#,
+                    path, exists
+                ));
+            }
+            plist.push_str(
+                r#"
+</dict>"#,
+            );
+        }
+        plist.push_str(
+            r#"
+</dict>"#,
+        );
+        plist
+    }
+}
+
+/// Kind of launchd job installed by the controller, see `MacosController::set_job()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LaunchdJob {
+    /// System wide daemon in `/Library/LaunchDaemons`, running as root.
+    Daemon,
+    /// Agent in `/Library/LaunchAgents`, started in the session of every user at login.
+    Agent,
+    /// Agent in `~/Library/LaunchAgents`, installed and started for the current user only.
+    UserAgent,
+}
+
+/// Manages the service on the system.
+#[derive(Clone)]
 pub struct MacosController {
-    /// Manages the service on the system.
-    pub service_name: String,
-    pub display_name: String,
-    pub description: String,
+    pub service_name: Cow<'static, str>,
+    pub display_name: Cow<'static, str>,
+    pub description: Cow<'static, str>,
     pub is_agent: bool,
+    /// Installs the agent for the current user only, with `launchctl bootstrap gui/<uid>`.
+    pub per_user: bool,
     pub session_types: Option<Vec<LaunchAgentTargetSesssion>>,
     pub keep_alive: bool,
+    /// Keeps the job running only under these conditions, replacing `keep_alive` when set.
+    pub keep_alive_conditions: Option<KeepAlive>,
+    /// Minimum time between two launches of the job, launchd defaults to 10 seconds.
+    pub throttle_interval: Option<Duration>,
+    /// Time launchd waits after `SIGTERM` before sending `SIGKILL` to stop the job, launchd
+    /// defaults to 20 seconds.
+    pub exit_timeout: Option<Duration>,
+    pub event_policy: EventPolicy,
+    /// Installs a rotating file logger writing to the log directory of the service.
+    pub log_config: Option<LogConfig>,
+    /// Time the service main function has to return after the `Stop` event, the process then
+    /// exits with `STOP_DEADLINE_EXIT_CODE` instead of staying in the stopping state forever.
+    pub stop_deadline: Option<Duration>,
+    /// Enables the two-phase stop: stop requests send `StopRequested`, followed by `StopNow`
+    /// once this grace period ends.
+    pub stop_grace_period: Option<Duration>,
+    /// Lets only one instance of the service run, see `instance::acquire()`.
+    pub single_instance: bool,
+    /// Runs the service once at the next boot, or the next login for agents: it is disabled
+    /// or deleted by the service wrapper after its main function returned 0. The job is not
+    /// kept alive. The cleanup requires the `install` feature.
+    pub run_once: Option<RunOnce>,
+    /// Service manager the operations are delegated to instead of the one of the system.
+    pub backend: Option<Arc<dyn Backend>>,
+    /// Logs the commands, files and system calls of the operations instead of performing
+    /// them, to audit what an installer does.
+    pub dry_run: bool,
+    /// Records the operations and their results, see `AuditLog`.
+    pub audit_log: Option<AuditLog>,
 }
 
 impl MacosController {
     pub fn new(service_name: &str, display_name: &str, description: &str) -> MacosController {
+        MacosController::with_identity(
+            Cow::Owned(service_name.to_string()),
+            Cow::Owned(display_name.to_string()),
+            Cow::Owned(description.to_string()),
+        )
+    }
+
+    /// Creates a controller like `new()` from names known at compile time, which are borrowed
+    /// instead of copied so that the controller can be a `const` or a `static`.
+    pub const fn const_new(
+        service_name: &'static str,
+        display_name: &'static str,
+        description: &'static str,
+    ) -> MacosController {
+        MacosController::with_identity(
+            Cow::Borrowed(service_name),
+            Cow::Borrowed(display_name),
+            Cow::Borrowed(description),
+        )
+    }
+
+    const fn with_identity(
+        service_name: Cow<'static, str>,
+        display_name: Cow<'static, str>,
+        description: Cow<'static, str>,
+    ) -> MacosController {
         MacosController {
-            service_name: service_name.to_string(),
-            display_name: display_name.to_string(),
-            description: description.to_string(),
+            service_name,
+            display_name,
+            description,
+            backend: None,
+            dry_run: false,
+            audit_log: None,
             is_agent: false,
+            per_user: false,
             session_types: None,
             keep_alive: true,
+            keep_alive_conditions: None,
+            throttle_interval: None,
+            exit_timeout: None,
+            event_policy: EventPolicy::new(),
+            log_config: None,
+            stop_deadline: None,
+            stop_grace_period: None,
+            single_instance: false,
+            run_once: None,
+        }
+    }
+
+    /// Selects whether a daemon, an agent for all users or an agent for the current user is
+    /// installed.
+    pub fn set_job(&mut self, job: LaunchdJob) {
+        self.is_agent = job != LaunchdJob::Daemon;
+        self.per_user = job == LaunchdJob::UserAgent;
+    }
+
+    pub fn job(&self) -> LaunchdJob {
+        match (self.is_agent, self.per_user) {
+            (false, _) => LaunchdJob::Daemon,
+            (true, false) => LaunchdJob::Agent,
+            (true, true) => LaunchdJob::UserAgent,
+        }
+    }
+
+    /// Returns the status of the service, its state is `running` when launchd reports a
+    /// process for the service and `stopped` otherwise.
+    pub fn status(&self) -> Result<ServiceStatus, Error> {
+        if let Some(backend) = &self.backend {
+            return backend.status(&self.service_name);
         }
+        let state = match launchctl_get_pid(&self.service_name) {
+            Ok(_) => "running",
+            Err(_) => "stopped",
+        };
+        Ok(ServiceStatus::new(
+            state,
+            status::load_start_record(&self.service_name),
+        ))
+    }
+
+    /// Returns the controls the service accepts, `STOP` and `PAUSE_CONTINUE` while it runs.
+    pub fn accepted_controls(&self) -> Result<AcceptedControls, Error> {
+        if self.backend.is_some() {
+            return Ok(AcceptedControls::signaled(
+                self.status()?.state == "running",
+            ));
+        }
+        let running = launchctl_get_pid(&self.service_name).is_ok();
+        Ok(AcceptedControls::signaled(running))
+    }
+
+    /// Subscribes to the state transitions of the service, its status is queried every second.
+    pub fn subscribe_status(&self) -> StatusSubscription {
+        let controller = self.clone();
+        status::poll(move || controller.status())
     }
 
     /// Register the `service_main_wrapper` function, this function is generated by the `Service!` macro.
+    #[cfg(feature = "runtime")]
     pub fn register(
         &mut self,
         service_main_wrapper: MacosServiceMainWrapperFn,
     ) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            backend.register(&self.service_name)?;
+        }
+        channel::set_event_policy(self.event_policy);
+        logging::set_log_config(self.log_config);
+        deadline::set_stop_deadline(self.stop_deadline);
+        stop::set_grace_period(self.stop_grace_period);
+        instance::set_single_instance(self.single_instance);
+        #[cfg(feature = "install")]
+        run_once::set_run_once(self.run_once, self.clone(), Self::finish_run_once);
         service_main_wrapper(env::args().collect());
         Ok(())
     }
 
+    /// Renders the property list `create()` writes for launchd, without installing it.
+    #[cfg(feature = "install")]
+    pub fn render_manifest(&self, format: ManifestFormat) -> Result<String, Error> {
+        match format {
+            ManifestFormat::LaunchdPlist => self.get_plist_content(),
+            format => Err(format.unsupported()),
+        }
+    }
+
     fn get_plist_content(&self) -> Result<String, Error> {
-        let mut current_exe = env::current_exe()
-            .map_err(|e| Error::new(&format!("env::current_exe() failed: {}", e)))?;
+        let mut current_exe =
+            env::current_exe().map_err(|e| Error::io("env::current_exe() failed", &e))?;
         let current_exe_str = current_exe
             .to_str()
             .expect("current_exe path to be unicode")
@@ -214,12 +570,35 @@ impl MacosController {
             }
         }
 
-        if self.keep_alive {
-            plist.push_str(
-                r#"
+        // Run-once jobs are only launched at load, launchd must not restart them.
+        if self.run_once.is_none() {
+            if let Some(keep_alive) = &self.keep_alive_conditions {
+                plist.push_str(&keep_alive.plist());
+            } else if self.keep_alive {
+                plist.push_str(
+                    r#"
 <key>KeepAlive</key>
 <true/>"#,
-            );
+                );
+            }
+        }
+
+        if let Some(throttle_interval) = self.throttle_interval {
+            plist.push_str(&format!(
+                r#"
+<key>ThrottleInterval</key>
+<integer>{}</integer>"#,
+                throttle_interval.as_secs()
+            ));
+        }
+
+        if let Some(exit_timeout) = self.exit_timeout {
+            plist.push_str(&format!(
+                r#"
+<key>ExitTimeOut</key>
+<integer>{}</integer>"#,
+                exit_timeout.as_secs()
+            ));
         }
 
         plist.push_str(
@@ -231,15 +610,73 @@ impl MacosController {
         Ok(plist)
     }
 
+    // Runs in the service process, the job is left loaded until it exits: a disabled job is no
+    // longer loaded at boot, a deleted one has no plist to load.
+    #[cfg(feature = "install")]
+    fn finish_run_once(&mut self, run_once: RunOnce) -> Result<(), Error> {
+        match run_once {
+            RunOnce::Disable => {
+                let domain = match self.job() {
+                    LaunchdJob::Daemon => "system".to_string(),
+                    _ => format!("gui/{}", unsafe { libc::getuid() }),
+                };
+                launchctl_disable(&format!("{}/{}", domain, self.service_name))
+            }
+            RunOnce::Delete => {
+                let plist_path = self.plist_path();
+                fs::remove_file(&plist_path).map_err(|e| {
+                    Error::io(&format!("Failed to delete {}", plist_path.display()), &e)
+                })
+            }
+        }
+    }
+
     fn write_plist(&self, path: &Path) -> Result<(), Error> {
-        info!("Writing plist file {}", path.display());
         let content = self.get_plist_content()?;
+        if dry_run::skip(|| format!("write plist file {}:\n{}", path.display(), content)) {
+            return Ok(());
+        }
+        info!("Writing plist file {}", path.display());
         File::create(path)
             .and_then(|mut file| file.write_all(content.as_bytes()))
-            .map_err(|e| Error::new(&format!("Failed to write {}: {}", path.display(), e)))
+            .map_err(|e| Error::io(&format!("Failed to write {}", path.display()), &e))
+    }
+
+    /// Checks of `doctor()` specific to launchd.
+    #[cfg(feature = "install")]
+    pub(crate) fn diagnose(&self) -> Vec<Check> {
+        let manager = if Path::new("/bin/launchctl").is_file() {
+            Check::passed("service manager")
+        } else {
+            Check::failed("service manager", "/bin/launchctl doesn't exist")
+        };
+        let plist_path = self.clone().plist_path();
+        let name = if plist_path.exists() {
+            Check::warning(
+                "service name",
+                format!(
+                    "{} is already installed at {}",
+                    self.service_name,
+                    plist_path.display()
+                ),
+            )
+        } else {
+            Check::passed("service name")
+        };
+        let binary = match env::current_exe() {
+            Ok(path) => binary_check(&path),
+            Err(e) => Check::failed("binary path", e.to_string()),
+        };
+        vec![manager, name, binary]
     }
 
     fn plist_path(&mut self) -> PathBuf {
+        if self.job() == LaunchdJob::UserAgent {
+            let home = env::var_os("HOME").unwrap_or_default();
+            return Path::new(&home)
+                .join("Library/LaunchAgents/")
+                .join(format!("{}.plist", &self.service_name));
+        }
         Path::new("/Library/")
             .join(if self.is_agent {
                 "LaunchAgents/"
@@ -250,50 +687,209 @@ impl MacosController {
     }
 }
 
+#[cfg(feature = "install")]
 impl ControllerInterface for MacosController {
     /// Creates the service on the system.
     fn create(&mut self) -> Result<(), Error> {
-        let plist_path = self.plist_path();
-
-        self.write_plist(&plist_path)?;
-        if !self.is_agent {
-            return launchctl_load_daemon(&plist_path);
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "create",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.create(&self.service_name, &self.display_name, &self.description),
+            );
         }
-        Ok(())
+        in_operation(
+            "create",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || {
+                if self.job() != LaunchdJob::UserAgent {
+                    require_elevated()?;
+                }
+                let plist_path = self.plist_path();
+
+                match self.job() {
+                    LaunchdJob::Daemon => {
+                        self.write_plist(&plist_path)?;
+                        create_service_dirs(&self.service_name)?;
+                        launchctl_load_daemon(&plist_path)
+                    }
+                    LaunchdJob::Agent => self.write_plist(&plist_path),
+                    LaunchdJob::UserAgent => {
+                        if let Some(dir) = plist_path
+                            .parent()
+                            .filter(|dir| !dry_run::skip(|| format!("create {}", dir.display())))
+                        {
+                            fs::create_dir_all(dir).map_err(|e| {
+                                Error::io(&format!("Failed to create {}", dir.display()), &e)
+                            })?;
+                        }
+                        self.write_plist(&plist_path)?;
+                        launchctl_user_domain("bootstrap", &plist_path)
+                    }
+                }
+            },
+        )
     }
     /// Deletes the service.
     fn delete(&mut self) -> Result<(), Error> {
-        let plist_path = self.plist_path();
-        if !self.is_agent {
-            launchctl_unload_daemon(&plist_path)?;
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "delete",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.delete(&self.service_name),
+            );
         }
-        fs::remove_file(&plist_path)
-            .map_err(|e| Error::new(&format!("Failed to delete {}: {}", plist_path.display(), e)))
+        in_operation(
+            "delete",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || {
+                if self.job() != LaunchdJob::UserAgent {
+                    require_elevated()?;
+                }
+                let plist_path = self.plist_path();
+                match self.job() {
+                    LaunchdJob::Daemon => launchctl_unload_daemon(&plist_path)?,
+                    LaunchdJob::Agent => (),
+                    LaunchdJob::UserAgent => {
+                        if let Err(e) = launchctl_user_domain("bootout", &plist_path) {
+                            warn!("{}", e);
+                        }
+                    }
+                }
+                if dry_run::skip(|| format!("delete {}", plist_path.display())) {
+                    return Ok(());
+                }
+                fs::remove_file(&plist_path).map_err(|e| {
+                    Error::io(&format!("Failed to delete {}", plist_path.display()), &e)
+                })
+            },
+        )
     }
     /// Starts the service.
     fn start(&mut self) -> Result<(), Error> {
-        launchctl_start_daemon(&self.service_name)
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "start",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.start(&self.service_name),
+            );
+        }
+        in_operation(
+            "start",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || launchctl_start_daemon(&self.service_name),
+        )
     }
     /// Stops the service.
     fn stop(&mut self) -> Result<(), Error> {
-        launchctl_stop_daemon(&self.service_name)
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "stop",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.stop(&self.service_name),
+            );
+        }
+        in_operation(
+            "stop",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || launchctl_stop_daemon(&self.service_name),
+        )
+    }
+    /// Forcibly terminates the service process.
+    fn kill(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "kill",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.kill(&self.service_name),
+            );
+        }
+        in_operation(
+            "kill",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || launchctl_kill_daemon(&self.service_name),
+        )
+    }
+    fn pause(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "pause",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.pause(&self.service_name),
+            );
+        }
+        in_operation(
+            "pause",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || launchctl_signal_daemon(&self.service_name, libc::SIGSTOP, "pause"),
+        )
+    }
+    fn resume(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "resume",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.resume(&self.service_name),
+            );
+        }
+        in_operation(
+            "resume",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || launchctl_signal_daemon(&self.service_name, libc::SIGCONT, "resume"),
+        )
     }
     // Loads the agent service.
     fn load(&mut self) -> Result<(), Error> {
-        launchctl_load_daemon(&self.plist_path())
+        in_operation(
+            "load",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || launchctl_load_daemon(&self.plist_path()),
+        )
     }
     // Loads the agent service.
     fn unload(&mut self) -> Result<(), Error> {
-        launchctl_unload_daemon(&self.plist_path())
+        in_operation(
+            "unload",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || launchctl_unload_daemon(&self.plist_path()),
+        )
     }
 }
 
 /// Generates a `service_main_wrapper` that wraps the provided service main function.
+#[cfg(feature = "runtime")]
 #[macro_export]
 macro_rules! Service {
-    ($name:expr, $function:ident) => {
-        extern "system" fn service_main_wrapper(args: Vec<String>) {
-            dispatch($function, args);
+    ($name:expr, legacy $function:expr) => {
+        fn service_main_wrapper(args: Vec<String>) {
+            dispatch($crate::__legacy_service_main!($function), $name, args);
+        }
+    };
+    ($name:expr, $function:expr) => {
+        fn service_main_wrapper(args: Vec<String>) {
+            dispatch($function, $name, args);
+        }
+    };
+    // The service main function runs on the main thread, its stack size is set by the system.
+    ($name:expr, $function:expr, stack_size = $stack_size:expr) => {
+        fn service_main_wrapper(args: Vec<String>) {
+            dispatch($function, $name, args);
         }
     };
 }
@@ -316,12 +912,39 @@ unsafe extern "C" fn on_sc_console_user_change<F>(
     info: *mut c_void,
 ) where
     F: FnMut(u32, EventType) + Send + 'static,
+{
+    // Unwinding into the run loop is undefined behavior, the notification is dropped instead.
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| {
+        handle_console_user_change::<F>(store, info)
+    })) {
+        error!(
+            "Console user change callback panicked: {}",
+            panic_message(payload.as_ref())
+        );
+    }
+}
+
+unsafe fn handle_console_user_change<F>(store: SCDynamicStoreRef, info: *mut c_void)
+where
+    F: FnMut(u32, EventType) + Send + 'static,
 {
     let uid = active_session_uid(Some(store));
     let ctx_box = Box::from_raw(info as *mut Arc<SyncSessionContext<F>>);
     let ctx_ptr = Box::leak(ctx_box);
     let mut ctx = ctx_ptr.lock().unwrap();
 
+    // Logons and logoffs change the session list, fast user switching only the console user.
+    let sessions = session_uids(store);
+    let logons: Vec<u32> = sessions.difference(&ctx.sessions).copied().collect();
+    let logoffs: Vec<u32> = ctx.sessions.difference(&sessions).copied().collect();
+    ctx.sessions = sessions;
+    for uid in logoffs {
+        (ctx.callback)(uid, EventType::Logoff);
+    }
+    for uid in logons {
+        (ctx.callback)(uid, EventType::Logon);
+    }
+
     let old_uid = ctx.uid;
     if uid != ctx.uid {
         ctx.uid = uid;
@@ -359,6 +982,52 @@ unsafe extern "C" fn on_sc_console_user_change<F>(
     }
 }
 
+/// Users of the sessions listed in the `SessionInfo` of the console user key.
+unsafe fn session_uids(store: SCDynamicStoreRef) -> HashSet<u32> {
+    let mut uids = HashSet::new();
+    let key = SCDynamicStoreKeyCreateConsoleUser(ptr::null());
+    let value = SCDynamicStoreCopyValue(store, key);
+    CFRelease(key as CFTypeRef);
+    if value.is_null() {
+        return uids;
+    }
+
+    let session_info_key = CFString::from_static_string("SessionInfo");
+    let user_id_key = CFString::from_static_string("kCGSSessionUserIDKey");
+    if CFGetTypeID(value) == CFDictionaryGetTypeID() {
+        let sessions = CFDictionaryGetValue(
+            value as CFDictionaryRef,
+            session_info_key.as_concrete_TypeRef() as *const c_void,
+        );
+        if !sessions.is_null() && CFGetTypeID(sessions) == CFArrayGetTypeID() {
+            for index in 0..CFArrayGetCount(sessions as CFArrayRef) {
+                let session = CFArrayGetValueAtIndex(sessions as CFArrayRef, index);
+                if CFGetTypeID(session) != CFDictionaryGetTypeID() {
+                    continue;
+                }
+                let user_id = CFDictionaryGetValue(
+                    session as CFDictionaryRef,
+                    user_id_key.as_concrete_TypeRef() as *const c_void,
+                );
+                let mut uid: i32 = 0;
+                if !user_id.is_null()
+                    && CFGetTypeID(user_id) == CFNumberGetTypeID()
+                    && CFNumberGetValue(
+                        user_id as CFNumberRef,
+                        kCFNumberSInt32Type,
+                        &mut uid as *mut i32 as *mut c_void,
+                    )
+                    && uid > 0
+                {
+                    uids.insert(uid as u32);
+                }
+            }
+        }
+    }
+    CFRelease(value);
+    uids
+}
+
 #[link(name = "SystemConfiguration", kind = "framework")]
 extern "C" {
     pub fn SCDynamicStoreKeyCreateConsoleUser(allocator: CFAllocatorRef) -> CFStringRef;
@@ -367,10 +1036,14 @@ extern "C" {
 pub enum EventType {
     Connect,
     Disconnect,
+    Logon,
+    Logoff,
 }
 
 pub struct SessionContext<F: FnMut(u32, EventType)> {
     uid: u32,
+    /// Users with a session, in the foreground or switched out with fast user switching.
+    sessions: HashSet<u32>,
     callback: F,
     pending_connect: AtomicBool,
     last_was_logout: AtomicBool,
@@ -383,6 +1056,7 @@ impl<F: FnMut(u32, EventType)> SessionContext<F> {
     {
         Self {
             uid: active_session_uid(None),
+            sessions: HashSet::new(),
             callback: cb,
             pending_connect: AtomicBool::new(false),
             last_was_logout: AtomicBool::new(true),
@@ -432,6 +1106,8 @@ impl<F: FnMut(u32, EventType)> Monitor<F> {
             // releases array
             let _: CFArray<CFType> = TCFType::wrap_under_create_rule(keys);
 
+            (*session_ctx_ptr).lock().unwrap().sessions = session_uids(store_ref);
+
             let rls = SCDynamicStoreCreateRunLoopSource(ptr::null_mut(), store_ref, 0);
             CFRunLoopAddSource(CFRunLoopGetCurrent(), rls, kCFRunLoopDefaultMode);
 
@@ -445,6 +1121,89 @@ impl<F: FnMut(u32, EventType)> Monitor<F> {
     }
 }
 
+type IoConnect = u32;
+type IoService = u32;
+type IoNotificationPortRef = *mut c_void;
+type IoServiceInterestCallback = unsafe extern "C" fn(
+    refcon: *mut c_void,
+    service: IoService,
+    message_type: u32,
+    message_argument: *mut c_void,
+);
+
+const IO_MESSAGE_CAN_SYSTEM_SLEEP: u32 = 0xe000_0270;
+const IO_MESSAGE_SYSTEM_WILL_SLEEP: u32 = 0xe000_0280;
+const IO_MESSAGE_SYSTEM_HAS_POWERED_ON: u32 = 0xe000_0300;
+
+#[link(name = "IOKit", kind = "framework")]
+extern "C" {
+    fn IORegisterForSystemPower(
+        refcon: *mut c_void,
+        notify_port: *mut IoNotificationPortRef,
+        callback: IoServiceInterestCallback,
+        notifier: *mut IoService,
+    ) -> IoConnect;
+    fn IONotificationPortGetRunLoopSource(
+        notify: IoNotificationPortRef,
+    ) -> core_foundation::runloop::CFRunLoopSourceRef;
+    fn IOAllowPowerChange(kernel_port: IoConnect, notification_id: isize) -> i32;
+}
+
+struct PowerContext<T> {
+    root_port: IoConnect,
+    tx: EventSender<T>,
+}
+
+unsafe extern "C" fn on_system_power<T>(
+    refcon: *mut c_void,
+    _service: IoService,
+    message_type: u32,
+    message_argument: *mut c_void,
+) {
+    let ctx = &*(refcon as *const PowerContext<T>);
+    match message_type {
+        // Sleep is not vetoed, the service is only notified.
+        IO_MESSAGE_CAN_SYSTEM_SLEEP => {
+            IOAllowPowerChange(ctx.root_port, message_argument as isize);
+        }
+        IO_MESSAGE_SYSTEM_WILL_SLEEP => {
+            ctx.tx.send(ServiceEvent::PowerEvent(PowerEvent::Suspend));
+            IOAllowPowerChange(ctx.root_port, message_argument as isize);
+        }
+        IO_MESSAGE_SYSTEM_HAS_POWERED_ON => {
+            ctx.tx.send(ServiceEvent::PowerEvent(PowerEvent::Resume));
+        }
+        _ => (),
+    }
+}
+
+/// Subscribes to the sleep and wake notifications of IOKit on the run loop of the current
+/// thread. The context lives as long as the process since IOKit keeps a pointer to it.
+fn register_power_notifications<T: Send + 'static>(tx: EventSender<T>) {
+    let ctx = Box::into_raw(Box::new(PowerContext { root_port: 0, tx }));
+    unsafe {
+        let mut notify_port: IoNotificationPortRef = ptr::null_mut();
+        let mut notifier: IoService = 0;
+        let root_port = IORegisterForSystemPower(
+            ctx as *mut c_void,
+            &mut notify_port,
+            on_system_power::<T>,
+            &mut notifier,
+        );
+        if root_port == 0 {
+            warn!("Failed to register for system power notifications");
+            drop(Box::from_raw(ctx));
+            return;
+        }
+        (*ctx).root_port = root_port;
+        CFRunLoopAddSource(
+            CFRunLoopGetCurrent(),
+            IONotificationPortGetRunLoopSource(notify_port),
+            kCFRunLoopDefaultMode,
+        );
+    }
+}
+
 pub struct MonitorLoopRef {
     loop_ref: CFRunLoopRef,
 }
@@ -456,18 +1215,26 @@ impl MonitorLoopRef {
     }
 }
 
-pub fn run_monitor<T: Send + 'static>(
-    tx: mpsc::Sender<ServiceEvent<T>>,
+pub(crate) fn run_monitor<T: Send + 'static>(
+    tx: EventSender<T>,
 ) -> Result<MonitorLoopRef, std::io::Error> {
     let (_tx, rx) = mpsc::channel();
     thread::spawn(move || {
+        register_power_notifications(tx.clone());
+        #[cfg(feature = "session-events")]
         let mon = Monitor::new(move |uid: u32, event: EventType| {
             match event {
                 EventType::Connect => {
-                    let _ = tx.send(ServiceEvent::SessionConnect(Session::new(uid)));
+                    tx.send(ServiceEvent::SessionConnect(Session::new(uid)));
                 }
                 EventType::Disconnect => {
-                    let _ = tx.send(ServiceEvent::SessionDisconnect(Session::new(uid)));
+                    tx.send(ServiceEvent::SessionDisconnect(Session::new(uid)));
+                }
+                EventType::Logon => {
+                    tx.send(ServiceEvent::SessionLogon(Session::new(uid)));
+                }
+                EventType::Logoff => {
+                    tx.send(ServiceEvent::SessionLogoff(Session::new(uid)));
                 }
             };
         });
@@ -476,6 +1243,7 @@ pub fn run_monitor<T: Send + 'static>(
         let mon_loop_ref = MonitorLoopRef { loop_ref: loop_ref };
         _tx.send(mon_loop_ref).unwrap();
         unsafe { CFRunLoopRun() };
+        #[cfg(feature = "session-events")]
         drop(mon);
     });
 
@@ -484,18 +1252,45 @@ pub fn run_monitor<T: Send + 'static>(
     return Ok(received);
 }
 
+#[cfg(feature = "runtime")]
 #[doc(hidden)]
-pub fn dispatch<T: Send + 'static>(service_main: ServiceMainFn<T>, args: Vec<String>) {
-    let (tx, rx) = mpsc::channel();
+pub fn dispatch<T: Send + 'static, F: ServiceMain<T>>(
+    service_main: F,
+    name: &str,
+    args: Vec<String>,
+) {
+    let (tx, rx, _tx) = channel::channel();
+
+    // Agents run as the logged on user, their directories can't be created at install time.
+    if context::is_per_user() {
+        if let Err(e) = create_service_dirs(name) {
+            warn!("{}", e);
+        }
+    }
 
     let mut session_monitor = run_monitor(tx.clone()).expect("Failed to run session monitor");
-    let _tx = tx.clone();
 
     ctrlc::set_handler(move || {
-        let _ = tx.send(ServiceEvent::Stop);
+        stop::request(&tx, StopReason::Requested);
     })
     .expect("Failed to register Ctrl-C handler");
-    service_main(rx, _tx, args, false);
+    restart::record_executable();
+    let mut ctx = ServiceContext::new(name, args);
+    logging::init_service_logger(&mut ctx);
+    let _instance = match instance::acquire_for_service(name) {
+        Ok(instance) => instance,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    status::record_start(name);
+    let exit_code = service_main(rx, _tx, ctx);
+    deadline::service_main_returned();
+    #[cfg(feature = "state")]
+    state::save_registered();
+    run_once::service_main_returned(exit_code);
 
     session_monitor.stop();
+    restart::reexec_if_requested();
 }
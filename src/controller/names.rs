@@ -0,0 +1,199 @@
+use crate::{Error, ErrorKind};
+
+/// Longest service and display names, in UTF-16 code units on Windows where the SCM rejects
+/// longer names and in bytes elsewhere, where the unit, plist or manifest file named after
+/// the service must fit the 255 bytes of a file name with its extension.
+#[cfg(windows)]
+pub const MAX_NAME_LEN: usize = 256;
+#[cfg(not(windows))]
+pub const MAX_NAME_LEN: usize = 247;
+
+#[cfg(windows)]
+const LEN_UNIT: &str = "UTF-16 characters";
+#[cfg(not(windows))]
+const LEN_UNIT: &str = "bytes";
+
+// The data directory of the service is named after it, Windows reserves these names for
+// devices whatever the extension. The numbered names go from 1 to 9 and include the
+// superscript digits ¹, ² and ³.
+const RESERVED_NAMES: &[&str] = &["CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$"];
+const RESERVED_NUMBERED_NAMES: &[&str] = &["COM", "LPT"];
+
+/// Checks the name the service is registered with: it must not be empty, be longer than
+/// `MAX_NAME_LEN`, contain slashes, backslashes or control characters, or be `.`, `..` or,
+/// on Windows, a reserved device name such as `CON` or `COM1`.
+pub fn validate_service_name(service_name: &str) -> Result<(), Error> {
+    let reason = if service_name.is_empty() {
+        Some("it must not be empty".to_string())
+    } else if name_len(service_name) > MAX_NAME_LEN {
+        Some(format!("it is longer than {} {}", MAX_NAME_LEN, LEN_UNIT))
+    } else if service_name.contains(['/', '\\']) {
+        Some("it must not contain slashes or backslashes".to_string())
+    } else if service_name.contains(char::is_control) {
+        Some("it must not contain control characters".to_string())
+    } else if service_name == "." || service_name == ".." {
+        Some("it must not be a relative directory".to_string())
+    } else if cfg!(windows) && is_reserved_device_name(service_name) {
+        Some("Windows reserves it for a device".to_string())
+    } else {
+        None
+    };
+    match reason {
+        Some(reason) => Err(invalid_name("service name", service_name, &reason)),
+        None => Ok(()),
+    }
+}
+
+/// Checks the name the service is displayed with, which must not be longer than
+/// `MAX_NAME_LEN` or contain control characters. It may be empty, the service manager then
+/// shows the service name.
+pub fn validate_display_name(display_name: &str) -> Result<(), Error> {
+    let reason = if name_len(display_name) > MAX_NAME_LEN {
+        Some(format!("it is longer than {} {}", MAX_NAME_LEN, LEN_UNIT))
+    } else if display_name.contains(char::is_control) {
+        Some("it must not contain control characters".to_string())
+    } else {
+        None
+    };
+    match reason {
+        Some(reason) => Err(invalid_name("display name", display_name, &reason)),
+        None => Ok(()),
+    }
+}
+
+fn name_len(name: &str) -> usize {
+    if cfg!(windows) {
+        name.encode_utf16().count()
+    } else {
+        name.len()
+    }
+}
+
+fn is_reserved_device_name(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+        || RESERVED_NUMBERED_NAMES.iter().any(|reserved| {
+            let mut number = stem.get(3..).unwrap_or_default().chars();
+            stem.get(..3)
+                .is_some_and(|prefix| prefix.eq_ignore_ascii_case(reserved))
+                && matches!(
+                    (number.next(), number.next()),
+                    (Some('1'..='9' | '¹' | '²' | '³'), None)
+                )
+        })
+}
+
+fn invalid_name(what: &str, name: &str, reason: &str) -> Error {
+    Error {
+        kind: ErrorKind::InvalidName,
+        ..Error::new(&format!("Invalid {} {:?}: {}", what, name, reason))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // U+1F600: 4 bytes in UTF-8, a surrogate pair in UTF-16.
+    const NON_BMP: &str = "\u{1F600}";
+
+    #[test]
+    fn validates_service_names() {
+        let too_long = "a".repeat(MAX_NAME_LEN + 1);
+        // 2 bytes but a single UTF-16 unit each.
+        let accented = "é".repeat(MAX_NAME_LEN / 2 + 1);
+        let cases: &[(&str, bool)] = &[
+            ("foobar", true),
+            ("Foo Bar-1.2_3", true),
+            (&"a".repeat(MAX_NAME_LEN), true),
+            ("", false),
+            (&too_long, false),
+            (&accented, cfg!(windows)),
+            (".", false),
+            ("..", false),
+            ("...", true),
+            ("foo/bar", false),
+            ("foo\\bar", false),
+            ("foo\0bar", false),
+            ("foo\nbar", false),
+            ("foo\u{1b}[0m", false),
+            ("foo\u{85}", false),
+        ];
+        for (name, valid) in cases {
+            assert_eq!(validate_service_name(name).is_ok(), *valid, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn reserved_device_names() {
+        let cases: &[(&str, bool)] = &[
+            ("CON", true),
+            ("con", true),
+            ("CON.txt", true),
+            ("con .log", true),
+            ("NUL.tar.gz", true),
+            ("PRN", true),
+            ("aux", true),
+            ("CONIN$", true),
+            ("conout$.txt", true),
+            ("COM1", true),
+            ("com9", true),
+            ("LPT1", true),
+            ("lpt9.txt", true),
+            ("COM¹", true),
+            ("com²", true),
+            ("LPT³.log", true),
+            ("COM0", false),
+            ("COM10", false),
+            ("LPT", false),
+            ("COMA", false),
+            ("CONFIG", false),
+            ("CONIN", false),
+            ("foo.CON", false),
+            ("COM⁴", false),
+            ("NULL", false),
+        ];
+        for (name, reserved) in cases {
+            assert_eq!(is_reserved_device_name(name), *reserved, "{:?}", name);
+            assert_eq!(
+                validate_service_name(name).is_ok(),
+                !(cfg!(windows) && *reserved),
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn display_name_may_be_empty() {
+        assert!(validate_display_name("").is_ok());
+        assert!(validate_display_name("Foobar Service").is_ok());
+    }
+
+    #[test]
+    fn display_name_rejects_control_characters() {
+        for name in ["foo\0bar", "foo\nbar", "foo\tbar", "foo\u{7f}"] {
+            let e = validate_display_name(name).unwrap_err();
+            assert_eq!(e.kind, ErrorKind::InvalidName, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn display_name_length_is_limited() {
+        assert!(validate_display_name(&"a".repeat(MAX_NAME_LEN)).is_ok());
+        assert!(validate_display_name(&"a".repeat(MAX_NAME_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn display_name_length_counts_units_of_non_bmp_characters() {
+        let unit_len = if cfg!(windows) { 2 } else { 4 };
+        let fitting = NON_BMP.repeat(MAX_NAME_LEN / unit_len);
+        assert!(validate_display_name(&fitting).is_ok());
+        let too_long = fitting + NON_BMP;
+        assert!(validate_display_name(&too_long).is_err());
+        // Counted in characters the names would be short enough.
+        assert!(too_long.chars().count() <= MAX_NAME_LEN);
+    }
+}
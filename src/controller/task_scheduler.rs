@@ -0,0 +1,230 @@
+use std::process::Command;
+use std::time::Duration;
+
+use crate::audit::{self, AuditLog};
+use crate::controller::windows::{get_filename, WindowsController};
+use crate::controller::{in_operation, ControllerInterface};
+use crate::dry_run;
+use crate::status::{self, AcceptedControls, ServiceStatus, StatusSubscription};
+use crate::Error;
+
+/// When Task Scheduler runs the task of a `ScheduledTaskController`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskTrigger {
+    /// When the user logs on, the usual trigger of per-user agents.
+    AtLogon,
+    /// When the system starts, before any user logs on.
+    AtStartup,
+    /// Every day at the given local time.
+    Daily { hour: u8, minute: u8 },
+    /// Repeatedly with the given interval, rounded to whole minutes.
+    Every(Duration),
+}
+
+/// Registers the executable as a Task Scheduler task instead of a service, for per-user agents
+/// that shouldn't run as real services. The task runs the executable with `arguments`, which
+/// must select the standalone mode of the program since the service control manager is not
+/// involved. Created with `WindowsController::scheduled_task()`.
+#[derive(Clone, Debug)]
+pub struct ScheduledTaskController {
+    /// Name of the task, the service name of the controller it was created from.
+    pub task_name: String,
+    pub trigger: TaskTrigger,
+    /// Path of the executable, defaults to the current executable.
+    pub binary_path: Option<String>,
+    pub arguments: Vec<String>,
+    /// Account the task runs as, such as `SYSTEM`. `None` runs it as the user creating it.
+    pub account_name: Option<String>,
+    /// Runs the task with the highest privileges of the account instead of a filtered token.
+    pub highest_privileges: bool,
+    /// Logs the `schtasks` commands of the operations instead of executing them.
+    pub dry_run: bool,
+    /// Records the operations and their results, see `AuditLog`.
+    pub audit_log: Option<AuditLog>,
+}
+
+impl WindowsController {
+    /// Manages the service as a Task Scheduler task started by `trigger`, see
+    /// `ScheduledTaskController`.
+    pub fn scheduled_task(&self, trigger: TaskTrigger) -> ScheduledTaskController {
+        ScheduledTaskController {
+            task_name: self.service_name.to_string(),
+            trigger,
+            binary_path: self.binary_path.clone(),
+            arguments: Vec::new(),
+            account_name: None,
+            highest_privileges: false,
+            dry_run: self.dry_run,
+            audit_log: self.audit_log.clone(),
+        }
+    }
+}
+
+impl ScheduledTaskController {
+    /// Returns the status of the task, its state is the status reported by Task Scheduler in
+    /// lower case such as `ready` or `running`.
+    pub fn status(&self) -> Result<ServiceStatus, Error> {
+        let output = schtasks(&["/Query", "/TN", &self.task_name, "/FO", "CSV", "/NH"])?;
+        // "TaskName","Next Run Time","Status"
+        let state = output
+            .lines()
+            .next()
+            .and_then(|line| line.rsplit(',').next())
+            .map(|state| state.trim().trim_matches('"').to_lowercase())
+            .unwrap_or_default();
+        Ok(ServiceStatus::new(&state, None))
+    }
+
+    /// Returns the controls the task accepts, a running task can only be ended with `stop()`.
+    pub fn accepted_controls(&self) -> Result<AcceptedControls, Error> {
+        Ok(if self.status()?.state == "running" {
+            AcceptedControls::STOP
+        } else {
+            AcceptedControls::empty()
+        })
+    }
+
+    /// Subscribes to the state transitions of the task, its status is queried every second.
+    pub fn subscribe_status(&self) -> StatusSubscription {
+        let controller = self.clone();
+        status::poll(move || controller.status())
+    }
+
+    fn command_line(&self) -> String {
+        let binary_path = self.binary_path.clone().unwrap_or_else(get_filename);
+        let mut command_line = format!("\"{}\"", binary_path);
+        for argument in &self.arguments {
+            if argument.contains(' ') {
+                command_line.push_str(&format!(" \"{}\"", argument));
+            } else {
+                command_line.push(' ');
+                command_line.push_str(argument);
+            }
+        }
+        command_line
+    }
+}
+
+impl ControllerInterface for ScheduledTaskController {
+    fn create(&mut self) -> Result<(), Error> {
+        in_operation(
+            "create",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.task_name),
+            || {
+                let command_line = self.command_line();
+                let mut args = vec![
+                    "/Create",
+                    "/F",
+                    "/TN",
+                    &self.task_name,
+                    "/TR",
+                    &command_line,
+                ];
+
+                let start_time;
+                let interval;
+                match self.trigger {
+                    TaskTrigger::AtLogon => args.extend(["/SC", "ONLOGON"]),
+                    TaskTrigger::AtStartup => args.extend(["/SC", "ONSTART"]),
+                    TaskTrigger::Daily { hour, minute } => {
+                        start_time = format!("{:02}:{:02}", hour, minute);
+                        args.extend(["/SC", "DAILY", "/ST", &start_time]);
+                    }
+                    TaskTrigger::Every(duration) => {
+                        interval = (duration.as_secs() / 60).max(1).to_string();
+                        args.extend(["/SC", "MINUTE", "/MO", &interval]);
+                    }
+                }
+                if let Some(account_name) = &self.account_name {
+                    args.extend(["/RU", account_name]);
+                }
+                if self.highest_privileges {
+                    args.extend(["/RL", "HIGHEST"]);
+                }
+
+                schtasks(&args).map(|_| ())
+            },
+        )
+    }
+
+    fn delete(&mut self) -> Result<(), Error> {
+        in_operation(
+            "delete",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.task_name),
+            || schtasks(&["/Delete", "/F", "/TN", &self.task_name]).map(|_| ()),
+        )
+    }
+
+    fn start(&mut self) -> Result<(), Error> {
+        in_operation(
+            "start",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.task_name),
+            || schtasks(&["/Run", "/TN", &self.task_name]).map(|_| ()),
+        )
+    }
+
+    /// Task Scheduler has no graceful stop, the task process is terminated.
+    fn stop(&mut self) -> Result<(), Error> {
+        in_operation(
+            "stop",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.task_name),
+            || schtasks(&["/End", "/TN", &self.task_name]).map(|_| ()),
+        )
+    }
+
+    fn kill(&mut self) -> Result<(), Error> {
+        in_operation(
+            "kill",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.task_name),
+            || schtasks(&["/End", "/TN", &self.task_name]).map(|_| ()),
+        )
+    }
+
+    // Scheduled tasks can only be started and ended.
+    fn pause(&mut self) -> Result<(), Error> {
+        in_operation(
+            "pause",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.task_name),
+            || Err(Error::new("Scheduled tasks can't be paused")),
+        )
+    }
+
+    fn resume(&mut self) -> Result<(), Error> {
+        in_operation(
+            "resume",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.task_name),
+            || Err(Error::new("Scheduled tasks can't be resumed")),
+        )
+    }
+}
+
+fn schtasks(args: &[&str]) -> Result<String, Error> {
+    if args[0] != "/Query" && dry_run::skip(|| format!("schtasks {}", args.join(" "))) {
+        return Ok(String::new());
+    }
+    let output = Command::new("schtasks")
+        .args(args)
+        .output()
+        .map_err(|e| Error {
+            function: Some("schtasks".to_string()),
+            ..Error::io("Failed to execute schtasks", &e)
+        })?;
+    if !output.status.success() {
+        return Err(Error {
+            function: Some("schtasks".to_string()),
+            ..Error::new(&format!(
+                "Command \"schtasks {}\" failed: {}",
+                args[0],
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
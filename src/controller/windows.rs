@@ -1,44 +1,635 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::env;
 use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
 use std::iter::once;
+use std::mem;
 use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::AsRawHandle;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
 use std::ptr;
-use std::sync::mpsc;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::{thread, time};
 
+use log::{debug, error, info, warn};
 use widestring::WideCString;
+use winapi::shared::guiddef::GUID;
 use winapi::shared::minwindef::*;
+use winapi::shared::ntdef::{BOOLEAN, NTSTATUS};
+use winapi::shared::ntstatus::STATUS_OBJECT_NAME_NOT_FOUND;
+#[cfg(feature = "install")]
+use winapi::shared::sddl::ConvertSidToStringSidW;
+use winapi::shared::windef::HWND;
 use winapi::shared::winerror::*;
 use winapi::um::errhandlingapi::*;
+use winapi::um::handleapi::*;
+#[cfg(feature = "install")]
+use winapi::um::http::*;
 use winapi::um::libloaderapi::*;
+#[cfg(feature = "install")]
+use winapi::um::lsalookup::{LSA_OBJECT_ATTRIBUTES, LSA_UNICODE_STRING};
+#[cfg(feature = "install")]
+use winapi::um::ntlsa::*;
+use winapi::um::processthreadsapi::*;
+#[cfg(feature = "install")]
+use winapi::um::shellapi::*;
+use winapi::um::synchapi::*;
+use winapi::um::sysinfoapi::{
+    ComputerNamePhysicalNetBIOS, GetComputerNameExW, GetNativeSystemInfo, SYSTEM_INFO,
+};
 use winapi::um::winbase::*;
 use winapi::um::winnt::*;
+use winapi::um::winreg::*;
 use winapi::um::winsvc::*;
 use winapi::um::winuser::*;
+use winapi::vc::excpt::EXCEPTION_CONTINUE_SEARCH;
 use winapi::{self, STRUCT};
 
-use crate::controller::{ControllerInterface, ServiceMainFn};
+use crate::audit::{self, AuditLog};
+use crate::channel::{self, EventPolicy, EventSender};
+use crate::context::{create_service_dirs, ServiceContext};
+#[cfg(feature = "install")]
+use crate::controller::doctor::{binary_check, Check};
+#[cfg(feature = "install")]
+use crate::controller::ControllerInterface;
+use crate::controller::{in_operation, panic_message, Backend, ServiceMain, MAX_NAME_LEN};
+#[cfg(feature = "install")]
+use crate::controller::{xml_escape, ConfigDifference, ConflictingService, ManifestFormat};
+use crate::deadline;
+use crate::dry_run;
+use crate::elevation::require_elevated;
+use crate::instance;
+use crate::logging::{self, LogConfig};
+use crate::messages;
+use crate::pause;
+use crate::restart;
+use crate::run_once::{self, RunOnce};
+use crate::secret::zeroize;
+#[cfg(feature = "session-events")]
 use crate::session;
-use crate::Error;
-use crate::ServiceEvent;
+use crate::shutdown;
+#[cfg(feature = "state")]
+use crate::state;
+use crate::status::{
+    self, AcceptedControls, ServiceStatus, StartRecord, StatusSender, StatusSubscription,
+};
+use crate::stop;
+use crate::{Error, ErrorKind, Secret, StartupGate};
+use crate::{NetBindChange, PowerEvent, ServiceEvent, StopReason};
 
-static mut SERVICE_CONTROL_HANDLE: SERVICE_STATUS_HANDLE = ptr::null_mut();
+/// Calls the Windows API function `$function`. With the `scm-tracing` feature, the call is
+/// traced with its parameters, its result and the last error, which is preserved for the
+/// caller.
+macro_rules! traced {
+    ($function:ident($($arg:expr),* $(,)?)) => {{
+        let result = $function($($arg),*);
+        #[cfg(feature = "scm-tracing")]
+        {
+            #[allow(unused_unsafe)]
+            let last_error = unsafe { GetLastError() };
+            tracing::debug!(
+                function = stringify!($function),
+                parameters = stringify!($($arg),*),
+                result = ?result,
+                last_error,
+            );
+            #[allow(unused_unsafe)]
+            unsafe {
+                SetLastError(last_error)
+            };
+        }
+        result
+    }};
+}
+
+static MINIDUMP_QUOTA: Mutex<Option<u64>> = Mutex::new(None);
+// Keyed by service name, a process shared with other services runs each service of a DLL
+// with the options of its own controller.
+static RUNTIME_OPTIONS: Mutex<BTreeMap<String, RuntimeOptions>> = Mutex::new(BTreeMap::new());
+static MINIDUMP_TARGET: OnceLock<MinidumpTarget> = OnceLock::new();
+
+const MINIDUMP_WITH_THREAD_INFO: DWORD = 0x1000;
+// Low resources controls of winsvc.h, missing from winapi.
+const SERVICE_CONTROL_LOWRESOURCES: DWORD = 0x60;
+const SERVICE_CONTROL_SYSTEMLOWRESOURCES: DWORD = 0x61;
+const SERVICE_ACCEPT_LOWRESOURCES: DWORD = 0x2000;
+const SERVICE_ACCEPT_SYSTEMLOWRESOURCES: DWORD = 0x4000;
+// Interval between two queries of the idle time of the sessions.
+#[cfg(feature = "session-events")]
+const IDLE_POLL_INTERVAL: time::Duration = time::Duration::from_secs(5);
+// Wait hint of the pause and continue pending states, in milliseconds.
+const PAUSE_WAIT_HINT: DWORD = 10_000;
+// Trigger actions of winsvc.h, missing from winapi.
+const SERVICE_TRIGGER_ACTION_SERVICE_START: DWORD = 1;
+const SERVICE_TRIGGER_ACTION_SERVICE_STOP: DWORD = 2;
 
 STRUCT! {#[allow(non_snake_case)]
     struct SERVICE_DESCRIPTION_W {
     lpDescription: LPWSTR,
 }}
 
+#[allow(non_snake_case)]
+#[repr(C)]
+struct SERVICE_CONTROL_STATUS_REASON_PARAMSW {
+    dwReason: DWORD,
+    pszComment: LPWSTR,
+    ServiceStatus: SERVICE_STATUS_PROCESS,
+}
+
+// Trigger structures of winsvc.h, missing from winapi.
+#[allow(non_snake_case)]
+#[repr(C)]
+struct SERVICE_TRIGGER_SPECIFIC_DATA_ITEM {
+    dwDataType: DWORD,
+    cbData: DWORD,
+    pData: PBYTE,
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+struct SERVICE_TRIGGER {
+    dwTriggerType: DWORD,
+    dwAction: DWORD,
+    pTriggerSubtype: *mut GUID,
+    cDataItems: DWORD,
+    pDataItems: *mut SERVICE_TRIGGER_SPECIFIC_DATA_ITEM,
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+struct SERVICE_TRIGGER_INFO {
+    cTriggers: DWORD,
+    pTriggers: *mut SERVICE_TRIGGER,
+    pReserved: PBYTE,
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+struct SERVICE_DELAYED_AUTO_START_INFO {
+    fDelayedAutostart: BOOL,
+}
+
+// Declared with 4 bytes packing in dbghelp.h.
+#[allow(non_snake_case)]
+#[repr(C, packed(4))]
+struct MINIDUMP_EXCEPTION_INFORMATION {
+    ThreadId: DWORD,
+    ExceptionPointers: *mut EXCEPTION_POINTERS,
+    ClientPointers: BOOL,
+}
+
+#[link(name = "dbghelp")]
+extern "system" {
+    fn MiniDumpWriteDump(
+        hProcess: HANDLE,
+        ProcessId: DWORD,
+        hFile: HANDLE,
+        DumpType: DWORD,
+        ExceptionParam: *const MINIDUMP_EXCEPTION_INFORMATION,
+        UserStreamParam: LPVOID,
+        CallbackParam: LPVOID,
+    ) -> BOOL;
+}
+
+const CLUSTER_CHANGE_GROUP_STATE: DWORD = 0x0000_1000;
+// CLUSTER_GROUP_STATE values.
+const CLUSTER_GROUP_ONLINE: i32 = 0;
+const CLUSTER_GROUP_PARTIAL_ONLINE: i32 = 3;
+const CLUSTER_GROUP_STATE_UNKNOWN: i32 = -1;
+
+#[link(name = "clusapi")]
+extern "system" {
+    fn OpenCluster(lpszClusterName: LPCWSTR) -> HANDLE;
+    fn CloseCluster(hCluster: HANDLE) -> BOOL;
+    fn OpenClusterGroup(hCluster: HANDLE, lpszGroupName: LPCWSTR) -> HANDLE;
+    fn CloseClusterGroup(hGroup: HANDLE) -> BOOL;
+    fn GetClusterGroupState(hGroup: HANDLE, lpszNodeName: LPWSTR, lpcchNodeName: LPDWORD) -> i32;
+    fn CreateClusterNotifyPort(
+        hChange: HANDLE,
+        hCluster: HANDLE,
+        dwFilter: DWORD,
+        dwNotifyKey: usize,
+    ) -> HANDLE;
+    fn CloseClusterNotifyPort(hChange: HANDLE) -> BOOL;
+    fn GetClusterNotify(
+        hChange: HANDLE,
+        lpdwNotifyKey: *mut usize,
+        lpdwFilterType: LPDWORD,
+        lpszName: LPWSTR,
+        lpcchName: LPDWORD,
+        dwMilliseconds: DWORD,
+    ) -> DWORD;
+}
+
 type WindowsServiceMainWrapperFn = extern "system" fn(argc: DWORD, argv: *mut LPWSTR);
+#[cfg(feature = "session-events")]
 pub type Session = session::Session_<u32>;
 
 struct Service {
     pub handle: SC_HANDLE,
 }
 
+impl Service {
+    fn query_status_process(&self) -> Result<SERVICE_STATUS_PROCESS, Error> {
+        unsafe {
+            let mut status: SERVICE_STATUS_PROCESS = mem::zeroed();
+            let mut bytes_needed: DWORD = 0;
+
+            if traced!(QueryServiceStatusEx(
+                self.handle,
+                SC_STATUS_PROCESS_INFO,
+                &mut status as *mut _ as LPBYTE,
+                mem::size_of::<SERVICE_STATUS_PROCESS>() as DWORD,
+                &mut bytes_needed,
+            )) == 0
+            {
+                return Err(last_error("QueryServiceStatusEx"));
+            }
+
+            Ok(status)
+        }
+    }
+
+    fn query_config(&self) -> Result<ServiceConfig, Error> {
+        unsafe {
+            let mut bytes_needed: DWORD = 0;
+            traced!(QueryServiceConfigW(
+                self.handle,
+                ptr::null_mut(),
+                0,
+                &mut bytes_needed
+            ));
+
+            let mut buffer = vec![0u64; (bytes_needed as usize).div_ceil(8)];
+            let config = buffer.as_mut_ptr() as LPQUERY_SERVICE_CONFIGW;
+            if traced!(QueryServiceConfigW(
+                self.handle,
+                config,
+                bytes_needed,
+                &mut bytes_needed
+            )) == 0
+            {
+                return Err(last_error("QueryServiceConfigW"));
+            }
+
+            Ok(ServiceConfig {
+                service_type: (*config).dwServiceType,
+                start_type: (*config).dwStartType,
+                error_control: (*config).dwErrorControl,
+                binary_path: from_wide_ptr((*config).lpBinaryPathName),
+                load_order_group: from_wide_ptr((*config).lpLoadOrderGroup),
+                tag_id: (*config).dwTagId,
+                dependencies: from_wide_multi_ptr((*config).lpDependencies),
+                account_name: from_wide_ptr((*config).lpServiceStartName),
+                display_name: from_wide_ptr((*config).lpDisplayName),
+                description: self.query_description()?,
+            })
+        }
+    }
+
+    fn query_description(&self) -> Result<String, Error> {
+        unsafe {
+            let mut bytes_needed: DWORD = 0;
+            traced!(QueryServiceConfig2W(
+                self.handle,
+                SERVICE_CONFIG_DESCRIPTION,
+                ptr::null_mut(),
+                0,
+                &mut bytes_needed,
+            ));
+
+            let mut buffer = vec![0u64; (bytes_needed as usize).div_ceil(8)];
+            if traced!(QueryServiceConfig2W(
+                self.handle,
+                SERVICE_CONFIG_DESCRIPTION,
+                buffer.as_mut_ptr() as LPBYTE,
+                bytes_needed,
+                &mut bytes_needed,
+            )) == 0
+            {
+                return Err(last_error("QueryServiceConfig2W"));
+            }
+
+            let sd = buffer.as_ptr() as *const SERVICE_DESCRIPTION_W;
+            Ok(from_wide_ptr((*sd).lpDescription))
+        }
+    }
+
+    fn change_description(&self, description: &str) -> Result<(), Error> {
+        self.change_description_wide(&to_wide("description", description, None)?)
+    }
+
+    fn change_description_wide(&self, description: &[u16]) -> Result<(), Error> {
+        unsafe {
+            // The description is only read by ChangeServiceConfig2W.
+            let mut sd = SERVICE_DESCRIPTION_W {
+                lpDescription: description.as_ptr() as LPWSTR,
+            };
+
+            let p_sd = &mut sd as *mut _ as *mut winapi::ctypes::c_void;
+            if traced!(ChangeServiceConfig2W(
+                self.handle,
+                SERVICE_CONFIG_DESCRIPTION,
+                p_sd
+            )) == 0
+            {
+                return Err(last_error("ChangeServiceConfig2W"));
+            }
+
+            Ok(())
+        }
+    }
+
+    fn query_failure_actions(&self) -> Result<FailureActions, Error> {
+        unsafe {
+            let mut bytes_needed: DWORD = 0;
+            traced!(QueryServiceConfig2W(
+                self.handle,
+                SERVICE_CONFIG_FAILURE_ACTIONS,
+                ptr::null_mut(),
+                0,
+                &mut bytes_needed,
+            ));
+
+            let mut buffer = vec![0u64; (bytes_needed as usize).div_ceil(8)];
+            if traced!(QueryServiceConfig2W(
+                self.handle,
+                SERVICE_CONFIG_FAILURE_ACTIONS,
+                buffer.as_mut_ptr() as LPBYTE,
+                bytes_needed,
+                &mut bytes_needed,
+            )) == 0
+            {
+                return Err(last_error("QueryServiceConfig2W"));
+            }
+
+            let fa = buffer.as_ptr() as *const SERVICE_FAILURE_ACTIONSW;
+            let actions = if (*fa).lpsaActions.is_null() {
+                Vec::new()
+            } else {
+                slice::from_raw_parts((*fa).lpsaActions, (*fa).cActions as usize)
+                    .iter()
+                    .map(|action| (action.Type, action.Delay))
+                    .collect()
+            };
+
+            let mut flag: SERVICE_FAILURE_ACTIONS_FLAG = mem::zeroed();
+            if traced!(QueryServiceConfig2W(
+                self.handle,
+                SERVICE_CONFIG_FAILURE_ACTIONS_FLAG,
+                &mut flag as *mut _ as LPBYTE,
+                mem::size_of::<SERVICE_FAILURE_ACTIONS_FLAG>() as DWORD,
+                &mut bytes_needed,
+            )) == 0
+            {
+                return Err(last_error("QueryServiceConfig2W"));
+            }
+
+            Ok(FailureActions {
+                reset_period: (*fa).dwResetPeriod,
+                actions,
+                non_crash_failures: flag.fFailureActionsOnNonCrashFailures != FALSE,
+            })
+        }
+    }
+
+    // The reboot message and the command of the failure actions are left unchanged.
+    fn change_failure_actions(&self, failure_actions: &FailureActions) -> Result<(), Error> {
+        unsafe {
+            // A non-null array of zero actions deletes the actions, a null one keeps them.
+            let mut actions: Vec<SC_ACTION> = failure_actions
+                .actions
+                .iter()
+                .map(|&(action_type, delay)| SC_ACTION {
+                    Type: action_type,
+                    Delay: delay,
+                })
+                .collect();
+            let mut fa = SERVICE_FAILURE_ACTIONSW {
+                dwResetPeriod: failure_actions.reset_period,
+                lpRebootMsg: ptr::null_mut(),
+                lpCommand: ptr::null_mut(),
+                cActions: actions.len() as DWORD,
+                lpsaActions: actions.as_mut_ptr(),
+            };
+            if traced!(ChangeServiceConfig2W(
+                self.handle,
+                SERVICE_CONFIG_FAILURE_ACTIONS,
+                &mut fa as *mut _ as LPVOID,
+            )) == 0
+            {
+                return Err(last_error("ChangeServiceConfig2W"));
+            }
+        }
+        self.change_failure_actions_flag(failure_actions.non_crash_failures)
+    }
+
+    fn change_failure_actions_flag(&self, non_crash_failures: bool) -> Result<(), Error> {
+        unsafe {
+            let mut flag = SERVICE_FAILURE_ACTIONS_FLAG {
+                fFailureActionsOnNonCrashFailures: non_crash_failures as BOOL,
+            };
+            if traced!(ChangeServiceConfig2W(
+                self.handle,
+                SERVICE_CONFIG_FAILURE_ACTIONS_FLAG,
+                &mut flag as *mut _ as LPVOID,
+            )) == 0
+            {
+                return Err(last_error("ChangeServiceConfig2W"));
+            }
+
+            Ok(())
+        }
+    }
+
+    fn change_start_type(&self, start_type: DWORD) -> Result<(), Error> {
+        unsafe {
+            if traced!(ChangeServiceConfigW(
+                self.handle,
+                SERVICE_NO_CHANGE,
+                start_type,
+                SERVICE_NO_CHANGE,
+                ptr::null(),
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+            )) == 0
+            {
+                return Err(last_error("ChangeServiceConfigW"));
+            }
+
+            Ok(())
+        }
+    }
+
+    fn query_config2(&self, info_level: DWORD) -> Result<Vec<u64>, Error> {
+        unsafe {
+            let mut bytes_needed: DWORD = 0;
+            traced!(QueryServiceConfig2W(
+                self.handle,
+                info_level,
+                ptr::null_mut(),
+                0,
+                &mut bytes_needed,
+            ));
+
+            let mut buffer = vec![0u64; (bytes_needed as usize).div_ceil(8)];
+            if traced!(QueryServiceConfig2W(
+                self.handle,
+                info_level,
+                buffer.as_mut_ptr() as LPBYTE,
+                bytes_needed,
+                &mut bytes_needed,
+            )) == 0
+            {
+                return Err(last_error("QueryServiceConfig2W"));
+            }
+            Ok(buffer)
+        }
+    }
+
+    fn query_delayed_auto_start(&self) -> Result<bool, Error> {
+        let buffer = self.query_config2(SERVICE_CONFIG_DELAYED_AUTO_START_INFO)?;
+        let info = buffer.as_ptr() as *const SERVICE_DELAYED_AUTO_START_INFO;
+        Ok(unsafe { (*info).fDelayedAutostart } != FALSE)
+    }
+
+    fn change_delayed_auto_start(&self, delayed: bool) -> Result<(), Error> {
+        let mut info = SERVICE_DELAYED_AUTO_START_INFO {
+            fDelayedAutostart: delayed as BOOL,
+        };
+        unsafe {
+            if traced!(ChangeServiceConfig2W(
+                self.handle,
+                SERVICE_CONFIG_DELAYED_AUTO_START_INFO,
+                &mut info as *mut _ as LPVOID,
+            )) == 0
+            {
+                return Err(last_error("ChangeServiceConfig2W"));
+            }
+        }
+        Ok(())
+    }
+
+    fn query_triggers(&self) -> Result<Vec<ServiceTrigger>, Error> {
+        let buffer = self.query_config2(SERVICE_CONFIG_TRIGGER_INFO)?;
+        unsafe {
+            let info = &*(buffer.as_ptr() as *const SERVICE_TRIGGER_INFO);
+            if info.pTriggers.is_null() {
+                return Ok(Vec::new());
+            }
+            Ok(
+                slice::from_raw_parts(info.pTriggers, info.cTriggers as usize)
+                    .iter()
+                    .map(|trigger| ServiceTrigger::from_raw(trigger))
+                    .collect(),
+            )
+        }
+    }
+
+    /// Replaces the triggers of the service, an empty list removes them.
+    fn change_triggers(&self, triggers: &[ServiceTrigger]) -> Result<(), Error> {
+        // The buffers the structures point to live until the call returns.
+        let mut subtypes: Vec<GUID> = triggers.iter().map(|t| guid_from_u128(t.subtype)).collect();
+        let mut data: Vec<Vec<Vec<u8>>> = triggers
+            .iter()
+            .map(|trigger| trigger.data.iter().map(TriggerData::to_bytes).collect())
+            .collect();
+        let mut items: Vec<Vec<SERVICE_TRIGGER_SPECIFIC_DATA_ITEM>> = triggers
+            .iter()
+            .zip(data.iter_mut())
+            .map(|(trigger, buffers)| {
+                trigger
+                    .data
+                    .iter()
+                    .zip(buffers.iter_mut())
+                    .map(|(item, buffer)| SERVICE_TRIGGER_SPECIFIC_DATA_ITEM {
+                        dwDataType: item.data_type(),
+                        cbData: buffer.len() as DWORD,
+                        pData: buffer.as_mut_ptr(),
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut raw_triggers: Vec<SERVICE_TRIGGER> = triggers
+            .iter()
+            .zip(subtypes.iter_mut())
+            .zip(items.iter_mut())
+            .map(|((trigger, subtype), items)| SERVICE_TRIGGER {
+                dwTriggerType: trigger.trigger_type,
+                dwAction: match trigger.action {
+                    TriggerAction::Start => SERVICE_TRIGGER_ACTION_SERVICE_START,
+                    TriggerAction::Stop => SERVICE_TRIGGER_ACTION_SERVICE_STOP,
+                },
+                pTriggerSubtype: subtype,
+                cDataItems: items.len() as DWORD,
+                pDataItems: if items.is_empty() {
+                    ptr::null_mut()
+                } else {
+                    items.as_mut_ptr()
+                },
+            })
+            .collect();
+        let mut info = SERVICE_TRIGGER_INFO {
+            cTriggers: raw_triggers.len() as DWORD,
+            pTriggers: if raw_triggers.is_empty() {
+                ptr::null_mut()
+            } else {
+                raw_triggers.as_mut_ptr()
+            },
+            pReserved: ptr::null_mut(),
+        };
+        unsafe {
+            if traced!(ChangeServiceConfig2W(
+                self.handle,
+                SERVICE_CONFIG_TRIGGER_INFO,
+                &mut info as *mut _ as LPVOID,
+            )) == 0
+            {
+                return Err(last_error("ChangeServiceConfig2W"));
+            }
+        }
+        Ok(())
+    }
+
+    fn change_display_name(&self, display_name: &str) -> Result<(), Error> {
+        let display_name = to_wide("display name", display_name, Some(MAX_NAME_LEN))?;
+        unsafe {
+            if traced!(ChangeServiceConfigW(
+                self.handle,
+                SERVICE_NO_CHANGE,
+                SERVICE_NO_CHANGE,
+                SERVICE_NO_CHANGE,
+                ptr::null(),
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+                display_name.as_ptr(),
+            )) == 0
+            {
+                return Err(last_error("ChangeServiceConfigW"));
+            }
+
+            Ok(())
+        }
+    }
+}
+
 impl Drop for Service {
     fn drop(&mut self) {
         if !self.handle.is_null() {
-            unsafe { CloseServiceHandle(self.handle) };
+            unsafe { traced!(CloseServiceHandle(self.handle)) };
         }
     }
 }
@@ -48,33 +639,85 @@ struct ServiceControlManager {
 }
 
 impl ServiceControlManager {
-    fn open(desired_access: DWORD) -> Result<ServiceControlManager, Error> {
-        let handle = unsafe { OpenSCManagerW(ptr::null_mut(), ptr::null_mut(), desired_access) };
+    fn open(
+        machine_name: Option<&str>,
+        desired_access: DWORD,
+    ) -> Result<ServiceControlManager, Error> {
+        // A dry run only reads, with the access any user is granted.
+        let desired_access = if dry_run::is_dry_run() {
+            desired_access
+                & (SC_MANAGER_CONNECT | SC_MANAGER_ENUMERATE_SERVICE | SC_MANAGER_QUERY_LOCK_STATUS)
+                | SC_MANAGER_CONNECT
+        } else {
+            desired_access
+        };
+        let machine_name = machine_name.map(get_utf16);
+        let handle = unsafe {
+            traced!(OpenSCManagerW(
+                machine_name
+                    .as_ref()
+                    .map_or(ptr::null(), |name| name.as_ptr()),
+                ptr::null(),
+                desired_access,
+            ))
+        };
 
         if handle.is_null() {
-            Err(Error::new(&format!(
-                "OpenSCManager: {}",
-                get_last_error_text()
-            )))
+            Err(last_error("OpenSCManager"))
         } else {
             Ok(ServiceControlManager { handle })
         }
     }
 
+    /// Name of the service whose display name is `display_name`, if any.
+    #[cfg(feature = "install")]
+    fn service_key_name(&self, display_name: &str) -> Option<String> {
+        let display_name = to_wide("display name", display_name, Some(MAX_NAME_LEN)).ok()?;
+        let mut key_name = [0u16; MAX_NAME_LEN + 1];
+        let mut length = key_name.len() as DWORD;
+        let found = unsafe {
+            traced!(GetServiceKeyNameW(
+                self.handle,
+                display_name.as_ptr(),
+                key_name.as_mut_ptr(),
+                &mut length,
+            ))
+        };
+        if found == 0 {
+            return None;
+        }
+        Some(String::from_utf16_lossy(&key_name[..length as usize]))
+    }
+
     fn open_service(&self, service_name: &str, desired_access: DWORD) -> Result<Service, Error> {
+        self.open_service_wide(&service_name_to_wide(service_name)?, desired_access)
+    }
+
+    fn open_service_wide(
+        &self,
+        service_name: &[u16],
+        desired_access: DWORD,
+    ) -> Result<Service, Error> {
+        let desired_access = if dry_run::is_dry_run() {
+            desired_access
+                & (SERVICE_QUERY_CONFIG
+                    | SERVICE_QUERY_STATUS
+                    | SERVICE_ENUMERATE_DEPENDENTS
+                    | SERVICE_INTERROGATE)
+                | SERVICE_QUERY_STATUS
+        } else {
+            desired_access
+        };
         let handle = unsafe {
-            OpenServiceW(
+            traced!(OpenServiceW(
                 self.handle,
-                get_utf16(service_name).as_ptr(),
-                desired_access,
-            )
+                service_name.as_ptr(),
+                desired_access
+            ))
         };
 
         if handle.is_null() {
-            Err(Error::new(&format!(
-                "OpenServiceW: {}",
-                get_last_error_text()
-            )))
+            Err(last_error("OpenServiceW"))
         } else {
             Ok(Service { handle })
         }
@@ -84,291 +727,4012 @@ impl ServiceControlManager {
 impl Drop for ServiceControlManager {
     fn drop(&mut self) {
         if !self.handle.is_null() {
-            unsafe { CloseServiceHandle(self.handle) };
+            unsafe { traced!(CloseServiceHandle(self.handle)) };
         }
     }
 }
 
-/// Manages the service on the system.
-pub struct WindowsController {
-    pub service_name: String,
-    pub display_name: String,
-    pub description: String,
-    pub desired_access: DWORD,
+/// Configuration of an installed service, as reported by the service control manager.
+pub struct ServiceConfig {
     pub service_type: DWORD,
     pub start_type: DWORD,
     pub error_control: DWORD,
-    pub tag_id: DWORD,
+    pub binary_path: String,
     pub load_order_group: String,
-    pub dependencies: String,
+    pub tag_id: DWORD,
+    pub dependencies: Vec<String>,
     pub account_name: String,
-    pub password: String,
-    pub service_status: SERVICE_STATUS,
-    pub status_handle: SERVICE_STATUS_HANDLE,
-    pub controls_accepted: DWORD,
+    pub display_name: String,
+    pub description: String,
 }
 
-impl ControllerInterface for WindowsController {
-    fn create(&mut self) -> Result<(), Error> {
-        unsafe {
-            let service_manager = ServiceControlManager::open(SC_MANAGER_ALL_ACCESS)?;
-
-            let filename = get_filename();
-            let tag_id = 0;
-
-            let service = CreateServiceW(
-                service_manager.handle,
-                get_utf16(self.service_name.as_str()).as_ptr(),
-                get_utf16(self.display_name.as_str()).as_ptr(),
-                self.desired_access,
-                self.service_type,
-                self.start_type,
-                self.error_control,
-                get_utf16(filename.as_str()).as_ptr(),
-                ptr::null_mut(),
-                ptr::null_mut(),
-                ptr::null_mut(),
-                ptr::null_mut(),
-                ptr::null_mut(),
-            );
+/// Kind of driver service, see `WindowsController::set_driver()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DriverType {
+    Kernel,
+    FileSystem,
+}
+
+/// Direction of the traffic allowed by a `FirewallRule`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FirewallDirection {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FirewallProtocol {
+    Tcp,
+    Udp,
+    Any,
+}
+
+/// What the SCM does with the service when the event of a `ServiceTrigger` occurs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriggerAction {
+    Start,
+    Stop,
+}
+
+/// Data item of a `ServiceTrigger`, which the event must match.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TriggerData {
+    Binary(Vec<u8>),
+    /// Strings, such as the hardware IDs of a device interface trigger or the `port;protocol`
+    /// of a firewall port trigger.
+    Strings(Vec<String>),
+    /// Level of an ETW custom trigger.
+    Level(u8),
+    /// ETW keywords of which the event must have one.
+    KeywordAny(u64),
+    /// ETW keywords the event must all have.
+    KeywordAll(u64),
+}
+
+impl TriggerData {
+    fn data_type(&self) -> DWORD {
+        match self {
+            TriggerData::Binary(_) => SERVICE_TRIGGER_DATA_TYPE_BINARY,
+            TriggerData::Strings(_) => SERVICE_TRIGGER_DATA_TYPE_STRING,
+            TriggerData::Level(_) => SERVICE_TRIGGER_DATA_TYPE_LEVEL,
+            TriggerData::KeywordAny(_) => SERVICE_TRIGGER_DATA_TYPE_KEYWORD_ANY,
+            TriggerData::KeywordAll(_) => SERVICE_TRIGGER_DATA_TYPE_KEYWORD_ALL,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            TriggerData::Binary(bytes) => bytes.clone(),
+            // A `REG_MULTI_SZ` value: nul-terminated strings followed by an extra nul.
+            TriggerData::Strings(strings) => strings
+                .iter()
+                .flat_map(|string| string.encode_utf16().chain(Some(0)))
+                .chain(Some(0))
+                .flat_map(u16::to_le_bytes)
+                .collect(),
+            TriggerData::Level(level) => vec![*level],
+            TriggerData::KeywordAny(keywords) | TriggerData::KeywordAll(keywords) => {
+                keywords.to_le_bytes().to_vec()
+            }
+        }
+    }
 
-            if service.is_null() {
-                return Err(Error::new(&format!(
-                    "CreateService: {}",
-                    get_last_error_text()
-                )));
+    unsafe fn from_raw(item: &SERVICE_TRIGGER_SPECIFIC_DATA_ITEM) -> TriggerData {
+        let bytes = if item.pData.is_null() {
+            &[][..]
+        } else {
+            slice::from_raw_parts(item.pData, item.cbData as usize)
+        };
+        let keywords = || {
+            let mut value = [0u8; 8];
+            let len = bytes.len().min(8);
+            value[..len].copy_from_slice(&bytes[..len]);
+            u64::from_le_bytes(value)
+        };
+        match item.dwDataType {
+            SERVICE_TRIGGER_DATA_TYPE_STRING => {
+                let wide: Vec<u16> = bytes
+                    .chunks_exact(2)
+                    .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                    .collect();
+                TriggerData::Strings(
+                    wide.split(|&c| c == 0)
+                        .filter(|string| !string.is_empty())
+                        .map(String::from_utf16_lossy)
+                        .collect(),
+                )
+            }
+            SERVICE_TRIGGER_DATA_TYPE_LEVEL => {
+                TriggerData::Level(bytes.first().copied().unwrap_or(0))
             }
+            SERVICE_TRIGGER_DATA_TYPE_KEYWORD_ANY => TriggerData::KeywordAny(keywords()),
+            SERVICE_TRIGGER_DATA_TYPE_KEYWORD_ALL => TriggerData::KeywordAll(keywords()),
+            _ => TriggerData::Binary(bytes.to_vec()),
+        }
+    }
+}
+
+/// Event starting or stopping a service whose start type is `SERVICE_DEMAND_START` or
+/// `SERVICE_AUTO_START`, such as the arrival of a device or of a network address. See
+/// `WindowsController::triggers`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceTrigger {
+    /// One of the `SERVICE_TRIGGER_TYPE_*` constants.
+    pub trigger_type: DWORD,
+    pub action: TriggerAction,
+    /// GUID of the event read as a number, `{53f5630d-b6bf-11d0-94f2-00a0c91efb8b}` is
+    /// `0x53f5630d_b6bf_11d0_94f2_00a0c91efb8b`. It is the device interface class of the
+    /// device interface triggers.
+    pub subtype: u128,
+    pub data: Vec<TriggerData>,
+}
+
+impl ServiceTrigger {
+    /// Starts the service when the first IP address of the machine becomes available.
+    pub fn first_ip_address_arrival() -> ServiceTrigger {
+        ServiceTrigger {
+            trigger_type: SERVICE_TRIGGER_TYPE_IP_ADDRESS_AVAILABILITY,
+            action: TriggerAction::Start,
+            subtype: guid_to_u128(&NETWORK_MANAGER_FIRST_IP_ADDRESS_ARRIVAL_GUID),
+            data: Vec::new(),
+        }
+    }
+
+    /// Stops the service when the last IP address of the machine is removed.
+    pub fn last_ip_address_removal() -> ServiceTrigger {
+        ServiceTrigger {
+            trigger_type: SERVICE_TRIGGER_TYPE_IP_ADDRESS_AVAILABILITY,
+            action: TriggerAction::Stop,
+            subtype: guid_to_u128(&NETWORK_MANAGER_LAST_IP_ADDRESS_REMOVAL_GUID),
+            data: Vec::new(),
+        }
+    }
+
+    /// Starts the service when the machine joins a domain.
+    pub fn domain_join() -> ServiceTrigger {
+        ServiceTrigger {
+            trigger_type: SERVICE_TRIGGER_TYPE_DOMAIN_JOIN,
+            action: TriggerAction::Start,
+            subtype: guid_to_u128(&DOMAIN_JOIN_GUID),
+            data: Vec::new(),
+        }
+    }
+
+    /// Starts the service when a firewall rule opens `port` for `protocol`.
+    pub fn firewall_port_open(port: u16, protocol: FirewallProtocol) -> ServiceTrigger {
+        let protocol = match protocol {
+            FirewallProtocol::Tcp => "TCP",
+            FirewallProtocol::Udp => "UDP",
+            FirewallProtocol::Any => "ANY",
+        };
+        ServiceTrigger {
+            trigger_type: SERVICE_TRIGGER_TYPE_FIREWALL_PORT_EVENT,
+            action: TriggerAction::Start,
+            subtype: guid_to_u128(&FIREWALL_PORT_OPEN_GUID),
+            data: vec![TriggerData::Strings(vec![format!("{};{}", port, protocol)])],
+        }
+    }
+
+    /// Starts the service when a device of the interface class `class` arrives, among the
+    /// devices with one of `hardware_ids` when it isn't empty.
+    pub fn device_interface_arrival(class: u128, hardware_ids: &[&str]) -> ServiceTrigger {
+        ServiceTrigger {
+            trigger_type: SERVICE_TRIGGER_TYPE_DEVICE_INTERFACE_ARRIVAL,
+            action: TriggerAction::Start,
+            subtype: class,
+            data: if hardware_ids.is_empty() {
+                Vec::new()
+            } else {
+                vec![TriggerData::Strings(
+                    hardware_ids.iter().map(|id| id.to_string()).collect(),
+                )]
+            },
+        }
+    }
+
+    unsafe fn from_raw(trigger: &SERVICE_TRIGGER) -> ServiceTrigger {
+        let data = if trigger.pDataItems.is_null() {
+            Vec::new()
+        } else {
+            slice::from_raw_parts(trigger.pDataItems, trigger.cDataItems as usize)
+                .iter()
+                .map(|item| TriggerData::from_raw(item))
+                .collect()
+        };
+        ServiceTrigger {
+            trigger_type: trigger.dwTriggerType,
+            action: if trigger.dwAction == SERVICE_TRIGGER_ACTION_SERVICE_STOP {
+                TriggerAction::Stop
+            } else {
+                TriggerAction::Start
+            },
+            subtype: if trigger.pTriggerSubtype.is_null() {
+                0
+            } else {
+                guid_to_u128(&*trigger.pTriggerSubtype)
+            },
+            data,
+        }
+    }
+}
+
+fn guid_to_u128(guid: &GUID) -> u128 {
+    let mut value =
+        (guid.Data1 as u128) << 96 | (guid.Data2 as u128) << 80 | (guid.Data3 as u128) << 64;
+    for (i, byte) in guid.Data4.iter().enumerate() {
+        value |= (*byte as u128) << (56 - 8 * i);
+    }
+    value
+}
+
+fn guid_from_u128(value: u128) -> GUID {
+    let mut data4 = [0u8; 8];
+    for (i, byte) in data4.iter_mut().enumerate() {
+        *byte = (value >> (56 - 8 * i)) as u8;
+    }
+    GUID {
+        Data1: (value >> 96) as u32,
+        Data2: (value >> 80) as u16,
+        Data3: (value >> 64) as u16,
+        Data4: data4,
+    }
+}
+
+/// Windows Firewall rule allowing the traffic of the service executable, added by `create()`
+/// and removed by `delete()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FirewallRule {
+    /// Name of the rule shown in the firewall console, rules are replaced and removed by name.
+    pub name: String,
+    pub direction: FirewallDirection,
+    pub protocol: FirewallProtocol,
+    /// Local ports the rule applies to, all the ports when empty.
+    pub local_ports: Vec<u16>,
+}
+
+impl FirewallRule {
+    /// Rule allowing inbound TCP connections to `local_ports`.
+    pub fn inbound_tcp(name: &str, local_ports: &[u16]) -> FirewallRule {
+        FirewallRule {
+            name: name.to_string(),
+            direction: FirewallDirection::Inbound,
+            protocol: FirewallProtocol::Tcp,
+            local_ports: local_ports.to_vec(),
+        }
+    }
+
+    fn add(&self, program: &str, service_name: &str) -> Result<(), Error> {
+        let mut args = vec![
+            "advfirewall".to_string(),
+            "firewall".to_string(),
+            "add".to_string(),
+            "rule".to_string(),
+            format!("name={}", self.name),
+            format!(
+                "dir={}",
+                match self.direction {
+                    FirewallDirection::Inbound => "in",
+                    FirewallDirection::Outbound => "out",
+                }
+            ),
+            "action=allow".to_string(),
+            format!("program={}", program),
+            format!("service={}", service_name),
+            format!(
+                "protocol={}",
+                match self.protocol {
+                    FirewallProtocol::Tcp => "tcp",
+                    FirewallProtocol::Udp => "udp",
+                    FirewallProtocol::Any => "any",
+                }
+            ),
+            "enable=yes".to_string(),
+        ];
+        // Ports can only be given for TCP and UDP rules.
+        if !self.local_ports.is_empty() && self.protocol != FirewallProtocol::Any {
+            let ports: Vec<String> = self.local_ports.iter().map(u16::to_string).collect();
+            args.push(format!("localport={}", ports.join(",")));
+        }
+        netsh(&args)
+    }
+
+    fn remove(&self) -> Result<(), Error> {
+        netsh(&[
+            "advfirewall".to_string(),
+            "firewall".to_string(),
+            "delete".to_string(),
+            "rule".to_string(),
+            format!("name={}", self.name),
+        ])
+    }
+}
+
+fn netsh(args: &[String]) -> Result<(), Error> {
+    let output = Command::new("netsh")
+        .args(args)
+        .output()
+        .map_err(|e| Error {
+            function: Some("netsh".to_string()),
+            ..Error::io("Failed to execute netsh", &e)
+        })?;
+    if !output.status.success() {
+        // netsh writes its errors to the standard output.
+        return Err(Error {
+            function: Some("netsh".to_string()),
+            ..Error::new(&format!(
+                "Command \"netsh {}\" failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stdout).trim()
+            ))
+        });
+    }
+    Ok(())
+}
+
+fn powershell(script: &str) -> Result<(), Error> {
+    let output = Command::new("powershell.exe")
+        .args(["-NoProfile", "-NonInteractive", "-Command"])
+        .arg(format!("$ErrorActionPreference = 'Stop'; {}", script))
+        .output()
+        .map_err(|e| Error {
+            function: Some("powershell".to_string()),
+            ..Error::io("Failed to execute powershell", &e)
+        })?;
+    if !output.status.success() {
+        return Err(Error {
+            function: Some("powershell".to_string()),
+            ..Error::new(&format!(
+                "Command \"{}\" failed: {}",
+                script,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        });
+    }
+    Ok(())
+}
+
+/// Quotes `value` as a PowerShell string literal.
+fn powershell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Session with the HTTP Server API opened to change its configuration.
+#[cfg(feature = "install")]
+struct HttpConfig;
+
+#[cfg(feature = "install")]
+impl HttpConfig {
+    fn open() -> Result<HttpConfig, Error> {
+        let code = unsafe {
+            traced!(HttpInitialize(
+                HTTPAPI_VERSION_1,
+                HTTP_INITIALIZE_CONFIG,
+                ptr::null_mut()
+            ))
+        };
+        if code != NO_ERROR {
+            return Err(Error::os(
+                "HttpInitialize",
+                code as i32,
+                &format_error_message(code),
+            ));
+        }
+        Ok(HttpConfig)
+    }
+
+    /// Reserves `url_prefix` for `sid`, replacing an existing reservation of the prefix.
+    fn add_url_reservation(&self, url_prefix: &str, sid: &str) -> Result<(), Error> {
+        let mut url_prefix = get_utf16(url_prefix);
+        let mut security_descriptor = get_utf16(&format!("D:(A;;GX;;;{})", sid));
+        let mut config = HTTP_SERVICE_CONFIG_URLACL_SET {
+            KeyDesc: HTTP_SERVICE_CONFIG_URLACL_KEY {
+                pUrlPrefix: url_prefix.as_mut_ptr(),
+            },
+            ParamDesc: HTTP_SERVICE_CONFIG_URLACL_PARAM {
+                pStringSecurityDescriptor: security_descriptor.as_mut_ptr(),
+            },
+        };
+        let set = |config: &mut HTTP_SERVICE_CONFIG_URLACL_SET| unsafe {
+            traced!(HttpSetServiceConfiguration(
+                ptr::null_mut(),
+                HttpServiceConfigUrlAclInfo,
+                config as *mut _ as PVOID,
+                mem::size_of::<HTTP_SERVICE_CONFIG_URLACL_SET>() as ULONG,
+                ptr::null_mut(),
+            ))
+        };
+        let mut code = set(&mut config);
+        if code == ERROR_ALREADY_EXISTS {
+            self.delete_url_reservation_with(&mut config)?;
+            code = set(&mut config);
+        }
+        if code != NO_ERROR {
+            return Err(Error::os(
+                "HttpSetServiceConfiguration",
+                code as i32,
+                &format_error_message(code),
+            ));
+        }
+        Ok(())
+    }
+
+    fn delete_url_reservation(&self, url_prefix: &str) -> Result<(), Error> {
+        let mut url_prefix = get_utf16(url_prefix);
+        let mut config = HTTP_SERVICE_CONFIG_URLACL_SET {
+            KeyDesc: HTTP_SERVICE_CONFIG_URLACL_KEY {
+                pUrlPrefix: url_prefix.as_mut_ptr(),
+            },
+            ParamDesc: HTTP_SERVICE_CONFIG_URLACL_PARAM {
+                pStringSecurityDescriptor: ptr::null_mut(),
+            },
+        };
+        self.delete_url_reservation_with(&mut config)
+    }
+
+    fn delete_url_reservation_with(
+        &self,
+        config: &mut HTTP_SERVICE_CONFIG_URLACL_SET,
+    ) -> Result<(), Error> {
+        let code = unsafe {
+            traced!(HttpDeleteServiceConfiguration(
+                ptr::null_mut(),
+                HttpServiceConfigUrlAclInfo,
+                config as *mut _ as PVOID,
+                mem::size_of::<HTTP_SERVICE_CONFIG_URLACL_SET>() as ULONG,
+                ptr::null_mut(),
+            ))
+        };
+        match code {
+            NO_ERROR | ERROR_FILE_NOT_FOUND => Ok(()),
+            code => Err(Error::os(
+                "HttpDeleteServiceConfiguration",
+                code as i32,
+                &format_error_message(code),
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "install")]
+impl Drop for HttpConfig {
+    fn drop(&mut self) {
+        unsafe {
+            traced!(HttpTerminate(HTTP_INITIALIZE_CONFIG, ptr::null_mut()));
+        }
+    }
+}
+
+/// String SID of the account the service runs as, LocalSystem when `account_name` is empty.
+#[cfg(feature = "install")]
+fn account_sid(account_name: &str) -> Result<String, Error> {
+    if account_name.is_empty() || account_name.eq_ignore_ascii_case("LocalSystem") {
+        return Ok("SY".to_string());
+    }
+
+    unsafe {
+        let mut sid = lookup_account(account_name)?;
+        let mut string_sid: LPWSTR = ptr::null_mut();
+        if traced!(ConvertSidToStringSidW(
+            sid.as_mut_ptr() as PSID,
+            &mut string_sid
+        )) == 0
+        {
+            return Err(last_error("ConvertSidToStringSidW"));
+        }
+        let result = WideCString::from_ptr_str(string_sid).to_string_lossy();
+        LocalFree(string_sid as HLOCAL);
+        Ok(result)
+    }
+}
+
+/// True for the accounts that run services without being granted the "Log on as a service"
+/// right: LocalSystem, LocalService, NetworkService and the virtual `NT SERVICE` accounts.
+fn is_builtin_account(account_name: &str) -> bool {
+    let account_name = account_name.to_ascii_lowercase();
+    account_name.is_empty()
+        || account_name == "localsystem"
+        || account_name.starts_with("nt authority\\")
+        || account_name.starts_with("nt service\\")
+}
+
+/// Binary SID of `account_name`.
+fn lookup_account(account_name: &str) -> Result<Vec<u8>, Error> {
+    unsafe {
+        let account_name = get_utf16(account_name);
+        let mut sid_size: DWORD = 0;
+        let mut domain_size: DWORD = 0;
+        let mut sid_use: SID_NAME_USE = 0;
+        traced!(LookupAccountNameW(
+            ptr::null(),
+            account_name.as_ptr(),
+            ptr::null_mut(),
+            &mut sid_size,
+            ptr::null_mut(),
+            &mut domain_size,
+            &mut sid_use,
+        ));
+        let mut sid = vec![0u8; sid_size as usize];
+        let mut domain = vec![0u16; domain_size as usize];
+        if traced!(LookupAccountNameW(
+            ptr::null(),
+            account_name.as_ptr(),
+            sid.as_mut_ptr() as PSID,
+            &mut sid_size,
+            domain.as_mut_ptr(),
+            &mut domain_size,
+            &mut sid_use,
+        )) == 0
+        {
+            return Err(last_error("LookupAccountNameW"));
+        }
+        Ok(sid)
+    }
+}
+
+/// Handle of the local security policy, used to manage the rights of the service account.
+// `LsaOpenPolicy` returns an untyped handle in winapi, the other functions take an `LSA_HANDLE`.
+#[cfg(feature = "install")]
+struct LsaPolicy {
+    handle: PVOID,
+}
+
+#[cfg(feature = "install")]
+impl LsaPolicy {
+    fn open(desired_access: ACCESS_MASK) -> Result<LsaPolicy, Error> {
+        unsafe {
+            let mut attributes: LSA_OBJECT_ATTRIBUTES = mem::zeroed();
+            let mut handle = ptr::null_mut();
+            let status = traced!(LsaOpenPolicy(
+                ptr::null_mut(),
+                &mut attributes,
+                desired_access,
+                &mut handle,
+            ));
+            if status != 0 {
+                return Err(lsa_error("LsaOpenPolicy", status));
+            }
+            Ok(LsaPolicy { handle })
+        }
+    }
+
+    /// Rights granted directly to the account with the binary SID `sid`, not through its
+    /// groups.
+    fn account_rights(&self, sid: &mut [u8]) -> Result<Vec<String>, Error> {
+        unsafe {
+            let mut rights: *mut LSA_UNICODE_STRING = ptr::null_mut();
+            let mut count = 0;
+            let status = traced!(LsaEnumerateAccountRights(
+                self.handle as LSA_HANDLE,
+                sid.as_mut_ptr() as PSID,
+                &mut rights,
+                &mut count,
+            ));
+            // An account without any right has no LSA account object.
+            if status == STATUS_OBJECT_NAME_NOT_FOUND {
+                return Ok(Vec::new());
+            }
+            if status != 0 {
+                return Err(lsa_error("LsaEnumerateAccountRights", status));
+            }
+            let names = slice::from_raw_parts(rights, count as usize)
+                .iter()
+                .map(|right| {
+                    String::from_utf16_lossy(slice::from_raw_parts(
+                        right.Buffer,
+                        right.Length as usize / 2,
+                    ))
+                })
+                .collect();
+            LsaFreeMemory(rights as PVOID);
+            Ok(names)
+        }
+    }
+
+    fn add_right(&self, sid: &mut [u8], right: &str) -> Result<(), Error> {
+        let mut right = get_utf16(right);
+        unsafe {
+            let status = traced!(LsaAddAccountRights(
+                self.handle as LSA_HANDLE,
+                sid.as_mut_ptr() as PSID,
+                &mut lsa_string(&mut right),
+                1,
+            ));
+            if status != 0 {
+                return Err(lsa_error("LsaAddAccountRights", status));
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_right(&self, sid: &mut [u8], right: &str) -> Result<(), Error> {
+        let mut right = get_utf16(right);
+        unsafe {
+            let status = traced!(LsaRemoveAccountRights(
+                self.handle as LSA_HANDLE,
+                sid.as_mut_ptr() as PSID,
+                FALSE as BOOLEAN,
+                &mut lsa_string(&mut right),
+                1,
+            ));
+            if status != 0 && status != STATUS_OBJECT_NAME_NOT_FOUND {
+                return Err(lsa_error("LsaRemoveAccountRights", status));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `LSA_UNICODE_STRING` of the nul terminated `value`, its length excludes the terminator.
+#[cfg(feature = "install")]
+fn lsa_string(value: &mut [u16]) -> LSA_UNICODE_STRING {
+    LSA_UNICODE_STRING {
+        Length: ((value.len() - 1) * 2) as u16,
+        MaximumLength: (value.len() * 2) as u16,
+        Buffer: value.as_mut_ptr(),
+    }
+}
+
+// Value of the `Parameters` key set when `create()` granted the account the right to log on as
+// a service, `delete()` then revokes it.
+const GRANTED_LOGON_RIGHT: &str = "GrantedLogonRight";
+
+/// Grants `SeServiceLogonRight` to `account_name` unless it already holds it, returns whether
+/// it was granted.
+#[cfg(feature = "install")]
+fn grant_logon_right(account_name: &str) -> Result<bool, Error> {
+    let mut sid = lookup_account(account_name)?;
+    let policy = LsaPolicy::open(POLICY_LOOKUP_NAMES | POLICY_CREATE_ACCOUNT)?;
+    if policy
+        .account_rights(&mut sid)?
+        .iter()
+        .any(|right| right == SE_SERVICE_LOGON_NAME)
+    {
+        return Ok(false);
+    }
+    policy.add_right(&mut sid, SE_SERVICE_LOGON_NAME)?;
+    Ok(true)
+}
+
+#[cfg(feature = "install")]
+fn revoke_logon_right(account_name: &str) -> Result<(), Error> {
+    let mut sid = lookup_account(account_name)?;
+    LsaPolicy::open(POLICY_LOOKUP_NAMES)?.remove_right(&mut sid, SE_SERVICE_LOGON_NAME)
+}
+
+fn set_granted_logon_right(service_name: &str) -> Result<(), Error> {
+    let key = get_utf16(&parameters_key(service_name));
+    let granted: DWORD = 1;
+    let status = unsafe {
+        traced!(RegSetKeyValueW(
+            HKEY_LOCAL_MACHINE,
+            key.as_ptr(),
+            get_utf16(GRANTED_LOGON_RIGHT).as_ptr(),
+            REG_DWORD,
+            &granted as *const _ as LPCVOID,
+            mem::size_of::<DWORD>() as DWORD,
+        ))
+    };
+    if status != ERROR_SUCCESS as LONG {
+        return Err(Error::os(
+            "RegSetKeyValueW",
+            status,
+            &format_error_message(status as DWORD),
+        ));
+    }
+    Ok(())
+}
+
+fn has_granted_logon_right(service_name: &str) -> bool {
+    let key = get_utf16(&parameters_key(service_name));
+    let mut granted: DWORD = 0;
+    let mut granted_size = mem::size_of::<DWORD>() as DWORD;
+    unsafe {
+        traced!(RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            key.as_ptr(),
+            get_utf16(GRANTED_LOGON_RIGHT).as_ptr(),
+            RRF_RT_REG_DWORD,
+            ptr::null_mut(),
+            &mut granted as *mut _ as PVOID,
+            &mut granted_size,
+        )) == ERROR_SUCCESS as LONG
+            && granted != 0
+    }
+}
+
+#[cfg(feature = "install")]
+impl Drop for LsaPolicy {
+    fn drop(&mut self) {
+        unsafe { traced!(LsaClose(self.handle as LSA_HANDLE)) };
+    }
+}
+
+#[cfg(feature = "install")]
+fn lsa_error(function: &str, status: NTSTATUS) -> Error {
+    let code = unsafe { LsaNtStatusToWinError(status) };
+    Error::os(function, code as i32, &format_error_message(code))
+}
+
+/// DLL implementing the service, loaded by a process shared with other services such as
+/// `svchost.exe`. The DLL exports the `ServiceMain` function generated by `ServiceDll!`, which
+/// `create()` registers as the `ServiceDll` and `ServiceMain` values of the `Parameters` key
+/// of the service.
+#[derive(Clone, Debug)]
+pub struct ServiceDll {
+    /// Path of the DLL, which may refer to environment variables such as `%ProgramFiles%`.
+    pub path: String,
+    /// svchost group the service runs in, the service is added to the group in the registry
+    /// and started as `svchost.exe -k <group>`. `None` when the service is hosted by the
+    /// executable set as `binary_path`, which loads the DLL and passes its `ServiceMain` to
+    /// `StartServiceCtrlDispatcherW()`.
+    pub svchost_group: Option<String>,
+    /// Lets svchost unload the DLL once the service stopped, so that it can be updated
+    /// without stopping the other services of the group.
+    pub unload_on_stop: bool,
+}
+
+impl ServiceDll {
+    /// DLL at `path` hosted by the svchost group `group` and unloaded when the service stops.
+    pub fn svchost(path: &str, group: &str) -> ServiceDll {
+        ServiceDll {
+            path: path.to_string(),
+            svchost_group: Some(group.to_string()),
+            unload_on_stop: true,
+        }
+    }
+}
+
+/// Manages the service on the system.
+///
+/// The service manager handles are opened for the duration of each operation, clones of a
+/// controller can be used concurrently from several threads. The UTF-16 encodings of the
+/// service name, display name and description are cached, clones share them.
+#[derive(Clone)]
+pub struct WindowsController {
+    pub service_name: Cow<'static, str>,
+    pub display_name: Cow<'static, str>,
+    pub description: Cow<'static, str>,
+    pub desired_access: DWORD,
+    pub service_type: DWORD,
+    pub start_type: DWORD,
+    pub error_control: DWORD,
+    /// Tag assigned by the SCM within `load_order_group`, set by `create()` for boot and
+    /// system start drivers.
+    pub tag_id: DWORD,
+    /// Load order group the service belongs to, empty for none. Groups are ordered by the
+    /// `ServiceGroupOrder` registry key.
+    pub load_order_group: String,
+    pub dependencies: String,
+    /// Account the service runs as, such as `.\\svc-app` or `DOMAIN\\svc-app`, LocalSystem
+    /// when empty.
+    pub account_name: String,
+    /// Password of `account_name`, empty for builtin and virtual accounts.
+    pub password: Secret,
+    /// Grants `SeServiceLogonRight` to `account_name` when the service is created, unless it
+    /// is a builtin account or already holds it, and revokes it when the service is deleted.
+    /// Without this right the service fails to start with `ERROR_SERVICE_LOGON_FAILED`.
+    pub grant_logon_right: bool,
+    /// Path of the service binary, defaults to the current executable.
+    pub binary_path: Option<String>,
+    /// Host whose service control manager is used, `None` for the local machine.
+    pub machine_name: Option<String>,
+    pub service_status: SERVICE_STATUS,
+    pub status_handle: SERVICE_STATUS_HANDLE,
+    pub controls_accepted: DWORD,
+    pub event_policy: EventPolicy,
+    /// Installs a rotating file logger writing to the log directory of the service.
+    pub log_config: Option<LogConfig>,
+    /// Time the service main function has to return after the `Stop` event, the process then
+    /// exits with `STOP_DEADLINE_EXIT_CODE` instead of staying in the stopping state forever.
+    pub stop_deadline: Option<time::Duration>,
+    /// Enables the two-phase stop: stop requests send `StopRequested`, followed by `StopNow`
+    /// once this grace period ends.
+    pub stop_grace_period: Option<time::Duration>,
+    /// Lets only one instance of the service run, see `instance::acquire()`.
+    pub single_instance: bool,
+    /// Time the service needs to start, reported to the SCM as the wait hint of the start
+    /// pending status so that slow starts don't fail with `ERROR_SERVICE_REQUEST_TIMEOUT`.
+    /// `start()` also gives up waiting for the service after this duration.
+    pub startup_timeout: Option<time::Duration>,
+    /// Condition the wrapper waits for before it reports the service as running, the service
+    /// is reported as starting meanwhile, such as `StartupGate::ready()` for a service calling
+    /// `ServiceContext::ready()`. The service receives `Stop` when the gate is still closed
+    /// after `startup_timeout`. Without a gate the service is reported as running when its
+    /// main function starts.
+    pub startup_gate: Option<StartupGate>,
+    /// Failover cluster group the service belongs to as a Generic Service resource, see
+    /// `add_to_cluster()`. The wrapper watches the group and sends `ClusterFailover` once it
+    /// goes offline or moves to another node.
+    pub cluster_group: Option<String>,
+    /// Sends `SessionIdle` once no input was received in a session for this long and
+    /// `SessionActive` when the user is back. The sessions are polled every few seconds.
+    #[cfg(feature = "session-events")]
+    pub idle_threshold: Option<time::Duration>,
+    /// Sends `DisplayChange` when the display configuration changes, from a hidden window the
+    /// service wrapper creates to receive `WM_DISPLAYCHANGE`.
+    pub display_events: bool,
+    /// Installs the service as a DLL loaded by a shared host process instead of an
+    /// executable, see `ServiceDll`.
+    pub service_dll: Option<ServiceDll>,
+    /// Writes a minidump to the `crashdumps` subdirectory of the data directory when the
+    /// service panics or raises an unhandled exception. The oldest dumps are deleted to keep
+    /// their total size under this number of bytes.
+    pub minidump_quota: Option<u64>,
+    /// Re-launches the current executable elevated, showing the UAC prompt, to perform
+    /// `create()`, `delete()` or `start()` when the process is not elevated or they fail with
+    /// `ERROR_ACCESS_DENIED`. The executable must call `handle_elevated_operation()` once the
+    /// controller is configured.
+    pub elevate: bool,
+    /// Windows Firewall rules added for the service executable when the service is created.
+    pub firewall_rules: Vec<FirewallRule>,
+    /// URL prefixes reserved with the HTTP Server API for the account of the service when
+    /// the service is created, such as `http://+:8080/api/`, so that it can listen on them
+    /// without running as an administrator. This is what `netsh http add urlacl` does.
+    pub url_reservations: Vec<String>,
+    /// Runs the service once at the next boot, with the default `SERVICE_AUTO_START` start
+    /// type: it is disabled or deleted by the service wrapper after its main function
+    /// returned 0. The cleanup requires the `install` feature.
+    pub run_once: Option<RunOnce>,
+    /// Reports `Pause` and `Continue` as pending until the service acknowledges them with
+    /// `ServiceContext::pause_completed()` and `ServiceContext::continue_completed()`, instead
+    /// of reporting the service as paused or running as soon as the event is sent.
+    pub acknowledge_pause: bool,
+    /// Runs the failure actions of the service when it stops with an error, not only when its
+    /// process crashes. The wrapper reports a non-zero exit code of the service main function
+    /// as `ERROR_SERVICE_SPECIFIC_ERROR`, which then triggers the recovery actions.
+    pub failure_actions_on_non_crash_failures: bool,
+    /// Starts the service about two minutes after the other `SERVICE_AUTO_START` services
+    /// once the system booted, so that it doesn't slow down the boot.
+    pub delayed_auto_start: bool,
+    /// Events starting or stopping the service, set when it is created. Combined with the
+    /// `SERVICE_DEMAND_START` start type the service only runs when it is needed.
+    pub triggers: Vec<ServiceTrigger>,
+    /// Environment variables set by the wrapper in the service process before the service
+    /// main function runs, such as locale, `PATH` additions or proxy variables: services
+    /// started by the SCM get a bare environment. `%NAME%` references in a value are expanded
+    /// from the environment of the service, `("PATH", "%PATH%;C:\\Tools")` extends `PATH`. The
+    /// variables are stored in the `Parameters` key of the service when it is created and are
+    /// not set in standalone mode.
+    pub environment: Vec<(String, String)>,
+    /// Service manager the operations are delegated to instead of the one of the system.
+    pub backend: Option<Arc<dyn Backend>>,
+    /// Logs the commands, files and system calls of the operations instead of performing
+    /// them, to audit what an installer does.
+    pub dry_run: bool,
+    /// Records the operations and their results, see `AuditLog`.
+    pub audit_log: Option<AuditLog>,
+    wide: WideIdentity,
+}
+
+/// UTF-16 encodings of the identity of the service, the clones of a controller share the
+/// encodings made before they were cloned.
+#[derive(Clone)]
+struct WideIdentity {
+    service_name: WideCache,
+    display_name: WideCache,
+    description: WideCache,
+}
+
+impl WideIdentity {
+    const fn new() -> WideIdentity {
+        WideIdentity {
+            service_name: WideCache::new(),
+            display_name: WideCache::new(),
+            description: WideCache::new(),
+        }
+    }
+}
+
+/// Encoding of the last string passed to `get()`, the public fields of the controller can
+/// change between two operations.
+struct WideCache(Mutex<Option<(String, Arc<[u16]>)>>);
+
+impl Clone for WideCache {
+    fn clone(&self) -> WideCache {
+        WideCache(Mutex::new(self.0.lock().unwrap().clone()))
+    }
+}
+
+impl WideCache {
+    const fn new() -> WideCache {
+        WideCache(Mutex::new(None))
+    }
+
+    fn get(
+        &self,
+        value: &str,
+        encode: impl FnOnce(&str) -> Result<Vec<u16>, Error>,
+    ) -> Result<Arc<[u16]>, Error> {
+        let mut cached = self.0.lock().unwrap();
+        match cached.as_ref() {
+            Some((encoded, wide)) if encoded == value => Ok(wide.clone()),
+            _ => {
+                let wide: Arc<[u16]> = encode(value)?.into();
+                *cached = Some((value.to_string(), wide.clone()));
+                Ok(wide)
+            }
+        }
+    }
+}
+
+// `status_handle` is an opaque SCM handle that is never dereferenced and can be used from any
+// thread, the other fields are plain data.
+unsafe impl Send for WindowsController {}
+unsafe impl Sync for WindowsController {}
+
+#[cfg(feature = "install")]
+impl ControllerInterface for WindowsController {
+    fn create(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "create",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.create(&self.service_name, &self.display_name, &self.description),
+            );
+        }
+        let result = in_operation(
+            "create",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || unsafe {
+                self.require_elevated()?;
+                if (self.start_type == SERVICE_BOOT_START
+                    || self.start_type == SERVICE_SYSTEM_START)
+                    && self.service_type & SERVICE_DRIVER == 0
+                {
+                    return Err(Error::from_message(messages::DRIVER_START_TYPE, &[]));
+                }
+                if self.service_type & SERVICE_INTERACTIVE_PROCESS != 0
+                    && !self.allows_interactive()
+                {
+                    return Err(Error::from_message(messages::INTERACTIVE_SERVICE, &[]));
+                }
+
+                if self.machine_name.is_some() && self.binary_path.is_none() {
+                    return Err(Error::from_message(messages::REMOTE_BINARY_PATH, &[]));
+                }
+
+                let service_manager = ServiceControlManager::open(
+                    self.machine_name.as_deref(),
+                    SC_MANAGER_ALL_ACCESS,
+                )?;
+
+                // Drivers have no data directory, remote services are created on another host.
+                if self.machine_name.is_none() && self.service_type & SERVICE_DRIVER == 0 {
+                    create_service_dirs(&self.service_name)?;
+                }
+
+                let filename = match self.svchost_group() {
+                    Some(group) => svchost_command_line(group),
+                    None => self.binary_path.clone().unwrap_or_else(get_filename),
+                };
+                // A 32-bit installer may register a service binary built for the native
+                // architecture, it must not register one the system can't run.
+                if self.machine_name.is_none() && self.service_type & SERVICE_DRIVER == 0 {
+                    let binary = self.service_dll.as_ref().map_or(&filename, |dll| &dll.path);
+                    match BinaryArch::of_file(binary) {
+                        Ok(arch) if !arch.runs_on(BinaryArch::native()) => {
+                            return Err(Error::from_message(
+                                messages::UNSUPPORTED_ARCH,
+                                &[
+                                    binary,
+                                    &format!("{:?}", arch),
+                                    &format!("{:?}", BinaryArch::native()),
+                                ],
+                            ));
+                        }
+                        Ok(_) => (),
+                        Err(e) => debug!("Failed to check the architecture of {}: {}", binary, e),
+                    }
+                }
+                let service_name = self.wide_service_name()?;
+                let display_name = self.wide_display_name()?;
+                let binary_path = to_wide("binary path", &filename, None)?;
+                let load_order_group = get_utf16(&self.load_order_group);
+                let account_name = get_utf16(&self.account_name);
+                let mut password = get_utf16(self.password.expose());
+                let mut tag_id = 0;
+                // The SCM only assigns tags to boot and system start drivers within a group.
+                let wants_tag = !self.load_order_group.is_empty()
+                    && self.service_type & SERVICE_DRIVER != 0
+                    && (self.start_type == SERVICE_BOOT_START
+                        || self.start_type == SERVICE_SYSTEM_START);
+
+                if dry_run::skip(|| self.describe_creation(&filename)) {
+                    return Ok(());
+                }
+
+                let service = traced!(CreateServiceW(
+                    service_manager.handle,
+                    service_name.as_ptr(),
+                    display_name.as_ptr(),
+                    self.desired_access,
+                    self.registered_service_type(),
+                    self.start_type,
+                    self.error_control,
+                    binary_path.as_ptr(),
+                    if self.load_order_group.is_empty() {
+                        ptr::null()
+                    } else {
+                        load_order_group.as_ptr()
+                    },
+                    if wants_tag {
+                        &mut tag_id
+                    } else {
+                        ptr::null_mut()
+                    },
+                    ptr::null_mut(),
+                    if self.account_name.is_empty() {
+                        ptr::null()
+                    } else {
+                        account_name.as_ptr()
+                    },
+                    if self.password.is_empty() {
+                        ptr::null()
+                    } else {
+                        password.as_ptr()
+                    },
+                ));
+                zeroize(&mut password);
+
+                if service.is_null() {
+                    let error = last_error("CreateService");
+                    if error.os_error == Some(ERROR_SERVICE_EXISTS as i32) {
+                        if let Some(conflict) = self.find_conflict(&service_manager, &filename) {
+                            return Err(conflict.into_error());
+                        }
+                    }
+                    return Err(error);
+                }
+
+                self.tag_id = tag_id;
+
+                let service = Service { handle: service };
+                if let Err(e) = self.configure_created(&service, &filename) {
+                    self.roll_back_creation(&service);
+                    return Err(e);
+                }
+                Ok(())
+            },
+        );
+        self.elevate_on_access_denied("create", result)
+    }
+
+    fn delete(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "delete",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.delete(&self.service_name),
+            );
+        }
+        let result = in_operation(
+            "delete",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || unsafe {
+                self.require_elevated()?;
+                let service_manager = ServiceControlManager::open(
+                    self.machine_name.as_deref(),
+                    SC_MANAGER_ALL_ACCESS,
+                )?;
+                let service = self.open_service(&service_manager, SERVICE_ALL_ACCESS)?;
+                if dry_run::skip(|| {
+                    format!(
+                    "ControlService({}, SERVICE_CONTROL_STOP), DeleteService({}) and remove the \
+                     firewall rules, URL reservations and granted logon right",
+                    self.service_name, self.service_name
+                )
+                }) {
+                    return Ok(());
+                }
+                // The `Parameters` key is removed with the service.
+                let revoke_logon_right =
+                    self.grants_logon_right() && has_granted_logon_right(&self.service_name);
+
+                if traced!(ControlService(
+                    service.handle,
+                    SERVICE_CONTROL_STOP,
+                    &mut self.service_status,
+                )) != 0
+                {
+                    while traced!(QueryServiceStatus(service.handle, &mut self.service_status)) != 0
+                    {
+                        if self.service_status.dwCurrentState != SERVICE_STOP_PENDING {
+                            break;
+                        }
+                        thread::sleep(time::Duration::from_millis(250));
+                    }
+                }
+
+                if traced!(DeleteService(service.handle)) == 0 {
+                    return Err(last_error("DeleteService"));
+                }
+                self.remove_from_svchost_group();
+                self.remove_local_config();
+                if revoke_logon_right {
+                    self.revoke_granted_logon_right();
+                }
+                Ok(())
+            },
+        );
+        self.elevate_on_access_denied("delete", result)
+    }
+
+    fn start(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "start",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.start(&self.service_name),
+            );
+        }
+        let result = in_operation(
+            "start",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || unsafe {
+                self.require_elevated()?;
+                let service_manager = ServiceControlManager::open(
+                    self.machine_name.as_deref(),
+                    SC_MANAGER_ALL_ACCESS,
+                )?;
+                let service = self.open_service(&service_manager, SERVICE_ALL_ACCESS)?;
+                if dry_run::skip(|| format!("StartServiceW({})", self.service_name)) {
+                    return Ok(());
+                }
+
+                if traced!(StartServiceW(service.handle, 0, ptr::null_mut())) != 0 {
+                    let started = time::Instant::now();
+                    while traced!(QueryServiceStatus(service.handle, &mut self.service_status)) != 0
+                    {
+                        if self.service_status.dwCurrentState != SERVICE_START_PENDING {
+                            break;
+                        }
+                        if let Some(timeout) = self.startup_timeout {
+                            if started.elapsed() > timeout {
+                                return Err(Error::from_message(messages::START_TIMED_OUT, &[]));
+                            }
+                        }
+                        thread::sleep(time::Duration::from_millis(250));
+                    }
+                }
+
+                if self.service_status.dwCurrentState != SERVICE_RUNNING {
+                    return Err(Error::from_message(messages::START_FAILED, &[]));
+                }
+
+                Ok(())
+            },
+        );
+        self.elevate_on_access_denied("start", result)
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "stop",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.stop(&self.service_name),
+            );
+        }
+        in_operation(
+            "stop",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || unsafe {
+                let service = match self.open_to_stop(|| {
+                    format!(
+                        "ControlService({}, SERVICE_CONTROL_STOP)",
+                        self.service_name
+                    )
+                })? {
+                    Some(service) => service,
+                    None => return Ok(()),
+                };
+
+                if traced!(ControlService(
+                    service.handle,
+                    SERVICE_CONTROL_STOP,
+                    &mut self.service_status,
+                )) == 0
+                {
+                    return Err(last_error("ControlService"));
+                }
+                self.wait_stopped(&service)
+            },
+        )
+    }
+
+    fn kill(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "kill",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.kill(&self.service_name),
+            );
+        }
+        in_operation(
+            "kill",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || unsafe {
+                self.require_elevated()?;
+                let service_manager = ServiceControlManager::open(
+                    self.machine_name.as_deref(),
+                    SC_MANAGER_ALL_ACCESS,
+                )?;
+                let service = self.open_service(&service_manager, SERVICE_ALL_ACCESS)?;
+
+                let status = service.query_status_process()?;
+                if status.dwProcessId == 0 {
+                    return Err(Error::from_message(messages::NOT_RUNNING, &[]));
+                }
+                // The host of shared services, such as svchost, runs other services.
+                if status.dwServiceType & SERVICE_WIN32_SHARE_PROCESS != 0 {
+                    return Err(Error::from_message(
+                        messages::SHARED_PROCESS,
+                        &[&self.service_name],
+                    ));
+                }
+                if dry_run::skip(|| format!("TerminateProcess({})", status.dwProcessId)) {
+                    return Ok(());
+                }
+
+                let process = traced!(OpenProcess(PROCESS_TERMINATE, FALSE, status.dwProcessId));
+                if process.is_null() {
+                    return Err(last_error("OpenProcess"));
+                }
+
+                let result = traced!(TerminateProcess(process, 1));
+                let error = last_error("TerminateProcess");
+                CloseHandle(process);
+
+                if result == 0 {
+                    return Err(error);
+                }
+
+                Ok(())
+            },
+        )
+    }
+
+    fn pause(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "pause",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.pause(&self.service_name),
+            );
+        }
+        in_operation(
+            "pause",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || self.control_pause(SERVICE_CONTROL_PAUSE, SERVICE_PAUSE_PENDING, SERVICE_PAUSED),
+        )
+    }
+
+    fn resume(&mut self) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "resume",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.resume(&self.service_name),
+            );
+        }
+        in_operation(
+            "resume",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || {
+                self.control_pause(
+                    SERVICE_CONTROL_CONTINUE,
+                    SERVICE_CONTINUE_PENDING,
+                    SERVICE_RUNNING,
+                )
+            },
+        )
+    }
+}
+
+impl WindowsController {
+    pub fn new(service_name: &str, display_name: &str, description: &str) -> WindowsController {
+        WindowsController::with_identity(
+            Cow::Owned(service_name.to_string()),
+            Cow::Owned(display_name.to_string()),
+            Cow::Owned(description.to_string()),
+        )
+    }
+
+    /// Creates a controller like `new()` from names known at compile time, which are borrowed
+    /// instead of copied so that the controller can be a `const` or a `static`.
+    pub const fn const_new(
+        service_name: &'static str,
+        display_name: &'static str,
+        description: &'static str,
+    ) -> WindowsController {
+        WindowsController::with_identity(
+            Cow::Borrowed(service_name),
+            Cow::Borrowed(display_name),
+            Cow::Borrowed(description),
+        )
+    }
+
+    const fn with_identity(
+        service_name: Cow<'static, str>,
+        display_name: Cow<'static, str>,
+        description: Cow<'static, str>,
+    ) -> WindowsController {
+        WindowsController {
+            service_name,
+            display_name,
+            description,
+            backend: None,
+            dry_run: false,
+            audit_log: None,
+            wide: WideIdentity::new(),
+            desired_access: SERVICE_ALL_ACCESS,
+            service_type: SERVICE_WIN32_OWN_PROCESS,
+            start_type: SERVICE_AUTO_START,
+            error_control: SERVICE_ERROR_NORMAL,
+            tag_id: 0,
+            load_order_group: String::new(),
+            dependencies: String::new(),
+            account_name: String::new(),
+            password: Secret::empty(),
+            grant_logon_right: true,
+            binary_path: None,
+            machine_name: None,
+            service_status: SERVICE_STATUS {
+                dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+                dwCurrentState: SERVICE_STOPPED,
+                dwControlsAccepted: 0,
+                dwWin32ExitCode: 0,
+                dwServiceSpecificExitCode: 0,
+                dwCheckPoint: 0,
+                dwWaitHint: 0,
+            },
+            status_handle: ptr::null_mut(),
+            controls_accepted: SERVICE_ACCEPT_STOP,
+            event_policy: EventPolicy::new(),
+            log_config: None,
+            stop_deadline: None,
+            stop_grace_period: None,
+            single_instance: false,
+            startup_timeout: None,
+            startup_gate: None,
+            cluster_group: None,
+            #[cfg(feature = "session-events")]
+            idle_threshold: None,
+            display_events: false,
+            service_dll: None,
+            minidump_quota: None,
+            elevate: false,
+            firewall_rules: Vec::new(),
+            url_reservations: Vec::new(),
+            run_once: None,
+            acknowledge_pause: false,
+            failure_actions_on_non_crash_failures: false,
+            delayed_auto_start: false,
+            triggers: Vec::new(),
+            environment: Vec::new(),
+        }
+    }
+
+    /// Performs the operation requested by a non-elevated instance of the executable then
+    /// exits, when the process was launched by the `elevate` option. Returns otherwise.
+    #[cfg(feature = "install")]
+    pub fn handle_elevated_operation(&mut self) {
+        let mut args = env::args().skip_while(|arg| arg != ELEVATED_ARG).skip(1);
+        let operation = match args.next() {
+            Some(operation) => operation,
+            None => return,
+        };
+
+        let result = match operation.as_str() {
+            "create" => self.create(),
+            "delete" => self.delete(),
+            "start" => self.start(),
+            _ => Err(Error::new(&format!(
+                "Unknown elevated operation {}",
+                operation
+            ))),
+        };
+        let exit_code = match result {
+            Ok(()) => 0,
+            Err(e) => {
+                error!("{}", e);
+                e.os_error.unwrap_or(1)
+            }
+        };
+        process::exit(exit_code);
+    }
+
+    /// Checks of `doctor()` specific to the SCM.
+    #[cfg(feature = "install")]
+    pub(crate) fn diagnose(&self) -> Vec<Check> {
+        let mut checks = Vec::new();
+        match ServiceControlManager::open(self.machine_name.as_deref(), SC_MANAGER_ALL_ACCESS) {
+            Ok(service_manager) => {
+                checks.push(Check::passed("service manager"));
+                checks.push(self.check_names(&service_manager));
+                let interactive = self
+                    .open_service(&service_manager, SERVICE_QUERY_CONFIG)
+                    .and_then(|service| service.query_config())
+                    .is_ok_and(|config| config.service_type & SERVICE_INTERACTIVE_PROCESS != 0);
+                if interactive && !self.allows_interactive() {
+                    checks.push(Check::failed(
+                        "interactive service",
+                        Error::from_message(messages::INTERACTIVE_SERVICE, &[]).localized_message(),
+                    ));
+                }
+            }
+            Err(e) => checks.push(Check::failed("service manager", e.to_string())),
+        }
+
+        checks.push(if self.machine_name.is_some() {
+            Check::warning("binary path", "not checked on a remote host")
+        } else {
+            binary_check(Path::new(
+                &self.binary_path.clone().unwrap_or_else(get_filename),
+            ))
+        });
+
+        if !is_builtin_account(&self.account_name) && self.machine_name.is_none() {
+            checks.push(self.check_logon_right());
+        }
+        checks
+    }
+
+    /// Compares the installed service with the configuration of the controller, `None` when
+    /// they match or the installed configuration can't be queried.
+    #[cfg(feature = "install")]
+    fn find_conflict(
+        &self,
+        service_manager: &ServiceControlManager,
+        binary_path: &str,
+    ) -> Option<ConflictingService> {
+        let existing = self
+            .open_service(service_manager, SERVICE_QUERY_CONFIG)
+            .and_then(|service| service.query_config())
+            .ok()?;
+        let account_name = |name: &str| {
+            if name.is_empty() {
+                "LocalSystem".to_string()
+            } else {
+                name.to_string()
+            }
+        };
+        let mut differences = Vec::new();
+        let mut compare = |setting, existing: String, requested: String| {
+            if !existing.eq_ignore_ascii_case(&requested) {
+                differences.push(ConfigDifference {
+                    setting,
+                    existing,
+                    requested,
+                });
+            }
+        };
+        compare("binary path", existing.binary_path, binary_path.to_string());
+        compare(
+            "display name",
+            existing.display_name,
+            self.display_name.to_string(),
+        );
+        compare(
+            "service type",
+            format!("{:#x}", existing.service_type),
+            format!("{:#x}", self.service_type),
+        );
+        compare(
+            "start type",
+            existing.start_type.to_string(),
+            self.start_type.to_string(),
+        );
+        compare(
+            "account",
+            account_name(&existing.account_name),
+            account_name(&self.account_name),
+        );
+        compare(
+            "load order group",
+            existing.load_order_group,
+            self.load_order_group.clone(),
+        );
+        if differences.is_empty() {
+            return None;
+        }
+        Some(ConflictingService {
+            service_name: self.service_name.to_string(),
+            differences,
+        })
+    }
+
+    #[cfg(feature = "install")]
+    fn check_names(&self, service_manager: &ServiceControlManager) -> Check {
+        if self
+            .open_service(service_manager, SERVICE_QUERY_STATUS)
+            .is_ok()
+        {
+            return Check::warning(
+                "service name",
+                format!("{} is already installed", self.service_name),
+            );
+        }
+        match service_manager.service_key_name(&self.display_name) {
+            Some(key_name) => Check::failed(
+                "service name",
+                format!(
+                    "the display name \"{}\" is used by the service {}",
+                    self.display_name, key_name
+                ),
+            ),
+            None => Check::passed("service name"),
+        }
+    }
+
+    #[cfg(feature = "install")]
+    fn check_logon_right(&self) -> Check {
+        let rights = lookup_account(&self.account_name)
+            .and_then(|mut sid| LsaPolicy::open(POLICY_LOOKUP_NAMES)?.account_rights(&mut sid));
+        match rights {
+            Ok(rights) if rights.iter().any(|right| right == SE_SERVICE_LOGON_NAME) => {
+                Check::passed("log on as a service")
+            }
+            // `create()` grants it.
+            Ok(_) if self.grant_logon_right => Check::passed("log on as a service"),
+            Ok(_) => Check::warning(
+                "log on as a service",
+                format!(
+                    "{} isn't granted {} directly, the service fails to start unless one of its \
+                     groups is",
+                    self.account_name, SE_SERVICE_LOGON_NAME
+                ),
+            ),
+            Err(e) => Check::failed("log on as a service", e.to_string()),
+        }
+    }
+
+    /// Whether the system still lets services interact with the desktop, only ReactOS does
+    /// not isolate them in session 0.
+    fn allows_interactive(&self) -> bool {
+        self.machine_name.is_none() && is_reactos()
+    }
+
+    /// Service type passed to the SCM, a DLL shares its process with other services.
+    fn registered_service_type(&self) -> DWORD {
+        match self.service_dll {
+            Some(_) => {
+                (self.service_type & !SERVICE_WIN32_OWN_PROCESS) | SERVICE_WIN32_SHARE_PROCESS
+            }
+            None => self.service_type,
+        }
+    }
+
+    fn svchost_group(&self) -> Option<&str> {
+        self.service_dll.as_ref()?.svchost_group.as_deref()
+    }
+
+    /// Describes the calls and registry writes of `create()` in a dry run.
+    #[cfg(feature = "install")]
+    fn describe_creation(&self, binary_path: &str) -> String {
+        let mut description = format!(
+            "CreateServiceW({}, \"{}\", service type {:#x}, start type {}, error control {}, {}, \
+             load order group \"{}\")\nChangeServiceConfig2W(SERVICE_CONFIG_DESCRIPTION, \"{}\")",
+            self.service_name,
+            self.display_name,
+            self.registered_service_type(),
+            self.start_type,
+            self.error_control,
+            binary_path,
+            self.load_order_group,
+            self.description
+        );
+        if let Some(dll) = &self.service_dll {
+            description.push_str(&format!(
+                "\nRegSetKeyValueW({}\\Parameters, ServiceDll, {})",
+                self.service_name, dll.path
+            ));
+            if let Some(group) = &dll.svchost_group {
+                description.push_str(&format!(
+                    "\nadd {} to the svchost group {}",
+                    self.service_name, group
+                ));
+            }
+        }
+        if !self.environment.is_empty() {
+            description.push_str(&format!(
+                "\nRegSetKeyValueW({}\\Parameters, Environment, {:?})",
+                self.service_name, self.environment
+            ));
+        }
+        if self.failure_actions_on_non_crash_failures {
+            description
+                .push_str("\nChangeServiceConfig2W(SERVICE_CONFIG_FAILURE_ACTIONS_FLAG, TRUE)");
+        }
+        if self.delayed_auto_start && self.start_type == SERVICE_AUTO_START {
+            description
+                .push_str("\nChangeServiceConfig2W(SERVICE_CONFIG_DELAYED_AUTO_START_INFO, TRUE)");
+        }
+        if !self.triggers.is_empty() {
+            description.push_str(&format!(
+                "\nChangeServiceConfig2W(SERVICE_CONFIG_TRIGGER_INFO, {:?})",
+                self.triggers
+            ));
+        }
+        if self.machine_name.is_none() {
+            for rule in &self.firewall_rules {
+                description.push_str(&format!("\nadd the firewall rule {}", rule.name));
+            }
+            for url_prefix in &self.url_reservations {
+                description.push_str(&format!(
+                    "\nreserve {} for {}",
+                    url_prefix, self.account_name
+                ));
+            }
+        }
+        if self.grants_logon_right() {
+            description.push_str(&format!(
+                "\nLsaAddAccountRights({}, {}) unless already granted",
+                self.account_name, SE_SERVICE_LOGON_NAME
+            ));
+        }
+        description
+    }
+
+    /// Renders a WiX Toolset v4 fragment installing the service binary and the service from
+    /// an MSI package instead of `create()`. The component goes in the `INSTALLFOLDER`
+    /// directory; the password of `account_name` is left out, the package sets it with a
+    /// `Password` attribute holding a property. DLL services can't be rendered, the svchost
+    /// registration is not part of `ServiceInstall`.
+    #[cfg(feature = "install")]
+    pub fn render_manifest(&self, format: ManifestFormat) -> Result<String, Error> {
+        if format != ManifestFormat::WixFragment {
+            return Err(format.unsupported());
+        }
+        if self.service_dll.is_some() {
+            return Err(Error::new(
+                "DLL services can't be rendered as a WiX fragment",
+            ));
+        }
+        let binary_path = self.binary_path.clone().unwrap_or_else(get_filename);
+        let service_type = match self.service_type & !SERVICE_INTERACTIVE_PROCESS {
+            SERVICE_KERNEL_DRIVER => "kernelDriver",
+            SERVICE_FILE_SYSTEM_DRIVER => "systemDriver",
+            SERVICE_WIN32_SHARE_PROCESS => "shareProcess",
+            _ => "ownProcess",
+        };
+        let start_type = match self.start_type {
+            SERVICE_BOOT_START => "boot",
+            SERVICE_SYSTEM_START => "system",
+            SERVICE_AUTO_START => "auto",
+            SERVICE_DISABLED => "disabled",
+            _ => "demand",
+        };
+        // WiX has no severe level, it is the critical one.
+        let error_control = match self.error_control {
+            SERVICE_ERROR_IGNORE => "ignore",
+            SERVICE_ERROR_NORMAL => "normal",
+            _ => "critical",
+        };
+        let mut attributes = format!(
+            "Type=\"{}\" Start=\"{}\" ErrorControl=\"{}\"",
+            service_type, start_type, error_control
+        );
+        if self.service_type & SERVICE_INTERACTIVE_PROCESS != 0 {
+            attributes.push_str(" Interactive=\"yes\"");
+        }
+        if !self.account_name.is_empty() {
+            attributes.push_str(&format!(" Account=\"{}\"", xml_escape(&self.account_name)));
+        }
+        if !self.load_order_group.is_empty() {
+            attributes.push_str(&format!(
+                " LoadOrderGroup=\"{}\"",
+                xml_escape(&self.load_order_group)
+            ));
+        }
+        let id = wix_id(&self.service_name);
+        Ok(format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<Wix xmlns="http://wixtoolset.org/schemas/v4/wxs">
+  <Fragment>
+    <Component Id="{id}" Directory="INSTALLFOLDER">
+      <File Id="{id}.exe" Source="{source}" KeyPath="yes"/>
+      <ServiceInstall Id="{id}" Name="{name}" DisplayName="{display_name}" Description="{description}" {attributes} Vital="yes"/>
+      <ServiceControl Id="{id}" Name="{name}" Start="install" Stop="both" Remove="uninstall" Wait="yes"/>
+    </Component>
+  </Fragment>
+</Wix>
+"#,
+            id = id,
+            source = xml_escape(binary_path.trim_matches('"')),
+            name = xml_escape(&self.service_name),
+            display_name = xml_escape(&self.display_name),
+            description = xml_escape(&self.description),
+            attributes = attributes,
+        ))
+    }
+
+    fn wide_service_name(&self) -> Result<Arc<[u16]>, Error> {
+        self.wide
+            .service_name
+            .get(&self.service_name, service_name_to_wide)
+    }
+
+    fn wide_display_name(&self) -> Result<Arc<[u16]>, Error> {
+        self.wide.display_name.get(&self.display_name, |value| {
+            to_wide("display name", value, Some(MAX_NAME_LEN))
+        })
+    }
+
+    fn wide_description(&self) -> Result<Arc<[u16]>, Error> {
+        self.wide.description.get(&self.description, |value| {
+            to_wide("description", value, None)
+        })
+    }
+
+    fn open_service(
+        &self,
+        service_manager: &ServiceControlManager,
+        desired_access: DWORD,
+    ) -> Result<Service, Error> {
+        service_manager.open_service_wide(&self.wide_service_name()?, desired_access)
+    }
+
+    /// True when `create()` grants the right to log on as a service to the service account,
+    /// the local policy of a remote host is not changed.
+    fn grants_logon_right(&self) -> bool {
+        self.grant_logon_right
+            && self.machine_name.is_none()
+            && self.service_type & SERVICE_DRIVER == 0
+            && !is_builtin_account(&self.account_name)
+    }
+
+    // Applies the configuration of the service that `CreateServiceW` doesn't take.
+    #[cfg(feature = "install")]
+    fn configure_created(&self, service: &Service, filename: &str) -> Result<(), Error> {
+        let _ = compatibility_fallback(
+            service.change_description_wide(&self.wide_description()?),
+            "Service description",
+        );
+        if !self.environment.is_empty() {
+            save_environment(
+                self.machine_name.as_deref(),
+                &self.service_name,
+                &self.environment,
+            )?;
+        }
+        if let Some(dll) = &self.service_dll {
+            register_service_dll(self.machine_name.as_deref(), &self.service_name, dll)?;
+        }
+        if self.failure_actions_on_non_crash_failures {
+            compatibility_fallback(
+                service.change_failure_actions_flag(true),
+                "Failure actions flag",
+            )?;
+        }
+        if self.delayed_auto_start && self.start_type == SERVICE_AUTO_START {
+            compatibility_fallback(
+                service.change_delayed_auto_start(true),
+                "Delayed auto-start",
+            )?;
+        }
+        if !self.triggers.is_empty() {
+            compatibility_fallback(service.change_triggers(&self.triggers), "Triggers")?;
+        }
+
+        // Firewall rules are local, remote hosts manage their own firewall.
+        if self.machine_name.is_none() {
+            for rule in &self.firewall_rules {
+                let _ = rule.remove();
+                rule.add(filename, &self.service_name)?;
+            }
+        }
+
+        if self.machine_name.is_none() && !self.url_reservations.is_empty() {
+            let http_config = HttpConfig::open()?;
+            let sid = account_sid(&self.account_name)?;
+            for url_prefix in &self.url_reservations {
+                http_config.add_url_reservation(url_prefix, &sid)?;
+            }
+        }
+
+        if self.grants_logon_right() && grant_logon_right(&self.account_name)? {
+            info!("Granted {} to {}", SE_SERVICE_LOGON_NAME, self.account_name);
+            if let Err(e) = set_granted_logon_right(&self.service_name) {
+                self.revoke_granted_logon_right();
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Deletes the service `create()` failed to configure, with what it configured.
+    #[cfg(feature = "install")]
+    fn roll_back_creation(&self, service: &Service) {
+        if unsafe { traced!(DeleteService(service.handle)) } == 0 {
+            warn!(
+                "Failed to delete the service {} after its creation failed: {}",
+                self.service_name,
+                last_error("DeleteService")
+            );
+        }
+        self.remove_from_svchost_group();
+        self.remove_local_config();
+    }
+
+    #[cfg(feature = "install")]
+    fn remove_from_svchost_group(&self) {
+        if let Some(group) = self.svchost_group() {
+            let removed = update_svchost_group(self.machine_name.as_deref(), group, |names| {
+                names.retain(|name| !name.eq_ignore_ascii_case(&self.service_name))
+            });
+            if let Err(e) = removed {
+                warn!(
+                    "Failed to remove {} from the svchost group {}: {}",
+                    self.service_name, group, e
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "install")]
+    fn revoke_granted_logon_right(&self) {
+        if let Err(e) = revoke_logon_right(&self.account_name) {
+            warn!(
+                "Failed to revoke {} from {}: {}",
+                SE_SERVICE_LOGON_NAME, self.account_name, e
+            );
+        }
+    }
+
+    // Removes the firewall rules and URL reservations added by `create()`.
+    #[cfg(feature = "install")]
+    fn remove_local_config(&self) {
+        if self.machine_name.is_none() {
+            for rule in &self.firewall_rules {
+                if let Err(e) = rule.remove() {
+                    warn!("Failed to remove the firewall rule {}: {}", rule.name, e);
+                }
+            }
+        }
+
+        if self.machine_name.is_none() && !self.url_reservations.is_empty() {
+            let result = HttpConfig::open().and_then(|http_config| {
+                for url_prefix in &self.url_reservations {
+                    http_config.delete_url_reservation(url_prefix)?;
+                }
+                Ok(())
+            });
+            if let Err(e) = result {
+                warn!("Failed to remove the URL reservations: {}", e);
+            }
+        }
+    }
+
+    // Runs in the service process before it reports the stopped status, a deleted service is
+    // removed by the SCM once it stopped.
+    #[cfg(feature = "install")]
+    fn finish_run_once(&mut self, run_once: RunOnce) -> Result<(), Error> {
+        let service_manager = ServiceControlManager::open(None, SC_MANAGER_CONNECT)?;
+        match run_once {
+            RunOnce::Disable => self
+                .open_service(&service_manager, SERVICE_CHANGE_CONFIG)?
+                .change_start_type(SERVICE_DISABLED),
+            RunOnce::Delete => {
+                let service = self.open_service(&service_manager, DELETE)?;
+                if unsafe { traced!(DeleteService(service.handle)) } == 0 {
+                    return Err(last_error("DeleteService"));
+                }
+                self.remove_local_config();
+                Ok(())
+            }
+        }
+    }
+
+    // Elevation only matters for the local service control manager, a dry run logs that the
+    // operation needs it instead of failing.
+    fn require_elevated(&self) -> Result<(), Error> {
+        match self.machine_name {
+            Some(_) => Ok(()),
+            None => match require_elevated() {
+                Err(e) if dry_run::is_dry_run() => {
+                    info!("Dry run: {}", e);
+                    Ok(())
+                }
+                result => result,
+            },
+        }
+    }
+
+    #[cfg(feature = "install")]
+    fn elevate_on_access_denied(
+        &self,
+        operation: &'static str,
+        result: Result<(), Error>,
+    ) -> Result<(), Error> {
+        match result {
+            Err(e)
+                if self.elevate
+                    && (e.kind == ErrorKind::NotElevated
+                        || e.os_error == Some(ERROR_ACCESS_DENIED as i32))
+                    && !env::args().any(|arg| arg == ELEVATED_ARG) =>
+            {
+                run_elevated(operation).map_err(|e| e.with_operation(operation))
+            }
+            result => result,
+        }
+    }
+
+    /// Manages the services of a remote host instead of the local machine. The binary path
+    /// given to `create()` must be set and refer to a path on the remote host.
+    pub fn connect(&mut self, host: &str) -> &mut Self {
+        self.machine_name = Some(host.to_string());
+        self
+    }
+
+    /// Configures the controller to install a driver service instead of a Win32 service.
+    /// `start_type` can be `SERVICE_BOOT_START` or `SERVICE_SYSTEM_START` in addition to the
+    /// start types supported by Win32 services, `binary_path` is the path of the `.sys` file.
+    /// The error control is reset to `SERVICE_ERROR_NORMAL` so a failing driver is logged
+    /// without affecting the boot, it can be raised afterwards for boot critical drivers.
+    pub fn set_driver(&mut self, driver_type: DriverType, start_type: DWORD, binary_path: &str) {
+        self.service_type = match driver_type {
+            DriverType::Kernel => SERVICE_KERNEL_DRIVER,
+            DriverType::FileSystem => SERVICE_FILE_SYSTEM_DRIVER,
+        };
+        self.start_type = start_type;
+        self.error_control = SERVICE_ERROR_NORMAL;
+        self.binary_path = Some(binary_path.to_string());
+        self.service_status.dwServiceType = self.service_type;
+    }
+
+    /// Stops the service and records why it was stopped. `reason` combines one of the
+    /// `SERVICE_STOP_REASON_FLAG_*` values with major and minor `SERVICE_STOP_REASON_*` codes,
+    /// `comment` is stored along with the reason in the system event log.
+    /// A custom backend stops the service with `Backend::stop()`, without the reason.
+    pub fn stop_with_reason(&mut self, reason: DWORD, comment: &str) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            return in_operation(
+                "stop",
+                self.dry_run,
+                audit::target(&self.audit_log, &self.service_name),
+                || backend.stop(&self.service_name),
+            );
+        }
+        in_operation(
+            "stop",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || unsafe {
+                let mut comment_wide =
+                    to_wide("stop comment", comment, Some(MAX_STOP_COMMENT_LEN))?;
+                let service = match self.open_to_stop(|| {
+                    format!(
+                        "ControlServiceExW({}, SERVICE_CONTROL_STOP, reason {:#x}, \"{}\")",
+                        self.service_name, reason, comment
+                    )
+                })? {
+                    Some(service) => service,
+                    None => return Ok(()),
+                };
+
+                let mut params = SERVICE_CONTROL_STATUS_REASON_PARAMSW {
+                    dwReason: reason,
+                    pszComment: comment_wide.as_mut_ptr(),
+                    ServiceStatus: mem::zeroed(),
+                };
+                if traced!(ControlServiceExW(
+                    service.handle,
+                    SERVICE_CONTROL_STOP,
+                    SERVICE_CONTROL_STATUS_REASON_INFO,
+                    &mut params as *mut _ as PVOID,
+                )) == 0
+                {
+                    return Err(last_error("ControlServiceExW"));
+                }
+                self.wait_stopped(&service)
+            },
+        )
+    }
+
+    /// Opens the service to stop it, `None` when the stop described by `action` is skipped
+    /// in a dry run.
+    fn open_to_stop(&self, action: impl FnOnce() -> String) -> Result<Option<Service>, Error> {
+        self.require_elevated()?;
+        let service_manager =
+            ServiceControlManager::open(self.machine_name.as_deref(), SC_MANAGER_ALL_ACCESS)?;
+        let service = self.open_service(&service_manager, SERVICE_ALL_ACCESS)?;
+        if dry_run::skip(action) {
+            return Ok(None);
+        }
+        Ok(Some(service))
+    }
+
+    /// Waits while the service is stop pending after the stop control was sent.
+    fn wait_stopped(&mut self, service: &Service) -> Result<(), Error> {
+        unsafe {
+            while traced!(QueryServiceStatus(service.handle, &mut self.service_status)) != 0 {
+                if self.service_status.dwCurrentState != SERVICE_STOP_PENDING {
+                    break;
+                }
+                thread::sleep(time::Duration::from_millis(250));
+            }
+        }
+        if self.service_status.dwCurrentState != SERVICE_STOPPED {
+            return Err(Error::from_message(messages::STOP_FAILED, &[]));
+        }
+        Ok(())
+    }
+
+    // Sends a pause or continue control and waits while the service reports `pending`, the
+    // services that don't accept it fail with ERROR_INVALID_SERVICE_CONTROL.
+    fn control_pause(&mut self, control: DWORD, pending: DWORD, done: DWORD) -> Result<(), Error> {
+        unsafe {
+            self.require_elevated()?;
+            let service_manager =
+                ServiceControlManager::open(self.machine_name.as_deref(), SC_MANAGER_ALL_ACCESS)?;
+            let service = self.open_service(&service_manager, SERVICE_ALL_ACCESS)?;
+            if dry_run::skip(|| {
+                format!(
+                    "ControlService({}, {})",
+                    self.service_name,
+                    if control == SERVICE_CONTROL_PAUSE {
+                        "SERVICE_CONTROL_PAUSE"
+                    } else {
+                        "SERVICE_CONTROL_CONTINUE"
+                    }
+                )
+            }) {
+                return Ok(());
+            }
+
+            if traced!(ControlService(
+                service.handle,
+                control,
+                &mut self.service_status
+            )) == 0
+            {
+                return Err(last_error("ControlService"));
+            }
+
+            while self.service_status.dwCurrentState == pending {
+                thread::sleep(time::Duration::from_millis(250));
+                if traced!(QueryServiceStatus(service.handle, &mut self.service_status)) == 0 {
+                    return Err(last_error("QueryServiceStatus"));
+                }
+            }
+
+            if self.service_status.dwCurrentState != done {
+                return Err(Error::new(if control == SERVICE_CONTROL_PAUSE {
+                    "Failed to pause service"
+                } else {
+                    "Failed to resume service"
+                }));
+            }
+
+            Ok(())
+        }
+    }
+
+    /// Returns the status of the service, its state is the `SERVICE_STATUS` state in lower case
+    /// words such as `running` or `stop pending`.
+    pub fn status(&self) -> Result<ServiceStatus, Error> {
+        if let Some(backend) = &self.backend {
+            return backend.status(&self.service_name);
+        }
+        let service_manager =
+            ServiceControlManager::open(self.machine_name.as_deref(), SC_MANAGER_CONNECT)?;
+        let service = self.open_service(&service_manager, SERVICE_QUERY_STATUS)?;
+        let state = service.query_status_process()?.dwCurrentState;
+        Ok(ServiceStatus::new(
+            state_name(state),
+            load_start_record(self.machine_name.as_deref(), &self.service_name),
+        ))
+    }
+
+    /// Subscribes to the state transitions of the service, notified by the SCM with
+    /// `NotifyServiceStatusChangeW`.
+    pub fn subscribe_status(&self) -> StatusSubscription {
+        if self.backend.is_some() {
+            let controller = self.clone();
+            return status::poll(move || controller.status());
+        }
+        let machine_name = self.machine_name.clone();
+        let service_name = self.service_name.clone();
+        StatusSubscription::spawn(move |sender| {
+            if let Err(e) = notify_status_changes(machine_name.as_deref(), &service_name, &sender) {
+                sender.send(Err(e));
+            }
+        })
+    }
+
+    /// Returns the controls the service accepts, as reported by the service to the SCM. A
+    /// service that isn't running accepts none.
+    pub fn accepted_controls(&self) -> Result<AcceptedControls, Error> {
+        if self.backend.is_some() {
+            return Ok(AcceptedControls::signaled(
+                self.status()?.state == "running",
+            ));
+        }
+        let service_manager =
+            ServiceControlManager::open(self.machine_name.as_deref(), SC_MANAGER_CONNECT)?;
+        let service = self.open_service(&service_manager, SERVICE_QUERY_STATUS)?;
+        let status = service.query_status_process()?;
+        Ok(AcceptedControls::from_bits(status.dwControlsAccepted))
+    }
+
+    /// Queries the configuration of the installed service.
+    pub fn get_config(&self) -> Result<ServiceConfig, Error> {
+        let service_manager =
+            ServiceControlManager::open(self.machine_name.as_deref(), SC_MANAGER_CONNECT)?;
+        let service = self.open_service(&service_manager, SERVICE_QUERY_CONFIG)?;
+        service.query_config()
+    }
+
+    /// Changes the description of the installed service.
+    pub fn set_description(&mut self, description: &str) -> Result<(), Error> {
+        in_operation(
+            "set-description",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || {
+                let service_manager = ServiceControlManager::open(
+                    self.machine_name.as_deref(),
+                    SC_MANAGER_ALL_ACCESS,
+                )?;
+                let service = self.open_service(&service_manager, SERVICE_ALL_ACCESS)?;
+                if dry_run::skip(|| {
+                    format!(
+                        "ChangeServiceConfig2W({}, SERVICE_CONFIG_DESCRIPTION, {:?})",
+                        self.service_name, description
+                    )
+                }) {
+                    return Ok(());
+                }
+                compatibility_fallback(
+                    service.change_description(description),
+                    "Service description",
+                )?;
+                self.description = description.to_string().into();
+                Ok(())
+            },
+        )
+    }
+
+    /// Changes whether the failure actions of the installed service also run when it stops
+    /// with an error, see `failure_actions_on_non_crash_failures`.
+    pub fn set_failure_actions_on_non_crash_failures(
+        &mut self,
+        enabled: bool,
+    ) -> Result<(), Error> {
+        in_operation(
+            "set-failure-actions-flag",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || {
+                let service_manager = ServiceControlManager::open(
+                    self.machine_name.as_deref(),
+                    SC_MANAGER_ALL_ACCESS,
+                )?;
+                let service = self.open_service(&service_manager, SERVICE_ALL_ACCESS)?;
+                if dry_run::skip(|| {
+                    format!(
+                        "ChangeServiceConfig2W({}, SERVICE_CONFIG_FAILURE_ACTIONS_FLAG, {})",
+                        self.service_name, enabled
+                    )
+                }) {
+                    return Ok(());
+                }
+                compatibility_fallback(
+                    service.change_failure_actions_flag(enabled),
+                    "Failure actions flag",
+                )?;
+                self.failure_actions_on_non_crash_failures = enabled;
+                Ok(())
+            },
+        )
+    }
+
+    /// Queries the triggers starting or stopping the installed service.
+    pub fn triggers(&self) -> Result<Vec<ServiceTrigger>, Error> {
+        let service_manager =
+            ServiceControlManager::open(self.machine_name.as_deref(), SC_MANAGER_CONNECT)?;
+        let service = self.open_service(&service_manager, SERVICE_QUERY_CONFIG)?;
+        service.query_triggers()
+    }
+
+    /// Replaces the triggers of the installed service, an empty list removes them.
+    pub fn set_triggers(&mut self, triggers: &[ServiceTrigger]) -> Result<(), Error> {
+        in_operation(
+            "set-triggers",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || {
+                let service_manager = ServiceControlManager::open(
+                    self.machine_name.as_deref(),
+                    SC_MANAGER_ALL_ACCESS,
+                )?;
+                let service = self.open_service(&service_manager, SERVICE_CHANGE_CONFIG)?;
+                if dry_run::skip(|| {
+                    format!(
+                        "ChangeServiceConfig2W({}, SERVICE_CONFIG_TRIGGER_INFO, {} triggers)",
+                        self.service_name,
+                        triggers.len()
+                    )
+                }) {
+                    return Ok(());
+                }
+                service.change_triggers(triggers)?;
+                self.triggers = triggers.to_vec();
+                Ok(())
+            },
+        )
+    }
+
+    /// Queries whether the installed service starts after the other auto-start services.
+    pub fn delayed_auto_start(&self) -> Result<bool, Error> {
+        let service_manager =
+            ServiceControlManager::open(self.machine_name.as_deref(), SC_MANAGER_CONNECT)?;
+        let service = self.open_service(&service_manager, SERVICE_QUERY_CONFIG)?;
+        service.query_delayed_auto_start()
+    }
+
+    /// Changes whether the installed service starts after the other auto-start services, see
+    /// `delayed_auto_start`.
+    pub fn set_delayed_auto_start(&mut self, delayed: bool) -> Result<(), Error> {
+        in_operation(
+            "set-delayed-auto-start",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || {
+                let service_manager = ServiceControlManager::open(
+                    self.machine_name.as_deref(),
+                    SC_MANAGER_ALL_ACCESS,
+                )?;
+                let service = self.open_service(&service_manager, SERVICE_CHANGE_CONFIG)?;
+                if dry_run::skip(|| {
+                    format!(
+                        "ChangeServiceConfig2W({}, SERVICE_CONFIG_DELAYED_AUTO_START_INFO, {})",
+                        self.service_name, delayed
+                    )
+                }) {
+                    return Ok(());
+                }
+                service.change_delayed_auto_start(delayed)?;
+                self.delayed_auto_start = delayed;
+                Ok(())
+            },
+        )
+    }
+
+    /// Adds the installed service to the failover cluster group `group` as a Generic Service
+    /// resource named after the service, with the `FailoverClusters` PowerShell module. The
+    /// service must be installed with the `SERVICE_DEMAND_START` start type on every node of
+    /// the cluster, which starts and stops it on the node owning the group.
+    pub fn add_to_cluster(&mut self, group: &str) -> Result<(), Error> {
+        in_operation(
+            "add-to-cluster",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || {
+                let script = format!(
+                    "Add-ClusterResource -Name {name} -Group {} -ResourceType 'Generic Service' | \
+                 Set-ClusterParameter -Name ServiceName -Value {name}",
+                    powershell_quote(group),
+                    name = powershell_quote(&self.service_name),
+                );
+                if dry_run::skip(|| format!("powershell {}", script)) {
+                    return Ok(());
+                }
+                powershell(&script)?;
+                self.cluster_group = Some(group.to_string());
+                Ok(())
+            },
+        )
+    }
+
+    /// Removes the Generic Service resource added by `add_to_cluster()` from its group.
+    pub fn remove_from_cluster(&mut self) -> Result<(), Error> {
+        in_operation(
+            "remove-from-cluster",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || {
+                let script = format!(
+                    "Remove-ClusterResource -Name {} -Force",
+                    powershell_quote(&self.service_name)
+                );
+                if dry_run::skip(|| format!("powershell {}", script)) {
+                    return Ok(());
+                }
+                powershell(&script)?;
+                self.cluster_group = None;
+                Ok(())
+            },
+        )
+    }
+
+    /// Changes the display name of the installed service.
+    pub fn set_display_name(&mut self, display_name: &str) -> Result<(), Error> {
+        in_operation(
+            "set-display-name",
+            self.dry_run,
+            audit::target(&self.audit_log, &self.service_name),
+            || {
+                let service_manager = ServiceControlManager::open(
+                    self.machine_name.as_deref(),
+                    SC_MANAGER_ALL_ACCESS,
+                )?;
+                let service = self.open_service(&service_manager, SERVICE_ALL_ACCESS)?;
+                if dry_run::skip(|| {
+                    format!(
+                        "ChangeServiceConfigW({}, lpDisplayName {:?})",
+                        self.service_name, display_name
+                    )
+                }) {
+                    return Ok(());
+                }
+                service.change_display_name(display_name)?;
+                self.display_name = display_name.to_string().into();
+                Ok(())
+            },
+        )
+    }
+
+    /// Register the `service_main_wrapper` function, this function is generated by the `Service!` macro.
+    #[cfg(feature = "runtime")]
+    pub fn register(
+        &mut self,
+        service_main_wrapper: WindowsServiceMainWrapperFn,
+    ) -> Result<(), Error> {
+        if let Some(backend) = &self.backend {
+            backend.register(&self.service_name)?;
+        }
+        self.set_runtime_options();
+        deadline::set_stop_deadline(self.stop_deadline);
+        *MINIDUMP_QUOTA.lock().unwrap() = self.minidump_quota;
+
+        unsafe {
+            let service_name = get_utf16(&self.service_name);
+
+            let service_table: &[*const SERVICE_TABLE_ENTRYW] = &[
+                &SERVICE_TABLE_ENTRYW {
+                    lpServiceName: service_name.as_ptr(),
+                    lpServiceProc: Some(service_main_wrapper),
+                },
+                ptr::null(),
+            ];
+
+            match traced!(StartServiceCtrlDispatcherW(*service_table.as_ptr())) {
+                0 => Err(last_error("StartServiceCtrlDispatcherW")),
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+impl WindowsController {
+    /// Applies the runtime options of the controller to the service of a DLL started by its
+    /// host process, which calls `ServiceMain` instead of `register()`. Called by the
+    /// `ServiceDll!` macro. `stop_deadline` and `minidump_quota` are ignored: exiting or
+    /// handling the crashes of the host process would affect the other services it hosts.
+    /// The startup gate and timeout, cluster group, idle threshold and display events apply
+    /// to the service named like the controller, the other options to every service of the
+    /// DLL in the process.
+    #[cfg(feature = "runtime")]
+    pub fn register_hosted(&self) {
+        if self.stop_deadline.is_some() || self.minidump_quota.is_some() {
+            warn!("The stop deadline and the minidumps aren't supported in a shared process");
+        }
+        self.set_runtime_options();
+    }
+
+    #[cfg(feature = "runtime")]
+    fn set_runtime_options(&self) {
+        channel::set_event_policy(self.event_policy);
+        logging::set_log_config(self.log_config);
+        stop::set_grace_period(self.stop_grace_period);
+        instance::set_single_instance(self.single_instance);
+        RUNTIME_OPTIONS.lock().unwrap().insert(
+            self.service_name.to_string(),
+            RuntimeOptions {
+                startup_timeout: self.startup_timeout,
+                startup_gate: self.startup_gate.clone(),
+                cluster_group: self.cluster_group.clone(),
+                #[cfg(feature = "session-events")]
+                idle_threshold: self.idle_threshold,
+                display_events: self.display_events,
+            },
+        );
+        #[cfg(feature = "install")]
+        run_once::set_run_once(self.run_once, self.clone(), Self::finish_run_once);
+        pause::set_acknowledged(self.acknowledge_pause);
+    }
+}
+
+/// Runtime options of a service, set by `register()` or `register_hosted()` for the dispatcher
+/// of the service.
+#[derive(Clone, Default)]
+struct RuntimeOptions {
+    startup_timeout: Option<time::Duration>,
+    startup_gate: Option<StartupGate>,
+    cluster_group: Option<String>,
+    #[cfg(feature = "session-events")]
+    idle_threshold: Option<time::Duration>,
+    display_events: bool,
+}
+
+/// Options of the service launched as `launched_name`, or of `name` given to the macro when
+/// the controller that registered them is named like the macro and not like the installed
+/// service.
+fn runtime_options(launched_name: &str, name: &str) -> RuntimeOptions {
+    let options = RUNTIME_OPTIONS.lock().unwrap();
+    options
+        .get(launched_name)
+        .or_else(|| options.get(name))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Reports the status of a service to the SCM. Each service of a process has its own handle,
+/// service type and checkpoint, the clones share them.
+#[derive(Clone)]
+struct StatusReporter {
+    inner: Arc<ReporterState>,
+}
+
+struct ReporterState {
+    // Set once the control handler is registered, before the first report.
+    handle: AtomicPtr<SERVICE_STATUS_HANDLE__>,
+    service_type: DWORD,
+    checkpoint: AtomicU32,
+}
+
+impl StatusReporter {
+    /// Reporter of a service of a process of its own, or of a process shared with other
+    /// services when `hosted`.
+    fn new(hosted: bool) -> StatusReporter {
+        StatusReporter {
+            inner: Arc::new(ReporterState {
+                handle: AtomicPtr::new(ptr::null_mut()),
+                service_type: if hosted {
+                    SERVICE_WIN32_SHARE_PROCESS
+                } else {
+                    SERVICE_WIN32_OWN_PROCESS
+                },
+                checkpoint: AtomicU32::new(0),
+            }),
+        }
+    }
+
+    fn set_handle(&self, handle: SERVICE_STATUS_HANDLE) {
+        self.inner.handle.store(handle, Ordering::SeqCst);
+    }
+
+    fn set(&self, current_state: DWORD, wait_hint: DWORD) {
+        self.report(current_state, wait_hint, 0, 0);
+    }
+
+    /// Reports the service as stopped. A non-zero `exit_code` returned by the service main
+    /// function is reported as a service specific error.
+    fn stopped(&self, exit_code: u32) {
+        if exit_code == 0 {
+            self.report(SERVICE_STOPPED, 0, NO_ERROR, 0);
+        } else {
+            self.report(SERVICE_STOPPED, 0, ERROR_SERVICE_SPECIFIC_ERROR, exit_code);
+        }
+    }
+
+    fn report(
+        &self,
+        current_state: DWORD,
+        wait_hint: DWORD,
+        win32_exit_code: DWORD,
+        service_exit_code: DWORD,
+    ) {
+        let mut controls_accepted = SERVICE_ACCEPT_STOP
+            | SERVICE_ACCEPT_SHUTDOWN
+            | SERVICE_ACCEPT_PAUSE_CONTINUE
+            | SERVICE_ACCEPT_NETBINDCHANGE
+            | SERVICE_ACCEPT_POWEREVENT;
+        if cfg!(feature = "session-events") && !is_headless_sku() && !is_compatibility_runtime() {
+            controls_accepted |= SERVICE_ACCEPT_SESSIONCHANGE;
+        }
+        if !is_compatibility_runtime() {
+            controls_accepted |= SERVICE_ACCEPT_LOWRESOURCES | SERVICE_ACCEPT_SYSTEMLOWRESOURCES;
+        }
+        if stop::grace_period().is_some() {
+            controls_accepted |= SERVICE_ACCEPT_PRESHUTDOWN;
+        }
+
+        // The checkpoint must grow with each report of a pending state for the SCM to
+        // consider that the service makes progress.
+        let checkpoint = match current_state {
+            SERVICE_START_PENDING
+            | SERVICE_STOP_PENDING
+            | SERVICE_PAUSE_PENDING
+            | SERVICE_CONTINUE_PENDING => self.inner.checkpoint.fetch_add(1, Ordering::SeqCst) + 1,
+            _ => {
+                self.inner.checkpoint.store(0, Ordering::SeqCst);
+                0
+            }
+        };
+
+        let mut service_status = SERVICE_STATUS {
+            dwServiceType: self.inner.service_type,
+            dwCurrentState: current_state,
+            dwControlsAccepted: controls_accepted,
+            dwWin32ExitCode: win32_exit_code,
+            dwServiceSpecificExitCode: service_exit_code,
+            dwCheckPoint: checkpoint,
+            dwWaitHint: wait_hint,
+        };
+        unsafe {
+            traced!(SetServiceStatus(
+                self.inner.handle.load(Ordering::SeqCst),
+                &mut service_status
+            ));
+        }
+    }
+}
+
+/// Context of the control handler of a service.
+struct ControlHandler<T> {
+    tx: EventSender<T>,
+    status: StatusReporter,
+}
+
+/// Wait hint of the start pending status in milliseconds, derived from the `startup_timeout`
+/// declared on the controller.
+fn startup_wait_hint(startup_timeout: Option<time::Duration>) -> DWORD {
+    startup_timeout.map_or(0, |timeout| {
+        timeout.as_millis().min(DWORD::MAX as u128) as DWORD
+    })
+}
+
+/// Reports the service as running once `gate` opens, from a thread so that the service main
+/// function runs meanwhile. The wait stops when the service stops first.
+fn wait_startup_gate<T: Send + 'static>(
+    gate: StartupGate,
+    timeout: Option<time::Duration>,
+    status: StatusReporter,
+    tx: EventSender<T>,
+) {
+    thread::spawn(move || {
+        let stopping =
+            || shutdown::service_token().is_shutdown() || deadline::has_service_main_returned();
+        if gate.wait(timeout, stopping, || {
+            status.set(SERVICE_START_PENDING, startup_wait_hint(timeout))
+        }) {
+            status.set(SERVICE_RUNNING, 0);
+        } else if !stopping() {
+            error!("The startup gate is still closed after the startup timeout, stopping");
+            tx.send(ServiceEvent::Stop {
+                reason: Some(StopReason::StartupTimeout),
+            });
+        }
+    });
+}
+
+/// Watches the failover cluster group `group` on a thread, sending `ClusterFailover` when the
+/// group stops being online on this node.
+fn watch_cluster_group<T: Send + 'static>(group: String, tx: EventSender<T>) {
+    thread::spawn(move || {
+        if let Err(e) = unsafe { wait_cluster_failover(&group, &tx) } {
+            warn!("Failed to watch the cluster group {}: {}", group, e);
+        }
+    });
+}
+
+/// Sends `SessionIdle` and `SessionActive` as the sessions cross `threshold`, until the
+/// service stops.
+#[cfg(feature = "session-events")]
+fn watch_idle_sessions<T: Send + 'static>(threshold: time::Duration, tx: EventSender<T>) {
+    thread::spawn(move || {
+        let mut idle = std::collections::HashSet::new();
+        while !shutdown::service_token().is_shutdown() && !deadline::has_service_main_returned() {
+            let idle_times = match crate::session::idle_times() {
+                Ok(idle_times) => idle_times,
+                Err(e) => {
+                    warn!("Failed to query the idle time of the sessions: {}", e);
+                    return;
+                }
+            };
+            for &(id, idle_time) in &idle_times {
+                if idle_time >= threshold {
+                    if idle.insert(id) {
+                        tx.send(ServiceEvent::SessionIdle(Session::new(id)));
+                    }
+                } else if idle.remove(&id) {
+                    tx.send(ServiceEvent::SessionActive(Session::new(id)));
+                }
+            }
+            // A session that ended or was disconnected is no longer idle.
+            idle.retain(|id| idle_times.iter().any(|(active, _)| active == id));
+            thread::sleep(IDLE_POLL_INTERVAL.min(threshold));
+        }
+    });
+}
+
+/// Sends `DisplayChange` for the `WM_DISPLAYCHANGE` messages received by a hidden window,
+/// until the service stops. Message-only windows don't receive the broadcast messages, the
+/// window is a top-level window that is never shown.
+fn watch_display_changes<T: Send + 'static>(tx: EventSender<T>) {
+    thread::spawn(move || unsafe {
+        let class_name = get_utf16("ceviche_display");
+        let class = WNDCLASSEXW {
+            cbSize: mem::size_of::<WNDCLASSEXW>() as UINT,
+            lpfnWndProc: Some(display_window_proc::<T>),
+            hInstance: GetModuleHandleW(ptr::null()),
+            lpszClassName: class_name.as_ptr(),
+            ..mem::zeroed()
+        };
+        if traced!(RegisterClassExW(&class)) == 0 {
+            warn!(
+                "Failed to watch the display changes: {}",
+                last_error("RegisterClassExW")
+            );
+            return;
+        }
+        let window = traced!(CreateWindowExW(
+            0,
+            class_name.as_ptr(),
+            class_name.as_ptr(),
+            0,
+            0,
+            0,
+            0,
+            0,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            class.hInstance,
+            ptr::null_mut(),
+        ));
+        if window.is_null() {
+            warn!(
+                "Failed to watch the display changes: {}",
+                last_error("CreateWindowExW")
+            );
+            return;
+        }
+        let sender = Box::into_raw(Box::new(tx));
+        SetWindowLongPtrW(window, GWLP_USERDATA, sender as _);
+
+        // The queue is checked every second for the end of the service.
+        while !shutdown::service_token().is_shutdown() && !deadline::has_service_main_returned() {
+            MsgWaitForMultipleObjects(0, ptr::null(), FALSE, 1000, QS_ALLINPUT);
+            let mut message: MSG = mem::zeroed();
+            while PeekMessageW(&mut message, ptr::null_mut(), 0, 0, PM_REMOVE) != 0 {
+                TranslateMessage(&message);
+                DispatchMessageW(&message);
+            }
+        }
+        SetWindowLongPtrW(window, GWLP_USERDATA, 0);
+        DestroyWindow(window);
+        drop(Box::from_raw(sender));
+    });
+}
+
+unsafe extern "system" fn display_window_proc<T: Send + 'static>(
+    window: HWND,
+    message: UINT,
+    wparam: WPARAM,
+    lparam: LPARAM,
+) -> LRESULT {
+    if message == WM_DISPLAYCHANGE {
+        let sender = GetWindowLongPtrW(window, GWLP_USERDATA) as *const EventSender<T>;
+        if let Some(tx) = sender.as_ref() {
+            tx.send(ServiceEvent::DisplayChange {
+                width: LOWORD(lparam as DWORD) as u32,
+                height: HIWORD(lparam as DWORD) as u32,
+                bits_per_pixel: wparam as u32,
+            });
+        }
+        return 0;
+    }
+    DefWindowProcW(window, message, wparam, lparam)
+}
+
+unsafe fn wait_cluster_failover<T>(group: &str, tx: &EventSender<T>) -> Result<(), Error> {
+    let cluster = traced!(OpenCluster(ptr::null()));
+    if cluster.is_null() {
+        return Err(last_error("OpenCluster"));
+    }
+    let group_name = get_utf16(group);
+    let group_handle = traced!(OpenClusterGroup(cluster, group_name.as_ptr()));
+    if group_handle.is_null() {
+        let e = last_error("OpenClusterGroup");
+        CloseCluster(cluster);
+        return Err(e);
+    }
+    let port = traced!(CreateClusterNotifyPort(
+        INVALID_HANDLE_VALUE,
+        cluster,
+        CLUSTER_CHANGE_GROUP_STATE,
+        0
+    ));
+    if port.is_null() {
+        let e = last_error("CreateClusterNotifyPort");
+        CloseClusterGroup(group_handle);
+        CloseCluster(cluster);
+        return Err(e);
+    }
+
+    let local_node = local_node_name();
+    // The group is pending while the service starts, only a group seen online fails over.
+    let mut online = false;
+    let result = loop {
+        if shutdown::service_token().is_shutdown() || deadline::has_service_main_returned() {
+            break Ok(());
+        }
+        let mut node = [0u16; 256];
+        let mut node_length = node.len() as DWORD;
+        let state = GetClusterGroupState(group_handle, node.as_mut_ptr(), &mut node_length);
+        let owner = String::from_utf16_lossy(&node[..node_length as usize]);
+        match state {
+            CLUSTER_GROUP_STATE_UNKNOWN => (),
+            CLUSTER_GROUP_ONLINE | CLUSTER_GROUP_PARTIAL_ONLINE
+                if owner.eq_ignore_ascii_case(&local_node) =>
+            {
+                online = true
+            }
+            _ if online => {
+                online = false;
+                info!("Cluster group {} is failing over", group);
+                tx.send(ServiceEvent::ClusterFailover {
+                    group: group.to_string(),
+                });
+            }
+            _ => (),
+        }
+
+        // Group changes wake the loop, the timeout lets it notice the stop of the service.
+        let mut key = 0;
+        let mut filter = 0;
+        let mut name = [0u16; 256];
+        let mut name_length = name.len() as DWORD;
+        match traced!(GetClusterNotify(
+            port,
+            &mut key,
+            &mut filter,
+            name.as_mut_ptr(),
+            &mut name_length,
+            1000
+        )) {
+            ERROR_SUCCESS | WAIT_TIMEOUT | ERROR_MORE_DATA => (),
+            code => {
+                break Err(Error::os(
+                    "GetClusterNotify",
+                    code as i32,
+                    &format_error_message(code),
+                ))
+            }
+        }
+    };
+    CloseClusterNotifyPort(port);
+    CloseClusterGroup(group_handle);
+    CloseCluster(cluster);
+    result
+}
+
+/// NetBIOS name of the computer, the name of its node in the cluster.
+fn local_node_name() -> String {
+    let mut name = [0u16; 256];
+    let mut length = name.len() as DWORD;
+    if unsafe { GetComputerNameExW(ComputerNamePhysicalNetBIOS, name.as_mut_ptr(), &mut length) }
+        == 0
+    {
+        return String::new();
+    }
+    String::from_utf16_lossy(&name[..length as usize])
+}
+
+/// Wait hint of the stop pending status in milliseconds: the stop grace period of a two-phase
+/// stop, otherwise the stop deadline when it is set.
+fn stop_wait_hint() -> DWORD {
+    stop::grace_period()
+        .or_else(deadline::stop_deadline)
+        .map_or(10, |duration| {
+            duration.as_millis().min(DWORD::MAX as u128) as DWORD
+        })
+}
+
+/// Reports the progress of a two-phase stop: a stop pending status with the remaining time as
+/// wait hint, or running again when the service cancelled the stop.
+fn report_stop_progress(status: &StatusReporter, remaining: Option<time::Duration>) {
+    match remaining {
+        Some(remaining) => status.set(
+            SERVICE_STOP_PENDING,
+            remaining.as_millis().min(DWORD::MAX as u128) as DWORD,
+        ),
+        None => status.set(SERVICE_RUNNING, 0),
+    }
+}
+
+/// Reports the completion of a pause or continue acknowledged by the service.
+fn report_pause_completed(status: &StatusReporter, paused: bool) {
+    let state = if paused {
+        SERVICE_PAUSED
+    } else {
+        SERVICE_RUNNING
+    };
+    status.set(state, 0);
+}
+
+unsafe extern "system" fn service_handler<T: Send + 'static>(
+    control: DWORD,
+    event_type: DWORD,
+    event_data: LPVOID,
+    context: LPVOID,
+) -> DWORD {
+    // Unwinding across the SCM callback is undefined behavior, panics are reported
+    // to the SCM as an exception in the service instead.
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        handle_control::<T>(control, event_type, event_data, context)
+    }))
+    .unwrap_or_else(|payload| {
+        error!(
+            "Service control handler panicked: {}",
+            panic_message(payload.as_ref())
+        );
+        ERROR_EXCEPTION_IN_SERVICE
+    })
+}
+
+// `event_data` is only used by the session change notifications.
+#[cfg_attr(not(feature = "session-events"), allow(unused_variables))]
+unsafe fn handle_control<T: Send + 'static>(
+    control: DWORD,
+    event_type: DWORD,
+    event_data: LPVOID,
+    context: LPVOID,
+) -> DWORD {
+    let handler = &*(context as *const ControlHandler<T>);
+    let tx = &handler.tx;
+    let status = &handler.status;
+
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_PRESHUTDOWN => {
+            status.set(SERVICE_STOP_PENDING, stop_wait_hint());
+            let reason = if control == SERVICE_CONTROL_PRESHUTDOWN {
+                StopReason::SystemShutdown
+            } else {
+                StopReason::Requested
+            };
+            stop::request(tx, reason);
+            0
+        }
+        SERVICE_CONTROL_SHUTDOWN => {
+            status.set(SERVICE_STOP_PENDING, stop_wait_hint());
+            stop::stop_now(tx, StopReason::SystemShutdown);
+            0
+        }
+        SERVICE_CONTROL_PAUSE => {
+            if pause::is_acknowledged() {
+                status.set(SERVICE_PAUSE_PENDING, PAUSE_WAIT_HINT);
+            }
+            tx.send(ServiceEvent::Pause);
+            if !pause::is_acknowledged() {
+                status.set(SERVICE_PAUSED, 0);
+            }
+            0
+        }
+        SERVICE_CONTROL_CONTINUE => {
+            if pause::is_acknowledged() {
+                status.set(SERVICE_CONTINUE_PENDING, PAUSE_WAIT_HINT);
+            }
+            tx.send(ServiceEvent::Continue);
+            if !pause::is_acknowledged() {
+                status.set(SERVICE_RUNNING, 0);
+            }
+            0
+        }
+        SERVICE_CONTROL_NETBINDADD => {
+            tx.send(ServiceEvent::NetBindChange(NetBindChange::Add));
+            0
+        }
+        SERVICE_CONTROL_NETBINDREMOVE => {
+            tx.send(ServiceEvent::NetBindChange(NetBindChange::Remove));
+            0
+        }
+        SERVICE_CONTROL_NETBINDENABLE => {
+            tx.send(ServiceEvent::NetBindChange(NetBindChange::Enable));
+            0
+        }
+        SERVICE_CONTROL_NETBINDDISABLE => {
+            tx.send(ServiceEvent::NetBindChange(NetBindChange::Disable));
+            0
+        }
+        SERVICE_CONTROL_LOWRESOURCES => {
+            tx.send(ServiceEvent::LowResources);
+            0
+        }
+        SERVICE_CONTROL_SYSTEMLOWRESOURCES => {
+            tx.send(ServiceEvent::SystemLowResources);
+            0
+        }
+        SERVICE_CONTROL_POWEREVENT => {
+            match event_type as usize {
+                PBT_APMSUSPEND => tx.send(ServiceEvent::PowerEvent(PowerEvent::Suspend)),
+                // Sent on every wake up, `PBT_APMRESUMESUSPEND` only follows user activity.
+                PBT_APMRESUMEAUTOMATIC => tx.send(ServiceEvent::PowerEvent(PowerEvent::Resume)),
+                _ => (),
+            }
+            0
+        }
+        #[cfg(feature = "session-events")]
+        SERVICE_CONTROL_SESSIONCHANGE => {
+            let event = event_type as usize;
+            let session_notification = event_data as PWTSSESSION_NOTIFICATION;
+            let session_id = (*session_notification).dwSessionId;
+            let session = Session::new(session_id);
+
+            if event == WTS_CONSOLE_CONNECT {
+                tx.send(ServiceEvent::SessionConnect(session));
+                0
+            } else if event == WTS_CONSOLE_DISCONNECT {
+                tx.send(ServiceEvent::SessionDisconnect(session));
+                0
+            } else if event == WTS_REMOTE_CONNECT {
+                tx.send(ServiceEvent::SessionRemoteConnect(session));
+                0
+            } else if event == WTS_REMOTE_DISCONNECT {
+                tx.send(ServiceEvent::SessionRemoteDisconnect(session));
+                0
+            } else if event == WTS_SESSION_LOGON {
+                tx.send(ServiceEvent::SessionLogon(session));
+                0
+            } else if event == WTS_SESSION_LOGOFF {
+                tx.send(ServiceEvent::SessionLogoff(session));
+                0
+            } else if event == WTS_SESSION_LOCK {
+                tx.send(ServiceEvent::SessionLock(session));
+                0
+            } else if event == WTS_SESSION_UNLOCK {
+                tx.send(ServiceEvent::SessionUnlock(session));
+                0
+            } else {
+                0
+            }
+        }
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+/// Returns true on Nano Server and Server Core installations. These SKUs have no interactive
+/// desktop sessions, the dispatcher doesn't register for session change notifications there.
+/// The controller itself only relies on SCM APIs that are available on every SKU.
+pub fn is_headless_sku() -> bool {
+    static HEADLESS: OnceLock<bool> = OnceLock::new();
+    *HEADLESS.get_or_init(|| {
+        let server_level = |name: &str| -> bool {
+            let mut value: DWORD = 0;
+            let mut size = mem::size_of::<DWORD>() as DWORD;
+            let status = unsafe {
+                traced!(RegGetValueW(
+                    HKEY_LOCAL_MACHINE,
+                    get_utf16(
+                        "SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion\\Server\\ServerLevels",
+                    )
+                    .as_ptr(),
+                    get_utf16(name).as_ptr(),
+                    RRF_RT_REG_DWORD | RRF_SUBKEY_WOW6464KEY,
+                    ptr::null_mut(),
+                    &mut value as *mut _ as PVOID,
+                    &mut size,
+                ))
+            };
+            status == ERROR_SUCCESS as LONG && value == 1
+        };
+
+        server_level("NanoServer")
+            || (server_level("ServerCore") && !server_level("Server-Gui-Shell"))
+    })
+}
+
+/// Returns true when running under Wine.
+pub fn is_wine() -> bool {
+    static WINE: OnceLock<bool> = OnceLock::new();
+    *WINE.get_or_init(|| unsafe {
+        let ntdll = GetModuleHandleW(get_utf16("ntdll.dll").as_ptr());
+        !ntdll.is_null()
+            && !GetProcAddress(ntdll, b"wine_get_version\0".as_ptr() as *const _).is_null()
+    })
+}
+
+/// Returns true when running on ReactOS.
+pub fn is_reactos() -> bool {
+    static REACTOS: OnceLock<bool> = OnceLock::new();
+    *REACTOS.get_or_init(|| {
+        let mut product_name = [0u16; 256];
+        let mut size = mem::size_of_val(&product_name) as DWORD;
+        let status = unsafe {
+            traced!(RegGetValueW(
+                HKEY_LOCAL_MACHINE,
+                get_utf16("SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion").as_ptr(),
+                get_utf16("ProductName").as_ptr(),
+                RRF_RT_REG_SZ | RRF_SUBKEY_WOW6464KEY,
+                ptr::null_mut(),
+                product_name.as_mut_ptr() as PVOID,
+                &mut size,
+            ))
+        };
+        status == ERROR_SUCCESS as LONG
+            && String::from_utf16_lossy(&product_name).starts_with("ReactOS")
+    })
+}
+
+/// Processor architecture of an executable or of the system.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryArch {
+    X86,
+    X64,
+    Arm,
+    Arm64,
+    /// Another `IMAGE_FILE_MACHINE_*` machine type.
+    Other(u16),
+}
+
+impl BinaryArch {
+    fn from_machine(machine: u16) -> BinaryArch {
+        match machine {
+            IMAGE_FILE_MACHINE_I386 => BinaryArch::X86,
+            IMAGE_FILE_MACHINE_AMD64 => BinaryArch::X64,
+            IMAGE_FILE_MACHINE_ARMNT => BinaryArch::Arm,
+            IMAGE_FILE_MACHINE_ARM64 => BinaryArch::Arm64,
+            machine => BinaryArch::Other(machine),
+        }
+    }
+
+    /// Architecture the current executable was built for.
+    pub fn current() -> BinaryArch {
+        if cfg!(target_arch = "x86") {
+            BinaryArch::X86
+        } else if cfg!(target_arch = "x86_64") {
+            BinaryArch::X64
+        } else if cfg!(target_arch = "arm") {
+            BinaryArch::Arm
+        } else if cfg!(target_arch = "aarch64") {
+            BinaryArch::Arm64
+        } else {
+            BinaryArch::Other(IMAGE_FILE_MACHINE_UNKNOWN)
+        }
+    }
+
+    /// Native architecture of the system, which differs from `current()` when the executable
+    /// runs under WOW64 or x64 emulation on ARM64.
+    pub fn native() -> BinaryArch {
+        static NATIVE: OnceLock<BinaryArch> = OnceLock::new();
+        *NATIVE.get_or_init(|| unsafe {
+            // IsWow64Process2 is only available from Windows 10 1709, ARM64 systems always
+            // have it and GetNativeSystemInfo is reliable on the other systems.
+            type IsWow64Process2Fn = unsafe extern "system" fn(HANDLE, *mut u16, *mut u16) -> BOOL;
+            let kernel32 = GetModuleHandleW(get_utf16("kernel32.dll").as_ptr());
+            let function = if kernel32.is_null() {
+                ptr::null_mut()
+            } else {
+                GetProcAddress(kernel32, b"IsWow64Process2\0".as_ptr() as *const _)
+            };
+            if !function.is_null() {
+                let is_wow64_process2: IsWow64Process2Fn = mem::transmute(function);
+                let mut process_machine = 0;
+                let mut native_machine = 0;
+                if is_wow64_process2(
+                    GetCurrentProcess(),
+                    &mut process_machine,
+                    &mut native_machine,
+                ) != 0
+                {
+                    return BinaryArch::from_machine(native_machine);
+                }
+            }
+
+            let mut info: SYSTEM_INFO = mem::zeroed();
+            GetNativeSystemInfo(&mut info);
+            match info.u.s().wProcessorArchitecture {
+                PROCESSOR_ARCHITECTURE_INTEL => BinaryArch::X86,
+                PROCESSOR_ARCHITECTURE_AMD64 => BinaryArch::X64,
+                PROCESSOR_ARCHITECTURE_ARM => BinaryArch::Arm,
+                PROCESSOR_ARCHITECTURE_ARM64 => BinaryArch::Arm64,
+                _ => BinaryArch::Other(IMAGE_FILE_MACHINE_UNKNOWN),
+            }
+        })
+    }
+
+    /// Architecture of the executable at `path`, read from the machine type of its PE header.
+    /// Paths in the system directory are resolved as the SCM sees them, see `native_path()`.
+    pub fn of_file(path: &str) -> Result<BinaryArch, Error> {
+        let path = native_path(path);
+        let read_machine = || -> std::io::Result<u16> {
+            let mut file = File::open(&path)?;
+            let mut dos_header = [0u8; 0x40];
+            file.read_exact(&mut dos_header)?;
+            let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "not a PE file");
+            if &dos_header[..2] != b"MZ" {
+                return Err(invalid());
+            }
+            let pe_offset = u32::from_le_bytes([
+                dos_header[0x3c],
+                dos_header[0x3d],
+                dos_header[0x3e],
+                dos_header[0x3f],
+            ]);
+            let mut pe_header = [0u8; 6];
+            file.seek(SeekFrom::Start(pe_offset as u64))?;
+            file.read_exact(&mut pe_header)?;
+            if &pe_header[..4] != b"PE\0\0" {
+                return Err(invalid());
+            }
+            Ok(u16::from_le_bytes([pe_header[4], pe_header[5]]))
+        };
+        read_machine()
+            .map(BinaryArch::from_machine)
+            .map_err(|e| Error::io(&format!("Failed to read {}", path.display()), &e))
+    }
+
+    /// True when executables of this architecture run on a system of the `native`
+    /// architecture. ARM64 systems emulate x86 and x64.
+    pub fn runs_on(self, native: BinaryArch) -> bool {
+        match native {
+            BinaryArch::X64 => matches!(self, BinaryArch::X86 | BinaryArch::X64),
+            BinaryArch::Arm64 => matches!(
+                self,
+                BinaryArch::X86 | BinaryArch::X64 | BinaryArch::Arm | BinaryArch::Arm64
+            ),
+            native => self == native,
+        }
+    }
+}
+
+/// Path under which the current process sees the file that the SCM and the other native
+/// processes see at `path`. The system directory of 32-bit processes on 64-bit Windows is
+/// redirected to `SysWOW64`, its native content is reached through `Sysnative`. Other paths
+/// are left as is, the binary path registered with the SCM keeps using `System32`.
+pub fn native_path(path: &str) -> PathBuf {
+    let redirected =
+        BinaryArch::current() == BinaryArch::X86 && BinaryArch::native() != BinaryArch::X86;
+    let windows_dir = env::var("SystemRoot").unwrap_or_else(|_| "C:\\Windows".to_string());
+    let system_dir = format!("{}\\System32\\", windows_dir);
+    match path.get(..system_dir.len()) {
+        Some(prefix) if redirected && prefix.eq_ignore_ascii_case(&system_dir) => {
+            Path::new(&windows_dir)
+                .join("Sysnative")
+                .join(&path[system_dir.len()..])
+        }
+        _ => PathBuf::from(path),
+    }
+}
+
+fn is_compatibility_runtime() -> bool {
+    is_wine() || is_reactos()
+}
+
+/// Wine and ReactOS don't implement every service configuration level, failures of optional
+/// configuration steps are logged there instead of failing the whole operation.
+fn compatibility_fallback(result: Result<(), Error>, feature: &str) -> Result<(), Error> {
+    match result {
+        Err(e) if is_compatibility_runtime() => {
+            warn!("{} is not supported by this runtime: {}", feature, e);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+fn parameters_key(service_name: &str) -> String {
+    format!(
+        "SYSTEM\\CurrentControlSet\\Services\\{}\\Parameters",
+        service_name
+    )
+}
+
+/// Name of a `SERVICE_*` state reported in the `ServiceStatus` of the service.
+fn state_name(state: DWORD) -> &'static str {
+    match state {
+        SERVICE_STOPPED => "stopped",
+        SERVICE_START_PENDING => "start pending",
+        SERVICE_STOP_PENDING => "stop pending",
+        SERVICE_RUNNING => "running",
+        SERVICE_CONTINUE_PENDING => "continue pending",
+        SERVICE_PAUSE_PENDING => "pause pending",
+        SERVICE_PAUSED => "paused",
+        _ => "unknown",
+    }
+}
+
+// Runs as an APC of the subscription thread while it waits in `SleepEx`.
+unsafe extern "system" fn status_notified(parameter: PVOID) {
+    let notify = &*(parameter as *const SERVICE_NOTIFY_2W);
+    (*(notify.pContext as *const AtomicBool)).store(true, Ordering::SeqCst);
+}
+
+/// Sends the current status of the service then its status after each state change, until
+/// the subscription is dropped. A notification must be requested again once it is delivered.
+fn notify_status_changes(
+    machine_name: Option<&str>,
+    service_name: &str,
+    sender: &StatusSender,
+) -> Result<(), Error> {
+    // Declared before the service handle, which cancels the pending notification when it is
+    // closed, so that the buffer outlives it.
+    let notified = AtomicBool::new(false);
+    let mut notify: SERVICE_NOTIFY_2W = unsafe { mem::zeroed() };
+
+    let service_manager = ServiceControlManager::open(machine_name, SC_MANAGER_CONNECT)?;
+    let service = service_manager.open_service(service_name, SERVICE_QUERY_STATUS)?;
+    let mut state = service.query_status_process()?.dwCurrentState;
+    loop {
+        let status = ServiceStatus::new(
+            state_name(state),
+            load_start_record(machine_name, service_name),
+        );
+        if !sender.send(Ok(status)) {
+            return Ok(());
+        }
+
+        notify.dwVersion = SERVICE_NOTIFY_STATUS_CHANGE;
+        notify.pfnNotifyCallback = Some(status_notified);
+        notify.pContext = &notified as *const AtomicBool as PVOID;
+        let result = unsafe {
+            traced!(NotifyServiceStatusChangeW(
+                service.handle,
+                SERVICE_NOTIFY_STOPPED
+                    | SERVICE_NOTIFY_START_PENDING
+                    | SERVICE_NOTIFY_STOP_PENDING
+                    | SERVICE_NOTIFY_RUNNING
+                    | SERVICE_NOTIFY_CONTINUE_PENDING
+                    | SERVICE_NOTIFY_PAUSE_PENDING
+                    | SERVICE_NOTIFY_PAUSED,
+                &mut notify,
+            ))
+        };
+        if result != ERROR_SUCCESS {
+            return Err(Error::os(
+                "NotifyServiceStatusChangeW",
+                result as i32,
+                &format_error_message(result),
+            ));
+        }
+
+        while !notified.swap(false, Ordering::SeqCst) {
+            unsafe { SleepEx(250, TRUE) };
+            if sender.is_closed() {
+                return Ok(());
+            }
+        }
+        if notify.dwNotificationStatus != ERROR_SUCCESS {
+            let code = notify.dwNotificationStatus;
+            return Err(Error::os(
+                "NotifyServiceStatusChangeW",
+                code as i32,
+                &format_error_message(code),
+            ));
+        }
+        state = notify.ServiceStatus.dwCurrentState;
+    }
+}
+
+/// Reads the start record from the `Parameters` key of the service, on `machine_name` when set.
+fn load_start_record(machine_name: Option<&str>, service_name: &str) -> Option<StartRecord> {
+    unsafe {
+        let mut hive: HKEY = HKEY_LOCAL_MACHINE;
+        if let Some(machine_name) = machine_name {
+            let machine_name = get_utf16(machine_name);
+            if traced!(RegConnectRegistryW(
+                machine_name.as_ptr(),
+                HKEY_LOCAL_MACHINE,
+                &mut hive
+            )) != ERROR_SUCCESS as LONG
+            {
+                return None;
+            }
+        }
+
+        let key = get_utf16(&parameters_key(service_name));
+        let mut start_time: u64 = 0;
+        let mut start_time_size = mem::size_of::<u64>() as DWORD;
+        let mut start_count: DWORD = 0;
+        let mut start_count_size = mem::size_of::<DWORD>() as DWORD;
+        let found = traced!(RegGetValueW(
+            hive,
+            key.as_ptr(),
+            get_utf16("StartTime").as_ptr(),
+            RRF_RT_REG_QWORD,
+            ptr::null_mut(),
+            &mut start_time as *mut _ as PVOID,
+            &mut start_time_size,
+        )) == ERROR_SUCCESS as LONG
+            && traced!(RegGetValueW(
+                hive,
+                key.as_ptr(),
+                get_utf16("StartCount").as_ptr(),
+                RRF_RT_REG_DWORD,
+                ptr::null_mut(),
+                &mut start_count as *mut _ as PVOID,
+                &mut start_count_size,
+            )) == ERROR_SUCCESS as LONG;
+
+        if machine_name.is_some() {
+            traced!(RegCloseKey(hive));
+        }
+
+        found.then_some(StartRecord {
+            start_time,
+            start_count,
+        })
+    }
+}
+
+/// Updates the start record of the service, called by the dispatcher at startup.
+fn record_start(service_name: &str) {
+    let record = load_start_record(None, service_name)
+        .unwrap_or_default()
+        .next();
+    let key = get_utf16(&parameters_key(service_name));
+    let status = unsafe {
+        let status = traced!(RegSetKeyValueW(
+            HKEY_LOCAL_MACHINE,
+            key.as_ptr(),
+            get_utf16("StartTime").as_ptr(),
+            REG_QWORD,
+            &record.start_time as *const _ as LPCVOID,
+            mem::size_of::<u64>() as DWORD,
+        ));
+        if status != ERROR_SUCCESS as LONG {
+            status
+        } else {
+            traced!(RegSetKeyValueW(
+                HKEY_LOCAL_MACHINE,
+                key.as_ptr(),
+                get_utf16("StartCount").as_ptr(),
+                REG_DWORD,
+                &record.start_count as *const _ as LPCVOID,
+                mem::size_of::<DWORD>() as DWORD,
+            ))
+        }
+    };
+    if status != ERROR_SUCCESS as LONG {
+        warn!(
+            "Failed to record the service start: {}",
+            format_error_message(status as DWORD)
+        );
+    }
+}
+
+// Value of the `Parameters` key holding the environment of the service, `NAME=value` strings.
+const ENVIRONMENT: &str = "Environment";
+
+fn save_environment(
+    machine_name: Option<&str>,
+    service_name: &str,
+    environment: &[(String, String)],
+) -> Result<(), Error> {
+    let mut value = Vec::new();
+    for (name, variable) in environment {
+        if name.is_empty() || name.contains('=') {
+            return Err(Error::new(&format!(
+                "Invalid environment variable name \"{}\"",
+                name
+            )));
+        }
+        let entry = to_wide(
+            "environment variable",
+            &format!("{}={}", name, variable),
+            None,
+        )?;
+        value.extend(entry);
+    }
+    value.push(0);
+
+    unsafe {
+        let mut hive: HKEY = HKEY_LOCAL_MACHINE;
+        if let Some(machine_name) = machine_name {
+            let machine_name = get_utf16(machine_name);
+            let status = traced!(RegConnectRegistryW(
+                machine_name.as_ptr(),
+                HKEY_LOCAL_MACHINE,
+                &mut hive
+            ));
+            if status != ERROR_SUCCESS as LONG {
+                return Err(Error::os(
+                    "RegConnectRegistryW",
+                    status,
+                    &format_error_message(status as DWORD),
+                ));
+            }
+        }
+
+        let key = get_utf16(&parameters_key(service_name));
+        let status = traced!(RegSetKeyValueW(
+            hive,
+            key.as_ptr(),
+            get_utf16(ENVIRONMENT).as_ptr(),
+            REG_MULTI_SZ,
+            value.as_ptr() as LPCVOID,
+            (value.len() * mem::size_of::<u16>()) as DWORD,
+        ));
+
+        if machine_name.is_some() {
+            traced!(RegCloseKey(hive));
+        }
+
+        if status != ERROR_SUCCESS as LONG {
+            return Err(Error::os(
+                "RegSetKeyValueW",
+                status,
+                &format_error_message(status as DWORD),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Key of the svchost groups, each value lists the services a group hosts.
+const SVCHOST_KEY: &str = "SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion\\Svchost";
+
+fn svchost_command_line(group: &str) -> String {
+    format!("%SystemRoot%\\System32\\svchost.exe -k {}", group)
+}
+
+/// WiX identifier derived from `name`: letters, digits, underscores and periods, starting
+/// with a letter or an underscore.
+#[cfg(feature = "install")]
+fn wix_id(name: &str) -> String {
+    let id: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    match id.chars().next() {
+        Some('a'..='z' | 'A'..='Z' | '_') => id,
+        _ => format!("_{}", id),
+    }
+}
+
+/// Writes the registry configuration svchost loads the DLL of the service with, and adds the
+/// service to its svchost group.
+fn register_service_dll(
+    machine_name: Option<&str>,
+    service_name: &str,
+    dll: &ServiceDll,
+) -> Result<(), Error> {
+    let path = to_wide("service DLL path", &dll.path, None)?;
+    let service_main = get_utf16("ServiceMain");
+    let unload_on_stop = dll.unload_on_stop as DWORD;
+    let values: [(&str, DWORD, &[u8]); 3] = unsafe {
+        [
+            ("ServiceDll", REG_EXPAND_SZ, wide_bytes(&path)),
+            ("ServiceMain", REG_SZ, wide_bytes(&service_main)),
+            (
+                "ServiceDllUnloadOnStop",
+                REG_DWORD,
+                slice::from_raw_parts(
+                    &unload_on_stop as *const _ as *const u8,
+                    mem::size_of::<DWORD>(),
+                ),
+            ),
+        ]
+    };
+
+    unsafe {
+        let hive = connect_registry(machine_name)?;
+        let key = get_utf16(&parameters_key(service_name));
+        let mut status = ERROR_SUCCESS as LONG;
+        for (name, kind, data) in values {
+            status = traced!(RegSetKeyValueW(
+                hive,
+                key.as_ptr(),
+                get_utf16(name).as_ptr(),
+                kind,
+                data.as_ptr() as LPCVOID,
+                data.len() as DWORD,
+            ));
+            if status != ERROR_SUCCESS as LONG {
+                break;
+            }
+        }
+        if machine_name.is_some() {
+            traced!(RegCloseKey(hive));
+        }
+        if status != ERROR_SUCCESS as LONG {
+            return Err(Error::os(
+                "RegSetKeyValueW",
+                status,
+                &format_error_message(status as DWORD),
+            ));
+        }
+    }
+
+    match &dll.svchost_group {
+        Some(group) => update_svchost_group(machine_name, group, |names| {
+            if !names
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(service_name))
+            {
+                names.push(service_name.to_string());
+            }
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Applies `update` to the list of the services hosted by the svchost group `group`, the
+/// group is created when it doesn't exist.
+fn update_svchost_group(
+    machine_name: Option<&str>,
+    group: &str,
+    update: impl FnOnce(&mut Vec<String>),
+) -> Result<(), Error> {
+    unsafe {
+        let hive = connect_registry(machine_name)?;
+        let mut key: HKEY = ptr::null_mut();
+        // The key is under SOFTWARE, which WOW64 redirects for a 32-bit installer.
+        let status = traced!(RegCreateKeyExW(
+            hive,
+            get_utf16(SVCHOST_KEY).as_ptr(),
+            0,
+            ptr::null_mut(),
+            0,
+            KEY_QUERY_VALUE | KEY_SET_VALUE | KEY_WOW64_64KEY,
+            ptr::null_mut(),
+            &mut key,
+            ptr::null_mut(),
+        ));
+        if machine_name.is_some() {
+            traced!(RegCloseKey(hive));
+        }
+        if status != ERROR_SUCCESS as LONG {
+            return Err(Error::os(
+                "RegCreateKeyExW",
+                status,
+                &format_error_message(status as DWORD),
+            ));
+        }
+
+        let value_name = get_utf16(group);
+        let mut size: DWORD = 0;
+        let mut names = Vec::new();
+        let status = traced!(RegGetValueW(
+            key,
+            ptr::null(),
+            value_name.as_ptr(),
+            RRF_RT_REG_MULTI_SZ,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut size,
+        ));
+        let mut status = if status == ERROR_SUCCESS as LONG {
+            let mut value = vec![0u16; size as usize / mem::size_of::<u16>() + 1];
+            let status = traced!(RegGetValueW(
+                key,
+                ptr::null(),
+                value_name.as_ptr(),
+                RRF_RT_REG_MULTI_SZ,
+                ptr::null_mut(),
+                value.as_mut_ptr() as PVOID,
+                &mut size,
+            ));
+            names = value
+                .split(|c| *c == 0)
+                .filter(|name| !name.is_empty())
+                .map(String::from_utf16_lossy)
+                .collect();
+            status
+        } else if status == ERROR_FILE_NOT_FOUND as LONG {
+            ERROR_SUCCESS as LONG
+        } else {
+            status
+        };
+
+        if status == ERROR_SUCCESS as LONG {
+            update(&mut names);
+            let mut value: Vec<u16> = names.iter().flat_map(|name| get_utf16(name)).collect();
+            value.push(0);
+            status = traced!(RegSetValueExW(
+                key,
+                value_name.as_ptr(),
+                0,
+                REG_MULTI_SZ,
+                value.as_ptr() as *const u8,
+                (value.len() * mem::size_of::<u16>()) as DWORD,
+            ));
+        }
+        traced!(RegCloseKey(key));
+        if status != ERROR_SUCCESS as LONG {
+            return Err(Error::os(
+                "RegSetValueExW",
+                status,
+                &format_error_message(status as DWORD),
+            ));
+        }
+    }
+    Ok(())
+}
 
-            self.tag_id = tag_id;
+/// Registry of `machine_name`, the local one when `None`. A remote registry must be closed.
+unsafe fn connect_registry(machine_name: Option<&str>) -> Result<HKEY, Error> {
+    let mut hive: HKEY = HKEY_LOCAL_MACHINE;
+    if let Some(machine_name) = machine_name {
+        let machine_name = get_utf16(machine_name);
+        let status = traced!(RegConnectRegistryW(
+            machine_name.as_ptr(),
+            HKEY_LOCAL_MACHINE,
+            &mut hive
+        ));
+        if status != ERROR_SUCCESS as LONG {
+            return Err(Error::os(
+                "RegConnectRegistryW",
+                status,
+                &format_error_message(status as DWORD),
+            ));
+        }
+    }
+    Ok(hive)
+}
 
-            let mut description = get_utf16(self.description.as_str());
+/// Bytes of a nul-terminated UTF-16 string, as stored in a string value of the registry.
+unsafe fn wide_bytes(value: &[u16]) -> &[u8] {
+    slice::from_raw_parts(value.as_ptr() as *const u8, mem::size_of_val(value))
+}
 
-            let mut sd = SERVICE_DESCRIPTION_W {
-                lpDescription: description.as_mut_ptr(),
-            };
+/// Sets the environment variables stored when the service was created, called by the
+/// dispatcher before the service main function runs.
+fn apply_environment(service_name: &str) {
+    let key = get_utf16(&parameters_key(service_name));
+    let value_name = get_utf16(ENVIRONMENT);
+    let mut size: DWORD = 0;
+    let status = unsafe {
+        traced!(RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            key.as_ptr(),
+            value_name.as_ptr(),
+            RRF_RT_REG_MULTI_SZ,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut size,
+        ))
+    };
+    if status != ERROR_SUCCESS as LONG {
+        return;
+    }
 
-            let p_sd = &mut sd as *mut _ as *mut winapi::ctypes::c_void;
-            ChangeServiceConfig2W(service, SERVICE_CONFIG_DESCRIPTION, p_sd);
-            CloseServiceHandle(service);
+    let mut value = vec![0u16; size as usize / mem::size_of::<u16>() + 1];
+    let status = unsafe {
+        traced!(RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            key.as_ptr(),
+            value_name.as_ptr(),
+            RRF_RT_REG_MULTI_SZ,
+            ptr::null_mut(),
+            value.as_mut_ptr() as PVOID,
+            &mut size,
+        ))
+    };
+    if status != ERROR_SUCCESS as LONG {
+        warn!(
+            "Failed to read the service environment: {}",
+            format_error_message(status as DWORD)
+        );
+        return;
+    }
 
-            Ok(())
+    for entry in from_wide_multi_ptr(value.as_mut_ptr()) {
+        if let Some((name, variable)) = entry.split_once('=') {
+            env::set_var(name, expand_variables(variable));
         }
     }
+}
 
-    fn delete(&mut self) -> Result<(), Error> {
-        unsafe {
-            let service_manager = ServiceControlManager::open(SC_MANAGER_ALL_ACCESS)?;
-            let service = service_manager.open_service(&self.service_name, SERVICE_ALL_ACCESS)?;
-
-            if ControlService(
-                service.handle,
-                SERVICE_CONTROL_STOP,
-                &mut self.service_status,
-            ) != 0
-            {
-                while QueryServiceStatus(service.handle, &mut self.service_status) != 0 {
-                    if self.service_status.dwCurrentState != SERVICE_STOP_PENDING {
-                        break;
+/// Replaces the `%NAME%` references of `value` by the variables of the environment, unknown
+/// variables are left as is like `ExpandEnvironmentStrings` does.
+fn expand_variables(value: &str) -> String {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('%') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.find('%') {
+            Some(end) => {
+                let name = &after[..end];
+                match env::var(name) {
+                    Ok(variable) if !name.is_empty() => expanded.push_str(&variable),
+                    _ => {
+                        expanded.push('%');
+                        expanded.push_str(name);
+                        expanded.push('%');
                     }
-                    thread::sleep(time::Duration::from_millis(250));
                 }
+                rest = &after[end + 1..];
             }
-
-            if DeleteService(service.handle) == 0 {
-                return Err(Error::new(&format!(
-                    "DeleteService: {}",
-                    get_last_error_text()
-                )));
+            None => {
+                expanded.push_str(&rest[start..]);
+                rest = "";
             }
-
-            Ok(())
         }
     }
+    expanded.push_str(rest);
+    expanded
+}
 
-    fn start(&mut self) -> Result<(), Error> {
-        unsafe {
-            let service_manager = ServiceControlManager::open(SC_MANAGER_ALL_ACCESS)?;
-            let service = service_manager.open_service(&self.service_name, SERVICE_ALL_ACCESS)?;
+/// Failure actions of a service: the failure count reset period in seconds and the actions
+/// as `(SC_ACTION_TYPE, delay in milliseconds)` pairs.
+struct FailureActions {
+    reset_period: DWORD,
+    actions: Vec<(DWORD, DWORD)>,
+    non_crash_failures: bool,
+}
 
-            if StartServiceW(service.handle, 0, ptr::null_mut()) != 0 {
-                while QueryServiceStatus(service.handle, &mut self.service_status) != 0 {
-                    if self.service_status.dwCurrentState != SERVICE_START_PENDING {
-                        break;
-                    }
-                    thread::sleep(time::Duration::from_millis(250));
-                }
-            }
+impl FailureActions {
+    fn to_registry(&self) -> Vec<DWORD> {
+        let mut value = vec![self.non_crash_failures as DWORD, self.reset_period];
+        for &(action_type, delay) in &self.actions {
+            value.extend([action_type, delay]);
+        }
+        value
+    }
 
-            if self.service_status.dwCurrentState != SERVICE_RUNNING {
-                return Err(Error::new("Failed to start service"));
+    fn from_registry(value: &[DWORD]) -> Option<FailureActions> {
+        match value {
+            [non_crash_failures, reset_period, actions @ ..] if actions.len() % 2 == 0 => {
+                Some(FailureActions {
+                    reset_period: *reset_period,
+                    actions: actions
+                        .chunks(2)
+                        .map(|action| (action[0], action[1]))
+                        .collect(),
+                    non_crash_failures: *non_crash_failures != 0,
+                })
             }
-
-            Ok(())
+            _ => None,
         }
     }
+}
 
-    fn stop(&mut self) -> Result<(), Error> {
-        unsafe {
-            let service_manager = ServiceControlManager::open(SC_MANAGER_ALL_ACCESS)?;
-            let service = service_manager.open_service(&self.service_name, SERVICE_ALL_ACCESS)?;
+// Value of the `Parameters` key holding the failure actions replaced by a self restart.
+const SAVED_FAILURE_ACTIONS: &str = "SelfRestartFailureActions";
 
-            if ControlService(
-                service.handle,
-                SERVICE_CONTROL_STOP,
-                &mut self.service_status,
-            ) != 0
-            {
-                while QueryServiceStatus(service.handle, &mut self.service_status) != 0 {
-                    if self.service_status.dwCurrentState != SERVICE_STOP_PENDING {
-                        break;
-                    }
-                    thread::sleep(time::Duration::from_millis(250));
-                }
-            } else {
-                return Err(Error::new("ControlService: failed to stop service"));
-            }
+/// Makes the SCM restart the service once it reports a stopped status with an error: the
+/// failure actions of the service are saved in its `Parameters` key and replaced by an
+/// immediate restart that non-crash failures also trigger.
+fn schedule_self_restart(service_name: &str) -> Result<(), Error> {
+    let service_manager = ServiceControlManager::open(None, SC_MANAGER_CONNECT)?;
+    let service = service_manager.open_service(
+        service_name,
+        SERVICE_CHANGE_CONFIG | SERVICE_QUERY_CONFIG | SERVICE_START,
+    )?;
 
-            if self.service_status.dwCurrentState != SERVICE_STOPPED {
-                return Err(Error::new("Failed to stop service"));
-            }
+    let saved = service.query_failure_actions()?.to_registry();
+    let key = get_utf16(&parameters_key(service_name));
+    let status = unsafe {
+        traced!(RegSetKeyValueW(
+            HKEY_LOCAL_MACHINE,
+            key.as_ptr(),
+            get_utf16(SAVED_FAILURE_ACTIONS).as_ptr(),
+            REG_BINARY,
+            saved.as_ptr() as LPCVOID,
+            (saved.len() * mem::size_of::<DWORD>()) as DWORD,
+        ))
+    };
+    if status != ERROR_SUCCESS as LONG {
+        return Err(Error::os(
+            "RegSetKeyValueW",
+            status,
+            &format_error_message(status as DWORD),
+        ));
+    }
 
-            Ok(())
+    service.change_failure_actions(&FailureActions {
+        reset_period: 0,
+        actions: vec![(SC_ACTION_RESTART, 0)],
+        non_crash_failures: true,
+    })
+}
+
+/// Restores the failure actions saved by a self restart, called by the dispatcher at startup.
+fn restore_failure_actions(service_name: &str) {
+    let key = get_utf16(&parameters_key(service_name));
+    let value_name = get_utf16(SAVED_FAILURE_ACTIONS);
+    let mut saved_size: DWORD = 0;
+    let mut saved = Vec::new();
+    let status = unsafe {
+        let status = traced!(RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            key.as_ptr(),
+            value_name.as_ptr(),
+            RRF_RT_REG_BINARY,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut saved_size,
+        ));
+        if status == ERROR_SUCCESS as LONG {
+            saved = vec![0 as DWORD; (saved_size as usize).div_ceil(mem::size_of::<DWORD>())];
+            traced!(RegGetValueW(
+                HKEY_LOCAL_MACHINE,
+                key.as_ptr(),
+                value_name.as_ptr(),
+                RRF_RT_REG_BINARY,
+                ptr::null_mut(),
+                saved.as_mut_ptr() as PVOID,
+                &mut saved_size,
+            ))
+        } else {
+            status
         }
+    };
+    if status == ERROR_FILE_NOT_FOUND as LONG {
+        return;
+    }
+    if status != ERROR_SUCCESS as LONG {
+        warn!(
+            "Failed to read the saved failure actions of the service: {}",
+            format_error_message(status as DWORD)
+        );
+        return;
+    }
+
+    let saved = &saved[..saved_size as usize / mem::size_of::<DWORD>()];
+    let result = FailureActions::from_registry(saved)
+        .ok_or_else(|| Error::new("Invalid saved failure actions"))
+        .and_then(|failure_actions| {
+            let service_manager = ServiceControlManager::open(None, SC_MANAGER_CONNECT)?;
+            service_manager
+                .open_service(service_name, SERVICE_CHANGE_CONFIG | SERVICE_START)?
+                .change_failure_actions(&failure_actions)
+        });
+    match result {
+        Ok(()) => unsafe {
+            traced!(RegDeleteKeyValueW(
+                HKEY_LOCAL_MACHINE,
+                key.as_ptr(),
+                value_name.as_ptr()
+            ));
+        },
+        Err(e) => warn!(
+            "Failed to restore the failure actions of the service: {}",
+            e
+        ),
     }
 }
 
-impl WindowsController {
-    pub fn new(service_name: &str, display_name: &str, description: &str) -> WindowsController {
-        WindowsController {
-            service_name: service_name.to_string(),
-            display_name: display_name.to_string(),
-            description: description.to_string(),
-            desired_access: SERVICE_ALL_ACCESS,
-            service_type: SERVICE_WIN32_OWN_PROCESS,
-            start_type: SERVICE_AUTO_START,
-            error_control: SERVICE_ERROR_NORMAL,
-            tag_id: 0,
-            load_order_group: "".to_string(),
-            dependencies: "".to_string(),
-            account_name: "".to_string(),
-            password: "".to_string(),
-            service_status: SERVICE_STATUS {
-                dwServiceType: SERVICE_WIN32_OWN_PROCESS,
-                dwCurrentState: SERVICE_STOPPED,
-                dwControlsAccepted: 0,
-                dwWin32ExitCode: 0,
-                dwServiceSpecificExitCode: 0,
-                dwCheckPoint: 0,
-                dwWaitHint: 0,
-            },
-            status_handle: ptr::null_mut(),
-            controls_accepted: SERVICE_ACCEPT_STOP,
-        }
+struct MinidumpTarget {
+    dir: PathBuf,
+    service_name: String,
+    quota: u64,
+}
+
+/// Writes minidumps for panics and unhandled exceptions when a quota is set on the controller.
+fn enable_minidumps(ctx: &ServiceContext) {
+    let quota = match *MINIDUMP_QUOTA.lock().unwrap() {
+        Some(quota) => quota,
+        None => return,
+    };
+
+    let target = MinidumpTarget {
+        dir: ctx.data_dir().join("crashdumps"),
+        service_name: ctx.service_name().to_string(),
+        quota,
+    };
+    if MINIDUMP_TARGET.set(target).is_err() {
+        return;
     }
 
-    /// Register the `service_main_wrapper` function, this function is generated by the `Service!` macro.
-    pub fn register(
-        &mut self,
-        service_main_wrapper: WindowsServiceMainWrapperFn,
-    ) -> Result<(), Error> {
-        unsafe {
-            let service_name = get_utf16(self.service_name.as_str());
+    // The dump is written from the panic hook, before unwinding, so that it contains the
+    // stack of the panicking thread.
+    let previous_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        write_minidump(ptr::null_mut());
+        previous_hook(info);
+    }));
+    unsafe { SetUnhandledExceptionFilter(Some(unhandled_exception_filter)) };
+}
 
-            let service_table: &[*const SERVICE_TABLE_ENTRYW] = &[
-                &SERVICE_TABLE_ENTRYW {
-                    lpServiceName: service_name.as_ptr(),
-                    lpServiceProc: Some(service_main_wrapper),
-                },
-                ptr::null(),
-            ];
+unsafe extern "system" fn unhandled_exception_filter(exception: *mut EXCEPTION_POINTERS) -> LONG {
+    write_minidump(exception);
+    EXCEPTION_CONTINUE_SEARCH
+}
 
-            match StartServiceCtrlDispatcherW(*service_table.as_ptr()) {
-                0 => Err(Error::new("StartServiceCtrlDispatcher")),
-                _ => Ok(()),
-            }
+fn write_minidump(exception: *mut EXCEPTION_POINTERS) {
+    let target = match MINIDUMP_TARGET.get() {
+        Some(target) => target,
+        None => return,
+    };
+
+    match create_minidump(target, exception) {
+        Ok(path) => {
+            error!("Wrote minidump {}", path.display());
+            prune_minidumps(target, &path);
         }
+        Err(e) => error!("Failed to write minidump: {}", e),
     }
 }
 
-fn set_service_status(
-    status_handle: SERVICE_STATUS_HANDLE,
-    current_state: DWORD,
-    wait_hint: DWORD,
-) {
-    let mut service_status = SERVICE_STATUS {
-        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
-        dwCurrentState: current_state,
-        dwControlsAccepted: SERVICE_ACCEPT_STOP
-            | SERVICE_ACCEPT_SHUTDOWN
-            | SERVICE_ACCEPT_PAUSE_CONTINUE
-            | SERVICE_ACCEPT_SESSIONCHANGE,
-        dwWin32ExitCode: 0,
-        dwServiceSpecificExitCode: 0,
-        dwCheckPoint: 0,
-        dwWaitHint: wait_hint,
-    };
+fn create_minidump(
+    target: &MinidumpTarget,
+    exception: *mut EXCEPTION_POINTERS,
+) -> Result<PathBuf, Error> {
+    fs::create_dir_all(&target.dir)
+        .map_err(|e| Error::io(&format!("Failed to create {}", target.dir.display()), &e))?;
+
+    let timestamp = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let path = target.dir.join(format!(
+        "{}-{}-{}.dmp",
+        target.service_name,
+        process::id(),
+        timestamp
+    ));
+    let file = fs::File::create(&path)
+        .map_err(|e| Error::io(&format!("Failed to create {}", path.display()), &e))?;
+
     unsafe {
-        SetServiceStatus(status_handle, &mut service_status);
+        let exception_info = MINIDUMP_EXCEPTION_INFORMATION {
+            ThreadId: GetCurrentThreadId(),
+            ExceptionPointers: exception,
+            ClientPointers: FALSE,
+        };
+        if MiniDumpWriteDump(
+            GetCurrentProcess(),
+            GetCurrentProcessId(),
+            file.as_raw_handle() as HANDLE,
+            MINIDUMP_WITH_THREAD_INFO,
+            if exception.is_null() {
+                ptr::null()
+            } else {
+                &exception_info
+            },
+            ptr::null_mut(),
+            ptr::null_mut(),
+        ) == 0
+        {
+            let error = last_error("MiniDumpWriteDump");
+            drop(file);
+            let _ = fs::remove_file(&path);
+            return Err(error);
+        }
     }
+
+    Ok(path)
 }
 
-unsafe extern "system" fn service_handler<T>(
-    control: DWORD,
-    event_type: DWORD,
-    event_data: LPVOID,
-    context: LPVOID,
-) -> DWORD {
-    let tx = context as *mut mpsc::Sender<ServiceEvent<T>>;
+/// Deletes the oldest minidumps, other than the one that was just written, until their total
+/// size fits in the quota.
+fn prune_minidumps(target: &MinidumpTarget, latest: &Path) {
+    let mut dumps: Vec<(time::SystemTime, u64, PathBuf)> = match fs::read_dir(&target.dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "dmp"))
+            .filter_map(|path| {
+                let metadata = fs::metadata(&path).ok()?;
+                Some((metadata.modified().ok()?, metadata.len(), path))
+            })
+            .collect(),
+        Err(_) => return,
+    };
+    dumps.sort();
 
-    match control {
-        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
-            set_service_status(SERVICE_CONTROL_HANDLE, SERVICE_STOP_PENDING, 10);
-            let _ = (*tx).send(ServiceEvent::Stop);
-            0
-        }
-        SERVICE_CONTROL_PAUSE => {
-            let _ = (*tx).send(ServiceEvent::Pause);
-            0
+    let mut total: u64 = dumps.iter().map(|(_, size, _)| size).sum();
+    for (_, size, path) in dumps {
+        if total <= target.quota {
+            break;
         }
-        SERVICE_CONTROL_CONTINUE => {
-            let _ = (*tx).send(ServiceEvent::Continue);
-            0
-        }
-        SERVICE_CONTROL_SESSIONCHANGE => {
-            let event = event_type as usize;
-            let session_notification = event_data as PWTSSESSION_NOTIFICATION;
-            let session_id = (*session_notification).dwSessionId;
-            let session = Session::new(session_id);
-
-            if event == WTS_CONSOLE_CONNECT {
-                let _ = (*tx).send(ServiceEvent::SessionConnect(session));
-                0
-            } else if event == WTS_CONSOLE_DISCONNECT {
-                let _ = (*tx).send(ServiceEvent::SessionDisconnect(session));
-                0
-            } else if event == WTS_REMOTE_CONNECT {
-                let _ = (*tx).send(ServiceEvent::SessionRemoteConnect(session));
-                0
-            } else if event == WTS_REMOTE_DISCONNECT {
-                let _ = (*tx).send(ServiceEvent::SessionRemoteDisconnect(session));
-                0
-            } else if event == WTS_SESSION_LOGON {
-                let _ = (*tx).send(ServiceEvent::SessionLogon(session));
-                0
-            } else if event == WTS_SESSION_LOGOFF {
-                let _ = (*tx).send(ServiceEvent::SessionLogoff(session));
-                0
-            } else if event == WTS_SESSION_LOCK {
-                let _ = (*tx).send(ServiceEvent::SessionLock(session));
-                0
-            } else if event == WTS_SESSION_UNLOCK {
-                let _ = (*tx).send(ServiceEvent::SessionUnlock(session));
-                0
-            } else {
-                0
-            }
+        if path != latest && fs::remove_file(&path).is_ok() {
+            total -= size;
         }
-        _ => ERROR_CALL_NOT_IMPLEMENTED,
     }
 }
 
@@ -384,42 +4748,167 @@ fn get_args(argc: DWORD, argv: *mut LPWSTR) -> Vec<String> {
     args
 }
 
+fn from_wide_ptr(value: LPWSTR) -> String {
+    if value.is_null() {
+        return String::new();
+    }
+    unsafe { WideCString::from_ptr_str(value).to_string_lossy() }
+}
+
+fn from_wide_multi_ptr(value: LPWSTR) -> Vec<String> {
+    let mut strings = Vec::new();
+    if value.is_null() {
+        return strings;
+    }
+    unsafe {
+        let mut current = value;
+        while *current != 0 {
+            let string = WideCString::from_ptr_str(current);
+            current = current.add(string.len() + 1);
+            strings.push(string.to_string_lossy());
+        }
+    }
+    strings
+}
+
 pub fn get_utf16(value: &str) -> Vec<u16> {
     OsStr::new(value).encode_wide().chain(once(0)).collect()
 }
 
+/// Maximum length of the comment of a stop reason, in UTF-16 code units.
+const MAX_STOP_COMMENT_LEN: usize = 127;
+
+/// Converts the `what` string passed to the SCM to a null-terminated wide string. Embedded
+/// NULs would silently truncate it and are rejected, as are values longer than `max_len`
+/// UTF-16 code units.
+fn to_wide(what: &str, value: &str, max_len: Option<usize>) -> Result<Vec<u16>, Error> {
+    if value.contains('\0') {
+        return Err(Error::new(&format!(
+            "The {} contains a NUL character",
+            what
+        )));
+    }
+    let wide = get_utf16(value);
+    match max_len {
+        Some(max_len) if wide.len() - 1 > max_len => Err(Error::new(&format!(
+            "The {} is longer than {} UTF-16 characters",
+            what, max_len
+        ))),
+        _ => Ok(wide),
+    }
+}
+
+/// Converts a service name, which the SCM also rejects when it contains a slash or a
+/// backslash.
+fn service_name_to_wide(service_name: &str) -> Result<Vec<u16>, Error> {
+    if service_name.is_empty() || service_name.contains(['/', '\\']) {
+        return Err(Error::new(&format!(
+            "Invalid service name \"{}\": it must not be empty or contain slashes",
+            service_name
+        )));
+    }
+    to_wide("service name", service_name, Some(MAX_NAME_LEN))
+}
+
 pub fn get_filename() -> String {
+    // GetModuleFileNameW truncates the paths longer than the buffer, which grows until the
+    // path fits up to the longest path supported by Windows.
+    let mut filename = vec![0u16; MAX_PATH];
+    loop {
+        let size = unsafe {
+            GetModuleFileNameW(
+                ptr::null_mut(),
+                filename.as_mut_ptr(),
+                filename.len() as DWORD,
+            )
+        } as usize;
+        if size < filename.len() || filename.len() >= 32_768 {
+            return String::from_utf16_lossy(&filename[..size.min(filename.len())]);
+        }
+        filename.resize(filename.len() * 2, 0);
+    }
+}
+
+/// Argument passed to the executable re-launched by the `elevate` option, followed by the
+/// name of the operation to perform.
+const ELEVATED_ARG: &str = "--ceviche-elevated";
+
+/// Runs `operation` in an elevated instance of the current executable and waits for it. The
+/// elevated instance exits with the OS error of the operation, `ERROR_CANCELLED` is returned
+/// when the user declines the UAC prompt.
+#[cfg(feature = "install")]
+fn run_elevated(operation: &str) -> Result<(), Error> {
+    let executable = env::current_exe()
+        .map_err(|e| Error::io("Failed to get the path of the current executable", &e))?;
+    let executable = get_utf16(&executable.to_string_lossy());
+    let verb = get_utf16("runas");
+    let parameters = get_utf16(&format!("{} {}", ELEVATED_ARG, operation));
+
     unsafe {
-        let mut filename = [0u16; MAX_PATH];
-        let _size = GetModuleFileNameW(
-            ptr::null_mut(),
-            filename.as_mut_ptr(),
-            filename.len() as DWORD,
-        );
-        String::from_utf16(&filename).unwrap_or_else(|_| String::from(""))
+        let mut info: SHELLEXECUTEINFOW = mem::zeroed();
+        info.cbSize = mem::size_of::<SHELLEXECUTEINFOW>() as DWORD;
+        info.fMask = SEE_MASK_NOCLOSEPROCESS | SEE_MASK_NOASYNC;
+        info.lpVerb = verb.as_ptr();
+        info.lpFile = executable.as_ptr();
+        info.lpParameters = parameters.as_ptr();
+        info.nShow = SW_HIDE;
+        if traced!(ShellExecuteExW(&mut info)) == 0 {
+            return Err(last_error("ShellExecuteExW"));
+        }
+
+        let mut exit_code: DWORD = 0;
+        WaitForSingleObject(info.hProcess, INFINITE);
+        let succeeded = GetExitCodeProcess(info.hProcess, &mut exit_code);
+        CloseHandle(info.hProcess);
+        if succeeded == 0 {
+            return Err(last_error("GetExitCodeProcess"));
+        }
+        match exit_code {
+            0 => Ok(()),
+            1 => Err(Error::new("Elevated operation failed")),
+            code => Err(Error::os(
+                "Elevated operation",
+                code as i32,
+                &format_error_message(code),
+            )),
+        }
     }
 }
 
-pub fn get_last_error_text() -> String {
+fn last_error(function: &str) -> Error {
+    let code = unsafe { GetLastError() };
+    Error::os(function, code as i32, &format_error_message(code))
+}
+
+fn format_error_message(code: DWORD) -> String {
     unsafe {
         let mut message = [0u16; 512];
         let length = FormatMessageW(
             FORMAT_MESSAGE_FROM_SYSTEM,
             ptr::null(),
-            GetLastError(),
+            code,
             0,
             message.as_mut_ptr(),
             message.len() as u32,
             ptr::null_mut(),
         );
-        String::from_utf16(&message[0..length as usize]).unwrap_or_else(|_| String::from(""))
+        String::from_utf16_lossy(&message[0..length as usize])
     }
 }
 
 /// Generates a `service_main_wrapper` that wraps the provided service main function.
+#[cfg(feature = "runtime")]
 #[macro_export]
 macro_rules! Service {
-    ($name:expr, $function:ident) => {
+    ($name:expr, legacy $function:expr) => {
+        use $crate::winapi::shared::minwindef::DWORD;
+        use $crate::winapi::um::winnt::LPWSTR;
+
+        extern "system" fn service_main_wrapper(argc: DWORD, argv: *mut LPWSTR) {
+            dispatch($crate::__legacy_service_main!($function), $name, argc, argv);
+        }
+    };
+    ($name:expr, $function:expr) => {
         use $crate::winapi::shared::minwindef::DWORD;
         use $crate::winapi::um::winnt::LPWSTR;
 
@@ -427,24 +4916,256 @@ macro_rules! Service {
             dispatch($function, $name, argc, argv);
         }
     };
+    ($name:expr, $function:expr, stack_size = $stack_size:expr) => {
+        use $crate::winapi::shared::minwindef::DWORD;
+        use $crate::winapi::um::winnt::LPWSTR;
+
+        extern "system" fn service_main_wrapper(argc: DWORD, argv: *mut LPWSTR) {
+            dispatch_on_thread($function, $name, argc, argv, $stack_size);
+        }
+    };
+}
+
+/// Generates the `ServiceMain` export of a service DLL hosted by `svchost.exe` or another
+/// process shared with other services, see `ServiceDll`. The runtime options are read from
+/// the controller built by the optional third argument:
+///
+/// ```rust,ignore
+/// ServiceDll!("foobar", my_service_main, Controller::new("foobar", "Foobar", "Foobar"));
+/// ```
+#[cfg(feature = "runtime")]
+#[macro_export]
+macro_rules! ServiceDll {
+    ($name:expr, $function:expr) => {
+        #[no_mangle]
+        pub extern "system" fn ServiceMain(
+            argc: $crate::winapi::shared::minwindef::DWORD,
+            argv: *mut $crate::winapi::um::winnt::LPWSTR,
+        ) {
+            $crate::controller::dispatch_hosted($function, $name, argc, argv);
+        }
+    };
+    ($name:expr, $function:expr, $controller:expr) => {
+        #[no_mangle]
+        pub extern "system" fn ServiceMain(
+            argc: $crate::winapi::shared::minwindef::DWORD,
+            argv: *mut $crate::winapi::um::winnt::LPWSTR,
+        ) {
+            $controller.register_hosted();
+            $crate::controller::dispatch_hosted($function, $name, argc, argv);
+        }
+    };
+}
+
+#[cfg(feature = "runtime")]
+#[doc(hidden)]
+pub fn dispatch<T: Send + 'static, F: ServiceMain<T>>(
+    service_main: F,
+    name: &str,
+    argc: DWORD,
+    argv: *mut LPWSTR,
+) {
+    run_service(service_main, name, argc, argv, None, false);
+}
+
+/// Runs the service main function of a DLL in a process shared with other services, used by
+/// the `ServiceDll!` macro. The process-wide setup of the wrapper is skipped: the environment
+/// stored with the service isn't applied and no unhandled exception filter is installed.
+#[cfg(feature = "runtime")]
+#[doc(hidden)]
+pub fn dispatch_hosted<T: Send + 'static, F: ServiceMain<T>>(
+    service_main: F,
+    name: &str,
+    argc: DWORD,
+    argv: *mut LPWSTR,
+) {
+    run_service(service_main, name, argc, argv, None, true);
 }
 
+/// Runs the service main function on a thread spawned with `stack_size` bytes of stack
+/// instead of the thread created by the SCM, whose stack is only as large as the default
+/// stack size of the executable. Used by the `stack_size` form of the macro.
+#[cfg(feature = "runtime")]
 #[doc(hidden)]
-pub fn dispatch<T>(service_main: ServiceMainFn<T>, name: &str, argc: DWORD, argv: *mut LPWSTR) {
+pub fn dispatch_on_thread<T: Send + 'static, F: ServiceMain<T>>(
+    service_main: F,
+    name: &str,
+    argc: DWORD,
+    argv: *mut LPWSTR,
+    stack_size: usize,
+) {
+    run_service(service_main, name, argc, argv, Some(stack_size), false);
+}
+
+fn run_service<T: Send + 'static, F: ServiceMain<T>>(
+    service_main: F,
+    name: &str,
+    argc: DWORD,
+    argv: *mut LPWSTR,
+    stack_size: Option<usize>,
+    hosted: bool,
+) {
     let args = get_args(argc, argv);
+    let options = runtime_options(args.first().map_or("", String::as_str), name);
+    // The SCM passes the name it started the service as, which differs from `name` when the
+    // binary is installed as several services.
+    let name = match args.first() {
+        Some(launched_name) if !launched_name.is_empty() => launched_name.clone(),
+        _ => name.to_string(),
+    };
+    let name = name.as_str();
     let service_name = get_utf16(name);
-    let (mut tx, rx) = mpsc::channel();
-    let _tx = tx.clone();
+    let (tx, rx, _tx) = channel::channel();
+    let status = StatusReporter::new(hosted);
+    let handler = ControlHandler {
+        tx,
+        status: status.clone(),
+    };
     let ctrl_handle = unsafe {
-        RegisterServiceCtrlHandlerExW(
+        traced!(RegisterServiceCtrlHandlerExW(
             service_name.as_ptr(),
             Some(service_handler::<T>),
-            &mut tx as *mut _ as LPVOID,
-        )
+            &handler as *const _ as LPVOID,
+        ))
     };
-    unsafe { SERVICE_CONTROL_HANDLE = ctrl_handle };
-    set_service_status(ctrl_handle, SERVICE_START_PENDING, 0);
-    set_service_status(ctrl_handle, SERVICE_RUNNING, 0);
-    service_main(rx, _tx, args, false);
-    set_service_status(ctrl_handle, SERVICE_STOPPED, 0);
+    status.set_handle(ctrl_handle);
+    let tx = handler.tx.clone();
+    let mut ctx = ServiceContext::new(name, args).with_status_handle(ctrl_handle);
+    logging::init_service_logger(&mut ctx);
+    if !hosted {
+        apply_environment(name);
+    }
+    let _instance = match instance::acquire_for_service(name) {
+        Ok(instance) => instance,
+        Err(e) => {
+            error!("{}", e);
+            status.report(SERVICE_STOPPED, 0, ERROR_SERVICE_ALREADY_RUNNING, 0);
+            return;
+        }
+    };
+    if !hosted {
+        enable_minidumps(&ctx);
+    }
+    record_start(name);
+    restore_failure_actions(name);
+    status.set(
+        SERVICE_START_PENDING,
+        startup_wait_hint(options.startup_timeout),
+    );
+    match options.startup_gate {
+        Some(gate) => wait_startup_gate(gate, options.startup_timeout, status.clone(), tx.clone()),
+        None => status.set(SERVICE_RUNNING, 0),
+    }
+    if let Some(group) = options.cluster_group {
+        watch_cluster_group(group, tx.clone());
+    }
+    #[cfg(feature = "session-events")]
+    if let Some(threshold) = options.idle_threshold {
+        watch_idle_sessions(threshold, tx.clone());
+    }
+    if options.display_events {
+        watch_display_changes(tx.clone());
+    }
+    let progress_status = status.clone();
+    stop::set_progress_reporter(move |remaining| report_stop_progress(&progress_status, remaining));
+    let pause_status = status.clone();
+    pause::set_reporter(move |paused| report_pause_completed(&pause_status, paused));
+    let exit_status = status.clone();
+    deadline::set_before_exit(move |exit_code| exit_status.stopped(exit_code));
+    let result = match stack_size {
+        None => panic::catch_unwind(AssertUnwindSafe(|| service_main(rx, _tx, ctx))),
+        Some(stack_size) => {
+            let thread = thread::Builder::new()
+                .name(name.to_string())
+                .stack_size(stack_size)
+                .spawn(move || service_main(rx, _tx, ctx));
+            match thread {
+                Ok(thread) => thread.join(),
+                Err(e) => {
+                    error!("Failed to spawn the service main thread: {}", e);
+                    status.report(SERVICE_STOPPED, 0, ERROR_NOT_ENOUGH_MEMORY, 0);
+                    return;
+                }
+            }
+        }
+    };
+    deadline::service_main_returned();
+    #[cfg(feature = "state")]
+    state::save_registered();
+    match result {
+        Ok(exit_code) => {
+            run_once::service_main_returned(exit_code);
+            let restarting = restart::is_requested()
+                && schedule_self_restart(name)
+                    .map_err(|e| error!("Failed to schedule the service restart: {}", e))
+                    .is_ok();
+            if restarting {
+                // The failure action only runs when the service stops with an error.
+                status.report(SERVICE_STOPPED, 0, ERROR_RESTART_APPLICATION, 0);
+            } else {
+                status.stopped(exit_code)
+            }
+        }
+        Err(payload) => {
+            error!("Service main panicked: {}", panic_message(payload.as_ref()));
+            status.report(SERVICE_STOPPED, 0, ERROR_EXCEPTION_IN_SERVICE, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // U+1F600, encoded as a surrogate pair.
+    const NON_BMP: &str = "\u{1F600}";
+
+    #[test]
+    fn to_wide_terminates_with_nul() {
+        assert_eq!(
+            to_wide("name", "ab", None).unwrap(),
+            [b'a' as u16, b'b' as u16, 0]
+        );
+        assert_eq!(to_wide("name", "", None).unwrap(), [0]);
+    }
+
+    #[test]
+    fn to_wide_rejects_embedded_nul() {
+        let e = to_wide("name", "a\0b", None).unwrap_err();
+        assert_eq!(e.to_string(), "The name contains a NUL character");
+        assert!(to_wide("name", "\0", Some(10)).is_err());
+    }
+
+    #[test]
+    fn to_wide_counts_utf16_units() {
+        assert!(to_wide("name", &"a".repeat(4), Some(4)).is_ok());
+        assert!(to_wide("name", &"a".repeat(5), Some(4)).is_err());
+        // A character outside the BMP takes two of the units allowed.
+        assert_eq!(to_wide("name", NON_BMP, None).unwrap().len(), 3);
+        assert!(to_wide("name", &NON_BMP.repeat(2), Some(4)).is_ok());
+        assert!(to_wide("name", &format!("a{}", NON_BMP.repeat(2)), Some(4)).is_err());
+    }
+
+    #[test]
+    fn to_wide_round_trips() {
+        for value in ["", "foobar", "Dienst für Bücher", NON_BMP, "a\u{10FFFF}b"] {
+            let mut wide = to_wide("name", value, None).unwrap();
+            assert_eq!(from_wide_ptr(wide.as_mut_ptr()), value);
+        }
+        assert_eq!(from_wide_ptr(ptr::null_mut()), "");
+    }
+
+    #[test]
+    fn service_name_to_wide_checks_the_name() {
+        assert!(service_name_to_wide("foobar").is_ok());
+        assert!(service_name_to_wide("").is_err());
+        assert!(service_name_to_wide("foo/bar").is_err());
+        assert!(service_name_to_wide("foo\\bar").is_err());
+        assert!(service_name_to_wide("foo\0bar").is_err());
+        assert!(service_name_to_wide(&"a".repeat(MAX_NAME_LEN)).is_ok());
+        assert!(service_name_to_wide(&"a".repeat(MAX_NAME_LEN + 1)).is_err());
+        // 128 surrogate pairs are 256 UTF-16 units.
+        assert!(service_name_to_wide(&NON_BMP.repeat(MAX_NAME_LEN / 2)).is_ok());
+        assert!(service_name_to_wide(&format!("a{}", NON_BMP.repeat(MAX_NAME_LEN / 2))).is_err());
+    }
 }
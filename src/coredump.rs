@@ -0,0 +1,61 @@
+use std::io;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(target_os = "linux")]
+use log::warn;
+
+use crate::Error;
+
+#[cfg(target_os = "linux")]
+static RAISE_AT_STARTUP: AtomicBool = AtomicBool::new(false);
+
+/// Raises the soft core size limit of the process to its hard limit so that a crash writes a
+/// core dump. Where the dump is written is controlled by the system, such as
+/// `kernel.core_pattern` on Linux: relative patterns are resolved against the working
+/// directory of the service.
+pub fn raise_core_limit() -> Result<(), Error> {
+    unsafe {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        if libc::getrlimit(libc::RLIMIT_CORE, &mut limit) != 0 {
+            return Err(Error {
+                function: Some("getrlimit".to_string()),
+                ..Error::io(
+                    "Failed to query the core size limit",
+                    &io::Error::last_os_error(),
+                )
+            });
+        }
+
+        limit.rlim_cur = limit.rlim_max;
+        if libc::setrlimit(libc::RLIMIT_CORE, &limit) != 0 {
+            return Err(Error {
+                function: Some("setrlimit".to_string()),
+                ..Error::io(
+                    "Failed to raise the core size limit",
+                    &io::Error::last_os_error(),
+                )
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn set_raise_at_startup(enabled: bool) {
+    RAISE_AT_STARTUP.store(enabled, Ordering::Relaxed);
+}
+
+/// Raises the core size limit before calling the service main function when core dumps are
+/// enabled on the controller.
+#[cfg(target_os = "linux")]
+pub(crate) fn raise_at_startup() {
+    if RAISE_AT_STARTUP.load(Ordering::Relaxed) {
+        if let Err(e) = raise_core_limit() {
+            warn!("{}", e);
+        }
+    }
+}
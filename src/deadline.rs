@@ -0,0 +1,84 @@
+// The dummy controller has no dispatcher, the deadline is never armed there.
+#![cfg_attr(
+    not(any(
+        windows,
+        target_os = "macos",
+        target_os = "linux",
+        target_os = "illumos",
+        target_os = "solaris",
+        target_os = "aix"
+    )),
+    allow(dead_code)
+)]
+
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use log::{error, logger};
+
+use crate::clock;
+use crate::controller::STOP_DEADLINE_EXIT_CODE;
+
+static STOP_DEADLINE: Mutex<Option<Duration>> = Mutex::new(None);
+static ARMED: AtomicBool = AtomicBool::new(false);
+static SERVICE_MAIN_RETURNED: AtomicBool = AtomicBool::new(false);
+static BEFORE_EXIT: Mutex<Option<BeforeExit>> = Mutex::new(None);
+
+type BeforeExit = Box<dyn Fn(u32) + Send>;
+
+pub(crate) fn set_stop_deadline(deadline: Option<Duration>) {
+    *STOP_DEADLINE.lock().unwrap() = deadline;
+}
+
+pub(crate) fn stop_deadline() -> Option<Duration> {
+    *STOP_DEADLINE.lock().unwrap()
+}
+
+/// Registers the function reporting the forced exit to the system, before the process exits.
+#[cfg(windows)]
+pub(crate) fn set_before_exit(before_exit: impl Fn(u32) + Send + 'static) {
+    *BEFORE_EXIT.lock().unwrap() = Some(Box::new(before_exit));
+}
+
+pub(crate) fn service_main_returned() {
+    SERVICE_MAIN_RETURNED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(windows)]
+pub(crate) fn has_service_main_returned() -> bool {
+    SERVICE_MAIN_RETURNED.load(Ordering::SeqCst)
+}
+
+/// Starts the stop deadline when the system sends the first `Stop` event, the process exits
+/// with `STOP_DEADLINE_EXIT_CODE` if the service main function is still running when it ends.
+pub(crate) fn arm() {
+    let deadline = match stop_deadline() {
+        Some(deadline) => deadline,
+        None => return,
+    };
+    if ARMED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let clock = clock::clock();
+    let exit_at = clock.elapsed() + deadline;
+    thread::spawn(move || {
+        clock.sleep_until(exit_at);
+        if SERVICE_MAIN_RETURNED.load(Ordering::SeqCst) {
+            return;
+        }
+
+        error!(
+            "Service main did not return within {:?} after the stop request, exiting",
+            deadline
+        );
+        logger().flush();
+        if let Some(before_exit) = &*BEFORE_EXIT.lock().unwrap() {
+            before_exit(STOP_DEADLINE_EXIT_CODE);
+        }
+        process::exit(STOP_DEADLINE_EXIT_CODE as i32);
+    });
+}
@@ -0,0 +1,80 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::controller::Controller;
+use crate::messages;
+use crate::Error;
+
+/// Interval between two status queries of the awaited service.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Blocks until the service `service_name` is running, for at most `timeout`. For services
+/// whose dependency must be up before they can work but that can't declare it to the service
+/// manager, or that start before the dependency finished starting.
+///
+/// Fails when the status of the service can't be queried, such as when it isn't installed,
+/// or once `timeout` elapsed.
+pub fn wait_for_service(service_name: &str, timeout: Duration) -> Result<(), Error> {
+    let controller = Controller::new(service_name, "", "");
+    let deadline = Instant::now() + timeout;
+    loop {
+        if controller.status()?.is_running() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::from_message(
+                messages::DEPENDENCY_TIMED_OUT,
+                &[&service_name],
+            ));
+        }
+        thread::sleep(POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+/// Future completing when the service `service_name` is running, see `wait_for_service()`.
+/// The status is queried on a thread, the future doesn't depend on a particular runtime.
+pub fn wait_for_service_async(service_name: &str, timeout: Duration) -> WaitService {
+    let shared = Arc::new(Mutex::new(Shared::default()));
+    let waiter = shared.clone();
+    let service_name = service_name.to_string();
+    thread::spawn(move || {
+        let result = wait_for_service(&service_name, timeout);
+        let mut shared = waiter.lock().unwrap();
+        shared.result = Some(result);
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    });
+    WaitService { shared }
+}
+
+#[derive(Default)]
+struct Shared {
+    result: Option<Result<(), Error>>,
+    waker: Option<Waker>,
+}
+
+/// Future returned by `wait_for_service_async()`.
+pub struct WaitService {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Future for WaitService {
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use std::cell::Cell;
+
+use log::info;
+
+thread_local! {
+    static ACTIVE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `body` in a dry run when `enabled`, the actions changing the system are then logged
+/// instead of being performed.
+pub(crate) fn scope<T>(enabled: bool, body: impl FnOnce() -> T) -> T {
+    let previous = ACTIVE.with(|active| active.replace(enabled));
+    let result = body();
+    ACTIVE.with(|active| active.set(previous));
+    result
+}
+
+/// True while a controller operation runs in a dry run, a custom backend checks it to log
+/// its actions instead of performing them.
+pub fn is_dry_run() -> bool {
+    ACTIVE.with(Cell::get)
+}
+
+/// Logs `action` and returns true when it must be skipped because of a dry run.
+pub(crate) fn skip(action: impl FnOnce() -> String) -> bool {
+    if !is_dry_run() {
+        return false;
+    }
+    info!("Dry run: {}", action());
+    true
+}
@@ -0,0 +1,75 @@
+// The dummy controller has no operations requiring privileges.
+#![cfg_attr(
+    not(any(
+        windows,
+        target_os = "macos",
+        target_os = "linux",
+        target_os = "illumos",
+        target_os = "solaris",
+        target_os = "aix"
+    )),
+    allow(dead_code)
+)]
+
+#[cfg(windows)]
+use std::ptr;
+
+#[cfg(windows)]
+use winapi::shared::minwindef::{BOOL, FALSE};
+#[cfg(windows)]
+use winapi::um::securitybaseapi::{AllocateAndInitializeSid, CheckTokenMembership, FreeSid};
+#[cfg(windows)]
+use winapi::um::winnt::{
+    DOMAIN_ALIAS_RID_ADMINS, PSID, SECURITY_BUILTIN_DOMAIN_RID, SECURITY_NT_AUTHORITY,
+    SID_IDENTIFIER_AUTHORITY,
+};
+
+use crate::Error;
+
+/// True when the process can manage system services: its token holds the enabled
+/// Administrators group on Windows, which requires an elevated process when UAC is on, and
+/// its effective user is root on Unix.
+pub fn is_elevated() -> bool {
+    cfg_if! {
+        if #[cfg(windows)] {
+            unsafe {
+                let mut authority = SID_IDENTIFIER_AUTHORITY {
+                    Value: SECURITY_NT_AUTHORITY,
+                };
+                let mut administrators: PSID = ptr::null_mut();
+                if AllocateAndInitializeSid(
+                    &mut authority,
+                    2,
+                    SECURITY_BUILTIN_DOMAIN_RID,
+                    DOMAIN_ALIAS_RID_ADMINS,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    &mut administrators,
+                ) == 0
+                {
+                    return false;
+                }
+                let mut is_member: BOOL = FALSE;
+                let succeeded =
+                    CheckTokenMembership(ptr::null_mut(), administrators, &mut is_member);
+                FreeSid(administrators);
+                succeeded != 0 && is_member != 0
+            }
+        } else {
+            unsafe { libc::geteuid() == 0 }
+        }
+    }
+}
+
+/// Fails early with `ErrorKind::NotElevated` when the process is not elevated, for the
+/// controller operations that change the system configuration.
+pub(crate) fn require_elevated() -> Result<(), Error> {
+    if is_elevated() {
+        return Ok(());
+    }
+    Err(Error::not_elevated())
+}
@@ -0,0 +1,67 @@
+use std::sync::mpsc;
+
+use log::{debug, warn};
+
+use crate::pause;
+use crate::ServiceEvent;
+
+/// Callbacks of `run_event_loop()`. Every method has a default implementation, a handler only
+/// implements the events it cares about.
+pub trait EventHandler<T> {
+    /// Called on `Stop` and `StopNow`, the event loop then returns the exit code.
+    fn on_stop(&mut self) -> u32 {
+        0
+    }
+
+    /// Called on `StopRequested`, the first phase of a two-phase stop.
+    fn on_stop_requested(&mut self) {}
+
+    /// Called on `Pause` while the service is running, the pause is reported as completed once
+    /// it returns.
+    fn on_pause(&mut self) {}
+
+    /// Called on `Continue` while the service is paused, the service is reported as running
+    /// again once it returns.
+    fn on_continue(&mut self) {}
+
+    /// Called on the custom events sent by the service itself.
+    fn on_custom(&mut self, _event: T) {}
+
+    /// Called on the other events, such as session changes, which are logged by default.
+    fn on_event(&mut self, event: ServiceEvent<T>) {
+        debug!("Unhandled service event: {}", event);
+    }
+}
+
+/// Receives the events from `rx` and dispatches them to `handler` until the service is asked
+/// to stop, returning the exit code of the service main function. `Pause` and `Continue` are
+/// only passed on when they change the state of the service. The loop also ends, with exit
+/// code 0, when all the senders are dropped.
+pub fn run_event_loop<T, H: EventHandler<T>>(
+    rx: &mpsc::Receiver<ServiceEvent<T>>,
+    handler: &mut H,
+) -> u32 {
+    let mut paused = false;
+    for event in rx {
+        debug!("Service event: {}", event);
+        match event {
+            ServiceEvent::Stop { .. } | ServiceEvent::StopNow => return handler.on_stop(),
+            ServiceEvent::StopRequested => handler.on_stop_requested(),
+            ServiceEvent::Pause if paused => warn!("Service is already paused"),
+            ServiceEvent::Pause => {
+                paused = true;
+                handler.on_pause();
+                pause::completed(true);
+            }
+            ServiceEvent::Continue if !paused => warn!("Service is not paused"),
+            ServiceEvent::Continue => {
+                paused = false;
+                handler.on_continue();
+                pause::completed(false);
+            }
+            ServiceEvent::Custom(event) => handler.on_custom(event),
+            event => handler.on_event(event),
+        }
+    }
+    0
+}
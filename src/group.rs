@@ -0,0 +1,209 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::controller::{Controller, ControllerInterface};
+use crate::messages;
+use crate::Error;
+
+struct Member {
+    controller: Controller,
+    depends_on: Vec<String>,
+    timeout: Duration,
+}
+
+/// Several cooperating services that are started and stopped together in dependency order.
+///
+/// Services that don't depend on each other are started concurrently. Dependencies on
+/// services outside of the group are ignored.
+///
+/// ```rust,ignore
+/// let mut group = ServiceGroup::new();
+/// group
+///     .add(Controller::new("db", "Database", ""), &[], Duration::from_secs(60))
+///     .add(Controller::new("api", "API", ""), &["db"], Duration::from_secs(30));
+/// group.start()?;
+/// ```
+#[derive(Default)]
+pub struct ServiceGroup {
+    members: Vec<Member>,
+}
+
+/// Services of a group for which an operation failed.
+#[derive(Debug)]
+pub struct GroupError {
+    pub failures: Vec<(String, Error)>,
+}
+
+impl fmt::Display for GroupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        for (i, (service_name, error)) in self.failures.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}: {}", service_name, error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for GroupError {}
+
+impl From<GroupError> for Error {
+    fn from(error: GroupError) -> Self {
+        Error::new(&error.to_string()).with_source(error)
+    }
+}
+
+impl ServiceGroup {
+    pub fn new() -> Self {
+        ServiceGroup::default()
+    }
+
+    /// Adds a service to the group. The service is started after the services named in
+    /// `depends_on`, `timeout` is the time allowed for each start or stop of the service.
+    pub fn add(
+        &mut self,
+        controller: Controller,
+        depends_on: &[&str],
+        timeout: Duration,
+    ) -> &mut Self {
+        self.members.push(Member {
+            controller,
+            depends_on: depends_on.iter().map(|name| name.to_string()).collect(),
+            timeout,
+        });
+        self
+    }
+
+    /// Starts the services in dependency order. A service whose dependency failed to start is
+    /// not started.
+    pub fn start(&mut self) -> Result<(), GroupError> {
+        let (levels, mut failures) = self.levels();
+        let mut failed: HashSet<String> = failures.iter().map(|(name, _)| name.clone()).collect();
+
+        for level in levels {
+            let mut pending = Vec::new();
+            for index in level {
+                let member = &self.members[index];
+                let service_name = member.controller.service_name.to_string();
+                match member.depends_on.iter().find(|name| failed.contains(*name)) {
+                    Some(dependency) => {
+                        let error =
+                            Error::new(&format!("Dependency {} failed to start", dependency));
+                        failures.push((service_name, error));
+                    }
+                    None => {
+                        let handle = member.controller.start_background();
+                        pending.push((service_name, handle, member.timeout));
+                    }
+                }
+            }
+
+            for (service_name, handle, timeout) in pending {
+                if let Err(error) = handle.wait_timeout(timeout) {
+                    failures.push((service_name, error));
+                }
+            }
+
+            failed.extend(failures.iter().map(|(name, _)| name.clone()));
+        }
+
+        into_result(failures)
+    }
+
+    /// Stops the services in reverse dependency order. All the services are stopped even if
+    /// some of them fail to stop.
+    pub fn stop(&mut self) -> Result<(), GroupError> {
+        let (levels, mut failures) = self.levels();
+
+        for level in levels.into_iter().rev() {
+            let mut pending = Vec::new();
+            for index in level {
+                let member = &self.members[index];
+                let mut controller = member.controller.clone();
+                let (tx, rx) = mpsc::channel();
+                thread::spawn(move || {
+                    let _ = tx.send(controller.stop());
+                });
+                pending.push((
+                    member.controller.service_name.to_string(),
+                    rx,
+                    member.timeout,
+                ));
+            }
+
+            for (service_name, rx, timeout) in pending {
+                let result = match rx.recv_timeout(timeout) {
+                    Ok(result) => result,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        Err(Error::from_message(messages::STOP_TIMED_OUT, &[]))
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        Err(Error::new("Service stop thread panicked"))
+                    }
+                };
+                if let Err(error) = result {
+                    failures.push((service_name, error));
+                }
+            }
+        }
+
+        into_result(failures)
+    }
+
+    /// Sorts the members in levels, each level only depends on the previous ones. Members that
+    /// are part of a dependency cycle are reported as failures.
+    fn levels(&self) -> (Vec<Vec<usize>>, Vec<(String, Error)>) {
+        let names: HashSet<&str> = self
+            .members
+            .iter()
+            .map(|member| member.controller.service_name.as_ref())
+            .collect();
+        let mut placed: HashSet<&str> = HashSet::new();
+        let mut remaining: Vec<usize> = (0..self.members.len()).collect();
+        let mut levels = Vec::new();
+
+        while !remaining.is_empty() {
+            let (level, rest): (Vec<usize>, Vec<usize>) = remaining.iter().partition(|&&index| {
+                self.members[index]
+                    .depends_on
+                    .iter()
+                    .all(|name| !names.contains(name.as_str()) || placed.contains(name.as_str()))
+            });
+
+            if level.is_empty() {
+                let failures = rest
+                    .into_iter()
+                    .map(|index| {
+                        (
+                            self.members[index].controller.service_name.to_string(),
+                            Error::new("Dependency cycle"),
+                        )
+                    })
+                    .collect();
+                return (levels, failures);
+            }
+
+            placed.extend(
+                level
+                    .iter()
+                    .map(|&index| self.members[index].controller.service_name.as_ref()),
+            );
+            levels.push(level);
+            remaining = rest;
+        }
+
+        (levels, Vec::new())
+    }
+}
+
+fn into_result(failures: Vec<(String, Error)>) -> Result<(), GroupError> {
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(GroupError { failures })
+    }
+}
@@ -0,0 +1,152 @@
+// The dummy controller has no dispatcher, only `acquire()` is used there.
+#![cfg_attr(
+    not(any(
+        windows,
+        target_os = "macos",
+        target_os = "linux",
+        target_os = "illumos",
+        target_os = "solaris",
+        target_os = "aix"
+    )),
+    allow(dead_code)
+)]
+
+#[cfg(unix)]
+use std::env;
+#[cfg(unix)]
+use std::fs::{File, OpenOptions};
+use std::io;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(windows)]
+use widestring::WideCString;
+#[cfg(windows)]
+use winapi::shared::minwindef::FALSE;
+#[cfg(windows)]
+use winapi::shared::winerror::ERROR_ALREADY_EXISTS;
+#[cfg(windows)]
+use winapi::um::handleapi::CloseHandle;
+#[cfg(windows)]
+use winapi::um::synchapi::CreateMutexW;
+#[cfg(windows)]
+use winapi::um::winnt::HANDLE;
+
+use crate::Error;
+
+static SINGLE_INSTANCE: AtomicBool = AtomicBool::new(false);
+
+/// Guard held by the only running instance of a service, the next instance can start once it
+/// is dropped. It is a named mutex in the global namespace on Windows and an exclusive `flock`
+/// on a lock file in `/var/run` (the temporary directory for non-root users) on Unix.
+#[derive(Debug)]
+pub struct InstanceGuard {
+    #[cfg(windows)]
+    mutex: HANDLE,
+    #[cfg(unix)]
+    _file: File,
+}
+
+// The mutex handle is only closed on drop, it can be moved to another thread.
+#[cfg(windows)]
+unsafe impl Send for InstanceGuard {}
+
+#[cfg(windows)]
+impl Drop for InstanceGuard {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.mutex);
+        }
+    }
+}
+
+/// Acquires the single-instance guard of `service_name`, failing with
+/// `ErrorKind::AlreadyRunning` when another instance holds it. Services started by the system
+/// acquire it in the wrapper when `single_instance` is set on the controller, standalone
+/// launches call it before running the service main function.
+pub fn acquire(service_name: &str) -> Result<InstanceGuard, Error> {
+    cfg_if! {
+        if #[cfg(windows)] {
+            let name = WideCString::from_str(format!("Global\\ceviche.{}", service_name))
+                .map_err(|_| Error::new("Service name contains a null character"))?;
+            unsafe {
+                let mutex = CreateMutexW(std::ptr::null_mut(), FALSE, name.as_ptr());
+                let error = io::Error::last_os_error();
+                if mutex.is_null() {
+                    return Err(Error {
+                        function: Some("CreateMutexW".to_string()),
+                        ..Error::io("Failed to create the instance mutex", &error)
+                    });
+                }
+                if error.raw_os_error() == Some(ERROR_ALREADY_EXISTS as i32) {
+                    CloseHandle(mutex);
+                    return Err(Error::already_running(service_name));
+                }
+                Ok(InstanceGuard { mutex })
+            }
+        } else if #[cfg(unix)] {
+            let path = lock_file(service_name);
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .map_err(|e| Error::io(&format!("Failed to open {}", path.display()), &e))?;
+            if unsafe { lock(file.as_raw_fd()) } != 0 {
+                let error = io::Error::last_os_error();
+                // `lockf` fails with `EACCES` when the file is locked.
+                if error.kind() == io::ErrorKind::WouldBlock
+                    || error.raw_os_error() == Some(libc::EACCES)
+                {
+                    return Err(Error::already_running(service_name));
+                }
+                return Err(Error {
+                    function: Some(LOCK_FUNCTION.to_string()),
+                    ..Error::io(&format!("Failed to lock {}", path.display()), &error)
+                });
+            }
+            Ok(InstanceGuard { _file: file })
+        }
+    }
+}
+
+// Solaris has no `flock`, `lockf` takes a POSIX record lock on the whole file instead.
+#[cfg(all(unix, not(target_os = "solaris")))]
+const LOCK_FUNCTION: &str = "flock";
+#[cfg(target_os = "solaris")]
+const LOCK_FUNCTION: &str = "lockf";
+
+#[cfg(all(unix, not(target_os = "solaris")))]
+unsafe fn lock(fd: libc::c_int) -> libc::c_int {
+    libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB)
+}
+
+#[cfg(target_os = "solaris")]
+unsafe fn lock(fd: libc::c_int) -> libc::c_int {
+    libc::lockf(fd, libc::F_TLOCK, 0)
+}
+
+#[cfg(unix)]
+fn lock_file(service_name: &str) -> PathBuf {
+    let dir = if unsafe { libc::geteuid() } == 0 {
+        PathBuf::from("/var/run")
+    } else {
+        env::temp_dir()
+    };
+    dir.join(format!("{}.lock", service_name))
+}
+
+pub(crate) fn set_single_instance(single_instance: bool) {
+    SINGLE_INSTANCE.store(single_instance, Ordering::Relaxed);
+}
+
+/// Acquires the guard for the dispatcher when `single_instance` is set on the controller.
+pub(crate) fn acquire_for_service(service_name: &str) -> Result<Option<InstanceGuard>, Error> {
+    if !SINGLE_INSTANCE.load(Ordering::Relaxed) {
+        return Ok(None);
+    }
+    acquire(service_name).map(Some)
+}
@@ -0,0 +1,181 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::warn;
+
+use crate::channel;
+use crate::controller::ServiceMain;
+use crate::standalone;
+use crate::stop;
+use crate::{Error, StopReason};
+
+/// Time the kubelet waits after `SIGTERM` before it kills the container when the pod doesn't
+/// set `terminationGracePeriodSeconds`.
+pub const DEFAULT_TERMINATION_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// How the service stops when its pod is deleted. The kubelet sends `SIGTERM` and kills the
+/// container once the termination grace period of the pod elapsed: the service receives
+/// `StopRequested` on `SIGTERM`, is marked as not ready so that it is removed from the
+/// endpoints of its services while it drains its work, and receives `StopNow` `exit_margin`
+/// before the kubelet kills it. A second `SIGTERM` or a `SIGINT` sends `StopNow` right away.
+#[derive(Clone)]
+pub struct Termination {
+    /// `terminationGracePeriodSeconds` of the pod.
+    pub grace_period: Duration,
+    /// Time left to the service main function to return after `StopNow`.
+    pub exit_margin: Duration,
+    /// Readiness marked as not ready on `SIGTERM`.
+    pub readiness: Option<Readiness>,
+}
+
+impl Termination {
+    pub fn new(grace_period: Duration) -> Termination {
+        Termination {
+            grace_period,
+            exit_margin: Duration::from_secs(5),
+            readiness: None,
+        }
+    }
+}
+
+impl Default for Termination {
+    fn default() -> Self {
+        Termination::new(DEFAULT_TERMINATION_GRACE_PERIOD)
+    }
+}
+
+#[derive(Clone)]
+enum Probe {
+    File(PathBuf),
+    Endpoint,
+}
+
+/// Readiness of the service as seen by the readiness probe of its pod, either a file checked
+/// by an `exec` probe or an HTTP endpoint checked by an `httpGet` or `tcpSocket` probe. The
+/// service is not ready until `set_ready(true)` is called, the clones share the readiness.
+#[derive(Clone)]
+pub struct Readiness {
+    ready: Arc<AtomicBool>,
+    probe: Probe,
+}
+
+impl Readiness {
+    /// Readiness present as the file `path`, to be checked with a probe such as
+    /// `exec: {command: [cat, /tmp/ready]}`. The file is removed when the service isn't ready.
+    pub fn file(path: impl Into<PathBuf>) -> Readiness {
+        let readiness = Readiness {
+            ready: Arc::new(AtomicBool::new(false)),
+            probe: Probe::File(path.into()),
+        };
+        readiness.set_ready(false);
+        readiness
+    }
+
+    /// Readiness served over HTTP on `address`, such as `0.0.0.0:8081`: every request is
+    /// answered with the status 200 while the service is ready and 503 otherwise.
+    pub fn endpoint(address: impl ToSocketAddrs) -> Result<Readiness, Error> {
+        let listener = TcpListener::bind(address)
+            .map_err(|e| Error::io("Failed to bind the readiness endpoint", &e))?;
+        let ready = Arc::new(AtomicBool::new(false));
+        let serving = ready.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request);
+                let status = if serving.load(Ordering::SeqCst) {
+                    "200 OK"
+                } else {
+                    "503 Service Unavailable"
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.0 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+            }
+        });
+        Ok(Readiness {
+            ready,
+            probe: Probe::Endpoint,
+        })
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::SeqCst);
+        if let Probe::File(path) = &self.probe {
+            let result = if ready {
+                File::create(path).map(|_| ())
+            } else {
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                    result => result,
+                }
+            };
+            if let Err(e) = result {
+                warn!(
+                    "Failed to update the readiness file {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+}
+
+/// Runs `service_main` as the entrypoint of a pod container, like
+/// `standalone::run_in_container()`, with the two-phase stop described by `termination`.
+/// `ServiceContext::delay_stop()` and `cancel_stop()` work as for an installed service, the
+/// service marks itself as ready again after cancelling the stop. Returns the exit code of
+/// the service main function:
+///
+/// ```rust,ignore
+/// let readiness = Readiness::endpoint("0.0.0.0:8081")?;
+/// let termination = Termination {
+///     readiness: Some(readiness.clone()),
+///     ..Termination::new(Duration::from_secs(60))
+/// };
+/// process::exit(kubernetes::run(SERVICE_NAME, vec![], termination, my_service_main) as i32);
+/// ```
+pub fn run<T: Send + 'static, F: ServiceMain<T>>(
+    service_name: &str,
+    args: Vec<String>,
+    termination: Termination,
+    service_main: F,
+) -> u32 {
+    stop::set_grace_period(Some(
+        termination
+            .grace_period
+            .saturating_sub(termination.exit_margin),
+    ));
+    let (sender, rx, tx) = channel::channel();
+    let readiness = termination.readiness;
+    let stop = Box::new(move || {
+        if let Some(readiness) = &readiness {
+            readiness.set_ready(false);
+        }
+        stop::request(&sender, StopReason::Requested);
+    });
+    standalone::run_with(
+        service_name,
+        args,
+        service_main,
+        (rx, tx),
+        stop,
+        standalone::is_init(),
+    )
+}
@@ -12,12 +12,11 @@
 //! fn my_service_main(
 //!     rx: mpsc::Receiver<ServiceEvent<CustomServiceEvent>>,
 //!     _tx: mpsc::Sender<ServiceEvent<CustomServiceEvent>>,
-//!     args: Vec<String>,
-//!     standalone_mode: bool) -> u32 {
+//!     ctx: ServiceContext) -> u32 {
 //!    loop {
 //!        if let Ok(control_code) = rx.recv() {
 //!            match control_code {
-//!                ServiceEvent::Stop => break,
+//!                ServiceEvent::Stop { .. } => break,
 //!                _ => (),
 //!            }
 //!        }
@@ -28,6 +27,34 @@
 //! Service!("Foobar", my_service_main);
 //! ```
 //!
+//! Besides a function, `Service!` accepts a method such as `MyService::run` or a closure
+//! building what the service needs when it starts, so the configuration doesn't have to live
+//! in statics:
+//!
+//! ```rust,ignore
+//! Service!("Foobar", {
+//!     let config = Config::load();
+//!     move |rx, tx, ctx| MyService::new(config).run(rx, tx, ctx)
+//! });
+//! ```
+//!
+//! Service main functions taking `args: Vec<String>, standalone_mode: bool` instead of the
+//! context are still supported with `Service!("Foobar", legacy my_service_main);`.
+//! `run_event_loop()` implements the usual event loop of a service main function, calling the
+//! methods of an `EventHandler` for the events the service handles.
+//!
+//! On Windows, `Service!("Foobar", my_service_main, stack_size = 8 * 1024 * 1024);` runs the
+//! service main function on a thread with a larger stack than the thread created by the SCM.
+//!
+//! The session change events such as `SessionLogon` require the `session-events` feature,
+//! enabled by default. Without it the `session` module and the session events are left out
+//! and Windows services don't register for session change notifications.
+//!
+//! The `install` feature provides the `ControllerInterface` used to create, remove, start or
+//! stop the service and the `runtime` feature provides the dispatcher and the `Service!`
+//! macro, both are enabled by default. Agents that never install themselves can be built with
+//! `default-features = false, features = ["runtime"]` to leave out the configuration APIs.
+//!
 //! The Controller is a helper to create, remove, start or stop the service
 //! on the system. ceviche also supports a standalone mode were the service
 //! code runs as a normal executable which can be useful for development and
@@ -52,13 +79,7 @@
 //!         "start" => controller.start(),
 //!         "stop" => controller.stop(),
 //!         "standalone" => {
-//!             let (tx, rx) = mpsc::channel();
-//!
-//!             ctrlc::set_handler(move || {
-//!                 let _ = tx.send(ServiceEvent::Stop);
-//!             }).expect("Failed to register Ctrl-C handler");
-//!
-//!             my_service_main(rx, vec![], true);
+//!             standalone::run(SERVICE_NAME, vec![], my_service_main);
 //!         }
 //!         _ => {
 //!             let _result = controller.register(service_main_wrapper);
@@ -67,86 +88,523 @@
 //! }
 //!
 //! ```
+//!
+//! `standalone::run()` stops the service on Ctrl-C, the close of the console and the
+//! termination signals. A standalone instance started by another supervisor or a debugger can
+//! also be stopped along with its parent process with `standalone::stop_on_parent_exit(tx)`.
+
+// A crate built with only one of the install and runtime features leaves the helpers of the
+// other half unused.
+#![cfg_attr(
+    not(all(feature = "install", feature = "runtime")),
+    allow(dead_code, unused_imports)
+)]
 
 #[macro_use]
 extern crate cfg_if;
 
+/// Audit log of the controller operations.
+pub mod audit;
+/// Event delivery between the system and the service.
+pub mod channel;
+mod clock;
+/// Information passed to the service main function.
+pub mod context;
 /// Manages the service on the system.
 pub mod controller;
+/// Core dump configuration for Unix services.
+#[cfg(unix)]
+pub mod coredump;
+mod deadline;
+#[cfg(any(
+    windows,
+    target_os = "macos",
+    target_os = "linux",
+    target_os = "illumos",
+    target_os = "solaris",
+    target_os = "aix"
+))]
+mod dependency;
+mod dry_run;
+mod elevation;
+mod event_loop;
+/// Starts and stops several services in dependency order.
+#[cfg(feature = "install")]
+pub mod group;
+/// Single-instance guard for services.
+pub mod instance;
+/// Graceful termination of services running in Kubernetes pods.
+#[cfg(any(windows, unix))]
+pub mod kubernetes;
+/// Rotating file logger for services.
+pub mod logging;
+/// Translations of the user-facing messages.
+pub mod messages;
+mod pause;
+mod restart;
+mod ring;
+mod run_once;
+mod secret;
+/// Sessions reported by the session change events.
+#[cfg(feature = "session-events")]
 pub mod session;
+/// Shutdown token set when the service is asked to stop.
+pub mod shutdown;
+/// Helpers of the standalone mode.
+#[cfg(any(windows, unix))]
+pub mod standalone;
+mod startup_gate;
+/// Service state kept across restarts.
+#[cfg(feature = "state")]
+pub mod state;
+/// Status of an installed service.
+pub mod status;
+mod stop;
+/// Scripted runs of a service main function for integration tests.
+#[cfg(any(test, feature = "test-util"))]
+pub mod test;
+/// Versioned serialization of events for control tooling.
+#[cfg(feature = "serde")]
+pub mod wire;
 
+#[cfg(any(
+    windows,
+    target_os = "macos",
+    target_os = "linux",
+    target_os = "illumos",
+    target_os = "solaris",
+    target_os = "aix"
+))]
+pub use self::dependency::{wait_for_service, wait_for_service_async, WaitService};
+pub use self::dry_run::is_dry_run;
+pub use self::elevation::is_elevated;
+pub use self::event_loop::{run_event_loop, EventHandler};
+pub use self::run_once::RunOnce;
+pub use self::secret::Secret;
+pub use self::startup_gate::StartupGate;
 #[cfg(windows)]
 pub use winapi;
 
+#[cfg(feature = "session-events")]
 use self::controller::Session;
 use std::fmt;
+use std::io;
+
+/// Result type returned by the controllers, usable with `?` in service mains and installers.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Category of an error, for the errors callers are expected to handle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// Another instance of the service holds the single-instance guard.
+    AlreadyRunning,
+    /// The operation requires administrator or root privileges, see `is_elevated()`.
+    NotElevated,
+    /// An operation of the standard library failed, `source()` returns its `io::Error`.
+    Io,
+    /// A system function failed with the OS error `os_error`.
+    Os,
+    /// `create()` found a service with the same name and another configuration, `source()`
+    /// returns the `controller::ConflictingService` listing the differences.
+    ConflictingService,
+    /// A service or display name was rejected by `Controller::try_new()`, see
+    /// `controller::validate_service_name()`.
+    InvalidName,
+    Other,
+}
 
 /// Service errors
+///
+/// `Error` implements `source()` instead of the deprecated `description()`, which it no longer
+/// provides: use its `Display` implementation or the `message` field for the description. The
+/// `io::Error` of a failed file or process operation is available through `source()`.
+///
+/// `Display` translates the message with the table set by `messages::set_message_table()`,
+/// the `message` field is always in English.
 #[derive(Debug)]
 pub struct Error {
+    pub kind: ErrorKind,
     pub message: String,
+    /// Controller operation that failed, such as `create` or `start`.
+    pub operation: Option<&'static str>,
+    /// System function or command that failed.
+    pub function: Option<String>,
+    /// `GetLastError()` on Windows, `errno` on the other platforms.
+    pub os_error: Option<i32>,
+    source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    // Message of the table and its arguments, for the translation.
+    localizable: Option<Box<(messages::Message, Vec<String>)>>,
 }
 
 impl From<&str> for Error {
     fn from(message: &str) -> Self {
+        Error::new(message)
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::new(&message)
+    }
+}
+
+/// Errors returned by the standard library, Win32 error codes can be converted with
+/// `std::io::Error::from_raw_os_error()` and `std::io::Error::last_os_error()`.
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
         Error {
-            message: message.to_string(),
+            kind: ErrorKind::Io,
+            message: error.to_string(),
+            operation: None,
+            function: None,
+            os_error: error.raw_os_error(),
+            source: Some(Box::new(error)),
+            localizable: None,
         }
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "{}", self.message,)
+        if let Some(operation) = self.operation {
+            write!(f, "{}: ", messages::operation(operation))?;
+        }
+        write!(f, "{}", self.localized_message())
     }
 }
 
 impl std::error::Error for Error {
-    fn description(&self) -> &str {
-        &self.message
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|source| source as &(dyn std::error::Error + 'static))
     }
 }
 
 impl Error {
     pub fn new(message: &str) -> Error {
         Error {
+            kind: ErrorKind::Other,
             message: String::from(message),
+            operation: None,
+            function: None,
+            os_error: None,
+            source: None,
+            localizable: None,
+        }
+    }
+
+    /// Error reported by the system function or command `function` with the OS error `code`.
+    pub fn os(function: &str, code: i32, message: &str) -> Error {
+        Error {
+            kind: ErrorKind::Os,
+            message: format!("{}: {} (os error {})", function, message.trim_end(), code),
+            operation: None,
+            function: Some(function.to_string()),
+            os_error: Some(code),
+            source: None,
+            localizable: None,
+        }
+    }
+
+    /// Error returned by the standard library, keeping its `errno`. `source()` returns an
+    /// `io::Error` of the same kind and OS error.
+    pub fn io(message: &str, error: &io::Error) -> Error {
+        let source = match error.raw_os_error() {
+            Some(code) => io::Error::from_raw_os_error(code),
+            None => io::Error::new(error.kind(), error.to_string()),
+        };
+        Error {
+            kind: ErrorKind::Io,
+            message: format!("{}: {}", message, error),
+            operation: None,
+            function: None,
+            os_error: error.raw_os_error(),
+            source: Some(Box::new(source)),
+            localizable: None,
+        }
+    }
+
+    /// Error described by `message` of the message tables with `args`.
+    pub fn from_message(message: messages::Message, args: &[&dyn fmt::Display]) -> Error {
+        let args = messages::args(args);
+        Error {
+            localizable: Some(Box::new((message, args.clone()))),
+            ..Error::new(&messages::english(message, &args))
         }
     }
+
+    /// Id of the message of the tables describing the error, such as `not-running`.
+    pub fn message_id(&self) -> Option<&'static str> {
+        self.localizable
+            .as_ref()
+            .map(|localizable| localizable.0.id)
+    }
+
+    /// Message translated with the table of `messages::set_message_table()`, the English
+    /// `message` when the table doesn't translate it.
+    pub fn localized_message(&self) -> String {
+        self.localizable
+            .as_ref()
+            .and_then(|localizable| messages::translate(localizable.0, &localizable.1))
+            .unwrap_or_else(|| self.message.clone())
+    }
+
+    /// Error returned when another instance of the service is running.
+    pub fn already_running(service_name: &str) -> Error {
+        Error {
+            kind: ErrorKind::AlreadyRunning,
+            ..Error::from_message(messages::ALREADY_RUNNING, &[&service_name])
+        }
+    }
+
+    /// Error returned by the controller operations that require privileges the process doesn't
+    /// have.
+    pub fn not_elevated() -> Error {
+        let message = if cfg!(windows) {
+            messages::NOT_ELEVATED
+        } else {
+            messages::NOT_ROOT
+        };
+        Error {
+            kind: ErrorKind::NotElevated,
+            ..Error::from_message(message, &[])
+        }
+    }
+
+    /// Records the error that caused this one, returned by `source()`.
+    pub fn with_source(mut self, source: impl std::error::Error + Send + Sync + 'static) -> Error {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// Records the controller operation that failed, the innermost operation is kept.
+    pub fn with_operation(mut self, operation: &'static str) -> Error {
+        self.operation.get_or_insert(operation);
+        self
+    }
+
+    /// Describes the error as a JSON object with its `kind`, `message`, `operation`,
+    /// `function` and `os_error`.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> String {
+        self.json_value().to_string()
+    }
+
+    #[cfg(feature = "json")]
+    fn json_value(&self) -> serde_json::Value {
+        serde_json::json!({
+            "kind": format!("{:?}", self.kind),
+            "message": self.message,
+            "operation": self.operation,
+            "function": self.function,
+            "os_error": self.os_error,
+        })
+    }
+}
+
+/// Describes the outcome of a controller operation as a JSON object, `{"success": true}` or
+/// `{"success": false, "error": ...}` with the error of `Error::to_json()`, so configuration
+/// management tools (Ansible, Salt) can parse it.
+#[cfg(feature = "json")]
+pub fn result_to_json(result: &Result<()>) -> String {
+    match result {
+        Ok(()) => serde_json::json!({ "success": true }),
+        Err(e) => serde_json::json!({ "success": false, "error": e.json_value() }),
+    }
+    .to_string()
 }
 
 /// Events that are sent to the service.
+///
+/// With the `serde` feature, events can be serialized when `T` implements `Serialize` and
+/// `Deserialize`, see the `wire` module for the format used by control tooling.
+///
+/// Events are produced on threads owned by the system (control handler, session monitors,
+/// signal handlers), `ServiceEvent<T>` is `Send` as long as the custom event type `T` is.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ServiceEvent<T> {
+    /// The service is asked to resume. When `acknowledge_pause` is set on the Windows
+    /// controller, the service reports that it resumed with `ServiceContext::continue_completed()`.
     Continue,
+    /// The service is asked to pause. When `acknowledge_pause` is set on the Windows
+    /// controller, the service reports that it paused with `ServiceContext::pause_completed()`.
     Pause,
-    Stop,
+    /// The service is asked to stop, the service main function must return. `reason` is
+    /// `None` when the event was sent by the service itself.
+    Stop {
+        reason: Option<StopReason>,
+    },
+    /// First phase of a two-phase stop, sent instead of `Stop` when a stop grace period is set
+    /// on the controller. The service starts draining its work and can delay or cancel the
+    /// stop with `ServiceContext::delay_stop()` and `ServiceContext::cancel_stop()`.
+    StopRequested,
+    /// Second phase of a two-phase stop: the grace period ended or the system is shutting down,
+    /// the service main function must return.
+    StopNow,
+    #[cfg(feature = "session-events")]
     SessionConnect(Session),
+    #[cfg(feature = "session-events")]
     SessionDisconnect(Session),
+    #[cfg(feature = "session-events")]
     SessionRemoteConnect(Session),
+    #[cfg(feature = "session-events")]
     SessionRemoteDisconnect(Session),
+    #[cfg(feature = "session-events")]
     SessionLogon(Session),
+    #[cfg(feature = "session-events")]
     SessionLogoff(Session),
+    #[cfg(feature = "session-events")]
     SessionLock(Session),
+    #[cfg(feature = "session-events")]
     SessionUnlock(Session),
+    /// No input was received in the session for the `idle_threshold` of the Windows
+    /// controller.
+    #[cfg(feature = "session-events")]
+    SessionIdle(Session),
+    /// The user of a session reported by `SessionIdle` is active again.
+    #[cfg(feature = "session-events")]
+    SessionActive(Session),
+    NetBindChange(NetBindChange),
+    PowerEvent(PowerEvent),
+    /// The service is asked to release the resources it doesn't need, sent on Windows to the
+    /// services of failover clusters and memory sensitive deployments.
+    LowResources,
+    /// The system is running low on resources, the service should reduce its usage.
+    SystemLowResources,
+    /// The failover cluster group of the service is going offline or moved to another node,
+    /// sent on Windows when `cluster_group` is set on the controller. The service releases
+    /// what the instance on the next node needs, such as files on the cluster disks, before
+    /// the cluster stops it.
+    ClusterFailover {
+        group: String,
+    },
+    /// The resolution or the color depth of the display changed, or a monitor was added or
+    /// removed, sent on Windows when `display_events` is set on the controller.
+    DisplayChange {
+        width: u32,
+        height: u32,
+        bits_per_pixel: u32,
+    },
     Custom(T),
 }
 
+/// Why the service is asked to stop, see `ServiceEvent::Stop`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum StopReason {
+    /// The stop was requested through the service manager, such as with `sc stop` or
+    /// `systemctl stop`, or with a termination signal or Ctrl-C.
+    Requested,
+    /// The system is shutting down or restarting, for instance to install updates.
+    SystemShutdown,
+    /// The parent process of a standalone instance exited, see
+    /// `standalone::stop_on_parent_exit()`.
+    ParentExited,
+    /// The startup gate of the controller was still closed after the startup timeout.
+    StartupTimeout,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            StopReason::Requested => write!(f, "Requested"),
+            StopReason::SystemShutdown => write!(f, "SystemShutdown"),
+            StopReason::ParentExited => write!(f, "ParentExited"),
+            StopReason::StartupTimeout => write!(f, "StartupTimeout"),
+        }
+    }
+}
+
+/// Kind of network binding change, see `ServiceEvent::NetBindChange`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NetBindChange {
+    /// A new network component was bound to the service.
+    Add,
+    /// A network component was unbound from the service.
+    Remove,
+    /// A previously disabled binding was enabled.
+    Enable,
+    /// A binding was disabled.
+    Disable,
+}
+
+impl fmt::Display for NetBindChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            NetBindChange::Add => write!(f, "Add"),
+            NetBindChange::Remove => write!(f, "Remove"),
+            NetBindChange::Enable => write!(f, "Enable"),
+            NetBindChange::Disable => write!(f, "Disable"),
+        }
+    }
+}
+
+/// Power state change of the system, see `ServiceEvent::PowerEvent`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PowerEvent {
+    /// The system is about to sleep.
+    Suspend,
+    /// The system woke up from sleep.
+    Resume,
+}
+
+impl fmt::Display for PowerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            PowerEvent::Suspend => write!(f, "Suspend"),
+            PowerEvent::Resume => write!(f, "Resume"),
+        }
+    }
+}
+
 impl<T> fmt::Display for ServiceEvent<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match self {
             ServiceEvent::Continue => write!(f, "Continue"),
             ServiceEvent::Pause => write!(f, "Pause"),
-            ServiceEvent::Stop => write!(f, "Stop"),
+            ServiceEvent::Stop {
+                reason: Some(reason),
+            } => write!(f, "Stop({})", reason),
+            ServiceEvent::Stop { reason: None } => write!(f, "Stop"),
+            ServiceEvent::StopRequested => write!(f, "StopRequested"),
+            ServiceEvent::StopNow => write!(f, "StopNow"),
+            #[cfg(feature = "session-events")]
             ServiceEvent::SessionConnect(id) => write!(f, "SessionConnect({})", id),
+            #[cfg(feature = "session-events")]
             ServiceEvent::SessionDisconnect(id) => write!(f, "SessionDisconnect({})", id),
+            #[cfg(feature = "session-events")]
             ServiceEvent::SessionRemoteConnect(id) => write!(f, "SessionRemoteConnect({})", id),
+            #[cfg(feature = "session-events")]
             ServiceEvent::SessionRemoteDisconnect(id) => {
                 write!(f, "SessionRemoteDisconnect({})", id)
             }
+            #[cfg(feature = "session-events")]
             ServiceEvent::SessionLogon(id) => write!(f, "SessionLogon({})", id),
+            #[cfg(feature = "session-events")]
             ServiceEvent::SessionLogoff(id) => write!(f, "SessionLogoff({})", id),
+            #[cfg(feature = "session-events")]
             ServiceEvent::SessionLock(id) => write!(f, "SessionLock({})", id),
+            #[cfg(feature = "session-events")]
             ServiceEvent::SessionUnlock(id) => write!(f, "SessionUnlock({})", id),
+            #[cfg(feature = "session-events")]
+            ServiceEvent::SessionIdle(id) => write!(f, "SessionIdle({})", id),
+            #[cfg(feature = "session-events")]
+            ServiceEvent::SessionActive(id) => write!(f, "SessionActive({})", id),
+            ServiceEvent::NetBindChange(kind) => write!(f, "NetBindChange({})", kind),
+            ServiceEvent::PowerEvent(event) => write!(f, "PowerEvent({})", event),
+            ServiceEvent::LowResources => write!(f, "LowResources"),
+            ServiceEvent::SystemLowResources => write!(f, "SystemLowResources"),
+            ServiceEvent::ClusterFailover { group } => write!(f, "ClusterFailover({})", group),
+            ServiceEvent::DisplayChange {
+                width,
+                height,
+                bits_per_pixel,
+            } => write!(f, "DisplayChange({}x{}x{})", width, height, bits_per_pixel),
             ServiceEvent::Custom(_) => write!(f, "Custom"),
         }
     }
@@ -0,0 +1,338 @@
+// The dummy controller has no dispatcher, only the logger itself is used there.
+#![cfg_attr(
+    not(any(
+        windows,
+        target_os = "macos",
+        target_os = "linux",
+        target_os = "illumos",
+        target_os = "solaris",
+        target_os = "aix"
+    )),
+    allow(dead_code)
+)]
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::context::ServiceContext;
+
+static LOG_CONFIG: Mutex<Option<LogConfig>> = Mutex::new(None);
+
+/// Rotates the log file when a new period starts, periods are in UTC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+/// Configuration of the rotating file logger initialized by the service wrapper.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogConfig {
+    /// Most verbose level written to the log file.
+    pub level: LevelFilter,
+    /// Rotates the log file when it grows past this size in bytes, `None` to disable.
+    pub max_size: Option<u64>,
+    pub rotation: Rotation,
+    /// Number of rotated log files kept, older files are deleted.
+    pub retention: usize,
+    /// Compresses the rotated log files with gzip.
+    #[cfg(feature = "log-compression")]
+    pub compress: bool,
+}
+
+impl LogConfig {
+    pub const fn new() -> Self {
+        LogConfig {
+            level: LevelFilter::Info,
+            max_size: Some(10 * 1024 * 1024),
+            rotation: Rotation::Never,
+            retention: 5,
+            #[cfg(feature = "log-compression")]
+            compress: false,
+        }
+    }
+
+    fn compress(&self) -> bool {
+        cfg_if! {
+            if #[cfg(feature = "log-compression")] {
+                self.compress
+            } else {
+                false
+            }
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig::new()
+    }
+}
+
+pub(crate) fn set_log_config(config: Option<LogConfig>) {
+    *LOG_CONFIG.lock().unwrap() = config;
+}
+
+/// Installs the rotating file logger configured on the controller, writing to
+/// `<log_dir>/<service_name>.log`. The logger is not installed in standalone mode.
+pub(crate) fn init_service_logger(ctx: &mut ServiceContext) {
+    let config = match *LOG_CONFIG.lock().unwrap() {
+        Some(config) if !ctx.is_standalone() => config,
+        _ => return,
+    };
+
+    let path = ctx.log_dir().join(format!("{}.log", ctx.service_name()));
+    match RotatingFileLogger::new(&path, config) {
+        Ok(logger) => {
+            let handle = LoggerHandle(Arc::new(logger));
+            if log::set_boxed_logger(Box::new(handle.clone())).is_ok() {
+                log::set_max_level(config.level);
+                ctx.set_logger(handle);
+            }
+        }
+        Err(e) => eprintln!("Failed to open the log file {}: {}", path.display(), e),
+    }
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+    period: u64,
+}
+
+/// Logger writing to a file that is rotated by size or time, keeping a limited number of
+/// rotated files named `<file>.1`, `<file>.2`, ... from the newest to the oldest.
+pub struct RotatingFileLogger {
+    path: PathBuf,
+    config: LogConfig,
+    file: Mutex<LogFile>,
+}
+
+impl RotatingFileLogger {
+    pub fn new<P: AsRef<Path>>(path: P, config: LogConfig) -> io::Result<RotatingFileLogger> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let file = open(&path)?;
+        let metadata = file.metadata()?;
+        let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+        let period = period(config.rotation, modified);
+
+        Ok(RotatingFileLogger {
+            path,
+            config,
+            file: Mutex::new(LogFile {
+                file,
+                size: metadata.len(),
+                period,
+            }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Rotates the log file immediately.
+    pub fn rotate(&self) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        self.rotate_file(&mut file)
+    }
+
+    fn rotate_file(&self, file: &mut LogFile) -> io::Result<()> {
+        file.file.flush()?;
+
+        let suffix = if self.config.compress() { ".gz" } else { "" };
+        if self.config.retention > 0 {
+            for index in (1..self.config.retention).rev() {
+                let from = self.rotated_path(index, suffix);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1, suffix))?;
+                }
+            }
+            let rotated = self.rotated_path(1, "");
+            fs::rename(&self.path, &rotated)?;
+            if self.config.compress() {
+                compress(&rotated)?;
+            }
+        }
+
+        file.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        file.size = 0;
+        file.period = period(self.config.rotation, SystemTime::now());
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize, suffix: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}{}", index, suffix));
+        PathBuf::from(path)
+    }
+
+    fn write(&self, line: &[u8]) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+
+        let size_exceeded = self
+            .config
+            .max_size
+            .is_some_and(|max_size| file.size > 0 && file.size + line.len() as u64 > max_size);
+        let period_ended = self.config.rotation != Rotation::Never
+            && period(self.config.rotation, SystemTime::now()) != file.period;
+        if size_exceeded || period_ended {
+            self.rotate_file(&mut file)?;
+        }
+
+        file.file.write_all(line)?;
+        file.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl Log for RotatingFileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.config.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = format!(
+            "{} {} [{}] {}\n",
+            format_timestamp(SystemTime::now()),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        if let Err(e) = self.write(line.as_bytes()) {
+            eprintln!("Failed to write to {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().file.flush();
+    }
+}
+
+/// Shared handle to the logger installed by the service wrapper.
+#[derive(Clone)]
+pub struct LoggerHandle(Arc<RotatingFileLogger>);
+
+impl LoggerHandle {
+    /// Path of the current log file.
+    pub fn path(&self) -> &Path {
+        self.0.path()
+    }
+
+    /// Rotates the log file immediately, for example when requested by the administrator.
+    pub fn rotate(&self) -> io::Result<()> {
+        self.0.rotate()
+    }
+
+    pub fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+impl Log for LoggerHandle {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.log(record)
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+impl fmt::Debug for LoggerHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LoggerHandle")
+            .field("path", &self.0.path)
+            .finish()
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn period(rotation: Rotation, time: SystemTime) -> u64 {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    match rotation {
+        Rotation::Never => 0,
+        Rotation::Hourly => secs / 3600,
+        Rotation::Daily => secs / 86400,
+    }
+}
+
+#[cfg(feature = "log-compression")]
+fn compress(path: &Path) -> io::Result<()> {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    let mut compressed = path.to_path_buf().into_os_string();
+    compressed.push(".gz");
+
+    let mut input = File::open(path)?;
+    let mut encoder = GzEncoder::new(File::create(compressed)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(path)
+}
+
+#[cfg(not(feature = "log-compression"))]
+fn compress(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Formats `time` as `YYYY-MM-DD HH:MM:SS` in UTC.
+fn format_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    // Converts the number of days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
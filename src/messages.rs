@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::RwLock;
+
+use crate::Error;
+
+/// User-facing message of the controller, identified by `id` in the message tables. `text`
+/// is the English message, `{0}`, `{1}`... are replaced by the arguments of the message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub id: &'static str,
+    pub text: &'static str,
+}
+
+impl Message {
+    const fn new(id: &'static str, text: &'static str) -> Message {
+        Message { id, text }
+    }
+}
+
+pub const ALREADY_RUNNING: Message = Message::new("already-running", "{0} is already running");
+pub const NOT_ELEVATED: Message = Message::new(
+    "not-elevated",
+    "Administrator privileges are required, run the program from an elevated prompt",
+);
+pub const NOT_ROOT: Message = Message::new(
+    "not-root",
+    "Root privileges are required, run the program as root or with sudo",
+);
+pub const NOT_RUNNING: Message = Message::new("not-running", "Service is not running");
+pub const SHARED_PROCESS: Message = Message::new(
+    "shared-process",
+    "{0} shares its process with other services, which killing it would kill too",
+);
+pub const START_FAILED: Message = Message::new("start-failed", "Failed to start service");
+pub const STOP_FAILED: Message = Message::new("stop-failed", "Failed to stop service");
+pub const START_TIMED_OUT: Message = Message::new(
+    "start-timed-out",
+    "Timed out waiting for the service to start",
+);
+pub const STOP_TIMED_OUT: Message = Message::new(
+    "stop-timed-out",
+    "Timed out waiting for the service to stop",
+);
+pub const DEPENDENCY_TIMED_OUT: Message =
+    Message::new("dependency-timed-out", "Timed out waiting for {0} to run");
+pub const DRIVER_START_TYPE: Message = Message::new(
+    "driver-start-type",
+    "Boot and system start types are only supported by driver services",
+);
+pub const REMOTE_BINARY_PATH: Message = Message::new(
+    "remote-binary-path",
+    "The binary path must be set to create a service on a remote host",
+);
+pub const UNSUPPORTED_ARCH: Message = Message::new(
+    "unsupported-arch",
+    "{0} is built for {1} and can't run on {2}",
+);
+pub const INTERACTIVE_SERVICE: Message = Message::new(
+    "interactive-service",
+    "SERVICE_INTERACTIVE_PROCESS is not supported: since Windows Vista services run in the \
+     isolated session 0 and can't display a user interface to the users, start the user \
+     interface in the session of the user with session::spawn_in_session() instead",
+);
+
+/// Every message of the crate, to write the table translators start from with
+/// `MessageTable::english()`.
+pub const MESSAGES: &[Message] = &[
+    ALREADY_RUNNING,
+    NOT_ELEVATED,
+    NOT_ROOT,
+    NOT_RUNNING,
+    SHARED_PROCESS,
+    START_FAILED,
+    STOP_FAILED,
+    START_TIMED_OUT,
+    STOP_TIMED_OUT,
+    DEPENDENCY_TIMED_OUT,
+    DRIVER_START_TYPE,
+    REMOTE_BINARY_PATH,
+    UNSUPPORTED_ARCH,
+    INTERACTIVE_SERVICE,
+];
+
+// Operations recorded in the errors are translated as `operation-<name>`, such as
+// `operation-create`.
+const OPERATION_PREFIX: &str = "operation-";
+
+static TABLE: RwLock<Option<MessageTable>> = RwLock::new(None);
+
+/// Translations of the messages, keyed by message id. The text format is the `id = text`
+/// subset of Fluent, one message per line with `#` starting a comment:
+///
+/// ```text
+/// # French
+/// not-running = Le service n'est pas démarré
+/// already-running = {0} est déjà démarré
+/// operation-start = démarrage
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MessageTable {
+    entries: HashMap<String, String>,
+}
+
+impl MessageTable {
+    /// Parses a table, fails on the first line that isn't a message, a comment or blank.
+    pub fn parse(text: &str) -> Result<MessageTable, Error> {
+        let mut table = MessageTable::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((id, text)) if !id.trim().is_empty() => {
+                    table.insert(id.trim(), text.trim());
+                }
+                _ => {
+                    return Err(Error::new(&format!(
+                        "Line {} of the message table is not an `id = text` message",
+                        index + 1
+                    )))
+                }
+            }
+        }
+        Ok(table)
+    }
+
+    /// Table of the English messages, in the text format of `parse()`.
+    pub fn english() -> String {
+        MESSAGES
+            .iter()
+            .map(|message| format!("{} = {}\n", message.id, message.text))
+            .collect()
+    }
+
+    pub fn insert(&mut self, id: &str, text: &str) {
+        self.entries.insert(id.to_string(), text.to_string());
+    }
+
+    pub fn get(&self, id: &str) -> Option<&str> {
+        self.entries.get(id).map(String::as_str)
+    }
+}
+
+/// Sets the table the messages of the errors are translated with, `None` restores the
+/// English messages. The table applies to the whole process.
+pub fn set_message_table(table: Option<MessageTable>) {
+    *TABLE.write().unwrap() = table;
+}
+
+/// English text of `message` with `args`, as stored in `Error::message`.
+pub(crate) fn english(message: Message, args: &[String]) -> String {
+    substitute(message.text, args)
+}
+
+/// Text of `message` with `args` in the language of the message table, `None` when the table
+/// doesn't translate it.
+pub(crate) fn translate(message: Message, args: &[String]) -> Option<String> {
+    let table = TABLE.read().unwrap();
+    Some(substitute(table.as_ref()?.get(message.id)?, args))
+}
+
+/// Name of the controller operation `operation` in the language of the message table.
+pub(crate) fn operation(operation: &'static str) -> String {
+    let table = TABLE.read().unwrap();
+    table
+        .as_ref()
+        .and_then(|table| table.get(&format!("{}{}", OPERATION_PREFIX, operation)))
+        .unwrap_or(operation)
+        .to_string()
+}
+
+pub(crate) fn args(args: &[&dyn Display]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+fn substitute(text: &str, args: &[String]) -> String {
+    let mut text = text.to_string();
+    for (index, arg) in args.iter().enumerate() {
+        text = text.replace(&format!("{{{}}}", index), arg);
+    }
+    text
+}
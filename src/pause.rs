@@ -0,0 +1,37 @@
+// Only Windows services are paused, the other platforms never report a pause.
+#![cfg_attr(not(windows), allow(dead_code))]
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+static ACKNOWLEDGED: AtomicBool = AtomicBool::new(false);
+static REPORTER: Mutex<Option<Reporter>> = Mutex::new(None);
+
+type Reporter = Box<dyn Fn(bool) + Send>;
+
+pub(crate) fn set_acknowledged(acknowledged: bool) {
+    ACKNOWLEDGED.store(acknowledged, Ordering::SeqCst);
+}
+
+/// True when the service reports the completion of `Pause` and `Continue` itself, the
+/// dispatcher then leaves the service in a pending state until it does.
+pub(crate) fn is_acknowledged() -> bool {
+    ACKNOWLEDGED.load(Ordering::SeqCst)
+}
+
+/// Registers the function reporting to the system that the service is paused (`true`) or
+/// running again (`false`), the last service started in the process reports it.
+#[cfg(windows)]
+pub(crate) fn set_reporter(report: impl Fn(bool) + Send + 'static) {
+    *REPORTER.lock().unwrap() = Some(Box::new(report));
+}
+
+/// Reports the completion of `Pause` or `Continue` by the service, when it acknowledges them.
+pub(crate) fn completed(paused: bool) {
+    if !is_acknowledged() {
+        return;
+    }
+    if let Some(report) = &*REPORTER.lock().unwrap() {
+        report(paused);
+    }
+}
@@ -0,0 +1,72 @@
+// The dummy controller has no dispatcher, the service is never restarted there.
+#![cfg_attr(
+    not(any(
+        windows,
+        target_os = "macos",
+        target_os = "linux",
+        target_os = "illumos",
+        target_os = "solaris",
+        target_os = "aix"
+    )),
+    allow(dead_code)
+)]
+
+#[cfg(unix)]
+use std::env;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(unix)]
+use std::sync::OnceLock;
+
+#[cfg(unix)]
+use log::{error, info, logger};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+#[cfg(unix)]
+static EXECUTABLE: OnceLock<PathBuf> = OnceLock::new();
+
+pub(crate) fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub(crate) fn is_requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Records the path of the executable at startup, `/proc/self/exe` no longer resolves to it
+/// once a new binary replaced it.
+#[cfg(unix)]
+pub(crate) fn record_executable() {
+    if let Ok(executable) = env::current_exe() {
+        let _ = EXECUTABLE.set(executable);
+    }
+}
+
+/// Executes the service again in the current process when a restart was requested, called by
+/// the dispatcher once the service main function returned. The process keeps its pid so the
+/// supervisor doesn't notice the restart, the binary found at the original path is executed.
+/// Returns when no restart was requested or when it failed.
+#[cfg(unix)]
+pub(crate) fn reexec_if_requested() {
+    if !is_requested() {
+        return;
+    }
+    let executable = match EXECUTABLE.get() {
+        Some(executable) => executable,
+        None => {
+            error!("Failed to restart the service: the executable path is unknown");
+            return;
+        }
+    };
+
+    info!("Restarting {}", executable.display());
+    logger().flush();
+    // Files are opened with close-on-exec, the instance lock is released by `exec`.
+    let e = Command::new(executable).args(env::args_os().skip(1)).exec();
+    error!("Failed to restart {}: {}", executable.display(), e);
+}
@@ -0,0 +1,229 @@
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Bounded queue with pre-allocated slots that several threads push to and pop from without
+/// locks or allocations, so that it can be used from the service control handler. Every slot
+/// carries a sequence number telling whether it is free or holds a value for the current lap.
+pub(crate) struct Ring<T> {
+    slots: Box<[Slot<T>]>,
+    enqueue: AtomicUsize,
+    dequeue: AtomicUsize,
+}
+
+struct Slot<T> {
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// A value is only accessed by the thread that claimed its slot.
+unsafe impl<T: Send> Send for Ring<T> {}
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Ring<T> {
+    /// Creates a ring holding up to `capacity` values, at least two: with a single slot, a
+    /// full slot would have the sequence number of a free one.
+    pub(crate) fn new(capacity: usize) -> Ring<T> {
+        Ring {
+            slots: (0..capacity.max(2))
+                .map(|index| Slot {
+                    sequence: AtomicUsize::new(index),
+                    value: UnsafeCell::new(MaybeUninit::uninit()),
+                })
+                .collect(),
+            enqueue: AtomicUsize::new(0),
+            dequeue: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pushes `value`, which is given back when the ring is full.
+    pub(crate) fn push(&self, value: T) -> Result<(), T> {
+        let mut position = self.enqueue.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[position % self.slots.len()];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match sequence.wrapping_sub(position) as isize {
+                0 => match self.enqueue.compare_exchange_weak(
+                    position,
+                    position.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(value) };
+                        slot.sequence
+                            .store(position.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => position = current,
+                },
+                // The slot still holds the value of the previous lap.
+                difference if difference < 0 => return Err(value),
+                _ => position = self.enqueue.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Pops the oldest value, `None` when the ring is empty.
+    pub(crate) fn pop(&self) -> Option<T> {
+        let mut position = self.dequeue.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[position % self.slots.len()];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match sequence.wrapping_sub(position.wrapping_add(1)) as isize {
+                0 => match self.dequeue.compare_exchange_weak(
+                    position,
+                    position.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).assume_init_read() };
+                        slot.sequence
+                            .store(position.wrapping_add(self.slots.len()), Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(current) => position = current,
+                },
+                // The slot wasn't written in this lap yet.
+                difference if difference < 0 => return None,
+                _ => position = self.dequeue.load(Ordering::Relaxed),
+            }
+        }
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn fills_to_capacity() {
+        let ring = Ring::new(3);
+        for value in 0..3 {
+            assert_eq!(ring.push(value), Ok(()));
+        }
+        assert_eq!(ring.push(3), Err(3));
+    }
+
+    #[test]
+    fn capacity_is_at_least_two() {
+        let ring = Ring::new(0);
+        assert_eq!(ring.push(0), Ok(()));
+        assert_eq!(ring.push(1), Ok(()));
+        assert_eq!(ring.push(2), Err(2));
+    }
+
+    #[test]
+    fn pops_in_fifo_order() {
+        let ring = Ring::new(4);
+        assert_eq!(ring.pop(), None);
+        for value in 0..4 {
+            ring.push(value).unwrap();
+        }
+        for value in 0..4 {
+            assert_eq!(ring.pop(), Some(value));
+        }
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn wraps_around_across_laps() {
+        let ring = Ring::new(3);
+        for lap in 0..10 {
+            for value in 0..3 {
+                ring.push(lap * 3 + value).unwrap();
+            }
+            assert!(ring.push(0).is_err());
+            for value in 0..3 {
+                assert_eq!(ring.pop(), Some(lap * 3 + value));
+            }
+        }
+        // Interleaved pushes and pops move the positions off the slot boundaries.
+        for value in 0..100 {
+            ring.push(value).unwrap();
+            ring.push(value + 1000).unwrap();
+            assert_eq!(ring.pop(), Some(value));
+            assert_eq!(ring.pop(), Some(value + 1000));
+        }
+    }
+
+    #[test]
+    fn drops_the_queued_values() {
+        let value = Arc::new(());
+        let ring = Ring::new(4);
+        ring.push(value.clone()).unwrap();
+        ring.push(value.clone()).unwrap();
+        drop(ring.pop());
+        ring.push(value.clone()).unwrap();
+        assert_eq!(Arc::strong_count(&value), 3);
+        drop(ring);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn concurrent_pushes_and_pops() {
+        const PRODUCERS: usize = 4;
+        const CONSUMERS: usize = 4;
+        const VALUES: usize = 10_000;
+
+        let ring = Arc::new(Ring::new(8));
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|producer| {
+                let ring = ring.clone();
+                thread::spawn(move || {
+                    for index in 0..VALUES {
+                        let mut value = producer * VALUES + index;
+                        while let Err(rejected) = ring.push(value) {
+                            value = rejected;
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+        let consumers: Vec<_> = (0..CONSUMERS)
+            .map(|_| {
+                let ring = ring.clone();
+                thread::spawn(move || {
+                    let mut values = Vec::new();
+                    while values.len() < PRODUCERS * VALUES / CONSUMERS {
+                        match ring.pop() {
+                            Some(value) => values.push(value),
+                            None => thread::yield_now(),
+                        }
+                    }
+                    values
+                })
+            })
+            .collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        let mut received = Vec::new();
+        for consumer in consumers {
+            let values = consumer.join().unwrap();
+            // The values of a producer are popped in the order it pushed them.
+            for producer in 0..PRODUCERS {
+                let own: Vec<_> = values
+                    .iter()
+                    .filter(|value| *value / VALUES == producer)
+                    .collect();
+                assert!(own.windows(2).all(|pair| pair[0] < pair[1]));
+            }
+            received.extend(values);
+        }
+        received.sort_unstable();
+        assert_eq!(received, (0..PRODUCERS * VALUES).collect::<Vec<_>>());
+        assert_eq!(ring.pop(), None);
+    }
+}
@@ -0,0 +1,61 @@
+// The dummy controller has no dispatcher, the service is never cleaned up there.
+#![cfg_attr(
+    not(any(
+        windows,
+        target_os = "macos",
+        target_os = "linux",
+        target_os = "illumos",
+        target_os = "solaris",
+        target_os = "aix"
+    )),
+    allow(dead_code)
+)]
+
+use std::sync::Mutex;
+
+use log::{error, info};
+
+use crate::Error;
+
+type Cleanup = Box<dyn FnOnce() -> Result<(), Error> + Send>;
+
+static CLEANUP: Mutex<Option<Cleanup>> = Mutex::new(None);
+
+/// What the service wrapper does with a service that runs once, at the next boot, after its
+/// main function returned 0. The service is left as is when it fails so that it runs again at
+/// the following boot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunOnce {
+    /// The service stays installed but no longer starts at boot.
+    Disable,
+    /// The service is deleted.
+    Delete,
+}
+
+/// Registers the cleanup of a run-once service, `finish` is called on `controller` by the
+/// dispatcher once the service main function succeeded.
+pub(crate) fn set_run_once<C: Send + 'static>(
+    run_once: Option<RunOnce>,
+    controller: C,
+    finish: fn(&mut C, RunOnce) -> Result<(), Error>,
+) {
+    *CLEANUP.lock().unwrap() = run_once.map(|run_once| {
+        let mut controller = controller;
+        Box::new(move || finish(&mut controller, run_once)) as Cleanup
+    });
+}
+
+/// Disables or deletes a run-once service when its main function returned 0.
+pub(crate) fn service_main_returned(exit_code: u32) {
+    if exit_code != 0 {
+        return;
+    }
+    let cleanup = match CLEANUP.lock().unwrap().take() {
+        Some(cleanup) => cleanup,
+        None => return,
+    };
+    match cleanup() {
+        Ok(()) => info!("Run-once service completed, cleaned up"),
+        Err(e) => error!("Failed to clean up the run-once service: {}", e),
+    }
+}
@@ -0,0 +1,218 @@
+use std::fmt;
+#[cfg(windows)]
+use std::io;
+#[cfg(target_os = "linux")]
+use std::process::Command;
+use std::ptr;
+#[cfg(windows)]
+use std::slice;
+use std::sync::atomic::{compiler_fence, Ordering};
+
+#[cfg(windows)]
+use winapi::shared::minwindef::{DWORD, HLOCAL};
+#[cfg(windows)]
+use winapi::um::dpapi::{
+    CryptProtectData, CryptUnprotectData, CRYPTPROTECT_LOCAL_MACHINE, CRYPTPROTECT_UI_FORBIDDEN,
+};
+#[cfg(windows)]
+use winapi::um::winbase::LocalFree;
+#[cfg(windows)]
+use winapi::um::wincred::{CredFree, CredReadW, CRED_TYPE_GENERIC, PCREDENTIALW};
+#[cfg(windows)]
+use winapi::um::wincrypt::DATA_BLOB;
+
+#[cfg(any(windows, target_os = "linux"))]
+use crate::Error;
+
+/// Password or other secret given to a controller, such as the password of the account a
+/// Windows service runs as. Its buffer is overwritten with zeros when it is dropped and its
+/// `Debug` implementation doesn't show it.
+#[derive(Clone, Default)]
+pub struct Secret {
+    value: String,
+}
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Secret {
+        Secret {
+            value: value.into(),
+        }
+    }
+
+    #[cfg(windows)]
+    pub(crate) const fn empty() -> Secret {
+        Secret {
+            value: String::new(),
+        }
+    }
+
+    /// The secret in clear text, copies of it are not zeroized.
+    pub fn expose(&self) -> &str {
+        &self.value
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.value.is_empty()
+    }
+
+    /// Reads the password of the generic credential `target` from the Windows Credential
+    /// Manager, such as one stored with `cmdkey /generic:<target> /user:<user> /pass`. The
+    /// credential must belong to the user running the installer.
+    #[cfg(windows)]
+    pub fn from_credential(target: &str) -> Result<Secret, Error> {
+        let target: Vec<u16> = target.encode_utf16().chain(Some(0)).collect();
+        unsafe {
+            let mut credential: PCREDENTIALW = ptr::null_mut();
+            if CredReadW(target.as_ptr(), CRED_TYPE_GENERIC, 0, &mut credential) == 0 {
+                return Err(os_error("CredReadW"));
+            }
+            let blob = slice::from_raw_parts_mut(
+                (*credential).CredentialBlob,
+                (*credential).CredentialBlobSize as usize,
+            );
+            let mut wide: Vec<u16> = blob
+                .chunks(2)
+                .map(|pair| u16::from_le_bytes([pair[0], *pair.get(1).unwrap_or(&0)]))
+                .collect();
+            let value = String::from_utf16_lossy(&wide);
+            zeroize(&mut wide);
+            zeroize(blob);
+            CredFree(credential as _);
+            Ok(Secret::new(value))
+        }
+    }
+
+    /// Looks up the secret stored with the `attributes` in the Secret Service of the desktop
+    /// session (GNOME Keyring, KWallet) through the `secret-tool` command of libsecret.
+    #[cfg(target_os = "linux")]
+    pub fn from_secret_service(attributes: &[(&str, &str)]) -> Result<Secret, Error> {
+        let mut command = Command::new("secret-tool");
+        command.arg("lookup");
+        for (name, value) in attributes {
+            command.args([name, value]);
+        }
+        let output = command.output().map_err(|e| Error {
+            function: Some("secret-tool".to_string()),
+            ..Error::io("Failed to execute command secret-tool lookup", &e)
+        })?;
+        if !output.status.success() {
+            return Err(Error {
+                function: Some("secret-tool".to_string()),
+                ..Error::new("No secret matches the attributes in the Secret Service")
+            });
+        }
+        let mut value = String::from_utf8(output.stdout)
+            .map_err(|_| Error::new("The secret of the Secret Service isn't valid UTF-8"))?;
+        // `secret-tool` ends the secret with a newline when its output is a terminal only.
+        if value.ends_with('\n') {
+            value.pop();
+        }
+        Ok(Secret::new(value))
+    }
+
+    /// Encrypts the secret with DPAPI for the local machine, to store it in a configuration
+    /// file readable by the service whatever account it runs as.
+    #[cfg(windows)]
+    pub fn protect(&self) -> Result<Vec<u8>, Error> {
+        let mut input = DATA_BLOB {
+            cbData: self.value.len() as DWORD,
+            pbData: self.value.as_ptr() as *mut u8,
+        };
+        dpapi_call("CryptProtectData", |output| unsafe {
+            CryptProtectData(
+                &mut input,
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                CRYPTPROTECT_LOCAL_MACHINE | CRYPTPROTECT_UI_FORBIDDEN,
+                output,
+            )
+        })
+    }
+
+    /// Decrypts a secret encrypted by `protect()`.
+    #[cfg(windows)]
+    pub fn unprotect(data: &[u8]) -> Result<Secret, Error> {
+        let mut input = DATA_BLOB {
+            cbData: data.len() as DWORD,
+            pbData: data.as_ptr() as *mut u8,
+        };
+        let value = dpapi_call("CryptUnprotectData", |output| unsafe {
+            CryptUnprotectData(
+                &mut input,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                CRYPTPROTECT_UI_FORBIDDEN,
+                output,
+            )
+        })?;
+        String::from_utf8(value).map(Secret::new).map_err(|e| {
+            zeroize(&mut e.into_bytes());
+            Error::new("The decrypted secret isn't valid UTF-8")
+        })
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Secret::new(value)
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret::new(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        // Zeros are valid UTF-8.
+        unsafe { zeroize(self.value.as_mut_vec()) };
+    }
+}
+
+/// Overwrites `buffer` with zeros, the writes are not optimized away.
+pub(crate) fn zeroize<T: Copy + Default>(buffer: &mut [T]) {
+    for item in buffer.iter_mut() {
+        unsafe { ptr::write_volatile(item, T::default()) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Calls a DPAPI function and copies its output, the output buffer is zeroized before it is
+/// freed.
+#[cfg(windows)]
+fn dpapi_call(function: &str, call: impl FnOnce(*mut DATA_BLOB) -> i32) -> Result<Vec<u8>, Error> {
+    let mut output = DATA_BLOB {
+        cbData: 0,
+        pbData: ptr::null_mut(),
+    };
+    if call(&mut output) == 0 {
+        return Err(os_error(function));
+    }
+    unsafe {
+        let data = slice::from_raw_parts_mut(output.pbData, output.cbData as usize);
+        let value = data.to_vec();
+        zeroize(data);
+        LocalFree(output.pbData as HLOCAL);
+        Ok(value)
+    }
+}
+
+#[cfg(windows)]
+fn os_error(function: &str) -> Error {
+    Error {
+        function: Some(function.to_string()),
+        ..Error::from(io::Error::last_os_error())
+    }
+}
@@ -1,6 +1,36 @@
+use std::collections::hash_map::{self, HashMap};
 use std::fmt::{Display, Formatter, Result};
+#[cfg(target_os = "linux")]
+use std::hash::{Hash, Hasher};
+#[cfg(windows)]
+use std::io;
+#[cfg(windows)]
+use std::{mem, ptr, slice};
 
+#[cfg(windows)]
+use widestring::WideCString;
+#[cfg(windows)]
+use winapi::shared::minwindef::{BOOL, DWORD, FALSE, LPVOID};
+#[cfg(windows)]
+use winapi::um::handleapi::CloseHandle;
+#[cfg(windows)]
+use winapi::um::processthreadsapi::{CreateProcessAsUserW, PROCESS_INFORMATION, STARTUPINFOW};
+#[cfg(windows)]
+use winapi::um::winbase::{WTSGetActiveConsoleSessionId, CREATE_UNICODE_ENVIRONMENT};
+#[cfg(windows)]
+use winapi::um::winnt::{HANDLE, LPWSTR, PVOID};
+
+use crate::controller::Session;
+#[cfg(windows)]
+use crate::Error;
+use crate::ServiceEvent;
+
+/// Session reported by the session change events, a newtype over the session identifier of
+/// the platform: the session id on Windows, the user id on macOS and the logind session id on
+/// Linux. Sessions are ordered and hashed by identifier, to key the state of each session.
 #[non_exhaustive]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Session_<T: Display + PartialEq> {
     pub id: T,
 }
@@ -14,15 +44,6 @@ where
     }
 }
 
-impl<T> PartialEq for Session_<T>
-where
-    T: Display + PartialEq,
-{
-    fn eq(&self, other: &Self) -> bool {
-        self.id == other.id
-    }
-}
-
 impl<T> Session_<T>
 where
     T: Display + PartialEq,
@@ -31,3 +52,458 @@ where
         Session_ { id }
     }
 }
+
+impl Session_<u32> {
+    /// Identifier of the session on the platform.
+    pub fn raw(&self) -> u32 {
+        self.id
+    }
+
+    /// Number the dispatcher compares the sessions with, the id itself.
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn key(&self) -> u64 {
+        u64::from(self.id)
+    }
+}
+
+impl Session_<String> {
+    /// Identifier of the session on the platform.
+    pub fn raw(&self) -> &str {
+        &self.id
+    }
+
+    /// Number the dispatcher compares the sessions with, a hash of the name so that it isn't
+    /// copied. Two sessions whose names have the same hash are coalesced as the same one.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn key(&self) -> u64 {
+        let mut hasher = hash_map::DefaultHasher::new();
+        self.id.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl From<u32> for Session_<u32> {
+    fn from(id: u32) -> Self {
+        Session_::new(id)
+    }
+}
+
+impl From<Session_<u32>> for u32 {
+    fn from(session: Session_<u32>) -> Self {
+        session.id
+    }
+}
+
+impl From<String> for Session_<String> {
+    fn from(id: String) -> Self {
+        Session_::new(id)
+    }
+}
+
+impl From<&str> for Session_<String> {
+    fn from(id: &str) -> Self {
+        Session_::new(id.to_string())
+    }
+}
+
+/// State of each session of the system, created when a user logs on the session and dropped
+/// when they log off, for the services keeping resources per session such as the RDS-aware
+/// ones. Pass every event of the service to `update()`:
+///
+/// ```rust,ignore
+/// let mut sessions = SessionMap::new(|session| Tray::spawn(session));
+/// while let Ok(event) = rx.recv() {
+///     sessions.update(&event);
+///     ...
+/// }
+/// ```
+///
+/// A session already open when the service started gets its state from its first event.
+/// logind only reports the connection of the sessions, on Linux the state is created on
+/// `SessionConnect` and dropped on `SessionDisconnect`.
+pub struct SessionMap<S> {
+    sessions: HashMap<Session, S>,
+    create: Box<dyn FnMut(&Session) -> S + Send>,
+}
+
+impl<S> SessionMap<S> {
+    /// Map creating the state of a session with `create`.
+    pub fn new(create: impl FnMut(&Session) -> S + Send + 'static) -> SessionMap<S> {
+        SessionMap {
+            sessions: HashMap::new(),
+            create: Box::new(create),
+        }
+    }
+
+    /// Creates or drops the state of the session of `event`, returns the state of a session
+    /// that ended so that it can be cleaned up. The other events are ignored.
+    pub fn update<T>(&mut self, event: &ServiceEvent<T>) -> Option<S> {
+        let (session, ended) = match event {
+            ServiceEvent::SessionLogoff(session) => (session, true),
+            ServiceEvent::SessionDisconnect(session) => (session, cfg!(target_os = "linux")),
+            ServiceEvent::SessionConnect(session)
+            | ServiceEvent::SessionRemoteConnect(session)
+            | ServiceEvent::SessionRemoteDisconnect(session)
+            | ServiceEvent::SessionLogon(session)
+            | ServiceEvent::SessionLock(session)
+            | ServiceEvent::SessionUnlock(session)
+            | ServiceEvent::SessionIdle(session)
+            | ServiceEvent::SessionActive(session) => (session, false),
+            _ => return None,
+        };
+        if ended {
+            return self.sessions.remove(session);
+        }
+        if !self.sessions.contains_key(session) {
+            let state = (self.create)(session);
+            self.sessions.insert(session.clone(), state);
+        }
+        None
+    }
+
+    pub fn get(&self, session: &Session) -> Option<&S> {
+        self.sessions.get(session)
+    }
+
+    pub fn get_mut(&mut self, session: &Session) -> Option<&mut S> {
+        self.sessions.get_mut(session)
+    }
+
+    /// Drops the state of `session` before the session ends, it is created again by the next
+    /// event of the session.
+    pub fn remove(&mut self, session: &Session) -> Option<S> {
+        self.sessions.remove(session)
+    }
+
+    pub fn iter(&self) -> hash_map::Iter<'_, Session, S> {
+        self.sessions.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+}
+
+/// Information about a Windows session, queried from the Remote Desktop Services.
+#[cfg(windows)]
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    pub id: u32,
+    /// User logged on the session, empty before the logon.
+    pub user_name: String,
+    pub domain_name: String,
+    /// Name of the window station of the session, `Console` for the local console and names
+    /// such as `RDP-Tcp#3` for remote sessions.
+    pub station_name: String,
+    /// Name of the computer the remote session is connected from, empty for the console.
+    pub client_name: String,
+    pub connection: ConnectionType,
+}
+
+#[cfg(windows)]
+impl SessionInfo {
+    /// Whether the user is connected from another computer.
+    pub fn is_remote(&self) -> bool {
+        matches!(self.connection, ConnectionType::Rdp | ConnectionType::Ica)
+    }
+}
+
+/// How a Windows session is connected, see `SessionInfo`.
+#[cfg(windows)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionType {
+    /// Session attached to the physical console.
+    Console,
+    /// Remote Desktop Protocol session.
+    Rdp,
+    /// Citrix ICA session.
+    Ica,
+    /// Session attached neither to the console nor to a client, such as the session 0 of the
+    /// services or a console session another session took the console from.
+    Virtual,
+}
+
+/// Queries the information about the session `id`. The query is an RPC to the Remote Desktop
+/// Services that can take a while, use `query_session_info_async()` from the service control
+/// handler and the threads that must stay responsive.
+#[cfg(windows)]
+pub fn query_session_info(id: u32) -> std::result::Result<SessionInfo, Error> {
+    Ok(SessionInfo {
+        id,
+        user_name: query_string(id, WTS_USER_NAME)?,
+        domain_name: query_string(id, WTS_DOMAIN_NAME)?,
+        station_name: query_string(id, WTS_WIN_STATION_NAME)?,
+        client_name: query_string(id, WTS_CLIENT_NAME)?,
+        connection: query_connection(id)?,
+    })
+}
+
+/// Queries the information about the session `id` on a background worker and passes it to
+/// `callback` on the worker thread. The workers are shared by the whole process and started
+/// by the first query.
+#[cfg(windows)]
+pub fn query_session_info_async(
+    id: u32,
+    callback: impl FnOnce(std::result::Result<SessionInfo, Error>) + Send + 'static,
+) {
+    workers::spawn(Box::new(move || callback(query_session_info(id))));
+}
+
+/// Starts `command_line` in the session `id` as the user logged on it, on the default
+/// desktop, and returns the process id. Services don't have access to the desktop of the
+/// users since the session 0 isolation, this is how they display a user interface: the
+/// process started in the session can talk to the service over a pipe or a socket. Requires
+/// the service to run as LocalSystem.
+#[cfg(windows)]
+pub fn spawn_in_session(id: u32, command_line: &str) -> std::result::Result<u32, Error> {
+    let mut command_line = WideCString::from_str(command_line)
+        .map_err(|_| Error::new("The command line contains a nul character"))?
+        .into_vec_with_nul();
+    let mut desktop = WideCString::from_str("winsta0\\default")
+        .unwrap()
+        .into_vec_with_nul();
+
+    unsafe {
+        let mut token: HANDLE = ptr::null_mut();
+        if WTSQueryUserToken(id, &mut token) == 0 {
+            return Err(last_error("WTSQueryUserToken"));
+        }
+        // The process gets the environment of the user rather than the one of the service.
+        let mut environment: LPVOID = ptr::null_mut();
+        if CreateEnvironmentBlock(&mut environment, token, FALSE) == 0 {
+            environment = ptr::null_mut();
+        }
+
+        let mut startup_info: STARTUPINFOW = mem::zeroed();
+        startup_info.cb = mem::size_of::<STARTUPINFOW>() as DWORD;
+        startup_info.lpDesktop = desktop.as_mut_ptr();
+        let mut process_info: PROCESS_INFORMATION = mem::zeroed();
+        let created = CreateProcessAsUserW(
+            token,
+            ptr::null(),
+            command_line.as_mut_ptr(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            FALSE,
+            CREATE_UNICODE_ENVIRONMENT,
+            environment,
+            ptr::null(),
+            &mut startup_info,
+            &mut process_info,
+        );
+        let result = if created == 0 {
+            Err(last_error("CreateProcessAsUserW"))
+        } else {
+            CloseHandle(process_info.hThread);
+            CloseHandle(process_info.hProcess);
+            Ok(process_info.dwProcessId)
+        };
+        if !environment.is_null() {
+            DestroyEnvironmentBlock(environment);
+        }
+        CloseHandle(token);
+        result
+    }
+}
+
+// WTS_INFO_CLASS values.
+#[cfg(windows)]
+const WTS_USER_NAME: u32 = 5;
+#[cfg(windows)]
+const WTS_WIN_STATION_NAME: u32 = 6;
+#[cfg(windows)]
+const WTS_DOMAIN_NAME: u32 = 7;
+#[cfg(windows)]
+const WTS_CLIENT_NAME: u32 = 10;
+#[cfg(windows)]
+const WTS_CLIENT_PROTOCOL_TYPE: u32 = 16;
+#[cfg(windows)]
+const WTS_SESSION_INFO: u32 = 24;
+
+// WTS_CONNECTSTATE_CLASS value of the sessions a user is connected to.
+#[cfg(windows)]
+const WTS_ACTIVE: i32 = 0;
+
+#[cfg(windows)]
+#[repr(C)]
+struct WtsSessionInfo {
+    session_id: DWORD,
+    win_station_name: LPWSTR,
+    state: i32,
+}
+
+// WTSINFOW, the times are FILETIME values.
+#[cfg(windows)]
+#[repr(C)]
+struct WtsInfo {
+    state: i32,
+    session_id: DWORD,
+    counters: [DWORD; 6],
+    win_station_name: [u16; 32],
+    domain: [u16; 17],
+    user_name: [u16; 21],
+    connect_time: i64,
+    disconnect_time: i64,
+    last_input_time: i64,
+    logon_time: i64,
+    current_time: i64,
+}
+
+#[cfg(windows)]
+#[link(name = "wtsapi32")]
+extern "system" {
+    fn WTSQuerySessionInformationW(
+        hServer: HANDLE,
+        SessionId: DWORD,
+        WTSInfoClass: u32,
+        ppBuffer: *mut LPWSTR,
+        pBytesReturned: *mut DWORD,
+    ) -> BOOL;
+    fn WTSFreeMemory(pMemory: PVOID);
+    fn WTSQueryUserToken(SessionId: DWORD, phToken: *mut HANDLE) -> BOOL;
+    fn WTSEnumerateSessionsW(
+        hServer: HANDLE,
+        Reserved: DWORD,
+        Version: DWORD,
+        ppSessionInfo: *mut *mut WtsSessionInfo,
+        pCount: *mut DWORD,
+    ) -> BOOL;
+}
+
+#[cfg(windows)]
+#[link(name = "userenv")]
+extern "system" {
+    fn CreateEnvironmentBlock(lpEnvironment: *mut LPVOID, hToken: HANDLE, bInherit: BOOL) -> BOOL;
+    fn DestroyEnvironmentBlock(lpEnvironment: LPVOID) -> BOOL;
+}
+
+#[cfg(windows)]
+fn last_error(function: &str) -> Error {
+    Error {
+        function: Some(function.to_string()),
+        ..Error::from(io::Error::last_os_error())
+    }
+}
+
+#[cfg(windows)]
+fn query_string(id: u32, info_class: u32) -> std::result::Result<String, Error> {
+    let wide = query(id, info_class)?;
+    let length = wide.iter().position(|c| *c == 0).unwrap_or(wide.len());
+    Ok(String::from_utf16_lossy(&wide[..length]))
+}
+
+#[cfg(windows)]
+fn query_connection(id: u32) -> std::result::Result<ConnectionType, Error> {
+    // The protocol type is a USHORT, 0 for the console, 1 for ICA and 2 for RDP.
+    let protocol = query(id, WTS_CLIENT_PROTOCOL_TYPE)?.first().copied();
+    Ok(match protocol {
+        Some(1) => ConnectionType::Ica,
+        Some(2) => ConnectionType::Rdp,
+        Some(0) if id == unsafe { WTSGetActiveConsoleSessionId() } => ConnectionType::Console,
+        _ => ConnectionType::Virtual,
+    })
+}
+
+/// Time since the last input of the user of each active session. The sessions that don't
+/// report their last input time, such as the console session on older versions of Windows,
+/// are left out.
+#[cfg(windows)]
+pub(crate) fn idle_times() -> std::result::Result<Vec<(u32, std::time::Duration)>, Error> {
+    let mut sessions: *mut WtsSessionInfo = ptr::null_mut();
+    let mut count = 0;
+    if unsafe { WTSEnumerateSessionsW(ptr::null_mut(), 0, 1, &mut sessions, &mut count) } == 0 {
+        return Err(last_error("WTSEnumerateSessionsW"));
+    }
+    let active: Vec<u32> = unsafe { slice::from_raw_parts(sessions, count as usize) }
+        .iter()
+        .filter(|session| session.state == WTS_ACTIVE)
+        .map(|session| session.session_id)
+        .collect();
+    unsafe { WTSFreeMemory(sessions as PVOID) };
+
+    let mut idle_times = Vec::new();
+    for id in active {
+        let info = query(id, WTS_SESSION_INFO)?;
+        if info.len() * 2 < mem::size_of::<WtsInfo>() {
+            continue;
+        }
+        let info = unsafe { ptr::read_unaligned(info.as_ptr() as *const WtsInfo) };
+        if info.last_input_time == 0 {
+            continue;
+        }
+        // FILETIME values count 100 ns intervals.
+        let idle = (info.current_time - info.last_input_time).max(0) as u64;
+        idle_times.push((id, std::time::Duration::from_nanos(idle * 100)));
+    }
+    Ok(idle_times)
+}
+
+/// Queries the information `info_class` of the session `id`, as UTF-16 units.
+#[cfg(windows)]
+fn query(id: u32, info_class: u32) -> std::result::Result<Vec<u16>, Error> {
+    let mut buffer: LPWSTR = ptr::null_mut();
+    let mut bytes = 0;
+    // A null server handle is WTS_CURRENT_SERVER_HANDLE.
+    if unsafe {
+        WTSQuerySessionInformationW(ptr::null_mut(), id, info_class, &mut buffer, &mut bytes)
+    } == 0
+    {
+        return Err(last_error("WTSQuerySessionInformationW"));
+    }
+    let value = unsafe { slice::from_raw_parts(buffer, bytes as usize / 2).to_vec() };
+    unsafe { WTSFreeMemory(buffer as PVOID) };
+    Ok(value)
+}
+
+/// Threads running the session queries, so that neither the control handler nor the
+/// service main function waits for the Remote Desktop Services.
+#[cfg(windows)]
+mod workers {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::{mpsc, Arc, Mutex, OnceLock};
+    use std::thread;
+
+    use log::error;
+
+    use crate::controller::panic_message;
+
+    const WORKERS: usize = 2;
+
+    type Job = Box<dyn FnOnce() + Send>;
+
+    static QUEUE: OnceLock<Mutex<mpsc::Sender<Job>>> = OnceLock::new();
+
+    pub(super) fn spawn(job: Job) {
+        let queue = QUEUE.get_or_init(|| {
+            let (tx, rx) = mpsc::channel::<Job>();
+            let rx = Arc::new(Mutex::new(rx));
+            for index in 0..WORKERS {
+                let rx = rx.clone();
+                let _ = thread::Builder::new()
+                    .name(format!("ceviche-session-{}", index))
+                    .spawn(move || loop {
+                        // The lock is released before the job runs, a job that panics doesn't
+                        // take the worker down with it.
+                        let job = rx.lock().unwrap().recv();
+                        match job {
+                            Ok(job) => {
+                                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                                    error!(
+                                        "Session job panicked: {}",
+                                        panic_message(payload.as_ref())
+                                    );
+                                }
+                            }
+                            Err(_) => break,
+                        }
+                    });
+            }
+            Mutex::new(tx)
+        });
+        let _ = queue.lock().unwrap().send(job);
+    }
+}
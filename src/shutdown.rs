@@ -0,0 +1,110 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+static SERVICE_TOKEN: OnceLock<ShutdownToken> = OnceLock::new();
+
+#[derive(Debug, Default)]
+struct Inner {
+    shutdown: AtomicBool,
+    lock: Mutex<Vec<Waker>>,
+    condvar: Condvar,
+}
+
+/// Clonable token observing the shutdown of the service, an alternative to watching for the
+/// `Stop` and `StopNow` events that doesn't require passing the event receiver to the worker
+/// code. The token of the service is returned by `ServiceContext::shutdown_token()`.
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownToken {
+    inner: Arc<Inner>,
+}
+
+impl ShutdownToken {
+    /// Token that is only set by `shutdown()`, useful in standalone mode.
+    pub fn new() -> ShutdownToken {
+        ShutdownToken::default()
+    }
+
+    /// True once the shutdown started.
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Sets the token, waking up all the threads and tasks waiting on it.
+    pub fn shutdown(&self) {
+        let mut wakers = self.inner.lock.lock().unwrap();
+        self.inner.shutdown.store(true, Ordering::SeqCst);
+        for waker in wakers.drain(..) {
+            waker.wake();
+        }
+        self.inner.condvar.notify_all();
+    }
+
+    /// Blocks until the shutdown starts.
+    pub fn wait(&self) {
+        let mut wakers = self.inner.lock.lock().unwrap();
+        while !self.is_shutdown() {
+            wakers = self.inner.condvar.wait(wakers).unwrap();
+        }
+    }
+
+    /// Blocks until the shutdown starts or `timeout` elapses, returns true when the shutdown
+    /// started.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut wakers = self.inner.lock.lock().unwrap();
+        while !self.is_shutdown() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            wakers = self
+                .inner
+                .condvar
+                .wait_timeout(wakers, deadline - now)
+                .unwrap()
+                .0;
+        }
+        true
+    }
+
+    /// Future completing when the shutdown starts, for use in async code such as a branch of
+    /// `tokio::select!`. It doesn't depend on a particular runtime.
+    pub fn wait_async(&self) -> WaitShutdown {
+        WaitShutdown {
+            token: self.clone(),
+        }
+    }
+}
+
+/// Future returned by `ShutdownToken::wait_async()`.
+#[derive(Debug)]
+pub struct WaitShutdown {
+    token: ShutdownToken,
+}
+
+impl Future for WaitShutdown {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.token.is_shutdown() {
+            return Poll::Ready(());
+        }
+        let mut wakers = self.token.inner.lock.lock().unwrap();
+        if self.token.is_shutdown() {
+            return Poll::Ready(());
+        }
+        if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+/// Token of the service, set by the dispatcher on `Stop` and `StopNow`.
+pub(crate) fn service_token() -> &'static ShutdownToken {
+    SERVICE_TOKEN.get_or_init(ShutdownToken::new)
+}
@@ -0,0 +1,431 @@
+#[cfg(unix)]
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(unix)]
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info, warn};
+#[cfg(windows)]
+use winapi::shared::minwindef::{BOOL, DWORD, TRUE};
+
+#[cfg(windows)]
+use winapi::shared::minwindef::FALSE;
+#[cfg(windows)]
+use winapi::um::consoleapi::SetConsoleCtrlHandler;
+#[cfg(windows)]
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+#[cfg(windows)]
+use winapi::um::processthreadsapi::{GetCurrentProcessId, OpenProcess};
+#[cfg(windows)]
+use winapi::um::synchapi::WaitForSingleObject;
+#[cfg(windows)]
+use winapi::um::tlhelp32::{
+    CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W, TH32CS_SNAPPROCESS,
+};
+#[cfg(windows)]
+use winapi::um::winbase::INFINITE;
+#[cfg(windows)]
+use winapi::um::wincon::{CTRL_CLOSE_EVENT, CTRL_LOGOFF_EVENT, CTRL_SHUTDOWN_EVENT};
+#[cfg(windows)]
+use winapi::um::winnt::{HANDLE, SYNCHRONIZE};
+
+use crate::context::ServiceContext;
+use crate::controller::{panic_message, ServiceMain, STOP_DEADLINE_EXIT_CODE};
+use crate::{Error, ServiceEvent, StopReason};
+
+/// Interval between two checks of the parent process on Unix.
+#[cfg(unix)]
+const PARENT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Time Windows leaves to a console process before terminating it once the console closes,
+/// the user logs off or the system shuts down.
+#[cfg(windows)]
+const CONSOLE_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Command line argument of the self-test mode, see `self_test()`.
+pub const SELF_TEST_ARG: &str = "--self-test";
+/// Time the service main function has to return after `Stop` in a self-test.
+pub const SELF_TEST_STOP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Exit code of a self-test whose service main function panicked, the exit code of a process
+/// whose main thread panicked.
+pub const SELF_TEST_PANIC_EXIT_CODE: u32 = 101;
+
+pub(crate) type StopFn = Box<dyn Fn() + Send>;
+
+static STOP: Mutex<Option<StopFn>> = Mutex::new(None);
+static FINISHED: (Mutex<bool>, Condvar) = (Mutex::new(false), Condvar::new());
+#[cfg(unix)]
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Runs `service_main` in standalone mode, sending it `Stop` on Ctrl-C, Ctrl-Break, the close
+/// of the console window, logoff and shutdown on Windows and on `SIGINT`, `SIGTERM` and
+/// `SIGQUIT` on Unix. Returns the exit code of the service main function.
+///
+/// Windows terminates the process once the handler of a console close, logoff or shutdown
+/// returns, the handler waits for the service main function to return first. The handlers
+/// replace those installed with the `ctrlc` crate.
+///
+/// ```rust,ignore
+/// "standalone" => process::exit(standalone::run(SERVICE_NAME, vec![], my_service_main) as i32),
+/// ```
+pub fn run<T: Send + 'static, F: ServiceMain<T>>(
+    service_name: &str,
+    args: Vec<String>,
+    service_main: F,
+) -> u32 {
+    let (tx, rx) = mpsc::channel();
+    let stop = delayed_stop(tx.clone(), None);
+    run_with(service_name, args, service_main, (rx, tx), stop, false)
+}
+
+/// Runs `service_main` as the entrypoint of a container, like `run()`. When the process is
+/// PID 1 it also reaps the zombie processes it adopts, as an init process must: the service
+/// can't wait for its own children then, their exit status is collected by the wrapper.
+///
+/// `stop_delay` postpones the `Stop` event after `SIGTERM`, so that the service keeps serving
+/// while a load balancer or a preStop hook drains its traffic. The returned exit code of the
+/// service main function should be the exit code of the process:
+///
+/// ```rust,ignore
+/// "container" => process::exit(standalone::run_in_container(
+///     SERVICE_NAME,
+///     vec![],
+///     Some(Duration::from_secs(5)),
+///     my_service_main,
+/// ) as i32),
+/// ```
+pub fn run_in_container<T: Send + 'static, F: ServiceMain<T>>(
+    service_name: &str,
+    args: Vec<String>,
+    stop_delay: Option<Duration>,
+    service_main: F,
+) -> u32 {
+    let (tx, rx) = mpsc::channel();
+    let stop = delayed_stop(tx.clone(), stop_delay);
+    run_with(service_name, args, service_main, (rx, tx), stop, is_init())
+}
+
+/// Runs `service_main` in standalone mode for `run_for`, sends it `Stop` and checks that it
+/// exits cleanly, for package postinstall checks and smoke tests. Returns the exit code of
+/// the process: 0 when the service main function returned 0 within
+/// `SELF_TEST_STOP_TIMEOUT` of the `Stop` event, its exit code when it failed, 1 when it
+/// returned before `Stop`, `SELF_TEST_PANIC_EXIT_CODE` when it panicked and
+/// `STOP_DEADLINE_EXIT_CODE` when it didn't return in time. The service main function is
+/// left running in the last case, the process should exit right away:
+///
+/// ```rust,ignore
+/// if let Some(run_for) = standalone::self_test_duration(&args) {
+///     process::exit(standalone::self_test(SERVICE_NAME, vec![], run_for, my_service_main) as i32);
+/// }
+/// ```
+pub fn self_test<T: Send + 'static, F: ServiceMain<T>>(
+    service_name: &str,
+    args: Vec<String>,
+    run_for: Duration,
+    service_main: F,
+) -> u32 {
+    let (tx, rx) = mpsc::channel();
+    let (result_tx, result_rx) = mpsc::channel();
+    let ctx = ServiceContext::standalone(service_name, args);
+    let service_tx = tx.clone();
+    thread::spawn(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| service_main(rx, service_tx, ctx)));
+        let _ = result_tx.send(result.map_err(|payload| panic_message(&*payload).to_string()));
+    });
+
+    let result = match result_rx.recv_timeout(run_for) {
+        Ok(Ok(exit_code)) => {
+            error!(
+                "Self-test failed: the service exited with {} before it was stopped",
+                exit_code
+            );
+            return exit_code.max(1);
+        }
+        Ok(Err(message)) => Err(message),
+        Err(_) => {
+            info!("Self-test: stopping the service after {:?}", run_for);
+            let _ = tx.send(ServiceEvent::Stop {
+                reason: Some(StopReason::Requested),
+            });
+            match result_rx.recv_timeout(SELF_TEST_STOP_TIMEOUT) {
+                Ok(result) => result,
+                Err(_) => {
+                    error!(
+                        "Self-test failed: the service didn't exit within {:?} of the stop",
+                        SELF_TEST_STOP_TIMEOUT
+                    );
+                    return STOP_DEADLINE_EXIT_CODE;
+                }
+            }
+        }
+    };
+    match result {
+        Ok(0) => {
+            info!("Self-test passed");
+            0
+        }
+        Ok(exit_code) => {
+            error!("Self-test failed: the service exited with {}", exit_code);
+            exit_code
+        }
+        Err(message) => {
+            error!("Self-test failed: the service panicked: {}", message);
+            SELF_TEST_PANIC_EXIT_CODE
+        }
+    }
+}
+
+/// Duration of the self-test requested by `args`, `--self-test` for 5 seconds or
+/// `--self-test=<seconds>`. `None` when the self-test isn't requested or the duration isn't
+/// a number of seconds.
+pub fn self_test_duration(args: &[String]) -> Option<Duration> {
+    args.iter()
+        .find_map(|arg| match arg.strip_prefix(SELF_TEST_ARG)? {
+            "" => Some(Duration::from_secs(5)),
+            seconds => seconds
+                .strip_prefix('=')?
+                .parse()
+                .ok()
+                .map(Duration::from_secs),
+        })
+}
+
+/// Runs `service_main` with the `stop` function called on the stop requests, reaping the
+/// children of the process when `reap` is set.
+pub(crate) fn run_with<T: Send + 'static, F: ServiceMain<T>>(
+    service_name: &str,
+    args: Vec<String>,
+    service_main: F,
+    (rx, tx): (
+        mpsc::Receiver<ServiceEvent<T>>,
+        mpsc::Sender<ServiceEvent<T>>,
+    ),
+    stop: StopFn,
+    reap: bool,
+) -> u32 {
+    *STOP.lock().unwrap() = Some(stop);
+    if let Err(e) = handle_stop_requests(reap) {
+        warn!("Failed to handle the stop requests: {}", e);
+    }
+
+    let exit_code = service_main(rx, tx, ServiceContext::standalone(service_name, args));
+    *FINISHED.0.lock().unwrap() = true;
+    FINISHED.1.notify_all();
+    exit_code
+}
+
+/// Whether the process is the init process of its PID namespace, such as a container
+/// entrypoint.
+pub(crate) fn is_init() -> bool {
+    #[cfg(unix)]
+    return unsafe { libc::getpid() } == 1;
+    #[cfg(windows)]
+    return false;
+}
+
+fn delayed_stop<T: Send + 'static>(
+    tx: mpsc::Sender<ServiceEvent<T>>,
+    stop_delay: Option<Duration>,
+) -> StopFn {
+    Box::new(move || {
+        let tx = tx.clone();
+        let stop = move || {
+            let _ = tx.send(ServiceEvent::Stop {
+                reason: Some(StopReason::Requested),
+            });
+        };
+        match stop_delay {
+            Some(stop_delay) => {
+                thread::spawn(move || {
+                    thread::sleep(stop_delay);
+                    stop();
+                });
+            }
+            None => stop(),
+        }
+    })
+}
+
+fn request_stop() {
+    if let Some(stop) = STOP.lock().unwrap().as_ref() {
+        stop();
+    }
+}
+
+#[cfg(windows)]
+fn wait_finished(timeout: Duration) {
+    let finished = FINISHED.0.lock().unwrap();
+    let _ = FINISHED
+        .1
+        .wait_timeout_while(finished, timeout, |finished| !*finished);
+}
+
+#[cfg(windows)]
+fn handle_stop_requests(_reap: bool) -> Result<(), Error> {
+    if unsafe { SetConsoleCtrlHandler(Some(console_handler), TRUE) } == 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(Error {
+            function: Some("SetConsoleCtrlHandler".to_string()),
+            ..Error::io("Failed to set the console handler", &e)
+        });
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn console_handler(ctrl_type: DWORD) -> BOOL {
+    request_stop();
+    if let CTRL_CLOSE_EVENT | CTRL_LOGOFF_EVENT | CTRL_SHUTDOWN_EVENT = ctrl_type {
+        wait_finished(CONSOLE_CLOSE_TIMEOUT);
+    }
+    TRUE
+}
+
+// The signal handler only writes the signal to a pipe, the stop is requested and the children
+// are reaped by the thread reading it.
+#[cfg(unix)]
+fn handle_stop_requests(reap: bool) -> Result<(), Error> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(Error::io(
+            "Failed to create the signal pipe",
+            &io::Error::last_os_error(),
+        ));
+    }
+    for fd in fds {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    SIGNAL_PIPE.store(fds[1], Ordering::SeqCst);
+    let mut signals = vec![libc::SIGINT, libc::SIGTERM, libc::SIGQUIT];
+    if reap {
+        signals.push(libc::SIGCHLD);
+    }
+    for signal in signals {
+        unsafe {
+            libc::signal(
+                signal,
+                on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            )
+        };
+    }
+
+    thread::spawn(move || {
+        let mut byte = 0u8;
+        loop {
+            match unsafe { libc::read(fds[0], &mut byte as *mut u8 as *mut libc::c_void, 1) } {
+                1 if byte as libc::c_int == libc::SIGCHLD => reap_children(),
+                1 => request_stop(),
+                -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => (),
+                _ => break,
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(unix)]
+fn reap_children() {
+    let mut status = 0;
+    while unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) } > 0 {}
+}
+
+#[cfg(unix)]
+extern "C" fn on_signal(signal: libc::c_int) {
+    let byte = signal as u8;
+    unsafe {
+        libc::write(
+            SIGNAL_PIPE.load(Ordering::SeqCst),
+            &byte as *const u8 as *const libc::c_void,
+            1,
+        )
+    };
+}
+
+/// Sends `Stop` to the service once its parent process exited, so that an instance started in
+/// standalone mode by another supervisor or a debugger doesn't outlive it. The parent is
+/// watched by a thread, waiting on the parent process handle on Windows and checking the
+/// parent pid on Unix, where an orphan is adopted by another process.
+pub fn stop_on_parent_exit<T: Send + 'static>(
+    tx: mpsc::Sender<ServiceEvent<T>>,
+) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        let parent = unsafe { libc::getppid() };
+        thread::spawn(move || {
+            while unsafe { libc::getppid() } == parent {
+                thread::sleep(PARENT_POLL_INTERVAL);
+            }
+            let _ = tx.send(ServiceEvent::Stop {
+                reason: Some(StopReason::ParentExited),
+            });
+        });
+        Ok(())
+    }
+    #[cfg(windows)]
+    {
+        let parent = ParentProcess::open()?;
+        thread::spawn(move || {
+            parent.wait();
+            let _ = tx.send(ServiceEvent::Stop {
+                reason: Some(StopReason::ParentExited),
+            });
+        });
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+struct ParentProcess(HANDLE);
+
+#[cfg(windows)]
+unsafe impl Send for ParentProcess {}
+
+#[cfg(windows)]
+impl ParentProcess {
+    fn open() -> Result<ParentProcess, Error> {
+        unsafe {
+            let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0);
+            if snapshot == INVALID_HANDLE_VALUE {
+                return Err(Error::from(std::io::Error::last_os_error()));
+            }
+            let pid = GetCurrentProcessId();
+            let mut entry: PROCESSENTRY32W = std::mem::zeroed();
+            entry.dwSize = std::mem::size_of::<PROCESSENTRY32W>() as u32;
+            let mut parent_pid = None;
+            let mut found = Process32FirstW(snapshot, &mut entry);
+            while found != 0 {
+                if entry.th32ProcessID == pid {
+                    parent_pid = Some(entry.th32ParentProcessID);
+                    break;
+                }
+                found = Process32NextW(snapshot, &mut entry);
+            }
+            CloseHandle(snapshot);
+
+            let parent_pid =
+                parent_pid.ok_or_else(|| Error::new("Failed to find the parent process"))?;
+            let handle = OpenProcess(SYNCHRONIZE, FALSE, parent_pid);
+            if handle.is_null() {
+                let e = std::io::Error::last_os_error();
+                return Err(Error {
+                    function: Some("OpenProcess".to_string()),
+                    ..Error::io("Failed to open the parent process", &e)
+                });
+            }
+            Ok(ParentProcess(handle))
+        }
+    }
+
+    fn wait(&self) {
+        unsafe { WaitForSingleObject(self.0, INFINITE) };
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ParentProcess {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0) };
+    }
+}
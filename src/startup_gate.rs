@@ -0,0 +1,164 @@
+// Only the Windows wrapper reports the start of the service to the system.
+#![cfg_attr(not(windows), allow(dead_code))]
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock;
+
+/// Time a probe of `port_open()` or `http_ok()` waits for the connection and the response.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Interval between two evaluations of a gate.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Set by `ServiceContext::ready()`.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Condition the service wrapper waits for before it reports the service as running, so that
+/// the services depending on it only start once it is ready to serve. The service is reported
+/// as starting until then, the service main function runs meanwhile.
+///
+/// Gates are combined with `and()` and `or()`:
+///
+/// ```no_run
+/// use ceviche::StartupGate;
+///
+/// let gate = StartupGate::port_open("127.0.0.1:8080")
+///     .and(StartupGate::http_ok("http://127.0.0.1:8080/health"));
+/// ```
+#[derive(Clone)]
+pub struct StartupGate {
+    check: Arc<dyn Fn() -> bool + Send + Sync>,
+}
+
+impl StartupGate {
+    /// Gate open once `check` returns true, it is called every 250 ms.
+    pub fn custom(check: impl Fn() -> bool + Send + Sync + 'static) -> StartupGate {
+        StartupGate {
+            check: Arc::new(check),
+        }
+    }
+
+    /// Gate open once the service main function called `ServiceContext::ready()`, for
+    /// services that know when they are ready to serve, such as once their listening sockets
+    /// are bound. Without a gate the service is reported as running when its main function
+    /// starts.
+    pub fn ready() -> StartupGate {
+        StartupGate::custom(|| READY.load(Ordering::SeqCst))
+    }
+
+    /// Gate open once a TCP connection to `address`, such as `127.0.0.1:8080`, succeeds.
+    pub fn port_open(address: &str) -> StartupGate {
+        let address = address.to_string();
+        StartupGate::custom(move || {
+            resolve(&address)
+                .iter()
+                .any(|address| TcpStream::connect_timeout(address, PROBE_TIMEOUT).is_ok())
+        })
+    }
+
+    /// Gate open once `path` exists, such as a socket or a file the service writes when it is
+    /// ready.
+    pub fn file_exists(path: impl Into<PathBuf>) -> StartupGate {
+        let path = path.into();
+        StartupGate::custom(move || path.exists())
+    }
+
+    /// Gate open once a `GET` of the plain HTTP `url`, such as
+    /// `http://127.0.0.1:8080/health`, returns the status 200.
+    pub fn http_ok(url: &str) -> StartupGate {
+        let url = url.to_string();
+        StartupGate::custom(move || http_status(&url) == Some(200))
+    }
+
+    /// Gate open when both gates are.
+    pub fn and(self, other: StartupGate) -> StartupGate {
+        StartupGate::custom(move || self.is_open() && other.is_open())
+    }
+
+    /// Gate open when one of the gates is.
+    pub fn or(self, other: StartupGate) -> StartupGate {
+        StartupGate::custom(move || self.is_open() || other.is_open())
+    }
+
+    /// Evaluates the gate once.
+    pub fn is_open(&self) -> bool {
+        (self.check)()
+    }
+
+    /// Evaluates the gate until it opens, returns false when `timeout` elapsed or `cancelled`
+    /// returned true first. `pending` is called before each new evaluation.
+    pub(crate) fn wait(
+        &self,
+        timeout: Option<Duration>,
+        cancelled: impl Fn() -> bool,
+        pending: impl Fn(),
+    ) -> bool {
+        let clock = clock::clock();
+        let started = clock.elapsed();
+        loop {
+            if cancelled() {
+                return false;
+            }
+            if self.is_open() {
+                return true;
+            }
+            let elapsed = clock.elapsed().saturating_sub(started);
+            if timeout.is_some_and(|timeout| elapsed >= timeout) {
+                return false;
+            }
+            clock.sleep_until(clock.elapsed() + POLL_INTERVAL);
+            pending();
+        }
+    }
+}
+
+/// Opens the `StartupGate::ready()` gate.
+pub(crate) fn signal_ready() {
+    READY.store(true, Ordering::SeqCst);
+}
+
+fn resolve(address: &str) -> Vec<SocketAddr> {
+    address
+        .to_socket_addrs()
+        .map(|addresses| addresses.collect())
+        .unwrap_or_default()
+}
+
+/// Status code of a `GET` of `url`, `None` when the request failed.
+fn http_status(url: &str) -> Option<u16> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let address = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+
+    let mut stream = resolve(&address)
+        .iter()
+        .find_map(|address| TcpStream::connect_timeout(address, PROBE_TIMEOUT).ok())?;
+    stream.set_read_timeout(Some(PROBE_TIMEOUT)).ok()?;
+    stream.set_write_timeout(Some(PROBE_TIMEOUT)).ok()?;
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, authority
+    )
+    .ok()?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line).ok()?;
+    let mut fields = status_line.split_whitespace();
+    if !fields.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    fields.next()?.parse().ok()
+}
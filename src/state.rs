@@ -0,0 +1,142 @@
+// The dummy controller has no dispatcher, the registered states are never saved there.
+#![cfg_attr(
+    not(any(
+        windows,
+        target_os = "macos",
+        target_os = "linux",
+        target_os = "illumos",
+        target_os = "solaris",
+        target_os = "aix"
+    )),
+    allow(dead_code)
+)]
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use log::error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::context::ServiceContext;
+use crate::Error;
+
+type SaveHook = Box<dyn FnOnce() + Send>;
+
+static SAVE_HOOKS: Mutex<Vec<SaveHook>> = Mutex::new(Vec::new());
+
+/// State of the service kept across restarts in a JSON file, so that a service restarted by
+/// its recovery actions can resume the operation it was doing.
+#[derive(Debug)]
+pub struct StateStore<S> {
+    path: PathBuf,
+    state: PhantomData<fn() -> S>,
+}
+
+impl<S> Clone for StateStore<S> {
+    fn clone(&self) -> Self {
+        StateStore {
+            path: self.path.clone(),
+            state: PhantomData,
+        }
+    }
+}
+
+impl<S: Serialize + DeserializeOwned> StateStore<S> {
+    /// Store kept in `state.json` in the data directory of the service.
+    pub fn new(ctx: &ServiceContext) -> StateStore<S> {
+        StateStore::at(ctx.data_dir().join("state.json"))
+    }
+
+    /// Store kept in the file at `path`.
+    pub fn at<P: AsRef<Path>>(path: P) -> StateStore<S> {
+        StateStore {
+            path: path.as_ref().to_path_buf(),
+            state: PhantomData,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Loads the saved state, `None` when no state was saved.
+    pub fn load(&self) -> Result<Option<S>, Error> {
+        let content = match fs::read(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(Error::io(
+                    &format!("Failed to read {}", self.path.display()),
+                    &e,
+                ))
+            }
+        };
+        serde_json::from_slice(&content).map(Some).map_err(|e| {
+            Error::new(&format!(
+                "Failed to parse the state in {}: {}",
+                self.path.display(),
+                e
+            ))
+        })
+    }
+
+    /// Saves `state` atomically: it is written to a temporary file which then replaces the
+    /// previous state, a crash never leaves a partially written state behind.
+    pub fn save(&self, state: &S) -> Result<(), Error> {
+        let content = serde_json::to_vec(state)
+            .map_err(|e| Error::new(&format!("Failed to serialize the state: {}", e)))?;
+
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        let write = || -> io::Result<()> {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut file = File::create(&temp_path)?;
+            file.write_all(&content)?;
+            file.sync_all()?;
+            fs::rename(&temp_path, &self.path)
+        };
+        write().map_err(|e| Error::io(&format!("Failed to write {}", self.path.display()), &e))
+    }
+
+    /// Deletes the saved state, typically once the operation it describes completed.
+    pub fn clear(&self) -> Result<(), Error> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::io(
+                &format!("Failed to delete {}", self.path.display()),
+                &e,
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<S: Serialize + DeserializeOwned + Send + 'static> StateStore<S> {
+    /// Saves `state` when the service main function returns, after the service was asked to
+    /// stop. The state is not saved in standalone mode, where the service main function is not
+    /// called by the dispatcher.
+    pub fn save_on_stop(&self, state: Arc<Mutex<S>>) {
+        let store = self.clone();
+        SAVE_HOOKS.lock().unwrap().push(Box::new(move || {
+            let state = state.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Err(e) = store.save(&state) {
+                error!("{}", e);
+            }
+        }));
+    }
+}
+
+/// Saves the states registered with `save_on_stop()`, called by the dispatcher when the
+/// service main function returns.
+pub(crate) fn save_registered() {
+    let hooks = std::mem::take(&mut *SAVE_HOOKS.lock().unwrap());
+    for hook in hooks {
+        hook();
+    }
+}
@@ -0,0 +1,321 @@
+// The dummy controller has no dispatcher and doesn't report a status.
+#![cfg_attr(
+    not(any(
+        windows,
+        target_os = "macos",
+        target_os = "linux",
+        target_os = "illumos",
+        target_os = "solaris",
+        target_os = "aix"
+    )),
+    allow(dead_code)
+)]
+
+#[cfg(not(windows))]
+use std::fs;
+use std::ops::{BitAnd, BitOr, BitOrAssign};
+#[cfg(not(windows))]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(not(windows))]
+use log::warn;
+
+#[cfg(not(windows))]
+use crate::context::data_dir;
+use crate::Error;
+
+/// Interval between two status queries of a subscription when the system doesn't notify the
+/// status changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Status of an installed service, returned by `Controller::status()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceStatus {
+    /// State reported by the system, such as `running` on Windows, `active` with systemd or
+    /// `online` with SMF.
+    pub state: String,
+    /// Time the service last started, `None` when it never ran.
+    pub start_time: Option<SystemTime>,
+    /// Number of times the service started again after its first start, a quickly growing
+    /// count reveals a crash loop.
+    pub restart_count: u32,
+}
+
+impl ServiceStatus {
+    pub(crate) fn new(state: &str, record: Option<StartRecord>) -> ServiceStatus {
+        let record = record.unwrap_or_default();
+        ServiceStatus {
+            state: state.to_string(),
+            start_time: record.start_time(),
+            restart_count: record.start_count.saturating_sub(1),
+        }
+    }
+
+    /// True when the state is the running state of the service manager: `running` on Windows
+    /// and macOS, `active` with systemd and the AIX SRC, `run` with runit, `up` with s6 and
+    /// `online` or `degraded` with SMF.
+    pub fn is_running(&self) -> bool {
+        matches!(
+            self.state.as_str(),
+            "running" | "active" | "run" | "up" | "online" | "degraded"
+        )
+    }
+
+    /// Time elapsed since the service last started, only meaningful while it is running.
+    pub fn uptime(&self) -> Option<Duration> {
+        self.start_time.and_then(|time| time.elapsed().ok())
+    }
+
+    /// Describes the status as a JSON object with its `state`, the `start_time` and `uptime`
+    /// in seconds since the Unix epoch and since the start, and the `restart_count`.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> String {
+        let start_time = self
+            .start_time
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|time| time.as_secs());
+        serde_json::json!({
+            "state": self.state,
+            "start_time": start_time,
+            "uptime": self.uptime().map(|uptime| uptime.as_secs()),
+            "restart_count": self.restart_count,
+        })
+        .to_string()
+    }
+}
+
+/// State transitions of a service, returned by `Controller::subscribe_status()`. The current
+/// status is received first, followed by a status each time the state changes. The
+/// subscription ends after an error, the watching thread stops once it is dropped.
+pub struct StatusSubscription {
+    rx: mpsc::Receiver<Result<ServiceStatus, Error>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl StatusSubscription {
+    /// Runs `watch` on a new thread, it sends the statuses until it fails or the sender is
+    /// closed.
+    pub(crate) fn spawn<F>(watch: F) -> StatusSubscription
+    where
+        F: FnOnce(StatusSender) + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        let sender = StatusSender {
+            tx,
+            closed: closed.clone(),
+        };
+        thread::spawn(move || watch(sender));
+        StatusSubscription { rx, closed }
+    }
+
+    /// Waits for the next status for at most `timeout`, `None` when none was received or the
+    /// subscription ended.
+    pub fn next_timeout(&self, timeout: Duration) -> Option<Result<ServiceStatus, Error>> {
+        self.rx.recv_timeout(timeout).ok()
+    }
+}
+
+impl Iterator for StatusSubscription {
+    type Item = Result<ServiceStatus, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rx.recv().ok()
+    }
+}
+
+impl Drop for StatusSubscription {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+pub(crate) struct StatusSender {
+    tx: mpsc::Sender<Result<ServiceStatus, Error>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl StatusSender {
+    /// Sends a status, returns false when the subscription was dropped.
+    pub(crate) fn send(&self, status: Result<ServiceStatus, Error>) -> bool {
+        !self.is_closed() && self.tx.send(status).is_ok()
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+/// Subscribes to the state transitions of a service by querying its status with `query`
+/// every second.
+pub(crate) fn poll<F>(mut query: F) -> StatusSubscription
+where
+    F: FnMut() -> Result<ServiceStatus, Error> + Send + 'static,
+{
+    StatusSubscription::spawn(move |sender| {
+        let mut last_state = None;
+        while !sender.is_closed() {
+            match query() {
+                Ok(status) => {
+                    if last_state.as_ref() != Some(&status.state) {
+                        last_state = Some(status.state.clone());
+                        if !sender.send(Ok(status)) {
+                            return;
+                        }
+                    }
+                }
+                Err(e) => {
+                    sender.send(Err(e));
+                    return;
+                }
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    })
+}
+
+/// Controls accepted by a service, returned by `Controller::accepted_controls()`. The flags
+/// have the values of the Windows `SERVICE_ACCEPT_*` flags, a service that isn't running
+/// accepts no control. On the other platforms a running service accepts `STOP` and
+/// `PAUSE_CONTINUE`, its process is signaled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct AcceptedControls(u32);
+
+impl AcceptedControls {
+    /// The service can be stopped with `stop()`.
+    pub const STOP: AcceptedControls = AcceptedControls(0x1);
+    /// The service can be paused with `pause()` and resumed with `resume()`.
+    pub const PAUSE_CONTINUE: AcceptedControls = AcceptedControls(0x2);
+    pub const SHUTDOWN: AcceptedControls = AcceptedControls(0x4);
+    pub const PARAMCHANGE: AcceptedControls = AcceptedControls(0x8);
+    pub const NETBINDCHANGE: AcceptedControls = AcceptedControls(0x10);
+    pub const HARDWAREPROFILECHANGE: AcceptedControls = AcceptedControls(0x20);
+    pub const POWEREVENT: AcceptedControls = AcceptedControls(0x40);
+    pub const SESSIONCHANGE: AcceptedControls = AcceptedControls(0x80);
+    pub const PRESHUTDOWN: AcceptedControls = AcceptedControls(0x100);
+    pub const TIMECHANGE: AcceptedControls = AcceptedControls(0x200);
+    pub const TRIGGEREVENT: AcceptedControls = AcceptedControls(0x400);
+    pub const LOWRESOURCES: AcceptedControls = AcceptedControls(0x2000);
+    pub const SYSTEMLOWRESOURCES: AcceptedControls = AcceptedControls(0x4000);
+
+    pub const fn empty() -> AcceptedControls {
+        AcceptedControls(0)
+    }
+
+    /// Flags from `SERVICE_STATUS::dwControlsAccepted`, unknown flags are kept.
+    pub const fn from_bits(bits: u32) -> AcceptedControls {
+        AcceptedControls(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// True when all the flags of `other` are set.
+    pub const fn contains(self, other: AcceptedControls) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Controls of a running service on the platforms where it is signaled, or managed by a
+    /// custom backend.
+    pub(crate) fn signaled(running: bool) -> AcceptedControls {
+        if running {
+            AcceptedControls::STOP | AcceptedControls::PAUSE_CONTINUE
+        } else {
+            AcceptedControls::empty()
+        }
+    }
+}
+
+impl BitOr for AcceptedControls {
+    type Output = AcceptedControls;
+
+    fn bitor(self, other: AcceptedControls) -> AcceptedControls {
+        AcceptedControls(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for AcceptedControls {
+    fn bitor_assign(&mut self, other: AcceptedControls) {
+        self.0 |= other.0;
+    }
+}
+
+impl BitAnd for AcceptedControls {
+    type Output = AcceptedControls;
+
+    fn bitand(self, other: AcceptedControls) -> AcceptedControls {
+        AcceptedControls(self.0 & other.0)
+    }
+}
+
+/// Start time and number of starts of the service, updated by the service wrapper each time
+/// the service starts. It is stored in the `Parameters` key of the service on Windows and in
+/// a state file of the data directory on the other platforms.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct StartRecord {
+    /// Seconds since the Unix epoch.
+    pub start_time: u64,
+    pub start_count: u32,
+}
+
+impl StartRecord {
+    /// Record of a start happening now, following `self`.
+    pub(crate) fn next(self) -> StartRecord {
+        StartRecord {
+            start_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            start_count: self.start_count.saturating_add(1),
+        }
+    }
+
+    fn start_time(&self) -> Option<SystemTime> {
+        if self.start_count == 0 {
+            return None;
+        }
+        UNIX_EPOCH.checked_add(Duration::from_secs(self.start_time))
+    }
+}
+
+#[cfg(not(windows))]
+fn state_file(service_name: &str) -> PathBuf {
+    data_dir(service_name).join("start.state")
+}
+
+#[cfg(not(windows))]
+pub(crate) fn load_start_record(service_name: &str) -> Option<StartRecord> {
+    let content = fs::read_to_string(state_file(service_name)).ok()?;
+    let mut record = StartRecord::default();
+    for line in content.lines() {
+        match line.split_once('=') {
+            Some(("start_time", value)) => record.start_time = value.trim().parse().ok()?,
+            Some(("start_count", value)) => record.start_count = value.trim().parse().ok()?,
+            _ => (),
+        }
+    }
+    Some(record)
+}
+
+/// Updates the start record of the service, called by the dispatcher at startup.
+#[cfg(not(windows))]
+pub(crate) fn record_start(service_name: &str) {
+    let record = load_start_record(service_name).unwrap_or_default().next();
+    let path = state_file(service_name);
+    let content = format!(
+        "start_time={}\nstart_count={}\n",
+        record.start_time, record.start_count
+    );
+    if let Err(e) = fs::write(&path, content) {
+        warn!("Failed to write {}: {}", path.display(), e);
+    }
+}
@@ -0,0 +1,155 @@
+// The dummy controller has no dispatcher, stop requests never happen there.
+#![cfg_attr(
+    not(any(
+        windows,
+        target_os = "macos",
+        target_os = "linux",
+        target_os = "illumos",
+        target_os = "solaris",
+        target_os = "aix"
+    )),
+    allow(dead_code)
+)]
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::channel::EventSender;
+use crate::clock;
+use crate::{ServiceEvent, StopReason};
+
+static GRACE_PERIOD: Mutex<Option<Duration>> = Mutex::new(None);
+// Time of the clock of the timers at which `StopNow` is sent.
+static STOP_NOW_AT: Mutex<Option<Duration>> = Mutex::new(None);
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static PROGRESS: Mutex<Option<ProgressReporter>> = Mutex::new(None);
+
+type ProgressReporter = Box<dyn Fn(Option<Duration>) + Send>;
+
+pub(crate) fn set_grace_period(grace_period: Option<Duration>) {
+    *GRACE_PERIOD.lock().unwrap() = grace_period;
+}
+
+pub(crate) fn grace_period() -> Option<Duration> {
+    *GRACE_PERIOD.lock().unwrap()
+}
+
+/// Registers the function reporting the progress of a stop to the system: the remaining time
+/// until `StopNow`, or `None` when the stop was cancelled. The stop state is shared by the
+/// services of a process, the last service started reports it.
+#[cfg(windows)]
+pub(crate) fn set_progress_reporter(report: impl Fn(Option<Duration>) + Send + 'static) {
+    *PROGRESS.lock().unwrap() = Some(Box::new(report));
+}
+
+fn report_progress(remaining: Option<Duration>) {
+    if let Some(report) = &*PROGRESS.lock().unwrap() {
+        report(remaining);
+    }
+}
+
+/// Handles a stop request from the system. Without a grace period the service receives
+/// `Stop`. Otherwise it receives `StopRequested` and then `StopNow` when the grace period
+/// ends, or right away when the system insists with a second request.
+pub(crate) fn request<T: Send + 'static>(tx: &EventSender<T>, reason: StopReason) {
+    let grace_period = match grace_period() {
+        Some(grace_period) => grace_period,
+        None => {
+            return tx.send(ServiceEvent::Stop {
+                reason: Some(reason),
+            })
+        }
+    };
+    if REQUESTED.swap(true, Ordering::SeqCst) {
+        return stop_now(tx, reason);
+    }
+
+    let clock = clock::clock();
+    *STOP_NOW_AT.lock().unwrap() = Some(clock.elapsed() + grace_period);
+    tx.send(ServiceEvent::StopRequested);
+
+    let tx = tx.clone();
+    thread::spawn(move || loop {
+        let pending = {
+            let mut stop_now_at = STOP_NOW_AT.lock().unwrap();
+            match *stop_now_at {
+                Some(at) if at <= clock.elapsed() => {
+                    *stop_now_at = None;
+                    None
+                }
+                Some(at) => Some(at),
+                None => return,
+            }
+        };
+        match pending {
+            // `delay()` may have pushed back `StopNow` meanwhile, the time is checked again.
+            Some(at) => clock.sleep_until(at),
+            None => return tx.send(ServiceEvent::StopNow),
+        }
+    });
+}
+
+/// Sends `StopNow` without waiting for the end of the grace period, such as when the system
+/// is shutting down. Falls back to `Stop` without a grace period.
+pub(crate) fn stop_now<T>(tx: &EventSender<T>, reason: StopReason) {
+    if grace_period().is_none() {
+        return tx.send(ServiceEvent::Stop {
+            reason: Some(reason),
+        });
+    }
+    *STOP_NOW_AT.lock().unwrap() = None;
+    tx.send(ServiceEvent::StopNow);
+}
+
+/// Pushes back `StopNow` by `extra`, see `ServiceContext::delay_stop()`.
+pub(crate) fn delay(extra: Duration) {
+    let remaining = match STOP_NOW_AT.lock().unwrap().as_mut() {
+        Some(at) => {
+            *at += extra;
+            at.saturating_sub(clock::clock().elapsed())
+        }
+        None => return,
+    };
+    report_progress(Some(remaining));
+}
+
+/// Cancels the pending `StopNow`, see `ServiceContext::cancel_stop()`.
+pub(crate) fn cancel() {
+    if STOP_NOW_AT.lock().unwrap().take().is_none() {
+        return;
+    }
+    REQUESTED.store(false, Ordering::SeqCst);
+    report_progress(None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::{self, EventPolicy};
+    use crate::test::{self, FakeClock, SystemClock};
+
+    #[test]
+    fn stop_now_is_sent_once_the_clock_passed_the_grace_period() {
+        let clock = FakeClock::new();
+        test::set_clock(clock.clone());
+        set_grace_period(Some(Duration::from_secs(30)));
+        let (tx, rx, _tx) = channel::channel_with::<()>(EventPolicy::new());
+
+        request(&tx, StopReason::Requested);
+        let next = || rx.recv_timeout(Duration::from_millis(200));
+        assert!(matches!(next(), Ok(ServiceEvent::StopRequested)));
+        clock.advance(Duration::from_secs(20));
+        assert!(next().is_err());
+        delay(Duration::from_secs(15));
+        clock.advance(Duration::from_secs(20));
+        assert!(next().is_err());
+        clock.advance(Duration::from_secs(5));
+        assert!(matches!(next(), Ok(ServiceEvent::StopNow)));
+
+        set_grace_period(None);
+        REQUESTED.store(false, Ordering::SeqCst);
+        test::set_clock(SystemClock::new());
+    }
+}
@@ -0,0 +1,641 @@
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::channel::{self, EventPolicy};
+use crate::clock;
+use crate::context::ServiceContext;
+#[cfg(feature = "session-events")]
+use crate::controller::Session;
+use crate::controller::{panic_message, ServiceMain};
+use crate::{NetBindChange, PowerEvent, ServiceEvent, StopReason};
+
+pub use crate::clock::{Clock, SystemClock};
+
+/// Sets the clock of the timers of the crate: the grace period before `StopNow`, the stop
+/// deadline and the evaluations and timeout of the startup gates. With a `FakeClock` they
+/// fire once the test advanced it, the clock is shared by the whole test process.
+pub fn set_clock(clock: impl Clock + 'static) {
+    clock::set_clock(Arc::new(clock));
+}
+
+/// Clock whose time only moves when it is advanced or slept on, sleeping returns right away
+/// and the timers given it with `set_clock()` fire once it passes their deadline. The clones
+/// share the time, so that the script and the service under test see the same clock.
+#[derive(Clone, Debug, Default)]
+pub struct FakeClock {
+    now: Arc<Mutex<Duration>>,
+}
+
+impl FakeClock {
+    pub fn new() -> FakeClock {
+        FakeClock::default()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for FakeClock {
+    fn elapsed(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+/// Event of a `ScriptedRun`, sent `delay` after the previous one or after the start of the
+/// service main function for the first one.
+pub struct ScriptStep<T> {
+    pub delay: Duration,
+    pub event: ServiceEvent<T>,
+}
+
+/// Sequence of events fed to a service main function in standalone mode, for deterministic
+/// tests of the behavior of a service without installing it:
+///
+/// ```rust,ignore
+/// ScriptedRun::new("foobar")
+///     .after(Duration::from_millis(100), ServiceEvent::Pause)
+///     .after(Duration::from_secs(1), ServiceEvent::Stop { reason: Some(StopReason::Requested) })
+///     .run(my_service_main)
+///     .assert_exit_code(0)
+///     .assert_finished_within(Duration::from_secs(2));
+/// ```
+pub struct ScriptedRun<T> {
+    pub service_name: String,
+    pub args: Vec<String>,
+    pub steps: Vec<ScriptStep<T>>,
+    /// Time the service main function has to return, counted from its start. The timeout is
+    /// in real time whatever the clock, so that a service that hangs fails the test.
+    pub timeout: Duration,
+    /// Clock the delays of the steps and `RunOutcome::elapsed` are measured with.
+    pub clock: Arc<dyn Clock>,
+    /// Policy of the dispatcher the events go through, as in a service, for instance to
+    /// coalesce the session events. Without it they are sent to the service as is.
+    pub event_policy: Option<EventPolicy>,
+}
+
+impl<T: Send + 'static> ScriptedRun<T> {
+    pub fn new(service_name: &str) -> ScriptedRun<T> {
+        ScriptedRun {
+            service_name: service_name.to_string(),
+            args: Vec::new(),
+            steps: Vec::new(),
+            timeout: Duration::from_secs(10),
+            clock: Arc::new(SystemClock::new()),
+            event_policy: None,
+        }
+    }
+
+    /// Measures the delays of the steps with `clock`, such as a `FakeClock` shared with the
+    /// service main function or given to `set_clock()` for the timers of the crate.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> ScriptedRun<T> {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Sends the events through the dispatcher of the services with `policy`. As in a service,
+    /// a stop event also sets `ServiceContext::shutdown_token()`, for the rest of the process.
+    pub fn with_event_policy(mut self, policy: EventPolicy) -> ScriptedRun<T> {
+        self.event_policy = Some(policy);
+        self
+    }
+
+    /// Appends `event`, sent `delay` after the previous step.
+    pub fn after(mut self, delay: Duration, event: ServiceEvent<T>) -> ScriptedRun<T> {
+        self.steps.push(ScriptStep { delay, event });
+        self
+    }
+
+    /// Runs `service_main` on a thread with the context of the standalone mode, sends the
+    /// events of the script and waits for it to return up to `timeout`. The events left when
+    /// the service main function returns are not sent; with a `FakeClock` the steps follow
+    /// each other without waiting, the service may return after the next events were sent.
+    pub fn run<F: ServiceMain<T>>(self, service_main: F) -> RunOutcome {
+        let (dispatcher, rx, tx) = match self.event_policy {
+            Some(policy) => {
+                let (dispatcher, rx, tx) = channel::channel_with(policy);
+                (Some(dispatcher), rx, tx)
+            }
+            None => {
+                let (tx, rx) = mpsc::channel();
+                (None, rx, tx)
+            }
+        };
+        let (result_tx, result_rx) = mpsc::channel();
+        let ctx = ServiceContext::standalone(&self.service_name, self.args);
+        let service_tx = tx.clone();
+        let clock = self.clock.clone();
+        let start = clock.elapsed();
+        let deadline = Instant::now() + self.timeout;
+        thread::spawn(move || {
+            let result =
+                panic::catch_unwind(AssertUnwindSafe(|| service_main(rx, service_tx, ctx)));
+            let elapsed = clock.elapsed().saturating_sub(start);
+            let result = result.map_err(|payload| panic_message(&*payload).to_string());
+            let _ = result_tx.send((result, elapsed));
+        });
+
+        let mut events_sent = 0;
+        for step in self.steps {
+            self.clock.sleep(step.delay);
+            // The receiver is dropped once the service main function returned.
+            let sent = match &dispatcher {
+                Some(dispatcher) => dispatcher.deliver(step.event),
+                None => tx.send(step.event).is_ok(),
+            };
+            if !sent {
+                break;
+            }
+            events_sent += 1;
+        }
+        let (result, elapsed) =
+            match result_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok((result, elapsed)) => (Some(result), elapsed),
+                Err(_) => (None, self.clock.elapsed().saturating_sub(start)),
+            };
+        RunOutcome {
+            exit_code: result.clone().and_then(Result::ok),
+            panic: result.and_then(Result::err),
+            elapsed,
+            events_sent,
+        }
+    }
+}
+
+/// How the service main function of a `ScriptedRun` finished.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunOutcome {
+    /// Exit code returned by the service main function, `None` when it panicked or didn't
+    /// return within the timeout.
+    pub exit_code: Option<u32>,
+    /// Message of the panic of the service main function.
+    pub panic: Option<String>,
+    /// Time from the start of the service main function to its return, or to the timeout, on
+    /// the clock of the run.
+    pub elapsed: Duration,
+    /// Number of events of the script sent before the service main function returned.
+    pub events_sent: usize,
+}
+
+impl RunOutcome {
+    /// Panics unless the service main function returned `exit_code`.
+    pub fn assert_exit_code(&self, exit_code: u32) -> &RunOutcome {
+        if self.exit_code != Some(exit_code) {
+            panic!("expected the exit code {}, the service {}", exit_code, self);
+        }
+        self
+    }
+
+    /// Panics unless the service main function returned within `duration` of its start.
+    pub fn assert_finished_within(&self, duration: Duration) -> &RunOutcome {
+        if self.exit_code.is_none() || self.elapsed > duration {
+            panic!(
+                "expected the service to return within {:?}, it {}",
+                duration, self
+            );
+        }
+        self
+    }
+
+    /// Panics unless the service main function returned at least `duration` after its start.
+    pub fn assert_finished_after(&self, duration: Duration) -> &RunOutcome {
+        if self.exit_code.is_none() || self.elapsed < duration {
+            panic!(
+                "expected the service to return after {:?}, it {}",
+                duration, self
+            );
+        }
+        self
+    }
+}
+
+impl fmt::Display for RunOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.exit_code, &self.panic) {
+            (Some(exit_code), _) => write!(f, "returned {}", exit_code)?,
+            (None, Some(message)) => write!(f, "panicked: {}", message)?,
+            (None, None) => write!(f, "didn't return")?,
+        }
+        write!(
+            f,
+            " after {:?} and {} events",
+            self.elapsed, self.events_sent
+        )
+    }
+}
+
+/// Session of the current platform identified by `id`: the session id on Windows and macOS,
+/// the name of the logind session on Linux.
+#[cfg(feature = "session-events")]
+pub fn session(id: u32) -> Session {
+    #[cfg(target_os = "linux")]
+    return Session::new(id.to_string());
+    #[cfg(not(target_os = "linux"))]
+    return Session::new(id);
+}
+
+/// Every variant of `ServiceEvent`, including those only sent by some platforms, for tests
+/// checking that a service handles or ignores each of them. The session events are about the
+/// session 1, `custom` is sent as `Custom`.
+pub fn all_events<T>(custom: T) -> Vec<ServiceEvent<T>> {
+    let mut events = vec![
+        ServiceEvent::Continue,
+        ServiceEvent::Pause,
+        ServiceEvent::Stop { reason: None },
+        ServiceEvent::Stop {
+            reason: Some(StopReason::Requested),
+        },
+        ServiceEvent::Stop {
+            reason: Some(StopReason::SystemShutdown),
+        },
+        ServiceEvent::Stop {
+            reason: Some(StopReason::ParentExited),
+        },
+        ServiceEvent::Stop {
+            reason: Some(StopReason::StartupTimeout),
+        },
+        ServiceEvent::StopRequested,
+        ServiceEvent::StopNow,
+    ];
+    #[cfg(feature = "session-events")]
+    events.extend((0..SESSION_EVENTS).map(|index| session_event(index, session(1))));
+    events.extend([
+        ServiceEvent::NetBindChange(NetBindChange::Add),
+        ServiceEvent::NetBindChange(NetBindChange::Remove),
+        ServiceEvent::NetBindChange(NetBindChange::Enable),
+        ServiceEvent::NetBindChange(NetBindChange::Disable),
+        ServiceEvent::PowerEvent(PowerEvent::Suspend),
+        ServiceEvent::PowerEvent(PowerEvent::Resume),
+        ServiceEvent::LowResources,
+        ServiceEvent::SystemLowResources,
+        ServiceEvent::ClusterFailover {
+            group: "Cluster Group".to_string(),
+        },
+        ServiceEvent::DisplayChange {
+            width: 1920,
+            height: 1080,
+            bits_per_pixel: 32,
+        },
+        ServiceEvent::Custom(custom),
+    ]);
+    events
+}
+
+#[cfg(feature = "session-events")]
+const SESSION_EVENTS: u8 = 10;
+#[cfg(not(feature = "session-events"))]
+const SESSION_EVENTS: u8 = 0;
+// Variants decoded by `event_from_bytes()` besides the session events.
+const OTHER_EVENTS: u8 = 12;
+
+/// Decodes an event from arbitrary bytes, such as the input of a fuzzer, and returns it with
+/// the bytes left. The first byte selects the variant and the next ones its fields, missing
+/// bytes read as zeros. Strings and the bytes `custom` builds the custom event from are
+/// prefixed with their length. `None` when `data` is empty.
+pub fn event_from_bytes<T>(
+    data: &[u8],
+    custom: impl FnOnce(&[u8]) -> T,
+) -> Option<(ServiceEvent<T>, &[u8])> {
+    let (&selector, data) = data.split_first()?;
+    let mut input = Input(data);
+    let event = match selector % (OTHER_EVENTS + SESSION_EVENTS) {
+        0 => ServiceEvent::Continue,
+        1 => ServiceEvent::Pause,
+        2 => ServiceEvent::Stop {
+            reason: match input.byte() % 5 {
+                0 => None,
+                1 => Some(StopReason::Requested),
+                2 => Some(StopReason::SystemShutdown),
+                3 => Some(StopReason::ParentExited),
+                _ => Some(StopReason::StartupTimeout),
+            },
+        },
+        3 => ServiceEvent::StopRequested,
+        4 => ServiceEvent::StopNow,
+        5 => ServiceEvent::NetBindChange(match input.byte() % 4 {
+            0 => NetBindChange::Add,
+            1 => NetBindChange::Remove,
+            2 => NetBindChange::Enable,
+            _ => NetBindChange::Disable,
+        }),
+        6 => ServiceEvent::PowerEvent(match input.byte() % 2 {
+            0 => PowerEvent::Suspend,
+            _ => PowerEvent::Resume,
+        }),
+        7 => ServiceEvent::LowResources,
+        8 => ServiceEvent::SystemLowResources,
+        9 => ServiceEvent::ClusterFailover {
+            group: String::from_utf8_lossy(input.chunk()).into_owned(),
+        },
+        10 => ServiceEvent::DisplayChange {
+            width: input.u32(),
+            height: input.u32(),
+            bits_per_pixel: input.u32(),
+        },
+        11 => ServiceEvent::Custom(custom(input.chunk())),
+        #[cfg(feature = "session-events")]
+        index => session_event(index - OTHER_EVENTS, session(input.u32())),
+        #[cfg(not(feature = "session-events"))]
+        _ => unreachable!(),
+    };
+    Some((event, input.0))
+}
+
+/// Decodes the events of `data` one after the other with `event_from_bytes()`, until every
+/// byte is consumed.
+pub fn events_from_bytes<'a, T: 'a>(
+    mut data: &'a [u8],
+    mut custom: impl FnMut(&[u8]) -> T + 'a,
+) -> impl Iterator<Item = ServiceEvent<T>> + 'a {
+    std::iter::from_fn(move || {
+        let (event, rest) = event_from_bytes(data, &mut custom)?;
+        data = rest;
+        Some(event)
+    })
+}
+
+#[cfg(feature = "session-events")]
+fn session_event<T>(index: u8, session: Session) -> ServiceEvent<T> {
+    match index {
+        0 => ServiceEvent::SessionConnect(session),
+        1 => ServiceEvent::SessionDisconnect(session),
+        2 => ServiceEvent::SessionRemoteConnect(session),
+        3 => ServiceEvent::SessionRemoteDisconnect(session),
+        4 => ServiceEvent::SessionLogon(session),
+        5 => ServiceEvent::SessionLogoff(session),
+        6 => ServiceEvent::SessionLock(session),
+        7 => ServiceEvent::SessionUnlock(session),
+        8 => ServiceEvent::SessionIdle(session),
+        _ => ServiceEvent::SessionActive(session),
+    }
+}
+
+// Bytes left to decode, reading zeros past the end.
+struct Input<'a>(&'a [u8]);
+
+impl<'a> Input<'a> {
+    fn byte(&mut self) -> u8 {
+        match self.0.split_first() {
+            Some((&byte, rest)) => {
+                self.0 = rest;
+                byte
+            }
+            None => 0,
+        }
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes([self.byte(), self.byte(), self.byte(), self.byte()])
+    }
+
+    fn chunk(&mut self) -> &'a [u8] {
+        let len = (self.byte() as usize).min(self.0.len());
+        let (chunk, rest) = self.0.split_at(len);
+        self.0 = rest;
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::mem;
+
+    const STOP: ServiceEvent<u8> = ServiceEvent::Stop {
+        reason: Some(StopReason::Requested),
+    };
+
+    // Service main function returning once stopped, with the names of the events it received
+    // before the stop.
+    fn recording_service(received: Arc<Mutex<Vec<String>>>) -> impl ServiceMain<u8> {
+        move |rx: mpsc::Receiver<ServiceEvent<u8>>, _tx, _ctx| {
+            for event in rx {
+                received.lock().unwrap().push(event.to_string());
+                if let ServiceEvent::Stop { .. } = event {
+                    return 0;
+                }
+            }
+            1
+        }
+    }
+
+    #[test]
+    fn fake_clock_moves_when_advanced_or_slept_on() {
+        let clock = FakeClock::new();
+        let shared = clock.clone();
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+        clock.advance(Duration::from_secs(2));
+        shared.sleep(Duration::from_millis(500));
+        assert_eq!(clock.elapsed(), Duration::from_millis(2500));
+        assert_eq!(shared.elapsed(), Duration::from_millis(2500));
+    }
+
+    #[test]
+    fn system_clock_follows_the_real_time() {
+        let clock = SystemClock::new();
+        clock.sleep(Duration::from_millis(20));
+        assert!(clock.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn scripted_run_sends_the_events_in_order() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let outcome = ScriptedRun::new("foobar")
+            .with_clock(FakeClock::new())
+            .after(Duration::from_millis(100), ServiceEvent::Pause)
+            .after(Duration::from_millis(200), ServiceEvent::Continue)
+            .after(Duration::from_secs(1), STOP)
+            .run(recording_service(received.clone()));
+        outcome
+            .assert_exit_code(0)
+            .assert_finished_after(Duration::from_millis(1300))
+            .assert_finished_within(Duration::from_millis(1300));
+        assert_eq!(outcome.events_sent, 3);
+        assert_eq!(
+            *received.lock().unwrap(),
+            ["Pause", "Continue", "Stop(Requested)"]
+        );
+    }
+
+    #[test]
+    fn scripted_run_delays_follow_the_system_clock() {
+        let outcome = ScriptedRun::new("foobar")
+            .after(Duration::from_millis(50), STOP)
+            .run(recording_service(Arc::default()));
+        outcome
+            .assert_exit_code(0)
+            .assert_finished_after(Duration::from_millis(50));
+    }
+
+    #[test]
+    fn scripted_run_stops_sending_once_the_service_returned() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let outcome = ScriptedRun::new("foobar")
+            .after(Duration::ZERO, STOP)
+            .after(Duration::from_millis(200), ServiceEvent::Pause)
+            .after(Duration::from_millis(200), ServiceEvent::Continue)
+            .run(recording_service(received.clone()));
+        outcome.assert_exit_code(0);
+        assert_eq!(outcome.events_sent, 1);
+        assert_eq!(*received.lock().unwrap(), ["Stop(Requested)"]);
+    }
+
+    #[test]
+    fn scripted_run_reports_a_panic() {
+        let outcome = ScriptedRun::<u8>::new("foobar")
+            .run(|_rx: mpsc::Receiver<_>, _tx, _ctx| -> u32 { panic!("boom") });
+        assert_eq!(outcome.exit_code, None);
+        assert_eq!(outcome.panic.as_deref(), Some("boom"));
+        assert!(outcome.to_string().starts_with("panicked: boom"));
+    }
+
+    #[test]
+    fn scripted_run_times_out_when_the_service_hangs() {
+        let mut run = ScriptedRun::<u8>::new("foobar");
+        run.timeout = Duration::from_millis(50);
+        let outcome = run.run(|rx: mpsc::Receiver<_>, _tx, _ctx| {
+            // The script keeps its sender until the run times out.
+            let _ = rx.recv();
+            0
+        });
+        assert_eq!(outcome.exit_code, None);
+        assert_eq!(outcome.panic, None);
+        assert!(outcome.to_string().starts_with("didn't return"));
+    }
+
+    #[cfg(feature = "session-events")]
+    #[test]
+    fn scripted_run_coalesces_through_the_dispatcher() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let recorded = received.clone();
+        let outcome = ScriptedRun::new("foobar")
+            .with_clock(FakeClock::new())
+            .with_event_policy(EventPolicy {
+                coalesce_session_events: true,
+                ..EventPolicy::new()
+            })
+            .after(Duration::ZERO, ServiceEvent::SessionLock(session(1)))
+            .after(Duration::ZERO, ServiceEvent::SessionLock(session(1)))
+            .after(Duration::ZERO, ServiceEvent::SessionLock(session(2)))
+            .after(Duration::ZERO, ServiceEvent::SessionUnlock(session(2)))
+            .after(Duration::ZERO, ServiceEvent::SessionUnlock(session(2)))
+            .after(Duration::ZERO, ServiceEvent::Custom(0))
+            .run(move |rx: mpsc::Receiver<ServiceEvent<u8>>, _tx, _ctx| {
+                // A stop would set the shutdown token of the whole test process.
+                for event in rx {
+                    if let ServiceEvent::Custom(_) = event {
+                        return 0;
+                    }
+                    recorded.lock().unwrap().push(event.to_string());
+                }
+                1
+            });
+        outcome.assert_exit_code(0);
+        assert_eq!(outcome.events_sent, 6);
+        assert_eq!(
+            *received.lock().unwrap(),
+            [
+                ServiceEvent::<u8>::SessionLock(session(1)).to_string(),
+                ServiceEvent::<u8>::SessionLock(session(2)).to_string(),
+                ServiceEvent::<u8>::SessionUnlock(session(2)).to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "expected the exit code 1, the service returned 0")]
+    fn assert_exit_code_panics_on_another_exit_code() {
+        ScriptedRun::new("foobar")
+            .after(Duration::ZERO, STOP)
+            .run(recording_service(Arc::default()))
+            .assert_exit_code(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected the service to return within")]
+    fn assert_finished_within_panics_when_late() {
+        ScriptedRun::new("foobar")
+            .with_clock(FakeClock::new())
+            .after(Duration::from_secs(5), STOP)
+            .run(recording_service(Arc::default()))
+            .assert_finished_within(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn all_events_has_every_variant_once() {
+        let events = all_events(7u8);
+        let names: HashSet<_> = events.iter().map(ToString::to_string).collect();
+        assert_eq!(names.len(), events.len());
+        assert!(matches!(events.last(), Some(ServiceEvent::Custom(7))));
+    }
+
+    #[test]
+    fn event_from_bytes_decodes_every_variant() {
+        let decoded: HashSet<_> = (0..OTHER_EVENTS + SESSION_EVENTS)
+            .map(|selector| {
+                let (event, _) = event_from_bytes(&[selector], |_| 0u8).unwrap();
+                mem::discriminant(&event)
+            })
+            .collect();
+        let expected: HashSet<_> = all_events(0u8).iter().map(mem::discriminant).collect();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn event_from_bytes_reads_the_fields() {
+        assert!(event_from_bytes(&[], |_| ()).is_none());
+        let decode = |data: &[u8]| {
+            let (event, rest) = event_from_bytes(data, |bytes| bytes.to_vec()).unwrap();
+            (event.to_string(), rest.len())
+        };
+        assert_eq!(decode(&[0, 1]), ("Continue".to_string(), 1));
+        assert_eq!(decode(&[2, 1]), ("Stop(Requested)".to_string(), 0));
+        assert_eq!(decode(&[2]), ("Stop".to_string(), 0));
+        assert_eq!(
+            decode(&[10, 1, 0, 0, 0, 2, 0, 0, 0, 32, 0, 0, 0]),
+            ("DisplayChange(1x2x32)".to_string(), 0)
+        );
+        // Missing bytes read as zeros.
+        assert_eq!(decode(&[10, 1]), ("DisplayChange(1x0x0)".to_string(), 0));
+        assert_eq!(
+            decode(&[9, 3, b'a', b'b', b'c', 4]),
+            ("ClusterFailover(abc)".to_string(), 1)
+        );
+        // The selector wraps around the variants.
+        assert_eq!(
+            decode(&[OTHER_EVENTS + SESSION_EVENTS + 1]),
+            ("Pause".to_string(), 0)
+        );
+
+        // The length of a chunk is clipped to the bytes left.
+        let (event, rest) = event_from_bytes(&[11, 10, 1, 2], |bytes| bytes.to_vec()).unwrap();
+        assert!(matches!(event, ServiceEvent::Custom(bytes) if bytes == [1, 2]));
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn events_from_bytes_consumes_every_byte() {
+        let events: Vec<_> = events_from_bytes(&[1, 0, 3, 2, 1, 11, 1, 9], |bytes| bytes[0])
+            .map(|event| event.to_string())
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "Pause",
+                "Continue",
+                "StopRequested",
+                "Stop(Requested)",
+                "Custom"
+            ]
+        );
+        assert_eq!(events_from_bytes(&[], |_| ()).count(), 0);
+    }
+}
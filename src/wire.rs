@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, ServiceEvent};
+
+/// Version of the wire format produced by `Envelope::new()`.
+pub const WIRE_VERSION: u32 = 1;
+
+/// Event as sent over a pipe or socket by control tooling. The envelope is independent of the
+/// serde data format; the version lets both ends detect an incompatible peer.
+///
+/// ```rust,ignore
+/// let message = serde_json::to_string(&Envelope::new(ServiceEvent::Custom(Reload)))?;
+/// let event: ServiceEvent<Reload> = serde_json::from_str::<Envelope<_>>(&message)?.into_event()?;
+/// ```
+#[derive(Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub version: u32,
+    pub event: ServiceEvent<T>,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(event: ServiceEvent<T>) -> Self {
+        Envelope {
+            version: WIRE_VERSION,
+            event,
+        }
+    }
+
+    /// Returns the event, or an error when the envelope was produced with another version of
+    /// the wire format.
+    pub fn into_event(self) -> Result<ServiceEvent<T>, Error> {
+        if self.version != WIRE_VERSION {
+            return Err(Error::new(&format!(
+                "Unsupported wire format version {} (expected {})",
+                self.version, WIRE_VERSION
+            )));
+        }
+        Ok(self.event)
+    }
+}